@@ -1,20 +1,26 @@
+use std::io;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Duration;
 
 use bytes::BytesMut;
 use slsk_rs::constants::{ConnectionType, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, TransferDirection};
-use slsk_rs::file::{FileOffset, FileTransferInit};
-use slsk_rs::peer::{PeerMessage, SearchResultFile, read_peer_message};
+use slsk_rs::file::{DownloadConfig, FileOffset, FileTransferInit};
+use slsk_rs::net::{Connector, TransferTimeouts};
+use slsk_rs::peer::{
+    FileAttributes, PeerAvailability, PeerMessage, RankCandidate, RankOptions, SearchResultFile, rank_search_results,
+    read_peer_message,
+};
 use slsk_rs::peer_init::{PeerInitMessage, write_peer_init_message};
 use slsk_rs::protocol::MessageWrite;
-use slsk_rs::server::{ServerRequest, ServerResponse, read_server_message};
+use slsk_rs::server::{
+    BackoffConfig, MessageStream, PeerAddress, SEARCH_SESSION_TIMEOUT, SearchSession,
+    ServerProfile, ServerRequest, ServerResponse, connect_with_backoff,
+};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::time::timeout;
 
 static TOKEN_COUNTER: AtomicU32 = AtomicU32::new(1);
@@ -23,9 +29,6 @@ fn next_token() -> u32 {
     TOKEN_COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
-const AGGREGATION_TIMEOUT: Duration = Duration::from_secs(8);
-const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
-const TRANSFER_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
 const RECONNECT_DELAY: Duration = Duration::from_secs(10);
 const MAX_RETRIES: u32 = 3;
 const MAX_CANDIDATES: usize = 10;
@@ -34,6 +37,17 @@ const MAX_CANDIDATES: usize = 10;
 struct AccumulatedResult {
     username: String,
     file: SearchResultFile,
+    availability: PeerAvailability,
+}
+
+impl RankCandidate for AccumulatedResult {
+    fn file(&self) -> &SearchResultFile {
+        &self.file
+    }
+
+    fn availability(&self) -> Option<PeerAvailability> {
+        Some(self.availability)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -250,169 +264,251 @@ async fn fetch_spotify_playlist(token: &str, playlist_id: &str) -> anyhow::Resul
     Ok((playlist_name, tracks))
 }
 
-fn get_bitrate(attributes: &[slsk_rs::peer::FileAttribute]) -> Option<u32> {
-    attributes.iter().find(|a| a.code == 0).map(|a| a.value)
-}
-
 fn pick_best_files<'a>(results: &'a [AccumulatedResult], exclude_users: &[String]) -> Vec<&'a AccumulatedResult> {
-    let audio_exts = [
-        ".mp3", ".flac", ".m4a", ".ogg", ".opus", ".wav", ".aac", ".wma", ".ape", ".alac", ".aiff",
-        ".aif", ".wv", ".mpc",
-    ];
-
-    let mut candidates: Vec<_> = results
-        .iter()
-        .filter(|r| {
-            let lower = r.file.filename.to_lowercase();
-            audio_exts.iter().any(|ext| lower.ends_with(ext))
-                && !exclude_users.contains(&r.username)
-        })
-        .collect();
-
-    if candidates.is_empty() {
-        return Vec::new();
-    }
-
-    candidates.sort_by(|a, b| {
-        let a_bitrate_opt = get_bitrate(&a.file.attributes);
-        let b_bitrate_opt = get_bitrate(&b.file.attributes);
-
-        let a_is_flac = a.file.filename.to_lowercase().ends_with(".flac");
-        let b_is_flac = b.file.filename.to_lowercase().ends_with(".flac");
-
-        // Prefer files with bitrate info
-        let a_has_bitrate = a_bitrate_opt.is_some() || a_is_flac;
-        let b_has_bitrate = b_bitrate_opt.is_some() || b_is_flac;
-        if a_has_bitrate != b_has_bitrate {
-            return b_has_bitrate.cmp(&a_has_bitrate);
-        }
-
-        // Prefer FLAC
-        if a_is_flac != b_is_flac {
-            return b_is_flac.cmp(&a_is_flac);
-        }
-
-        // Higher bitrate wins
-        let a_bitrate = a_bitrate_opt.unwrap_or(0);
-        let b_bitrate = b_bitrate_opt.unwrap_or(0);
-        b_bitrate.cmp(&a_bitrate)
-    });
+    let candidates = results.iter().filter(|r| !exclude_users.contains(&r.username));
 
     // Return top candidates (unique users)
     let mut seen_users = std::collections::HashSet::new();
-    candidates
+    let options = RankOptions {
+        prefer_available: true,
+        ..RankOptions::default()
+    };
+    rank_search_results(candidates, &options)
         .into_iter()
         .filter(|c| seen_users.insert(c.username.clone()))
         .take(MAX_CANDIDATES)
         .collect()
 }
 
-struct SoulseekClient {
-    stream: TcpStream,
-    read_buf: BytesMut,
-    username: String,
+/// One line describing a candidate: uploader, file name, quality and size.
+fn describe_match(result: &AccumulatedResult) -> String {
+    let filename = &result.file.filename;
+    let quality = if filename.to_lowercase().ends_with(".flac") {
+        "FLAC".to_string()
+    } else {
+        let bitrate = FileAttributes::new(&result.file.attributes).bitrate();
+        format!("{}kbps", bitrate.unwrap_or(0))
+    };
+    format!(
+        "[{}] {} ({} {:.1}MB)",
+        result.username,
+        filename.rsplit(['/', '\\']).next().unwrap_or(filename),
+        quality,
+        result.file.size as f64 / 1_000_000.0
+    )
 }
 
-impl SoulseekClient {
-    async fn connect_with_retry(username: &str, password: &str, max_attempts: u32) -> anyhow::Result<Self> {
-        let mut attempt = 0;
-        loop {
-            attempt += 1;
-            match Self::connect_once(username, password).await {
-                Ok(client) => return Ok(client),
+/// The best match found for each track, as printed by `--dry-run`.
+fn format_match_summary(matches: &[(String, Option<AccumulatedResult>)]) -> String {
+    let matched = matches.iter().filter(|(_, best)| best.is_some()).count();
+    let mut lines = vec![
+        "========================================".to_string(),
+        format!("DRY RUN: {}/{} tracks matched", matched, matches.len()),
+        "========================================".to_string(),
+    ];
+    for (i, (track, best)) in matches.iter().enumerate() {
+        lines.push(format!("{}. {}", i + 1, track));
+        match best {
+            Some(best) => lines.push(format!("   {}", describe_match(best))),
+            None => lines.push("   ✗ No match".to_string()),
+        }
+    }
+    lines.join("\n")
+}
+
+/// Outcome of searching for one track.
+enum SearchOutcome {
+    /// Ranked best first, one per user; empty if nothing usable was found.
+    Candidates(Vec<AccumulatedResult>),
+    /// The search failed but the server connection was re-established.
+    Reconnected,
+    /// The search failed and so did reconnecting.
+    Failed(String),
+}
+
+/// Search for `query` and rank the results, reconnecting if the search fails.
+async fn search_track(
+    client: &mut SoulseekClient,
+    username: &str,
+    password: &str,
+    query: &str,
+    exclude_users: &[String],
+) -> SearchOutcome {
+    let results = match client.search(query).await {
+        Ok(r) => r,
+        Err(e) => {
+            println!("  ✗ Search failed: {}", e);
+
+            // Reconnect on any error with delay
+            println!("  Waiting {}s before reconnecting...", RECONNECT_DELAY.as_secs());
+            tokio::time::sleep(RECONNECT_DELAY).await;
+
+            return match SoulseekClient::connect(username, password).await {
+                Ok(new_client) => {
+                    *client = new_client;
+                    SearchOutcome::Reconnected
+                }
                 Err(e) => {
-                    let err_str = e.to_string();
-                    if attempt >= max_attempts {
-                        return Err(e);
-                    }
-                    // If rate limited, wait longer
-                    let delay = if err_str.contains("rate limit") || err_str.contains("reset") {
-                        Duration::from_secs(30 * attempt as u64)
-                    } else {
-                        // Exponential backoff: 10, 20, 40, 60 seconds
-                        Duration::from_secs((10 * 2u64.pow((attempt - 1).min(2))).min(60))
-                    };
-                    println!("  Connect attempt {}/{} failed: {}", attempt, max_attempts, e);
-                    println!("  Retrying in {}s...", delay.as_secs());
-                    tokio::time::sleep(delay).await;
+                    println!("  ✗ Reconnect failed: {}", e);
+                    println!("  Waiting {}s before retry...", RECONNECT_DELAY.as_secs());
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    SearchOutcome::Failed(e.to_string())
                 }
-            }
+            };
         }
-    }
+    };
+    println!("  Found {} results", results.len());
 
-    async fn connect(username: &str, password: &str) -> anyhow::Result<Self> {
-        Self::connect_with_retry(username, password, 5).await
-    }
+    let candidates = pick_best_files(&results, exclude_users);
+    SearchOutcome::Candidates(candidates.into_iter().cloned().collect())
+}
 
-    async fn connect_once(username: &str, password: &str) -> anyhow::Result<Self> {
-        let server_host = std::env::var("SOULSEEK_SERVER").unwrap_or_else(|_| DEFAULT_SERVER_HOST.to_string());
-        let server_port: u16 = std::env::var("SOULSEEK_PORT")
-            .ok()
-            .and_then(|p| p.parse().ok())
-            .unwrap_or(DEFAULT_SERVER_PORT);
-        
-        println!("Connecting to {}:{}...", server_host, server_port);
-        let mut stream = TcpStream::connect((&*server_host, server_port)).await?;
-        stream.set_nodelay(true)?;
-        println!("Connected!");
-
-        let login = ServerRequest::Login {
-            username: username.to_string(),
-            password: password.to_string(),
-            version: 160,
-            minor_version: 3,
+/// Try each candidate in turn until one downloads, returning whether any did.
+async fn download_track(
+    client: &mut SoulseekClient,
+    username: &str,
+    password: &str,
+    download: &mut TrackDownload,
+    candidates: &[AccumulatedResult],
+    config: &DownloadConfig,
+) -> bool {
+    for (candidate_idx, best) in candidates.iter().enumerate() {
+        let matched = MatchedFile {
+            username: best.username.clone(),
+            filename: best.file.filename.clone(),
+            size: best.file.size,
         };
 
-        let mut buf = BytesMut::new();
-        login.write_message(&mut buf);
-        stream.write_all(&buf).await?;
-        stream.flush().await?;
+        println!("  Trying [{}/{}]: {}", candidate_idx + 1, candidates.len(), describe_match(best));
 
-        let mut read_buf = BytesMut::with_capacity(65536);
+        download.tried_users.push(matched.username.clone());
+        download.status = DownloadStatus::Downloading;
 
-        loop {
-            let read_result = timeout(Duration::from_secs(30), stream.read_buf(&mut read_buf)).await;
-            match read_result {
-                Err(_) => anyhow::bail!("Timeout waiting for login response"),
-                Ok(Err(e)) => anyhow::bail!("Read error: {}", e),
-                Ok(Ok(0)) => anyhow::bail!("Connection closed during login (server may be rate limiting)"),
-                Ok(Ok(_)) => {}
+        match client.download_file(&matched, config).await {
+            Ok(path) => {
+                println!("  ✓ Saved to {:?}", path);
+                return true;
+            }
+            Err(e) => {
+                println!("    ✗ Failed: {}", e);
+
+                // Reconnect if connection issues
+                if is_connection_lost(&e) {
+                    println!("    Waiting {}s before reconnecting...", RECONNECT_DELAY.as_secs());
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    if let Ok(new_client) = SoulseekClient::connect(username, password).await {
+                        *client = new_client;
+                    }
+                }
             }
+        }
+    }
+    false
+}
 
-            if read_buf.len() >= 4 {
-                let msg_len = u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]]) as usize;
+/// Whether `e` came from a connection that broke or was closed under us.
+fn is_connection_lost(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        let io_error = match cause.downcast_ref::<slsk_rs::Error>() {
+            Some(slsk_rs::Error::Io(e)) => Some(e),
+            _ => cause.downcast_ref::<io::Error>(),
+        };
+        io_error.is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
 
-                if read_buf.len() >= 4 + msg_len {
-                    let mut msg_buf = read_buf.split_to(4 + msg_len);
+/// Count a failed attempt, giving up on the track once its retries are used up.
+///
+/// Returns whether the track has now failed for good.
+fn retry_or_fail(download: &mut TrackDownload, reason: &str) -> bool {
+    download.retry_count += 1;
+    if download.retry_count > MAX_RETRIES {
+        download.status = DownloadStatus::Failed(reason.to_string());
+        true
+    } else {
+        download.status = DownloadStatus::Pending;
+        false
+    }
+}
 
-                    match read_server_message(&mut msg_buf) {
-                        Ok(ServerResponse::LoginSuccess { .. }) => {
-                            println!("✓ Login successful!");
-                            break;
-                        }
-                        Ok(ServerResponse::LoginFailure { reason, detail }) => {
-                            anyhow::bail!("Login failed: {:?} - {:?}", reason, detail);
-                        }
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Failed to parse message: {e}");
-                        }
-                    }
-                }
+/// Search for every track and print the best match for each, without downloading.
+async fn dry_run(
+    client: &mut SoulseekClient,
+    username: &str,
+    password: &str,
+    tracks: &[SpotifyTrack],
+) {
+    let mut matches = Vec::with_capacity(tracks.len());
+    for (i, track) in tracks.iter().enumerate() {
+        println!("\n[{}/{}] Searching: {}", i + 1, tracks.len(), track.display_name());
+
+        let mut best = None;
+        for _ in 0..=MAX_RETRIES {
+            let query = track.to_search_query();
+            if let SearchOutcome::Candidates(candidates) =
+                search_track(client, username, password, &query, &[]).await
+            {
+                best = candidates.into_iter().next();
+                break;
             }
         }
+        matches.push((track.display_name(), best));
 
-        buf.clear();
+        // Small delay between tracks
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    println!("\n{}", format_match_summary(&matches));
+}
+
+struct SoulseekClient {
+    writer: OwnedWriteHalf,
+    messages: MessageStream<OwnedReadHalf>,
+    username: String,
+    timeouts: TransferTimeouts,
+}
+
+impl SoulseekClient {
+    async fn connect(username: &str, password: &str) -> anyhow::Result<Self> {
+        let profile = ServerProfile {
+            host: std::env::var("SOULSEEK_SERVER").unwrap_or_else(|_| DEFAULT_SERVER_HOST.to_string()),
+            port: std::env::var("SOULSEEK_PORT")
+                .ok()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(DEFAULT_SERVER_PORT),
+            ..ServerProfile::default()
+        };
+
+        println!("Connecting to {}:{}...", profile.host, profile.port);
+        let config = BackoffConfig::default();
+        let (conn, _) = connect_with_backoff(&profile, username, password, &config, |n, e, delay| {
+            println!("  Connect attempt {}/{} failed: {}", n, config.max_attempts, e);
+            println!("  Retrying in {}s...", delay.as_secs());
+        })
+        .await?;
+        println!("✓ Login successful!");
+        let (stream, read_buf) = conn.into_parts();
+        let (reader, mut writer) = stream.into_split();
+
+        let mut buf = BytesMut::new();
         let set_status = ServerRequest::SetStatus {
             status: slsk_rs::constants::UserStatus::Online,
         };
-        set_status.write_message(&mut buf);
-        stream.write_all(&buf).await?;
+        set_status.write_message(&mut buf)?;
+        writer.write_all(&buf).await?;
 
         Ok(Self {
-            stream,
-            read_buf,
+            writer,
+            messages: MessageStream::with_buffer(reader, read_buf),
             username: username.to_string(),
+            timeouts: TransferTimeouts::new(),
         })
     }
 
@@ -423,72 +519,52 @@ impl SoulseekClient {
             token: search_token,
             query: query.to_string(),
         };
-        search.write_message(&mut buf);
-        self.stream.write_all(&buf).await?;
-        self.stream.flush().await?;
-
-        let accumulated_results: Arc<Mutex<Vec<AccumulatedResult>>> = Arc::new(Mutex::new(Vec::new()));
-        let mut peer_tasks = Vec::new();
-
-        let start = std::time::Instant::now();
-
-        while start.elapsed() < AGGREGATION_TIMEOUT {
-            match timeout(Duration::from_millis(200), self.stream.read_buf(&mut self.read_buf)).await {
-                Ok(Ok(0)) => {
-                    // Connection closed - need to reconnect
-                    return Err(anyhow::anyhow!("Server connection closed during search"));
-                }
-                Ok(Ok(_)) => {
-                    while self.read_buf.len() >= 4 {
-                        let msg_len = u32::from_le_bytes([
-                            self.read_buf[0],
-                            self.read_buf[1],
-                            self.read_buf[2],
-                            self.read_buf[3],
-                        ]) as usize;
-
-                        if self.read_buf.len() < 4 + msg_len {
-                            break;
-                        }
-
-                        let mut msg_buf = self.read_buf.split_to(4 + msg_len);
-
-                        if let Ok(response) = read_server_message(&mut msg_buf) {
-                            if let ServerResponse::ConnectToPeer {
-                                username,
-                                connection_type: ConnectionType::Peer,
-                                ip,
-                                port,
-                                token,
-                                ..
-                            } = response
-                            {
-                                let results = accumulated_results.clone();
-                                let peer_user = username.clone();
-                                let task = tokio::spawn(async move {
-                                    let _ = connect_and_receive_search(&peer_user, ip, port, token, &results).await;
-                                });
-                                peer_tasks.push(task);
-                            }
-                        }
+        search.write_message(&mut buf)?;
+        self.writer.write_all(&buf).await?;
+        self.writer.flush().await?;
+
+        let (session, peers) = SearchSession::new(search_token, SEARCH_SESSION_TIMEOUT, usize::MAX);
+        let collect = session.collect();
+        tokio::pin!(collect);
+
+        // Keep reading the server so peers with results can reach us until the session ends
+        let results = loop {
+            tokio::select! {
+                results = &mut collect => break results,
+                message = self.messages.next_message() => match message {
+                    Ok(None) => {
+                        // Connection closed - need to reconnect
+                        return Err(anyhow::anyhow!("Server connection closed during search"));
                     }
-                }
-                Ok(Err(e)) => {
-                    return Err(anyhow::anyhow!("Read error during search: {}", e));
-                }
-                Err(_) => {} // Timeout, continue loop
-            }
-        }
-
-        // Wait for peer tasks with timeout
-        let _ = timeout(Duration::from_secs(3), async {
-            for task in peer_tasks {
-                let _ = task.await;
+                    Ok(Some(ServerResponse::ConnectToPeer {
+                        connection_type: ConnectionType::Peer,
+                        ip,
+                        port,
+                        token,
+                        ..
+                    })) => peers.connect_to_peer(ip, port, token),
+                    Ok(Some(_)) => {}
+                    Err(slsk_rs::Error::Io(e)) => {
+                        return Err(anyhow::anyhow!("Read error during search: {}", e));
+                    }
+                    // The undecodable frame was consumed, so carry on with the next one
+                    Err(_) => {}
+                },
             }
-        }).await;
+        };
 
-        let results = accumulated_results.lock().await;
-        Ok(results.clone())
+        Ok(results
+            .into_iter()
+            .map(|r| AccumulatedResult {
+                username: r.username,
+                file: r.file,
+                availability: PeerAvailability {
+                    slot_free: r.slot_free,
+                    queue_length: r.queue_length,
+                    avg_speed: r.avg_speed,
+                },
+            })
+            .collect())
     }
 
     async fn get_peer_address(&mut self, username: &str) -> anyhow::Result<(Ipv4Addr, u32)> {
@@ -496,9 +572,9 @@ impl SoulseekClient {
         let req = ServerRequest::GetPeerAddress {
             username: username.to_string(),
         };
-        req.write_message(&mut buf);
-        self.stream.write_all(&buf).await?;
-        self.stream.flush().await?;
+        req.write_message(&mut buf)?;
+        self.writer.write_all(&buf).await?;
+        self.writer.flush().await?;
 
         let start = std::time::Instant::now();
         loop {
@@ -506,49 +582,35 @@ impl SoulseekClient {
                 anyhow::bail!("Timeout waiting for peer address");
             }
 
-            match timeout(Duration::from_millis(100), self.stream.read_buf(&mut self.read_buf)).await {
-                Ok(Ok(0)) => anyhow::bail!("Connection closed"),
-                Ok(Ok(_)) => {
-                    while self.read_buf.len() >= 4 {
-                        let msg_len = u32::from_le_bytes([
-                            self.read_buf[0],
-                            self.read_buf[1],
-                            self.read_buf[2],
-                            self.read_buf[3],
-                        ]) as usize;
-
-                        if self.read_buf.len() < 4 + msg_len {
-                            break;
-                        }
-
-                        let mut msg_buf = self.read_buf.split_to(4 + msg_len);
-
-                        if let Ok(ServerResponse::GetPeerAddress { username: u, ip, port, .. }) =
-                            read_server_message(&mut msg_buf)
-                        {
-                            if u == username {
-                                if ip == Ipv4Addr::new(0, 0, 0, 0) {
-                                    anyhow::bail!("User {} is offline", username);
-                                }
-                                return Ok((ip, port));
-                            }
+            match self.messages.next_message_timeout(Duration::from_millis(100)).await {
+                Ok(None) => {
+                    let closed = io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed");
+                    return Err(closed.into());
+                }
+                Ok(Some(message)) => {
+                    if let Some((u, address)) = PeerAddress::from_response(&message)
+                        && u == username
+                    {
+                        if address.is_offline() {
+                            anyhow::bail!("User {} is offline", username);
                         }
+                        return Ok((address.ip, address.port));
                     }
                 }
-                Ok(Err(e)) => anyhow::bail!("Read error: {}", e),
+                Err(e) if e.is_timeout() => {}
+                Err(slsk_rs::Error::Io(e)) => return Err(e.into()),
                 Err(_) => {}
             }
         }
     }
 
-    async fn download_file(&mut self, matched: &MatchedFile) -> anyhow::Result<PathBuf> {
+    async fn download_file(&mut self, matched: &MatchedFile, config: &DownloadConfig) -> anyhow::Result<PathBuf> {
         let (ip, port) = self.get_peer_address(&matched.username).await?;
 
         let addr = format!("{}:{}", ip, port);
-        let mut peer_stream = match timeout(PEER_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
-            Ok(Ok(s)) => s,
-            Ok(Err(e)) => anyhow::bail!("Connect failed: {}", e),
-            Err(_) => anyhow::bail!("Connect timeout"),
+        let mut peer_stream = match self.timeouts.connector().connect(&addr).await {
+            Ok(s) => s,
+            Err(e) => anyhow::bail!("Connect failed: {}", e),
         };
         peer_stream.set_nodelay(true)?;
 
@@ -559,14 +621,14 @@ impl SoulseekClient {
             token: peer_token,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&init, &mut buf);
+        write_peer_init_message(&init, &mut buf)?;
         peer_stream.write_all(&buf).await?;
 
         buf.clear();
         let queue_msg = PeerMessage::QueueUpload {
             filename: matched.filename.clone(),
         };
-        queue_msg.write_message(&mut buf);
+        queue_msg.write_message(&mut buf)?;
         peer_stream.write_all(&buf).await?;
         peer_stream.flush().await?;
 
@@ -576,7 +638,7 @@ impl SoulseekClient {
         let mut file_size = matched.size;
 
         loop {
-            if start.elapsed() > TRANSFER_WAIT_TIMEOUT {
+            if start.elapsed() > self.timeouts.transfer_wait {
                 anyhow::bail!("Timeout waiting for transfer request");
             }
 
@@ -608,24 +670,22 @@ impl SoulseekClient {
                                 token,
                                 filename,
                                 file_size: size,
-                            }) => {
-                                if filename == matched.filename {
-                                    transfer_token = Some(token);
-                                    if let Some(sz) = size {
-                                        file_size = sz;
-                                    }
-
-                                    buf.clear();
-                                    let response = PeerMessage::TransferResponse {
-                                        token,
-                                        allowed: true,
-                                        reason: None,
-                                        file_size: None,
-                                    };
-                                    response.write_message(&mut buf);
-                                    peer_stream.write_all(&buf).await?;
-                                    peer_stream.flush().await?;
+                            }) if filename == matched.filename => {
+                                transfer_token = Some(token);
+                                if let Some(sz) = size {
+                                    file_size = sz;
                                 }
+
+                                buf.clear();
+                                let response = PeerMessage::TransferResponse {
+                                    token,
+                                    allowed: true,
+                                    reason: None,
+                                    file_size: None,
+                                };
+                                response.write_message(&mut buf)?;
+                                peer_stream.write_all(&buf).await?;
+                                peer_stream.flush().await?;
                             }
                             Ok(PeerMessage::UploadDenied { reason, .. }) => {
                                 anyhow::bail!("Upload denied: {:?}", reason);
@@ -656,10 +716,9 @@ impl SoulseekClient {
         // Small delay before opening file connection
         tokio::time::sleep(Duration::from_millis(100)).await;
 
-        let mut file_stream = match timeout(PEER_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
-            Ok(Ok(s)) => s,
-            Ok(Err(e)) => anyhow::bail!("File connect failed: {}", e),
-            Err(_) => anyhow::bail!("File connect timeout"),
+        let mut file_stream = match self.timeouts.connector().connect(&addr).await {
+            Ok(s) => s,
+            Err(e) => anyhow::bail!("File connect failed: {}", e),
         };
         file_stream.set_nodelay(true)?;
 
@@ -669,7 +728,7 @@ impl SoulseekClient {
             token: peer_token,
         };
         buf.clear();
-        write_peer_init_message(&file_init, &mut buf);
+        write_peer_init_message(&file_init, &mut buf)?;
         file_stream.write_all(&buf).await?;
 
         buf.clear();
@@ -683,14 +742,10 @@ impl SoulseekClient {
         file_stream.write_all(&buf).await?;
         file_stream.flush().await?;
 
-        let filename = matched
-            .filename
-            .rsplit(['/', '\\'])
-            .next()
-            .unwrap_or(&matched.filename);
-        let download_path = PathBuf::from("downloads").join(filename);
-
-        tokio::fs::create_dir_all("downloads").await?;
+        let download_path = config.local_path(&matched.filename);
+        if let Some(parent) = download_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
         let mut file = File::create(&download_path).await?;
 
         let mut received = 0u64;
@@ -698,7 +753,7 @@ impl SoulseekClient {
         let mut last_print = std::time::Instant::now();
 
         loop {
-            match timeout(Duration::from_secs(30), file_stream.read(&mut file_buf)).await {
+            match timeout(self.timeouts.transfer_stall, file_stream.read(&mut file_buf)).await {
                 Ok(Ok(0)) => break,
                 Ok(Ok(n)) => {
                     file.write_all(&file_buf[..n]).await?;
@@ -713,7 +768,9 @@ impl SoulseekClient {
                     }
                 }
                 Ok(Err(e)) => anyhow::bail!("Read error during transfer: {}", e),
-                Err(_) => anyhow::bail!("Transfer stalled (30s timeout)"),
+                Err(_) => {
+                    anyhow::bail!("Transfer stalled ({:?} timeout)", self.timeouts.transfer_stall)
+                }
             }
         }
 
@@ -730,78 +787,16 @@ impl SoulseekClient {
     }
 }
 
-async fn connect_and_receive_search(
-    peer_username: &str,
-    ip: Ipv4Addr,
-    port: u32,
-    token: u32,
-    accumulated: &Arc<Mutex<Vec<AccumulatedResult>>>,
-) -> anyhow::Result<usize> {
-    let addr = format!("{}:{}", ip, port);
-
-    let mut stream = match timeout(PEER_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
-        Ok(Ok(s)) => s,
-        Ok(Err(_)) => return Ok(0),
-        Err(_) => return Ok(0),
-    };
-
-    let pierce = PeerInitMessage::PierceFirewall { token };
-    let mut buf = BytesMut::new();
-    write_peer_init_message(&pierce, &mut buf);
-    if stream.write_all(&buf).await.is_err() {
-        return Ok(0);
-    }
-
-    let mut read_buf = BytesMut::with_capacity(256 * 1024);
-    let mut result_count = 0;
-
-    let start = std::time::Instant::now();
-    while start.elapsed() < Duration::from_secs(3) {
-        match timeout(Duration::from_millis(500), stream.read_buf(&mut read_buf)).await {
-            Ok(Ok(0)) => break,
-            Ok(Ok(_)) => {
-                while read_buf.len() >= 4 {
-                    let msg_len =
-                        u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]])
-                            as usize;
-
-                    if read_buf.len() < 4 + msg_len {
-                        break;
-                    }
-
-                    let mut msg_buf = read_buf.split_to(4 + msg_len);
-
-                    if let Ok(PeerMessage::FileSearchResponse { results, .. }) = read_peer_message(&mut msg_buf) {
-                        result_count += results.len();
-                        let mut acc = accumulated.lock().await;
-                        for file in results {
-                            acc.push(AccumulatedResult {
-                                username: peer_username.to_string(),
-                                file,
-                            });
-                        }
-                    }
-                }
-            }
-            Ok(Err(_)) => break,
-            Err(_) => {}
-        }
-    }
-
-    Ok(result_count)
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
 
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: slsk-debug <spotify-playlist-url-or-search-query>");
+    let dry_run_only = args[1..].iter().any(|a| a == "--dry-run");
+    let Some(url) = args[1..].iter().find(|a| *a != "--dry-run") else {
+        eprintln!("Usage: slsk-debug [--dry-run] <spotify-playlist-url-or-search-query>");
         std::process::exit(1);
-    }
-
-    let url = &args[1];
+    };
     let username = std::env::var("SOULSEEK_ACCOUNT").expect("SOULSEEK_ACCOUNT not set");
     let password = std::env::var("SOULSEEK_PASSWORD").expect("SOULSEEK_PASSWORD not set");
 
@@ -829,8 +824,21 @@ async fn main() -> anyhow::Result<()> {
         }]
     };
 
+    let download_config = DownloadConfig {
+        base_dir: std::env::var_os("SOULSEEK_DOWNLOAD_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("downloads")),
+        preserve_remote_dirs: std::env::var("SOULSEEK_PRESERVE_DIRS").is_ok_and(|v| v == "1"),
+        ..DownloadConfig::default()
+    };
+
     let mut client = SoulseekClient::connect(&username, &password).await?;
 
+    if dry_run_only {
+        dry_run(&mut client, &username, &password, &tracks).await;
+        return Ok(());
+    }
+
     let mut downloads: Vec<TrackDownload> = tracks
         .into_iter()
         .map(|track| TrackDownload {
@@ -869,109 +877,39 @@ async fn main() -> anyhow::Result<()> {
 
         downloads[idx].status = DownloadStatus::Searching;
 
-        let results = match client.search(&query).await {
-            Ok(r) => r,
-            Err(e) => {
-                let err_str = e.to_string();
-                println!("  ✗ Search failed: {}", err_str);
-                
-                // Reconnect on any error with delay
-                println!("  Waiting {}s before reconnecting...", RECONNECT_DELAY.as_secs());
-                tokio::time::sleep(RECONNECT_DELAY).await;
-                
-                match SoulseekClient::connect(&username, &password).await {
-                    Ok(new_client) => {
-                        client = new_client;
-                        downloads[idx].status = DownloadStatus::Pending;
-                        continue;
-                    }
-                    Err(e) => {
-                        println!("  ✗ Reconnect failed: {}", e);
-                        println!("  Waiting {}s before retry...", RECONNECT_DELAY.as_secs());
-                        tokio::time::sleep(RECONNECT_DELAY).await;
-                        downloads[idx].retry_count += 1;
-                        if downloads[idx].retry_count > MAX_RETRIES {
-                            downloads[idx].status = DownloadStatus::Failed(e.to_string());
-                            failed += 1;
-                        } else {
-                            downloads[idx].status = DownloadStatus::Pending;
-                        }
-                        continue;
-                    }
-                }
-            }
-        };
-        println!("  Found {} results", results.len());
-
-        let candidates = pick_best_files(&results, &tried_users);
-        if !candidates.is_empty() {
-            let mut downloaded = false;
-            
-            for (candidate_idx, best) in candidates.iter().enumerate() {
-                let matched = MatchedFile {
-                    username: best.username.clone(),
-                    filename: best.file.filename.clone(),
-                    size: best.file.size,
-                };
-
-                let is_flac = matched.filename.to_lowercase().ends_with(".flac");
-                let bitrate = get_bitrate(&best.file.attributes);
-
-                println!(
-                    "  Trying [{}/{}]: [{}] {} ({} {})",
-                    candidate_idx + 1,
-                    candidates.len(),
-                    matched.username,
-                    matched.filename.rsplit(['/', '\\']).next().unwrap_or(&matched.filename),
-                    if is_flac { "FLAC".to_string() } else { format!("{}kbps", bitrate.unwrap_or(0)) },
-                    format!("{:.1}MB", matched.size as f64 / 1_000_000.0)
-                );
-
-                downloads[idx].tried_users.push(matched.username.clone());
-                downloads[idx].status = DownloadStatus::Downloading;
-
-                match client.download_file(&matched).await {
-                    Ok(path) => {
-                        println!("  ✓ Saved to {:?}", path);
-                        downloads[idx].status = DownloadStatus::Completed;
-                        completed += 1;
-                        downloaded = true;
-                        break;
-                    }
-                    Err(e) => {
-                        let err_str = e.to_string();
-                        println!("    ✗ Failed: {}", err_str);
-                        
-                        // Reconnect if connection issues
-                        if err_str.contains("Broken pipe") || err_str.contains("reset") || err_str.contains("closed") {
-                            println!("    Waiting {}s before reconnecting...", RECONNECT_DELAY.as_secs());
-                            tokio::time::sleep(RECONNECT_DELAY).await;
-                            if let Ok(new_client) = SoulseekClient::connect(&username, &password).await {
-                                client = new_client;
-                            }
-                        }
-                    }
-                }
+        let candidates = match search_track(&mut client, &username, &password, &query, &tried_users).await {
+            SearchOutcome::Candidates(candidates) => candidates,
+            SearchOutcome::Reconnected => {
+                downloads[idx].status = DownloadStatus::Pending;
+                continue;
             }
-            
-            if !downloaded {
-                downloads[idx].retry_count += 1;
-                if downloads[idx].retry_count > MAX_RETRIES {
-                    downloads[idx].status = DownloadStatus::Failed("All sources failed".to_string());
+            SearchOutcome::Failed(reason) => {
+                if retry_or_fail(&mut downloads[idx], &reason) {
                     failed += 1;
-                } else {
-                    downloads[idx].status = DownloadStatus::Pending;
                 }
+                continue;
             }
-        } else {
+        };
+
+        if candidates.is_empty() {
             println!("  ✗ No audio files found");
-            downloads[idx].retry_count += 1;
-            if downloads[idx].retry_count > MAX_RETRIES {
-                downloads[idx].status = DownloadStatus::Failed("No matches found".to_string());
+            if retry_or_fail(&mut downloads[idx], "No matches found") {
                 failed += 1;
-            } else {
-                downloads[idx].status = DownloadStatus::Pending;
             }
+        } else if download_track(
+            &mut client,
+            &username,
+            &password,
+            &mut downloads[idx],
+            &candidates,
+            &download_config,
+        )
+        .await
+        {
+            downloads[idx].status = DownloadStatus::Completed;
+            completed += 1;
+        } else if retry_or_fail(&mut downloads[idx], "All sources failed") {
+            failed += 1;
         }
 
         // Small delay between tracks
@@ -994,3 +932,70 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(username: &str, filename: &str, size: u64, bitrate: Option<u32>) -> AccumulatedResult {
+        AccumulatedResult {
+            username: username.to_string(),
+            file: SearchResultFile {
+                filename: filename.to_string(),
+                size,
+                extension: String::new(),
+                attributes: bitrate
+                    .map(|value| vec![slsk_rs::peer::FileAttribute { code: 0, value }])
+                    .unwrap_or_default(),
+            },
+            availability: PeerAvailability {
+                slot_free: true,
+                queue_length: 0,
+                avg_speed: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn test_format_match_summary() {
+        let matches = vec![
+            (
+                "Artist - Song".to_string(),
+                Some(result("alice", "Music\\Artist\\01 Song.mp3", 9_500_000, Some(320))),
+            ),
+            (
+                "Artist - Other".to_string(),
+                Some(result("bob", "Music/Artist/02 Other.FLAC", 30_000_000, None)),
+            ),
+            ("Nobody - Missing".to_string(), None),
+        ];
+
+        assert_eq!(
+            format_match_summary(&matches).lines().collect::<Vec<_>>(),
+            [
+                "========================================",
+                "DRY RUN: 2/3 tracks matched",
+                "========================================",
+                "1. Artist - Song",
+                "   [alice] 01 Song.mp3 (320kbps 9.5MB)",
+                "2. Artist - Other",
+                "   [bob] 02 Other.FLAC (FLAC 30.0MB)",
+                "3. Nobody - Missing",
+                "   ✗ No match",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_connection_lost() {
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer");
+        assert!(is_connection_lost(&anyhow::Error::new(slsk_rs::Error::Io(reset))));
+        let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed");
+        assert!(is_connection_lost(&anyhow::Error::new(eof).context("Read error")));
+
+        // Peers turning us down mention "closed" without the connection being gone
+        assert!(!is_connection_lost(&anyhow::anyhow!("Peer closed connection")));
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(!is_connection_lost(&refused.into()));
+    }
+}
@@ -2,135 +2,175 @@
 //!
 //! Connects to the Soulseek network, discovers users via rooms,
 //! fetches their shared file lists, and stores them in SQLite for local searching.
+//! Single users can also be browsed without touching the index.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use std::fmt::Write;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use bytes::BytesMut;
-use slsk_rs::constants::{ConnectionType, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, UserStatus};
-use slsk_rs::db::Database;
-use slsk_rs::peer::{PeerMessage, SharedDirectory, read_peer_message};
-use slsk_rs::peer_init::{PeerInitMessage, write_peer_init_message};
+use slsk_rs::constants::{DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, UserStatus};
+use slsk_rs::db::{Database, storable_text};
+use slsk_rs::net::TransferTimeouts;
+use slsk_rs::peer::{
+    BrowseOptions, SharedDirectory, browse_user, connect_to_peer_and_browse,
+};
 use slsk_rs::protocol::MessageWrite;
-use slsk_rs::server::{ServerRequest, ServerResponse, read_server_message};
+use slsk_rs::server::{
+    BackoffConfig, PeerAddress, ServerProfile, ServerRequest, ServerResponse,
+    connect_with_backoff, drain_messages, filter_rooms,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-use tokio::sync::{Mutex, Semaphore};
+use tokio::sync::{Semaphore, mpsc};
 use tokio::time::timeout;
 
-const MAX_CONCURRENT_PEERS: usize = 10;
+/// Peers fetched at once, unless `--concurrency` or `SLSK_INDEX_CONCURRENCY` says otherwise.
+const DEFAULT_CONCURRENCY: usize = 10;
 
-const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
-const PEER_READ_TIMEOUT: Duration = Duration::from_secs(30);
-const DELAY_BETWEEN_PEERS: Duration = Duration::from_millis(500);
+/// `GetPeerAddress` requests outstanding at once, unless `--lookups` says otherwise.
+const DEFAULT_ADDRESS_LOOKUPS: usize = 20;
 
-struct IndexerClient {
-    stream: TcpStream,
-    read_buf: BytesMut,
-    username: String,
+/// Rows shown by `top-sharers` and `largest` when no limit is given.
+const DEFAULT_LIST_LIMIT: usize = 20;
+
+/// Extensions listed by `stats` before the rest are lumped together.
+const STATS_EXTENSIONS: usize = 10;
+
+/// Fetched users stored per database transaction while indexing.
+const WRITE_BATCH_SIZE: usize = 50;
+
+/// How long to wait for the server to answer a single address lookup.
+const ADDRESS_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rooms smaller than this are skipped unless named with `--rooms`.
+const MIN_ROOM_USERS: u32 = 50;
+
+/// Age after which `--refresh` re-indexes a user, when no value is given.
+const DEFAULT_REFRESH_DAYS: u64 = 7;
+
+type UserShares = (String, Vec<SharedDirectory>);
+
+/// Options for the `index` command.
+#[derive(Debug, Clone, PartialEq)]
+struct IndexOptions {
+    /// Rooms to join, or `None` for every sufficiently large room.
+    rooms: Option<Vec<String>>,
+    /// Re-index users whose index is older than this.
+    refresh: Option<Duration>,
+    /// Peers whose shares are fetched at once.
+    concurrency: usize,
+    /// Address lookups pipelined on the server connection at once.
+    address_lookups: usize,
 }
 
-impl IndexerClient {
-    async fn connect(username: &str, password: &str) -> anyhow::Result<Self> {
-        let max_attempts = 5;
-        let mut attempt = 0;
-        loop {
-            attempt += 1;
-            match Self::connect_once(username, password).await {
-                Ok(client) => return Ok(client),
-                Err(e) => {
-                    let err_str = e.to_string();
-                    if attempt >= max_attempts {
-                        return Err(e);
+impl IndexOptions {
+    /// Parse the flags after `index`, with `concurrency_env` as the default concurrency.
+    fn parse(args: &[String], concurrency_env: Option<&str>) -> anyhow::Result<Self> {
+        let mut options = IndexOptions {
+            rooms: None,
+            refresh: None,
+            concurrency: match concurrency_env {
+                Some(value) => parse_limit("SLSK_INDEX_CONCURRENCY", value)?,
+                None => DEFAULT_CONCURRENCY,
+            },
+            address_lookups: DEFAULT_ADDRESS_LOOKUPS,
+        };
+
+        let mut flags = args.iter().peekable();
+        while let Some(flag) = flags.next() {
+            match flag.as_str() {
+                "--rooms" => {
+                    if let Some(list) = flags.next() {
+                        options.rooms =
+                            Some(list.split(',').map(|s| s.trim().to_string()).collect());
                     }
-                    let delay = if err_str.contains("rate limit") || err_str.contains("reset") {
-                        Duration::from_secs(30 * attempt as u64)
-                    } else {
-                        Duration::from_secs((10 * 2u64.pow((attempt - 1).min(2))).min(60))
+                }
+                "--refresh" => {
+                    let days = flags
+                        .next_if(|v| !v.starts_with("--"))
+                        .map(|v| v.parse::<u64>())
+                        .transpose()?
+                        .unwrap_or(DEFAULT_REFRESH_DAYS);
+                    options.refresh = Some(Duration::from_secs(days * 24 * 60 * 60));
+                }
+                "--concurrency" | "--lookups" => {
+                    let Some(value) = flags.next() else {
+                        anyhow::bail!("{} needs a value", flag);
                     };
-                    println!("  Connect attempt {}/{} failed: {}", attempt, max_attempts, e);
-                    println!("  Retrying in {}s...", delay.as_secs());
-                    tokio::time::sleep(delay).await;
+                    let limit = parse_limit(flag, value)?;
+                    if flag == "--concurrency" {
+                        options.concurrency = limit;
+                    } else {
+                        options.address_lookups = limit;
+                    }
                 }
+                _ => anyhow::bail!("Unknown option: {}", flag),
             }
         }
-    }
-
-    async fn connect_once(username: &str, password: &str) -> anyhow::Result<Self> {
-        let server_host =
-            std::env::var("SOULSEEK_SERVER").unwrap_or_else(|_| DEFAULT_SERVER_HOST.to_string());
-        let server_port: u16 = std::env::var("SOULSEEK_PORT")
-            .ok()
-            .and_then(|p| p.parse().ok())
-            .unwrap_or(DEFAULT_SERVER_PORT);
 
-        println!("Connecting to {}:{}...", server_host, server_port);
-        let mut stream = TcpStream::connect((&*server_host, server_port)).await?;
-        stream.set_nodelay(true)?;
-
-        let login = ServerRequest::Login {
-            username: username.to_string(),
-            password: password.to_string(),
-            version: 160,
-            minor_version: 3,
-        };
+        Ok(options)
+    }
+}
 
-        let mut buf = BytesMut::new();
-        login.write_message(&mut buf);
-        stream.write_all(&buf).await?;
-        stream.flush().await?;
+fn parse_limit(name: &str, value: &str) -> anyhow::Result<usize> {
+    match value.parse::<usize>() {
+        Ok(limit) if limit >= 1 => Ok(limit),
+        _ => anyhow::bail!("{} must be a whole number of at least 1, got {:?}", name, value),
+    }
+}
 
-        let mut read_buf = BytesMut::with_capacity(65536);
+/// Peers that take longer than this to accept are skipped, to keep a crawl moving.
+const PEER_TIMEOUTS: TransferTimeouts = TransferTimeouts {
+    connect: Duration::from_secs(5),
+    ..TransferTimeouts::new()
+};
 
-        loop {
-            let read_result = timeout(Duration::from_secs(30), stream.read_buf(&mut read_buf)).await;
-            match read_result {
-                Err(_) => anyhow::bail!("Timeout waiting for login response"),
-                Ok(Err(e)) => anyhow::bail!("Read error: {}", e),
-                Ok(Ok(0)) => anyhow::bail!("Connection closed during login"),
-                Ok(Ok(_)) => {}
-            }
+/// Limits on each share list fetched while indexing.
+const BROWSE_OPTIONS: BrowseOptions = BrowseOptions {
+    timeout: PEER_TIMEOUTS.read,
+    ..BrowseOptions::new()
+};
 
-            if read_buf.len() >= 4 {
-                let msg_len =
-                    u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]])
-                        as usize;
+struct IndexerClient {
+    stream: TcpStream,
+    read_buf: BytesMut,
+}
 
-                if read_buf.len() >= 4 + msg_len {
-                    let mut msg_buf = read_buf.split_to(4 + msg_len);
+impl IndexerClient {
+    async fn connect(username: &str, password: &str) -> anyhow::Result<Self> {
+        let profile = ServerProfile {
+            host: std::env::var("SOULSEEK_SERVER")
+                .unwrap_or_else(|_| DEFAULT_SERVER_HOST.to_string()),
+            port: std::env::var("SOULSEEK_PORT")
+                .ok()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(DEFAULT_SERVER_PORT),
+            ..ServerProfile::default()
+        };
 
-                    match read_server_message(&mut msg_buf) {
-                        Ok(ServerResponse::LoginSuccess { .. }) => {
-                            println!("✓ Login successful!");
-                            break;
-                        }
-                        Ok(ServerResponse::LoginFailure { reason, detail }) => {
-                            anyhow::bail!("Login failed: {:?} - {:?}", reason, detail);
-                        }
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Failed to parse message: {e}");
-                        }
-                    }
-                }
-            }
-        }
+        println!("Connecting to {}:{}...", profile.host, profile.port);
+        let config = BackoffConfig::default();
+        let (conn, _) = connect_with_backoff(&profile, username, password, &config, |n, e, delay| {
+            println!("  Connect attempt {}/{} failed: {}", n, config.max_attempts, e);
+            println!("  Retrying in {}s...", delay.as_secs());
+        })
+        .await?;
+        println!("✓ Login successful!");
+        let (mut stream, read_buf) = conn.into_parts();
 
-        buf.clear();
+        let mut buf = BytesMut::new();
         let set_status = ServerRequest::SetStatus {
             status: UserStatus::Online,
         };
-        set_status.write_message(&mut buf);
+        set_status.write_message(&mut buf)?;
         stream.write_all(&buf).await?;
 
-        Ok(Self {
-            stream,
-            read_buf,
-            username: username.to_string(),
-        })
+        Ok(Self { stream, read_buf })
     }
 
     async fn join_room(&mut self, room: &str) -> anyhow::Result<Vec<String>> {
@@ -139,7 +179,7 @@ impl IndexerClient {
             room: room.to_string(),
             private: false,
         };
-        req.write_message(&mut buf);
+        req.write_message(&mut buf)?;
         self.stream.write_all(&buf).await?;
         self.stream.flush().await?;
 
@@ -154,26 +194,11 @@ impl IndexerClient {
             {
                 Ok(Ok(0)) => anyhow::bail!("Connection closed"),
                 Ok(Ok(_)) => {
-                    while self.read_buf.len() >= 4 {
-                        let msg_len = u32::from_le_bytes([
-                            self.read_buf[0],
-                            self.read_buf[1],
-                            self.read_buf[2],
-                            self.read_buf[3],
-                        ]) as usize;
-
-                        if self.read_buf.len() < 4 + msg_len {
-                            break;
-                        }
-
-                        let mut msg_buf = self.read_buf.split_to(4 + msg_len);
-
-                        if let Ok(ServerResponse::JoinRoom { room: r, users, .. }) =
-                            read_server_message(&mut msg_buf)
+                    for message in drain_messages(&mut self.read_buf) {
+                        if let Ok(ServerResponse::JoinRoom { room: r, users, .. }) = message
+                            && r == room
                         {
-                            if r == room {
-                                return Ok(users.into_iter().map(|u| u.username).collect());
-                            }
+                            return Ok(users.into_iter().map(|u| u.username).collect());
                         }
                     }
                 }
@@ -186,7 +211,7 @@ impl IndexerClient {
     async fn get_room_list(&mut self) -> anyhow::Result<Vec<(String, u32)>> {
         let mut buf = BytesMut::new();
         let req = ServerRequest::RoomList;
-        req.write_message(&mut buf);
+        req.write_message(&mut buf)?;
         self.stream.write_all(&buf).await?;
         self.stream.flush().await?;
 
@@ -201,23 +226,8 @@ impl IndexerClient {
             {
                 Ok(Ok(0)) => anyhow::bail!("Connection closed"),
                 Ok(Ok(_)) => {
-                    while self.read_buf.len() >= 4 {
-                        let msg_len = u32::from_le_bytes([
-                            self.read_buf[0],
-                            self.read_buf[1],
-                            self.read_buf[2],
-                            self.read_buf[3],
-                        ]) as usize;
-
-                        if self.read_buf.len() < 4 + msg_len {
-                            break;
-                        }
-
-                        let mut msg_buf = self.read_buf.split_to(4 + msg_len);
-
-                        if let Ok(ServerResponse::RoomList { rooms, .. }) =
-                            read_server_message(&mut msg_buf)
-                        {
+                    for message in drain_messages(&mut self.read_buf) {
+                        if let Ok(ServerResponse::RoomList { rooms, .. }) = message {
                             return Ok(rooms);
                         }
                     }
@@ -233,7 +243,7 @@ impl IndexerClient {
         let req = ServerRequest::GetPeerAddress {
             username: username.to_string(),
         };
-        req.write_message(&mut buf);
+        req.write_message(&mut buf)?;
         self.stream.write_all(&buf).await?;
         self.stream.flush().await?;
 
@@ -248,33 +258,15 @@ impl IndexerClient {
             {
                 Ok(Ok(0)) => anyhow::bail!("Connection closed"),
                 Ok(Ok(_)) => {
-                    while self.read_buf.len() >= 4 {
-                        let msg_len = u32::from_le_bytes([
-                            self.read_buf[0],
-                            self.read_buf[1],
-                            self.read_buf[2],
-                            self.read_buf[3],
-                        ]) as usize;
-
-                        if self.read_buf.len() < 4 + msg_len {
-                            break;
-                        }
-
-                        let mut msg_buf = self.read_buf.split_to(4 + msg_len);
-
-                        if let Ok(ServerResponse::GetPeerAddress {
-                            username: u,
-                            ip,
-                            port,
-                            ..
-                        }) = read_server_message(&mut msg_buf)
+                    for message in drain_messages(&mut self.read_buf) {
+                        let Ok(message) = message else { continue };
+                        if let Some((u, address)) = PeerAddress::from_response(&message)
+                            && u == username
                         {
-                            if u == username {
-                                if ip == Ipv4Addr::new(0, 0, 0, 0) {
-                                    anyhow::bail!("User {} is offline", username);
-                                }
-                                return Ok((ip, port));
+                            if address.is_offline() {
+                                anyhow::bail!("User {} is offline", username);
                             }
+                            return Ok((address.ip, address.port));
                         }
                     }
                 }
@@ -283,93 +275,86 @@ impl IndexerClient {
             }
         }
     }
-}
 
-async fn fetch_shared_files(
-    our_username: &str,
-    peer_username: &str,
-    ip: Ipv4Addr,
-    port: u32,
-) -> anyhow::Result<Vec<SharedDirectory>> {
-    let addr = format!("{}:{}", ip, port);
-    let mut stream = match timeout(PEER_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
-        Ok(Ok(s)) => s,
-        Ok(Err(e)) => anyhow::bail!("Connect failed: {}", e),
-        Err(_) => anyhow::bail!("Connect timeout"),
-    };
-    stream.set_nodelay(true)?;
+    /// Resolve many users at once, keeping up to `max_in_flight` lookups outstanding.
+    ///
+    /// The server may answer in any order, so replies are matched to lookups by
+    /// username. Offline users and users the server never answers for are left out.
+    async fn resolve_peer_addresses(
+        &mut self,
+        usernames: &[String],
+        max_in_flight: usize,
+    ) -> anyhow::Result<Vec<(String, Ipv4Addr, u32)>> {
+        let mut queued = usernames.iter();
+        // When each outstanding lookup was sent
+        let mut in_flight: HashMap<String, Instant> = HashMap::new();
+        let mut resolved = Vec::new();
+        let mut answered = 0;
 
-    // Send PeerInit
-    let init = PeerInitMessage::PeerInit {
-        username: our_username.to_string(),
-        connection_type: ConnectionType::Peer,
-        token: 0,
-    };
-    let mut buf = BytesMut::new();
-    write_peer_init_message(&init, &mut buf);
-    stream.write_all(&buf).await?;
-    stream.flush().await?;
-
-    // Send SharedFileListRequest
-    buf.clear();
-    PeerMessage::SharedFileListRequest.write_message(&mut buf);
-    stream.write_all(&buf).await?;
-    stream.flush().await?;
-
-    // Read response
-    let mut read_buf = BytesMut::with_capacity(1024 * 1024); // 1MB buffer for large file lists
-
-    let start = std::time::Instant::now();
-    while start.elapsed() < PEER_READ_TIMEOUT {
-        match timeout(Duration::from_secs(5), stream.read_buf(&mut read_buf)).await {
-            Ok(Ok(0)) => break,
-            Ok(Ok(_)) => {
-                while read_buf.len() >= 4 {
-                    let msg_len = u32::from_le_bytes([
-                        read_buf[0],
-                        read_buf[1],
-                        read_buf[2],
-                        read_buf[3],
-                    ]) as usize;
-
-                    if read_buf.len() < 4 + msg_len {
-                        break;
-                    }
+        loop {
+            let mut buf = BytesMut::new();
+            while in_flight.len() < max_in_flight
+                && let Some(username) = queued.next()
+            {
+                let req = ServerRequest::GetPeerAddress {
+                    username: username.clone(),
+                };
+                req.write_message(&mut buf)?;
+                in_flight.insert(username.clone(), Instant::now());
+            }
+            if !buf.is_empty() {
+                self.stream.write_all(&buf).await?;
+                self.stream.flush().await?;
+            }
 
-                    let mut msg_buf = read_buf.split_to(4 + msg_len);
+            if in_flight.is_empty() {
+                return Ok(resolved);
+            }
+            // Give up on lookups the server is ignoring, freeing room for the rest
+            let before = in_flight.len();
+            in_flight.retain(|_, sent| sent.elapsed() <= ADDRESS_LOOKUP_TIMEOUT);
+            answered += before - in_flight.len();
 
-                    match read_peer_message(&mut msg_buf) {
-                        Ok(PeerMessage::SharedFileListResponse {
-                            directories,
-                            private_directories,
-                        }) => {
-                            let mut all_dirs = directories;
-                            all_dirs.extend(private_directories);
-                            return Ok(all_dirs);
-                        }
-                        Ok(_) => {}
-                        Err(e) => {
-                            // Some parse errors are okay, continue
-                            if read_buf.is_empty() {
-                                anyhow::bail!("Parse error and no more data: {}", e);
+            match timeout(Duration::from_millis(100), self.stream.read_buf(&mut self.read_buf))
+                .await
+            {
+                Ok(Ok(0)) => anyhow::bail!("Connection closed"),
+                Ok(Ok(_)) => {
+                    for message in drain_messages(&mut self.read_buf) {
+                        let Ok(message) = message else { continue };
+                        if let Some((username, address)) = PeerAddress::from_response(&message)
+                            && in_flight.remove(username).is_some()
+                        {
+                            answered += 1;
+                            if answered % 50 == 0 {
+                                let total = usernames.len();
+                                println!("  Resolved {}/{} addresses...", answered, total);
+                            }
+                            if !address.is_offline() {
+                                resolved.push((username.to_string(), address.ip, address.port));
                             }
                         }
                     }
                 }
+                Ok(Err(e)) => anyhow::bail!("Read error: {}", e),
+                Err(_) => {}
             }
-            Ok(Err(e)) => anyhow::bail!("Read error: {}", e),
-            Err(_) => {}
         }
     }
-
-    anyhow::bail!("No file list received from {}", peer_username)
 }
 
 fn print_usage() {
     eprintln!("Usage:");
     eprintln!("  slsk-indexer index [--rooms <room1,room2,...>]  - Index users from rooms");
+    eprintln!("        [--refresh [days]]                        - Also re-index users older than <days> (default 7)");
+    eprintln!("        [--concurrency <n>]                       - Peers fetched at once (default 10)");
+    eprintln!("        [--lookups <n>]                           - Address lookups in flight (default 20)");
     eprintln!("  slsk-indexer search <query>                     - Search local index");
     eprintln!("  slsk-indexer stats                              - Show index statistics");
+    eprintln!("  slsk-indexer top-sharers [limit]                - Users sharing the most files");
+    eprintln!("  slsk-indexer largest [limit]                    - Largest indexed files");
+    eprintln!("  slsk-indexer compact                            - Shrink the database file");
+    eprintln!("  slsk-indexer browse <username>                  - List one user's shares");
     eprintln!();
     eprintln!("Environment variables:");
     eprintln!("  SOULSEEK_ACCOUNT   - Soulseek username");
@@ -377,6 +362,7 @@ fn print_usage() {
     eprintln!("  SOULSEEK_SERVER    - Server host (default: server.slsknet.org)");
     eprintln!("  SOULSEEK_PORT      - Server port (default: 2416)");
     eprintln!("  SLSK_INDEX_DB      - Database path (default: slsk_index.db)");
+    eprintln!("  SLSK_INDEX_CONCURRENCY - Default for --concurrency");
 }
 
 #[tokio::main]
@@ -397,13 +383,17 @@ async fn main() -> anyhow::Result<()> {
             let username = std::env::var("SOULSEEK_ACCOUNT").expect("SOULSEEK_ACCOUNT not set");
             let password = std::env::var("SOULSEEK_PASSWORD").expect("SOULSEEK_PASSWORD not set");
 
-            let rooms: Option<Vec<String>> = if args.len() > 3 && args[2] == "--rooms" {
-                Some(args[3].split(',').map(|s| s.trim().to_string()).collect())
-            } else {
-                None // Will join all rooms
+            let concurrency_env = std::env::var("SLSK_INDEX_CONCURRENCY").ok();
+            let options = match IndexOptions::parse(&args[2..], concurrency_env.as_deref()) {
+                Ok(options) => options,
+                Err(e) => {
+                    eprintln!("{}\n", e);
+                    print_usage();
+                    std::process::exit(1);
+                }
             };
 
-            run_indexer(&username, &password, rooms.as_deref(), &mut db).await?;
+            run_indexer(&username, &password, &options, &mut db).await?;
         }
         "search" => {
             if args.len() < 3 {
@@ -416,6 +406,33 @@ async fn main() -> anyhow::Result<()> {
         "stats" => {
             show_stats(&db)?;
         }
+        "compact" => {
+            compact(&db)?;
+        }
+        "top-sharers" | "largest" => {
+            let limit = match args.get(2) {
+                Some(value) => parse_limit("limit", value)?,
+                None => DEFAULT_LIST_LIMIT,
+            };
+            if args[1] == "largest" {
+                show_largest_files(&db, limit)?;
+            } else {
+                show_top_sharers(&db, limit)?;
+            }
+        }
+        "browse" => {
+            let [_, _, peer_username] = args.as_slice() else {
+                eprintln!("Usage: slsk-indexer browse <username>");
+                std::process::exit(1);
+            };
+            let username = std::env::var("SOULSEEK_ACCOUNT").expect("SOULSEEK_ACCOUNT not set");
+            let password = std::env::var("SOULSEEK_PASSWORD").expect("SOULSEEK_PASSWORD not set");
+
+            if let Err(e) = run_browse(&username, &password, peer_username).await {
+                eprintln!("✗ {}", e);
+                std::process::exit(1);
+            }
+        }
         _ => {
             print_usage();
             std::process::exit(1);
@@ -428,7 +445,7 @@ async fn main() -> anyhow::Result<()> {
 async fn run_indexer(
     username: &str,
     password: &str,
-    rooms: Option<&[String]>,
+    options: &IndexOptions,
     db: &mut Database,
 ) -> anyhow::Result<()> {
     let mut client = IndexerClient::connect(username, password).await?;
@@ -443,23 +460,18 @@ async fn run_indexer(
 
     // Show top 10 rooms by user count
     let mut sorted_rooms = room_list.clone();
-    sorted_rooms.sort_by(|a, b| b.1.cmp(&a.1));
+    sorted_rooms.sort_by_key(|r| std::cmp::Reverse(r.1));
     println!("\nTop 10 rooms:");
     for (name, count) in sorted_rooms.iter().take(10) {
         println!("  {} ({} users)", name, count);
     }
 
     // Determine which rooms to join
-    let rooms_to_join: Vec<String> = match rooms {
-        Some(r) => r.to_vec(),
+    let rooms_to_join: Vec<String> = match &options.rooms {
+        Some(r) => r.clone(),
         None => {
-            // Join all rooms with at least 50 users
-            println!("\nJoining all rooms with 50+ users...");
-            sorted_rooms
-                .iter()
-                .filter(|(_, count)| *count >= 50)
-                .map(|(name, _)| name.clone())
-                .collect()
+            println!("\nJoining all rooms with {}+ users...", MIN_ROOM_USERS);
+            filter_rooms(&room_list, MIN_ROOM_USERS, usize::MAX, &[])
         }
     };
 
@@ -488,93 +500,146 @@ async fn run_indexer(
 
     // Get already indexed users
     let indexed_users = db.get_indexed_users()?;
-    let indexed_set: HashSet<_> = indexed_users.into_iter().collect();
-
-    let users_to_index: Vec<_> = all_users
-        .difference(&indexed_set)
-        .cloned()
-        .collect();
+    // Users seen again whose index has gone stale are merged rather than skipped
+    let stale = match options.refresh {
+        Some(max_age) => db.get_stale_users(max_age)?,
+        None => Vec::new(),
+    };
+    let (mut users_to_index, stale_users) = select_users(&all_users, &indexed_users, &stale);
 
     println!("New users to index: {}", users_to_index.len());
-    println!("Already indexed: {}", indexed_set.len());
-    println!("Concurrent connections: {}", MAX_CONCURRENT_PEERS);
-
-    // First, get all peer addresses (must be done sequentially through server connection)
-    println!("\nResolving peer addresses...");
-    let mut peer_addresses: Vec<(String, Ipv4Addr, u32)> = Vec::new();
-    for (i, peer_user) in users_to_index.iter().enumerate() {
-        if i % 50 == 0 {
-            println!("  Resolved {}/{} addresses...", i, users_to_index.len());
-        }
-        match client.get_peer_address(peer_user).await {
-            Ok((ip, port)) => {
-                peer_addresses.push((peer_user.clone(), ip, port));
-            }
-            Err(_) => {
-                // Skip offline users silently
-            }
-        }
+    println!("Already indexed: {}", indexed_users.len());
+    if options.refresh.is_some() {
+        println!("Stale users to refresh: {}", stale_users.len());
     }
+    users_to_index.extend(stale_users.iter().cloned());
+    println!("Concurrent connections: {}", options.concurrency);
+
+    // First, get all peer addresses, pipelined over the one server connection
+    println!("\nResolving peer addresses ({} at a time)...", options.address_lookups);
+    let peer_addresses = client
+        .resolve_peer_addresses(&users_to_index, options.address_lookups)
+        .await?;
     println!("  Resolved {} peer addresses", peer_addresses.len());
 
     // Now fetch file lists in parallel
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_PEERS));
+    let semaphore = Arc::new(Semaphore::new(options.concurrency));
     let progress = Arc::new(std::sync::atomic::AtomicU32::new(0));
     let total = peer_addresses.len() as u32;
-    let results: Arc<Mutex<Vec<(String, Vec<SharedDirectory>)>>> = Arc::new(Mutex::new(Vec::new()));
+    let (results_tx, mut results_rx) = mpsc::unbounded_channel::<UserShares>();
     let our_username = username.to_string();
 
-    let mut handles = Vec::new();
-
     for (peer_user, ip, port) in peer_addresses {
-        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let semaphore = semaphore.clone();
         let prog = progress.clone();
-        let results = results.clone();
+        let results_tx = results_tx.clone();
         let our_user = our_username.clone();
 
-        let handle = tokio::spawn(async move {
+        tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await.unwrap();
             let current = prog.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
-            
-            match fetch_shared_files(&our_user, &peer_user, ip, port).await {
+
+            let addr = format!("{}:{}", ip, port);
+            let connector = PEER_TIMEOUTS.connector();
+            match browse_user(&connector, &addr, &our_user, current, &BROWSE_OPTIONS).await {
                 Ok(directories) => {
                     let file_count: usize = directories.iter().map(|d| d.files.len()).sum();
                     println!(
                         "[{}/{}] ✓ {} - {} files",
                         current, total, peer_user, file_count
                     );
-                    
-                    let mut res = results.lock().await;
-                    res.push((peer_user, directories));
+                    let _ = results_tx.send((peer_user, directories));
                 }
                 Err(e) => {
                     println!("[{}/{}] ✗ {} - {}", current, total, peer_user, e);
                 }
             }
-
-            drop(permit);
         });
-
-        handles.push(handle);
     }
-
-    // Wait for all tasks
-    for handle in handles {
-        let _ = handle.await;
+    drop(results_tx);
+
+    // Write results as they arrive, so a crash keeps every batch already committed
+    let mut totals = IndexTotals::default();
+    let mut batch = Vec::with_capacity(WRITE_BATCH_SIZE);
+    while let Some(shares) = results_rx.recv().await {
+        batch.push(shares);
+        if batch.len() >= WRITE_BATCH_SIZE {
+            write_batch(db, &mut batch, &stale_users, &mut totals)?;
+        }
     }
-
-    // Write results to database in a single transaction
-    println!("\nWriting to database...");
-    let results = Arc::try_unwrap(results).unwrap().into_inner();
-    let (success_count, fail_count) = db.index_users_batch(results)?;
+    write_batch(db, &mut batch, &stale_users, &mut totals)?;
 
     println!("\n========================================");
     println!("INDEXING COMPLETE");
     println!("========================================");
-    println!("Success: {} | Failed: {}", success_count, fail_count);
+    println!("Success: {} | Failed: {}", totals.success, totals.failed);
+    if options.refresh.is_some() {
+        println!(
+            "Refreshed: {} users | +{} files | -{} files",
+            totals.refreshed, totals.added, totals.removed
+        );
+    }
 
     Ok(())
 }
 
+/// Running counts of what `write_batch` has stored.
+#[derive(Debug, Default, PartialEq)]
+struct IndexTotals {
+    success: u32,
+    failed: u32,
+    refreshed: usize,
+    added: u32,
+    removed: u32,
+}
+
+/// Split users seen on the network into those missing from the index and those
+/// whose index is in `stale`, comparing names the way the index stores them.
+fn select_users(
+    seen: &HashSet<String>,
+    indexed: &[String],
+    stale: &[String],
+) -> (Vec<String>, HashSet<String>) {
+    let indexed: HashSet<&str> = indexed.iter().map(String::as_str).collect();
+    let stale: HashSet<&str> = stale.iter().map(String::as_str).collect();
+
+    let mut new_users = Vec::new();
+    let mut stale_users = HashSet::new();
+    for user in seen {
+        let stored = storable_text(user);
+        if !indexed.contains(stored.as_ref()) {
+            new_users.push(user.clone());
+        } else if stale.contains(stored.as_ref()) {
+            stale_users.insert(user.clone());
+        }
+    }
+    (new_users, stale_users)
+}
+
+/// Store and clear a batch of fetched shares, merging users in `stale_users` into their
+/// existing index and indexing the rest from scratch.
+fn write_batch(
+    db: &mut Database,
+    batch: &mut Vec<UserShares>,
+    stale_users: &HashSet<String>,
+    totals: &mut IndexTotals,
+) -> anyhow::Result<()> {
+    let (refreshed, new_users): (Vec<_>, Vec<_>) =
+        batch.drain(..).partition(|(user, _)| stale_users.contains(user));
+
+    let (success, failed) = db.index_users_batch(new_users)?;
+    totals.success += success;
+    totals.failed += failed;
+
+    for (user, directories) in &refreshed {
+        let counts = db.index_user_merge(user, directories)?;
+        totals.added += counts.added;
+        totals.removed += counts.removed;
+    }
+    totals.refreshed += refreshed.len();
+    Ok(())
+}
+
 fn run_search(query: &str, db: &Database) -> anyhow::Result<()> {
     println!("Searching for: {}\n", query);
 
@@ -601,11 +666,334 @@ fn run_search(query: &str, db: &Database) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn run_browse(username: &str, password: &str, peer_username: &str) -> anyhow::Result<()> {
+    let mut client = IndexerClient::connect(username, password).await?;
+    let (ip, port) = client.get_peer_address(peer_username).await?;
+
+    let addr = format!("{}:{}", ip, port);
+    println!("Browsing {} at {}...\n", peer_username, addr);
+    let directories = connect_to_peer_and_browse(username, 0, &addr, &PEER_TIMEOUTS)
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not browse {}: {}", peer_username, e))?;
+
+    print!("{}", format_share_tree(&directories));
+    Ok(())
+}
+
+/// Render shares as one line per directory with its files indented below, then a total.
+fn format_share_tree(directories: &[SharedDirectory]) -> String {
+    let mut sorted: Vec<_> = directories.iter().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut out = String::new();
+    let (mut file_count, mut total_size) = (0, 0);
+    for dir in sorted {
+        let _ = writeln!(out, "{}", dir.path);
+        for file in &dir.files {
+            let _ = writeln!(out, "  {} ({:.1} MB)", file.filename, file.size as f64 / 1_000_000.0);
+            file_count += 1;
+            total_size += file.size;
+        }
+    }
+    let _ = writeln!(
+        out,
+        "\n{} directories, {} files, {:.1} MB",
+        directories.len(),
+        file_count,
+        total_size as f64 / 1_000_000.0
+    );
+    out
+}
+
+fn show_top_sharers(db: &Database, limit: usize) -> anyhow::Result<()> {
+    let sharers = db.top_sharers(limit)?;
+    if sharers.is_empty() {
+        println!("No users indexed.");
+        return Ok(());
+    }
+
+    println!("Top {} sharers:\n", sharers.len());
+    for (i, (username, file_count)) in sharers.iter().enumerate() {
+        println!("{}. {} ({} files)", i + 1, username, file_count);
+    }
+    Ok(())
+}
+
+fn show_largest_files(db: &Database, limit: usize) -> anyhow::Result<()> {
+    let files = db.largest_files(limit)?;
+    if files.is_empty() {
+        println!("No files indexed.");
+        return Ok(());
+    }
+
+    println!("Largest {} files:\n", files.len());
+    for (i, file) in files.iter().enumerate() {
+        let size_mb = file.size as f64 / 1_000_000.0;
+        println!("{}. [{}] {} ({:.1} MB)", i + 1, file.username, file.filename, size_mb);
+    }
+    Ok(())
+}
+
+fn compact(db: &Database) -> anyhow::Result<()> {
+    let before = db.get_stats()?.db_size_bytes;
+    println!("Compacting database ({:.1} MB)...", before as f64 / 1_000_000.0);
+    db.vacuum()?;
+    let after = db.get_stats()?.db_size_bytes;
+    println!(
+        "✓ {:.1} MB -> {:.1} MB ({:.1} MB freed)",
+        before as f64 / 1_000_000.0,
+        after as f64 / 1_000_000.0,
+        before.saturating_sub(after) as f64 / 1_000_000.0
+    );
+    Ok(())
+}
+
 fn show_stats(db: &Database) -> anyhow::Result<()> {
     let stats = db.get_stats()?;
     println!("Index Statistics:");
     println!("  Users indexed: {}", stats.user_count);
     println!("  Total files: {}", stats.file_count);
     println!("  Database size: {:.1} MB", stats.db_size_bytes as f64 / 1_000_000.0);
+
+    let histogram = db.extension_histogram()?;
+    if !histogram.is_empty() {
+        println!("\nFiles by extension:");
+        for (extension, count) in histogram.iter().take(STATS_EXTENSIONS) {
+            let name = if extension.is_empty() { "(none)" } else { extension };
+            println!("  {:<10} {}", name, count);
+        }
+        if histogram.len() > STATS_EXTENSIONS {
+            let rest: u64 = histogram[STATS_EXTENSIONS..].iter().map(|(_, n)| n).sum();
+            println!("  {:<10} {}", "(other)", rest);
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slsk_rs::peer::SharedFile;
+
+    fn file(filename: &str, size: u64) -> SharedFile {
+        SharedFile {
+            filename: filename.to_string(),
+            size,
+            extension: SharedFile::infer_extension(filename),
+            attributes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_format_share_tree() {
+        let directories = vec![
+            SharedDirectory {
+                path: "Music\\Zebra".to_string(),
+                files: vec![file("b.flac", 25_000_000)],
+            },
+            SharedDirectory {
+                path: "Music\\Album".to_string(),
+                files: vec![file("01 One.mp3", 4_000_000), file("02 Two.mp3", 5_500_000)],
+            },
+            SharedDirectory {
+                path: "Music\\Empty".to_string(),
+                files: vec![],
+            },
+        ];
+
+        let tree = format_share_tree(&directories);
+        assert_eq!(
+            tree.lines().collect::<Vec<_>>(),
+            [
+                "Music\\Album",
+                "  01 One.mp3 (4.0 MB)",
+                "  02 Two.mp3 (5.5 MB)",
+                "Music\\Empty",
+                "Music\\Zebra",
+                "  b.flac (25.0 MB)",
+                "",
+                "3 directories, 3 files, 34.5 MB",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_peer_addresses_out_of_order() {
+        use slsk_rs::constants::ObfuscationType;
+        use slsk_rs::protocol::FrameDecoder;
+        use slsk_rs::server::read_server_request;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Answers each pair of lookups in reverse, with an unrelated reply in between
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = FrameDecoder::new();
+            let mut pending = Vec::new();
+            let mut requests = Vec::new();
+            while requests.len() < 4 {
+                stream.read_buf(frames.buffer_mut()).await.unwrap();
+                while let Some(frame) = frames.next_frame() {
+                    if let ServerRequest::GetPeerAddress { username } =
+                        read_server_request(&mut frame.unwrap()).unwrap()
+                    {
+                        pending.push(username.clone());
+                        requests.push(username);
+                    }
+                }
+                if pending.len() < 2 {
+                    continue;
+                }
+
+                let mut buf = BytesMut::new();
+                let reply = |username: &str, ip, port| ServerResponse::GetPeerAddress {
+                    username: username.to_string(),
+                    ip,
+                    port,
+                    obfuscation_type: ObfuscationType::None,
+                    obfuscated_port: 0,
+                };
+                reply("stranger", Ipv4Addr::new(10, 0, 0, 99), 1).write_message(&mut buf).unwrap();
+                for username in pending.drain(..).rev() {
+                    let (ip, port) = match username.as_str() {
+                        "alice" => (Ipv4Addr::new(10, 0, 0, 1), 2001),
+                        "bob" => (Ipv4Addr::new(10, 0, 0, 2), 2002),
+                        "carol" => (Ipv4Addr::new(10, 0, 0, 3), 2003),
+                        _ => (Ipv4Addr::new(0, 0, 0, 0), 0),
+                    };
+                    reply(&username, ip, port).write_message(&mut buf).unwrap();
+                }
+                stream.write_all(&buf).await.unwrap();
+            }
+            (stream, requests)
+        });
+
+        let mut client = IndexerClient {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            read_buf: BytesMut::new(),
+        };
+        let usernames = ["alice", "bob", "offline", "carol"].map(String::from);
+        let mut resolved = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.resolve_peer_addresses(&usernames, 2),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        resolved.sort();
+
+        assert_eq!(
+            resolved,
+            [
+                ("alice".to_string(), Ipv4Addr::new(10, 0, 0, 1), 2001),
+                ("bob".to_string(), Ipv4Addr::new(10, 0, 0, 2), 2002),
+                ("carol".to_string(), Ipv4Addr::new(10, 0, 0, 3), 2003),
+            ]
+        );
+        let (_stream, requests) = server.await.unwrap();
+        assert_eq!(requests, usernames);
+    }
+
+    #[test]
+    fn test_written_batch_survives_restart() {
+        let path = std::env::temp_dir().join(format!("slsk-index-{}.db", std::process::id()));
+        let shares = |user: &str| {
+            let dir = SharedDirectory {
+                path: "Music".to_string(),
+                files: vec![file("Music\\song.mp3", 1_000)],
+            };
+            (user.to_string(), vec![dir])
+        };
+
+        {
+            let mut db = Database::open(&path).unwrap();
+            let mut totals = IndexTotals::default();
+            let mut batch = vec![shares("alice"), shares("bob")];
+            write_batch(&mut db, &mut batch, &HashSet::new(), &mut totals).unwrap();
+            assert!(batch.is_empty());
+            assert_eq!(totals.success, 2);
+
+            // Fetched after the last write, then the run crashes
+            batch.push(shares("carol"));
+        }
+
+        let db = Database::open(&path).unwrap();
+        let mut indexed = db.get_indexed_users().unwrap();
+        indexed.sort();
+        assert_eq!(indexed, ["alice", "bob"]);
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_select_users_matches_stored_names() {
+        let db = Database::open(":memory:").unwrap();
+        for user in ["alice", "bad\0name"] {
+            db.index_user(user, &[]).unwrap();
+        }
+        let seen: HashSet<String> = ["alice", "bad\0name", "carol"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let indexed = db.get_indexed_users().unwrap();
+
+        let (new_users, stale_users) = select_users(&seen, &indexed, &[]);
+        assert_eq!(new_users, ["carol"]);
+        assert!(stale_users.is_empty());
+
+        // Stale users keep the name the server knows them by
+        let (_, stale_users) = select_users(&seen, &indexed, &indexed);
+        let mut stale_users: Vec<_> = stale_users.into_iter().collect();
+        stale_users.sort();
+        assert_eq!(stale_users, ["alice", "bad\0name"]);
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_index_options_defaults_and_flags() {
+        let options = IndexOptions::parse(&[], None).unwrap();
+        assert_eq!(options.concurrency, DEFAULT_CONCURRENCY);
+        assert_eq!(options.address_lookups, DEFAULT_ADDRESS_LOOKUPS);
+        assert_eq!(options.rooms, None);
+
+        let flags = args(&[
+            "--rooms", "a, b", "--concurrency", "4", "--lookups", "50", "--refresh",
+        ]);
+        let options = IndexOptions::parse(&flags, Some("8")).unwrap();
+        assert_eq!(options.rooms, Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(options.concurrency, 4);
+        assert_eq!(options.address_lookups, 50);
+        assert_eq!(
+            options.refresh,
+            Some(Duration::from_secs(DEFAULT_REFRESH_DAYS * 24 * 60 * 60))
+        );
+
+        // The environment only supplies the default
+        assert_eq!(IndexOptions::parse(&[], Some("8")).unwrap().concurrency, 8);
+    }
+
+    #[test]
+    fn test_index_options_rejects_bad_limits() {
+        for flags in [
+            args(&["--concurrency", "0"]),
+            args(&["--concurrency", "-1"]),
+            args(&["--lookups", "many"]),
+            args(&["--lookups"]),
+            args(&["--bogus"]),
+        ] {
+            assert!(IndexOptions::parse(&flags, None).is_err(), "{flags:?}");
+        }
+        assert!(IndexOptions::parse(&[], Some("0")).is_err());
+        assert!(IndexOptions::parse(&[], Some("")).is_err());
+    }
+
+    #[test]
+    fn test_format_share_tree_empty() {
+        assert_eq!(format_share_tree(&[]), "\n0 directories, 0 files, 0.0 MB\n");
+    }
+}
@@ -26,6 +26,22 @@ pub struct Config {
 
     /// Number of potential parents to send to clients
     pub potential_parents_count: u32,
+
+    /// Seconds without a ping before a session is reaped
+    #[serde(default = "default_session_timeout_secs")]
+    pub session_timeout_secs: u64,
+
+    /// Forward searches to branch roots of the distributed network, not just the local index
+    #[serde(default = "default_distributed_search")]
+    pub distributed_search: bool,
+}
+
+fn default_session_timeout_secs() -> u64 {
+    900
+}
+
+fn default_distributed_search() -> bool {
+    true
 }
 
 impl Default for Config {
@@ -38,6 +54,8 @@ impl Default for Config {
             min_version: 100,
             max_distributed_depth: 8,
             potential_parents_count: 10,
+            session_timeout_secs: default_session_timeout_secs(),
+            distributed_search: default_distributed_search(),
         }
     }
 }
@@ -3,14 +3,16 @@
 use std::net::SocketAddr;
 
 use anyhow::Result;
+use std::sync::Arc;
+
 use bytes::BytesMut;
 use slsk_rs::server::read_server_request;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{Notify, mpsc};
 
 use crate::config::Config;
-use crate::handlers::handle_client_message;
+use crate::handlers::{disconnect_user, handle_client_message};
 use crate::state::{SharedState, next_connection_id};
 
 pub async fn handle_connection(
@@ -31,6 +33,7 @@ pub async fn handle_connection(
 
     let (tx, mut rx) = mpsc::unbounded_channel::<BytesMut>();
     let connection_id = next_connection_id();
+    let close = Arc::new(Notify::new());
 
     // Writer task
     let write_handle = tokio::spawn(async move {
@@ -45,7 +48,11 @@ pub async fn handle_connection(
     let mut username: Option<String> = None;
 
     loop {
-        let n = read_half.read_buf(&mut read_buf).await?;
+        let n = tokio::select! {
+            n = read_half.read_buf(&mut read_buf) => n?,
+            // The session was reaped or replaced, so nothing is listening for this client
+            _ = close.notified() => break,
+        };
         if n == 0 {
             break;
         }
@@ -62,10 +69,20 @@ pub async fn handle_connection(
 
             match read_server_request(&mut msg_buf) {
                 Ok(request) => {
+                    // Any request shows the client is alive, not only ServerPing
+                    if let Some(ref name) = username {
+                        let mut state = state.write().await;
+                        if let Some(user) = state.get_user_mut(name)
+                            && user.id == connection_id
+                        {
+                            user.touch();
+                        }
+                    }
                     let session_info = SessionInfo {
                         connection_id,
                         ip,
                         tx: tx.clone(),
+                        close: close.clone(),
                         username: username.clone(),
                     };
 
@@ -86,35 +103,13 @@ pub async fn handle_connection(
         }
     }
 
-    // Clean up on disconnect
+    // Clean up on disconnect, unless a newer login has taken the username over
     if let Some(ref name) = username {
         let mut state = state.write().await;
-        if let Some(session) = state.remove_user(name) {
+        if state.get_user(name).is_some_and(|user| user.id == connection_id)
+            && let Some(session) = disconnect_user(&mut state, name, config.max_distributed_depth)
+        {
             println!("User disconnected: {} (was online)", session.username);
-
-            // Notify watchers that user went offline
-            let watchers: Vec<_> = state
-                .users
-                .values()
-                .filter(|u| u.watched_users.contains(name))
-                .map(|u| u.tx.clone())
-                .collect();
-
-            drop(state);
-
-            for watcher_tx in watchers {
-                let mut buf = BytesMut::new();
-                use slsk_rs::protocol::MessageWrite;
-                use slsk_rs::server::ServerResponse;
-
-                let msg = ServerResponse::GetUserStatus {
-                    username: name.clone(),
-                    status: slsk_rs::constants::UserStatus::Offline,
-                    privileged: false,
-                };
-                msg.write_message(&mut buf);
-                let _ = watcher_tx.send(buf);
-            }
         }
     }
 
@@ -127,5 +122,6 @@ pub struct SessionInfo {
     pub connection_id: u32,
     pub ip: std::net::Ipv4Addr,
     pub tx: mpsc::UnboundedSender<BytesMut>,
+    pub close: Arc<Notify>,
     pub username: Option<String>,
 }
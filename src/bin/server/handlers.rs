@@ -1,21 +1,22 @@
 //! Message handlers for client requests.
 
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::time::Duration;
 
 use anyhow::Result;
 use bytes::BytesMut;
 use slsk_rs::constants::{ConnectionType, ObfuscationType, UserStatus};
-use slsk_rs::peer::{PeerMessage, SearchResultFile};
+use slsk_rs::distributed::{DistributedMessage, matches_query};
+use slsk_rs::net::{Connector, TimeoutConnector};
+use slsk_rs::peer::{SearchResponseBuilder, SearchResultFile, SharedFile};
 use slsk_rs::peer_init::{PeerInitMessage, write_peer_init_message};
 use slsk_rs::protocol::MessageWrite;
 use slsk_rs::server::{PossibleParent, ServerRequest, ServerResponse, UserStats};
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
 
 use crate::config::Config;
 use crate::connection::SessionInfo;
-use crate::state::{SharedState, UserSession};
+use crate::state::{PendingMessage, ServerState, SharedState, UserSession};
 
 /// Handle a client message, returns Some(username) if login succeeded
 pub async fn handle_client_message(
@@ -34,16 +35,12 @@ pub async fn handle_client_message(
             handle_login(username, password, version, session, state, config).await
         }
 
-        ServerRequest::SetWaitPort {
-            port,
-            obfuscated_port,
-            ..
-        } => {
+        ServerRequest::SetWaitPort { port, obfuscation } => {
             if let Some(ref username) = session.username {
                 let mut state = state.write().await;
                 if let Some(user) = state.get_user_mut(username) {
                     user.port = port;
-                    user.obfuscated_port = obfuscated_port;
+                    user.obfuscated_port = obfuscation.map(|(_, obs_port)| obs_port);
                 }
             }
             Ok(None)
@@ -92,7 +89,7 @@ pub async fn handle_client_message(
                 }
             };
 
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
             Ok(None)
         }
@@ -112,7 +109,7 @@ pub async fn handle_client_message(
                 status,
                 privileged,
             };
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
             Ok(None)
         }
@@ -137,7 +134,7 @@ pub async fn handle_client_message(
                 username: target,
                 stats,
             };
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
             Ok(None)
         }
@@ -167,7 +164,7 @@ pub async fn handle_client_message(
                         }),
                         country_code: None,
                     };
-                    response.write_message(&mut buf);
+                    response.write_message(&mut buf)?;
                 } else {
                     let response = ServerResponse::WatchUser {
                         username: target,
@@ -176,7 +173,7 @@ pub async fn handle_client_message(
                         stats: None,
                         country_code: None,
                     };
-                    response.write_message(&mut buf);
+                    response.write_message(&mut buf)?;
                 }
                 let _ = session.tx.send(buf);
             }
@@ -198,10 +195,8 @@ pub async fn handle_client_message(
         }
 
         ServerRequest::HaveNoParent { no_parent } => {
-            if no_parent {
-                if let Some(ref username) = session.username {
-                    send_potential_parents(username, &session.tx, state, config).await;
-                }
+            if let (true, Some(username)) = (no_parent, &session.username) {
+                send_potential_parents(username, &session.tx, state, config).await?;
             }
             Ok(None)
         }
@@ -222,12 +217,8 @@ pub async fn handle_client_message(
                 let mut state = state.write().await;
                 if let Some(user) = state.get_user_mut(username) {
                     user.branch_level = level as i32;
-                    if level == 0 {
-                        state.branch_roots.insert(username.clone());
-                    } else {
-                        state.branch_roots.remove(username);
-                    }
                 }
+                state.update_branch(username);
                 state.update_potential_parents(config.max_distributed_depth);
             }
             Ok(None)
@@ -239,6 +230,8 @@ pub async fn handle_client_message(
                 if let Some(user) = state.get_user_mut(username) {
                     user.branch_root = Some(root);
                 }
+                state.update_branch(username);
+                state.update_potential_parents(config.max_distributed_depth);
             }
             Ok(None)
         }
@@ -253,35 +246,77 @@ pub async fn handle_client_message(
                 .filter(|r| !r.is_private)
                 .map(|r| (r.name.clone(), r.users.len() as u32))
                 .collect();
+            let private = session
+                .username
+                .as_deref()
+                .map(|username| state.private_rooms_for(username))
+                .unwrap_or_default();
 
             let response = ServerResponse::RoomList {
                 rooms,
-                owned_private_rooms: vec![],
-                private_rooms: vec![],
-                operated_private_rooms: vec![],
+                owned_private_rooms: private.owned,
+                private_rooms: private.member,
+                operated_private_rooms: private.operated,
             };
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
             Ok(None)
         }
 
-        ServerRequest::JoinRoom { room, .. } => {
+        ServerRequest::JoinRoom { room, private } => {
+            if let Some(ref username) = session.username {
+                handle_join_room(username, &room, private, &session.tx, state).await?;
+            }
+            Ok(None)
+        }
+
+        ServerRequest::AddRoomMember { room, username: member } => {
+            if let Some(ref username) = session.username {
+                handle_add_room_member(username, &room, &member, state).await?;
+            }
+            Ok(None)
+        }
+
+        ServerRequest::RemoveRoomMember { room, username: member } => {
+            if let Some(ref username) = session.username {
+                handle_remove_room_member(username, &room, &member, state).await?;
+            }
+            Ok(None)
+        }
+
+        ServerRequest::CancelRoomMembership { room } => {
             if let Some(ref username) = session.username {
-                handle_join_room(username, &room, &session.tx, state).await;
+                handle_cancel_room_membership(username, &room, state).await?;
             }
             Ok(None)
         }
 
         ServerRequest::LeaveRoom { room } => {
             if let Some(ref username) = session.username {
-                handle_leave_room(username, &room, state).await;
+                handle_leave_room(username, &room, state).await?;
             }
             Ok(None)
         }
 
         ServerRequest::SayChatroom { room, message } => {
             if let Some(ref username) = session.username {
-                handle_say_chatroom(username, &room, &message, state).await;
+                handle_say_chatroom(username, &room, &message, state).await?;
+            }
+            Ok(None)
+        }
+
+        ServerRequest::JoinGlobalRoom => {
+            if let Some(ref username) = session.username {
+                let mut state = state.write().await;
+                state.global_room_users.insert(username.clone());
+            }
+            Ok(None)
+        }
+
+        ServerRequest::LeaveGlobalRoom => {
+            if let Some(ref username) = session.username {
+                let mut state = state.write().await;
+                state.global_room_users.remove(username);
             }
             Ok(None)
         }
@@ -291,33 +326,30 @@ pub async fn handle_client_message(
             username: target,
             connection_type,
         } => {
-            // Forward connection request to target user
-            let state = state.read().await;
-            if let (Some(username), Some(target_user)) =
-                (&session.username, state.get_user(&target))
-            {
-                if let Some(requester) = state.get_user(username) {
-                    let mut buf = BytesMut::new();
-                    let response = ServerResponse::ConnectToPeer {
-                        username: username.clone(),
-                        connection_type,
-                        ip: requester.ip,
-                        port: requester.port,
-                        token,
-                        privileged: requester.privileged,
-                        obfuscation_type: ObfuscationType::None,
-                        obfuscated_port: 0,
-                    };
-                    response.write_message(&mut buf);
-                    let _ = target_user.tx.send(buf);
-                }
+            if let Some(ref username) = session.username {
+                let state = state.read().await;
+                forward_connect_to_peer(&state, username, &target, token, connection_type)?;
             }
             Ok(None)
         }
 
         ServerRequest::MessageUser { username: target, message } => {
             if let Some(ref username) = session.username {
-                handle_private_message(username, &target, &message, state).await;
+                handle_private_message(username, &target, &message, state).await?;
+            }
+            Ok(None)
+        }
+
+        ServerRequest::MessageUsers { usernames, message } => {
+            if let Some(ref username) = session.username {
+                handle_message_users(username, &usernames, &message, state).await?;
+            }
+            Ok(None)
+        }
+
+        ServerRequest::MessageAcked { message_id } => {
+            if let Some(ref username) = session.username {
+                state.write().await.ack_message(username, message_id);
             }
             Ok(None)
         }
@@ -325,13 +357,13 @@ pub async fn handle_client_message(
         ServerRequest::CheckPrivileges => {
             let mut buf = BytesMut::new();
             let response = ServerResponse::CheckPrivileges { time_left: 0 };
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
             Ok(None)
         }
 
         ServerRequest::ServerPing => {
-            // No response needed
+            // No response needed; the connection loop records every request as activity
             Ok(None)
         }
 
@@ -339,8 +371,7 @@ pub async fn handle_client_message(
             if let Some(ref username) = session.username {
                 let mut state = state.write().await;
                 if let Some(user) = state.get_user_mut(username) {
-                    user.avg_speed = speed;
-                    user.upload_count += 1;
+                    user.record_upload_speed(speed);
                 }
             }
             Ok(None)
@@ -353,6 +384,115 @@ pub async fn handle_client_message(
     }
 }
 
+/// Remove a user and tell their rooms and watchers they went offline.
+///
+/// The user is removed even if a notification can't be encoded. Parents their
+/// branch had no room for before are offered again, up to `max_depth`.
+pub fn disconnect_user(
+    state: &mut ServerState,
+    username: &str,
+    max_depth: u32,
+) -> Option<UserSession> {
+    let session = state.remove_user(username)?;
+    state.update_potential_parents(max_depth);
+
+    for room_name in &session.joined_rooms {
+        let Some(room) = state.rooms.get(room_name) else {
+            continue;
+        };
+        let mut buf = BytesMut::new();
+        let left = ServerResponse::UserLeftRoom {
+            room: room_name.clone(),
+            username: username.to_string(),
+        }
+        .write_message(&mut buf);
+        if left.is_err() {
+            continue;
+        }
+        for other in &room.users {
+            if let Some(other_user) = state.users.get(other) {
+                let _ = other_user.tx.send(buf.clone());
+            }
+        }
+    }
+
+    let mut buf = BytesMut::new();
+    let offline = ServerResponse::GetUserStatus {
+        username: username.to_string(),
+        status: UserStatus::Offline,
+        privileged: false,
+    }
+    .write_message(&mut buf);
+    if offline.is_ok() {
+        for watcher in state
+            .users
+            .values()
+            .filter(|u| u.watched_users.contains(username))
+        {
+            let _ = watcher.tx.send(buf.clone());
+        }
+    }
+
+    Some(session)
+}
+
+/// Pass a `ConnectToPeer` on to its target, or tell the requester the target is unreachable.
+pub fn forward_connect_to_peer(
+    state: &ServerState,
+    requester: &str,
+    target: &str,
+    token: u32,
+    connection_type: ConnectionType,
+) -> Result<()> {
+    let Some(requester_user) = state.get_user(requester) else {
+        return Ok(());
+    };
+
+    let mut buf = BytesMut::new();
+    match state.get_user(target) {
+        Some(target_user) => {
+            ServerResponse::ConnectToPeer {
+                username: requester.to_string(),
+                connection_type,
+                ip: requester_user.ip,
+                port: requester_user.port,
+                token,
+                privileged: requester_user.privileged,
+                obfuscation_type: ObfuscationType::None,
+                obfuscated_port: 0,
+            }
+            .write_message(&mut buf)?;
+            let _ = target_user.tx.send(buf);
+        }
+        None => {
+            ServerResponse::CantConnectToPeer {
+                token,
+                username: target.to_string(),
+            }
+            .write_message(&mut buf)?;
+            let _ = requester_user.tx.send(buf);
+        }
+    }
+    Ok(())
+}
+
+/// Drop sessions whose connection closed or that sent nothing within `timeout`,
+/// hanging up their connections.
+pub async fn reap_stale_sessions(
+    state: &SharedState,
+    timeout: Duration,
+    max_depth: u32,
+) -> Vec<String> {
+    let mut state = state.write().await;
+    let stale = state.stale_users(timeout);
+    for username in &stale {
+        if let Some(session) = disconnect_user(&mut state, username, max_depth) {
+            session.close.notify_one();
+        }
+    }
+    stale
+}
+
 async fn handle_login(
     username: String,
     password: String,
@@ -369,7 +509,7 @@ async fn handle_login(
             reason: slsk_rs::constants::LoginRejectionReason::InvalidVersion,
             detail: None,
         };
-        response.write_message(&mut buf);
+        response.write_message(&mut buf)?;
         let _ = session.tx.send(buf);
         return Ok(None);
     }
@@ -380,7 +520,7 @@ async fn handle_login(
             reason: slsk_rs::constants::LoginRejectionReason::InvalidUsername,
             detail: None,
         };
-        response.write_message(&mut buf);
+        response.write_message(&mut buf)?;
         let _ = session.tx.send(buf);
         return Ok(None);
     }
@@ -391,7 +531,7 @@ async fn handle_login(
             reason: slsk_rs::constants::LoginRejectionReason::EmptyPassword,
             detail: None,
         };
-        response.write_message(&mut buf);
+        response.write_message(&mut buf)?;
         let _ = session.tx.send(buf);
         return Ok(None);
     }
@@ -406,8 +546,9 @@ async fn handle_login(
         if let Some(old_session) = state.remove_user(&username) {
             let mut relogged_buf = BytesMut::new();
             let relogged = ServerResponse::Relogged;
-            relogged.write_message(&mut relogged_buf);
+            relogged.write_message(&mut relogged_buf)?;
             let _ = old_session.tx.send(relogged_buf);
+            old_session.close.notify_one();
         }
     }
 
@@ -417,7 +558,7 @@ async fn handle_login(
             reason: slsk_rs::constants::LoginRejectionReason::ServerFull,
             detail: None,
         };
-        response.write_message(&mut buf);
+        response.write_message(&mut buf)?;
         let _ = session.tx.send(buf);
         return Ok(None);
     }
@@ -426,13 +567,13 @@ async fn handle_login(
     match state.register_or_verify(&username, &password_hash) {
         Ok(_) => {
             // Login success
-            let user_session = UserSession::new(
+            let mut user_session = UserSession::new(
                 session.connection_id,
                 username.clone(),
-                password_hash.clone(),
                 session.ip,
                 session.tx.clone(),
             );
+            user_session.close = session.close.clone();
 
             let privileged = state
                 .registered
@@ -451,25 +592,32 @@ async fn handle_login(
                 password_hash,
                 is_supporter: privileged,
             };
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
 
             // Send distributed network params
             let mut buf2 = BytesMut::new();
             let parent_speed = ServerResponse::ParentMinSpeed { speed: 1 };
-            parent_speed.write_message(&mut buf2);
+            parent_speed.write_message(&mut buf2)?;
             let _ = session.tx.send(buf2);
 
             let mut buf3 = BytesMut::new();
             let speed_ratio = ServerResponse::ParentSpeedRatio { ratio: 50 };
-            speed_ratio.write_message(&mut buf3);
+            speed_ratio.write_message(&mut buf3)?;
             let _ = session.tx.send(buf3);
 
             let mut buf4 = BytesMut::new();
             let wishlist_interval = ServerResponse::WishlistInterval { interval: 720 };
-            wishlist_interval.write_message(&mut buf4);
+            wishlist_interval.write_message(&mut buf4)?;
             let _ = session.tx.send(buf4);
 
+            // Redeliver private messages that were never acknowledged
+            for pending in state.pending_messages_for(&username) {
+                let mut buf = BytesMut::new();
+                private_message_response(pending, false).write_message(&mut buf)?;
+                let _ = session.tx.send(buf);
+            }
+
             Ok(Some(username))
         }
         Err(reason) => {
@@ -477,7 +625,7 @@ async fn handle_login(
                 reason: slsk_rs::constants::LoginRejectionReason::InvalidPassword,
                 detail: Some(reason.to_string()),
             };
-            response.write_message(&mut buf);
+            response.write_message(&mut buf)?;
             let _ = session.tx.send(buf);
             Ok(None)
         }
@@ -489,18 +637,22 @@ async fn handle_file_search(
     query: String,
     session: SessionInfo,
     state: &SharedState,
-    _config: &Config,
+    config: &Config,
 ) -> Result<Option<String>> {
-    let Some(ref _username) = session.username else {
+    let Some(ref username) = session.username else {
         return Ok(None);
     };
 
-    // Get the client's listen port and IP
-    let (client_ip, client_port) = {
+    if config.distributed_search {
+        forward_search_to_branch_roots(&*state.read().await, username, token, &query)?;
+    }
+
+    // Get the client's listen port and IP, and the index to search
+    let (client_ip, client_port, index) = {
         let state = state.read().await;
         if let Some(ref username) = session.username {
             if let Some(user) = state.get_user(username) {
-                (user.ip, user.port)
+                (user.ip, user.port, state.index.clone())
             } else {
                 return Ok(None);
             }
@@ -509,18 +661,15 @@ async fn handle_file_search(
         }
     };
 
+    let Some(index) = index else {
+        return Ok(None);
+    };
     if client_port == 0 {
         return Ok(None);
     }
 
     // Search the local index
-    let db_path = std::env::var("SLSK_INDEX_DB").unwrap_or_else(|_| "slsk_index.db".to_string());
-    let db = match slsk_rs::db::Database::open(&db_path) {
-        Ok(db) => db,
-        Err(_) => return Ok(None),
-    };
-
-    let results = match db.search(&query, 200) {
+    let results = match index.search(&query, 200).await {
         Ok(r) => r,
         Err(_) => return Ok(None),
     };
@@ -531,13 +680,11 @@ async fn handle_file_search(
 
     // Group results by username
     let mut by_user: HashMap<String, Vec<SearchResultFile>> = HashMap::new();
-    for result in results {
-        let extension = result
-            .filename
-            .rsplit('.')
-            .next()
-            .unwrap_or("")
-            .to_string();
+    for result in results
+        .into_iter()
+        .filter(|r| matches_query(&query, &r.filename))
+    {
+        let extension = SharedFile::infer_extension(&result.filename);
 
         by_user.entry(result.username).or_default().push(SearchResultFile {
             filename: result.filename,
@@ -550,52 +697,77 @@ async fn handle_file_search(
     println!("Search '{}': {} results from {} users", query, by_user.values().map(|v| v.len()).sum::<usize>(), by_user.len());
 
     // Connect to the client and send results as each user
-    let client_ip = client_ip;
-    let client_port = client_port;
-
     for (peer_username, files) in by_user {
         let addr = format!("{}:{}", client_ip, client_port);
         let peer_user = peer_username.clone();
 
         tokio::spawn(async move {
-            if let Ok(mut stream) = TcpStream::connect(&addr).await {
-                // Send PeerInit identifying as the peer user
-                let init = PeerInitMessage::PeerInit {
-                    username: peer_user.clone(),
-                    connection_type: ConnectionType::Peer,
-                    token: 0,
-                };
-                let mut buf = BytesMut::new();
-                write_peer_init_message(&init, &mut buf);
-                let _ = stream.write_all(&buf).await;
-
-                // Send FileSearchResponse
-                buf.clear();
-                let response = PeerMessage::FileSearchResponse {
-                    username: peer_user,
-                    token,
-                    results: files,
-                    slot_free: true,
-                    avg_speed: 0,
-                    queue_length: 0,
-                    private_results: vec![],
-                };
-                response.write_message(&mut buf);
-                let _ = stream.write_all(&buf).await;
-                let _ = stream.flush().await;
-            }
+            let mut stream = TimeoutConnector::default().connect(&addr).await?;
+            // Send PeerInit identifying as the peer user
+            let init = PeerInitMessage::PeerInit {
+                username: peer_user.clone(),
+                connection_type: ConnectionType::Peer,
+                token: 0,
+            };
+            let mut buf = BytesMut::new();
+            write_peer_init_message(&init, &mut buf)?;
+            stream.write_all(&buf).await?;
+
+            // Send FileSearchResponse
+            buf.clear();
+            let response = SearchResponseBuilder::for_token(token)
+                .as_user(peer_user)
+                .add_files(files)
+                .build();
+            response.write_message(&mut buf)?;
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+            Ok::<_, anyhow::Error>(())
         });
     }
 
     Ok(None)
 }
 
+/// Send a search down the distributed network, embedded as branch roots expect it.
+///
+/// Each root passes it on to its children, and peers with matches answer the
+/// searcher directly.
+fn forward_search_to_branch_roots(
+    state: &ServerState,
+    username: &str,
+    token: u32,
+    query: &str,
+) -> Result<()> {
+    let search = DistributedMessage::Search {
+        unknown: 0x31,
+        username: username.to_string(),
+        token,
+        query: query.to_string(),
+    };
+    let mut data = Vec::new();
+    search.write_payload(&mut data);
+    let mut buf = BytesMut::new();
+    ServerResponse::EmbeddedMessage {
+        code: search.code().into(),
+        data,
+    }
+    .write_message(&mut buf)?;
+
+    for root in state.branch_roots.iter().filter(|root| *root != username) {
+        if let Some(user) = state.get_user(root) {
+            let _ = user.tx.send(buf.clone());
+        }
+    }
+    Ok(())
+}
+
 async fn send_potential_parents(
     _username: &str,
     tx: &tokio::sync::mpsc::UnboundedSender<BytesMut>,
     state: &SharedState,
     config: &Config,
-) {
+) -> Result<()> {
     let state = state.read().await;
 
     let parents: Vec<PossibleParent> = state
@@ -612,49 +784,64 @@ async fn send_potential_parents(
     if !parents.is_empty() {
         let mut buf = BytesMut::new();
         let response = ServerResponse::PossibleParents { parents };
-        response.write_message(&mut buf);
+        response.write_message(&mut buf)?;
         let _ = tx.send(buf);
     }
+    Ok(())
 }
 
 async fn handle_join_room(
     username: &str,
     room_name: &str,
+    private: bool,
     tx: &tokio::sync::mpsc::UnboundedSender<BytesMut>,
     state: &SharedState,
-) {
+) -> Result<()> {
     let mut state = state.write().await;
 
-    let room = state.get_or_create_room(room_name);
+    // Private rooms we aren't a member of look the same as invalid names
+    let Some(room) = state.join_room(room_name, username, private) else {
+        let mut buf = BytesMut::new();
+        ServerResponse::CantCreateRoom {
+            room: room_name.to_string(),
+        }
+        .write_message(&mut buf)?;
+        let _ = tx.send(buf);
+        return Ok(());
+    };
     room.users.insert(username.to_string());
 
     // Get user list for the room
     let users: Vec<String> = room.users.iter().cloned().collect();
+    let owner = room.owner.clone();
+    let mut operators: Vec<String> = room.operators.iter().cloned().collect();
+    operators.sort();
 
     // Notify others that user joined
     for other_username in &users {
-        if other_username != username {
-            if let Some(other_user) = state.get_user(other_username) {
-                let mut buf = BytesMut::new();
-                let user_stats = state.get_user(username).map(|u| UserStats {
-                    avg_speed: u.avg_speed,
-                    upload_num: u.upload_count,
-                    unknown: 0,
-                    files: u.shared_files,
-                    dirs: u.shared_folders,
-                });
-
-                let msg = ServerResponse::UserJoinedRoom {
-                    room: room_name.to_string(),
-                    username: username.to_string(),
-                    status: UserStatus::Online,
-                    stats: user_stats.unwrap_or_default(),
-                    slots_full: false,
-                    country_code: String::new(),
-                };
-                msg.write_message(&mut buf);
-                let _ = other_user.tx.send(buf);
-            }
+        if other_username == username {
+            continue;
+        }
+        if let Some(other_user) = state.get_user(other_username) {
+            let mut buf = BytesMut::new();
+            let user_stats = state.get_user(username).map(|u| UserStats {
+                avg_speed: u.avg_speed,
+                upload_num: u.upload_count,
+                unknown: 0,
+                files: u.shared_files,
+                dirs: u.shared_folders,
+            });
+
+            let msg = ServerResponse::UserJoinedRoom {
+                room: room_name.to_string(),
+                username: username.to_string(),
+                status: UserStatus::Online,
+                stats: user_stats.unwrap_or_default(),
+                slots_full: false,
+                country_code: String::new(),
+            };
+            msg.write_message(&mut buf)?;
+            let _ = other_user.tx.send(buf);
         }
     }
 
@@ -702,10 +889,10 @@ async fn handle_join_room(
     let response = ServerResponse::JoinRoom {
         room: room_name.to_string(),
         users: room_users,
-        owner: None,
-        operators: vec![],
+        owner,
+        operators,
     };
-    response.write_message(&mut buf);
+    response.write_message(&mut buf)?;
     let _ = tx.send(buf);
 
     // Send tickers
@@ -715,12 +902,13 @@ async fn handle_join_room(
             room: room_name.to_string(),
             tickers,
         };
-        ticker_msg.write_message(&mut ticker_buf);
+        ticker_msg.write_message(&mut ticker_buf)?;
         let _ = tx.send(ticker_buf);
     }
+    Ok(())
 }
 
-async fn handle_leave_room(username: &str, room_name: &str, state: &SharedState) {
+async fn handle_leave_room(username: &str, room_name: &str, state: &SharedState) -> Result<()> {
     let mut state = state.write().await;
 
     if let Some(room) = state.rooms.get_mut(room_name) {
@@ -735,7 +923,7 @@ async fn handle_leave_room(username: &str, room_name: &str, state: &SharedState)
                     room: room_name.to_string(),
                     username: username.to_string(),
                 };
-                msg.write_message(&mut buf);
+                msg.write_message(&mut buf)?;
                 let _ = other_user.tx.send(buf);
             }
         }
@@ -744,9 +932,131 @@ async fn handle_leave_room(username: &str, room_name: &str, state: &SharedState)
     if let Some(user) = state.get_user_mut(username) {
         user.joined_rooms.remove(room_name);
     }
+    Ok(())
 }
 
-async fn handle_say_chatroom(username: &str, room_name: &str, message: &str, state: &SharedState) {
+async fn handle_add_room_member(
+    by: &str,
+    room_name: &str,
+    member: &str,
+    state: &SharedState,
+) -> Result<()> {
+    let mut state = state.write().await;
+    if !state.add_room_member(room_name, by, member) {
+        return Ok(());
+    }
+
+    let granted = ServerResponse::RoomMembershipGranted {
+        room: room_name.to_string(),
+    };
+    send_to(&state, member, &granted)?;
+    let added = ServerResponse::AddRoomMember {
+        room: room_name.to_string(),
+        username: member.to_string(),
+    };
+    for username in room_member_audience(&state, room_name, member) {
+        send_to(&state, &username, &added)?;
+    }
+    Ok(())
+}
+
+async fn handle_remove_room_member(
+    by: &str,
+    room_name: &str,
+    member: &str,
+    state: &SharedState,
+) -> Result<()> {
+    {
+        let mut state = state.write().await;
+        if !state.remove_room_member(room_name, by, member) {
+            return Ok(());
+        }
+
+        let revoked = ServerResponse::RoomMembershipRevoked {
+            room: room_name.to_string(),
+        };
+        send_to(&state, member, &revoked)?;
+        let removed = ServerResponse::RemoveRoomMember {
+            room: room_name.to_string(),
+            username: member.to_string(),
+        };
+        for username in room_member_audience(&state, room_name, member) {
+            send_to(&state, &username, &removed)?;
+        }
+    }
+    eject_from_room(member, room_name, state).await
+}
+
+async fn handle_cancel_room_membership(
+    username: &str,
+    room_name: &str,
+    state: &SharedState,
+) -> Result<()> {
+    {
+        let mut state = state.write().await;
+        if !state.cancel_room_membership(room_name, username) {
+            return Ok(());
+        }
+
+        let removed = ServerResponse::RemoveRoomMember {
+            room: room_name.to_string(),
+            username: username.to_string(),
+        };
+        for other in room_member_audience(&state, room_name, username) {
+            send_to(&state, &other, &removed)?;
+        }
+    }
+    eject_from_room(username, room_name, state).await
+}
+
+/// Owner and members of a private room other than `except`
+fn room_member_audience(state: &ServerState, room_name: &str, except: &str) -> Vec<String> {
+    state
+        .rooms
+        .get(room_name)
+        .map(|room| {
+            room.member_audience()
+                .filter(|u| *u != except)
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Take a user who lost access out of a room they are in
+async fn eject_from_room(username: &str, room_name: &str, state: &SharedState) -> Result<()> {
+    let in_room = state
+        .read()
+        .await
+        .rooms
+        .get(room_name)
+        .is_some_and(|room| room.users.contains(username));
+    if !in_room {
+        return Ok(());
+    }
+
+    handle_leave_room(username, room_name, state).await?;
+    let left = ServerResponse::LeaveRoom {
+        room: room_name.to_string(),
+    };
+    send_to(&*state.read().await, username, &left)
+}
+
+fn send_to(state: &ServerState, username: &str, response: &ServerResponse) -> Result<()> {
+    if let Some(user) = state.get_user(username) {
+        let mut buf = BytesMut::new();
+        response.write_message(&mut buf)?;
+        let _ = user.tx.send(buf);
+    }
+    Ok(())
+}
+
+async fn handle_say_chatroom(
+    username: &str,
+    room_name: &str,
+    message: &str,
+    state: &SharedState,
+) -> Result<()> {
     let state = state.read().await;
 
     if let Some(room) = state.rooms.get(room_name) {
@@ -758,11 +1068,28 @@ async fn handle_say_chatroom(username: &str, room_name: &str, message: &str, sta
                     username: username.to_string(),
                     message: message.to_string(),
                 };
-                msg.write_message(&mut buf);
+                msg.write_message(&mut buf)?;
                 let _ = other_user.tx.send(buf);
             }
         }
+
+        // Mirror public rooms to the global room feed
+        if !room.is_private {
+            let mut buf = BytesMut::new();
+            ServerResponse::GlobalRoomMessage {
+                room: room_name.to_string(),
+                username: username.to_string(),
+                message: message.to_string(),
+            }
+            .write_message(&mut buf)?;
+            for subscriber in &state.global_room_users {
+                if let Some(user) = state.get_user(subscriber) {
+                    let _ = user.tx.send(buf.clone());
+                }
+            }
+        }
     }
+    Ok(())
 }
 
 async fn handle_private_message(
@@ -770,22 +1097,667 @@ async fn handle_private_message(
     to: &str,
     message: &str,
     state: &SharedState,
-) {
-    let state = state.read().await;
+) -> Result<()> {
+    let mut state = state.write().await;
+
+    // Messages to unknown users are dropped; registered users get them on next login
+    if !state.registered.contains_key(to) && !state.is_online(to) {
+        return Ok(());
+    }
+    let pending = state.queue_private_message(from, to, message);
 
     if let Some(target_user) = state.get_user(to) {
         let mut buf = BytesMut::new();
-        let msg = ServerResponse::MessageUser {
-            id: 0, // TODO: message ID tracking
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .map(|d| d.as_secs() as u32)
-                .unwrap_or(0),
-            username: from.to_string(),
-            message: message.to_string(),
-            new_message: true,
-        };
-        msg.write_message(&mut buf);
+        private_message_response(&pending, true).write_message(&mut buf)?;
         let _ = target_user.tx.send(buf);
     }
+    Ok(())
+}
+
+/// Deliver `message` to each online user in `usernames` as a private message.
+///
+/// Unlike a single `MessageUser`, nothing is held for users who are offline.
+async fn handle_message_users(
+    from: &str,
+    usernames: &[String],
+    message: &str,
+    state: &SharedState,
+) -> Result<()> {
+    for to in usernames {
+        if state.read().await.is_online(to) {
+            handle_private_message(from, to, message, state).await?;
+        }
+    }
+    Ok(())
+}
+
+fn private_message_response(pending: &PendingMessage, new_message: bool) -> ServerResponse {
+    ServerResponse::MessageUser {
+        id: pending.id,
+        timestamp: pending.timestamp,
+        username: pending.from.clone(),
+        message: pending.message.clone(),
+        new_message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::{online_users, session_info, user_session};
+    use crate::state::{MAX_PENDING_MESSAGES_PER_USER, MAX_ROOM_NAME_LEN};
+    use slsk_rs::distributed::decode_embedded;
+    use slsk_rs::server::read_server_message;
+    use std::sync::Arc;
+    use tokio::sync::{RwLock, mpsc};
+
+    fn join(state: &mut ServerState, username: &str, room: &str) {
+        state
+            .get_or_create_room(room)
+            .unwrap()
+            .users
+            .insert(username.to_string());
+        state
+            .get_user_mut(username)
+            .unwrap()
+            .joined_rooms
+            .insert(room.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_reap_closed_session_updates_rooms() {
+        let mut server = ServerState::new();
+
+        let (ghost_tx, ghost_rx) = mpsc::unbounded_channel();
+        let (alive_tx, mut alive_rx) = mpsc::unbounded_channel();
+        server.add_user(user_session(1, "ghost", ghost_tx));
+        server.add_user(user_session(2, "alive", alive_tx));
+        join(&mut server, "ghost", "lobby");
+        join(&mut server, "alive", "lobby");
+        drop(ghost_rx);
+
+        let state: SharedState = Arc::new(RwLock::new(server));
+        let max_depth = Config::default().max_distributed_depth;
+        let reaped = reap_stale_sessions(&state, Duration::from_secs(60), max_depth).await;
+        assert_eq!(reaped, vec!["ghost".to_string()]);
+
+        let server = state.read().await;
+        assert!(!server.is_online("ghost"));
+        assert!(server.is_online("alive"));
+        assert!(!server.rooms["lobby"].users.contains("ghost"));
+
+        let mut expected = BytesMut::new();
+        ServerResponse::UserLeftRoom {
+            room: "lobby".into(),
+            username: "ghost".into(),
+        }
+        .write_message(&mut expected).unwrap();
+        assert_eq!(alive_rx.try_recv().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_reap_session_past_ping_timeout() {
+        let mut server = ServerState::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut session = user_session(1, "idle", tx);
+        session.last_seen -= Duration::from_secs(120);
+        let close = session.close.clone();
+        server.add_user(session);
+
+        let state: SharedState = Arc::new(RwLock::new(server));
+        let max_depth = Config::default().max_distributed_depth;
+        assert!(
+            reap_stale_sessions(&state, Duration::from_secs(300), max_depth)
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            reap_stale_sessions(&state, Duration::from_secs(60), max_depth).await,
+            vec!["idle".to_string()]
+        );
+        // The connection task is told to hang up rather than left open without a session
+        tokio::time::timeout(Duration::from_secs(1), close.notified())
+            .await
+            .expect("reaped connection was not closed");
+    }
+
+    #[tokio::test]
+    async fn test_any_request_keeps_session_alive_until_reaped() {
+        use crate::connection::handle_connection;
+        use slsk_rs::server::ServerConnection;
+        use slsk_rs::server::ServerProfile;
+        use tokio::net::{TcpListener, TcpStream};
+
+        let state: SharedState = Arc::new(RwLock::new(ServerState::new()));
+        let max_depth = Config::default().max_distributed_depth;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, peer, server_state, Config::default()).await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = ServerConnection::from_stream(stream, ServerProfile::default());
+        client.login("chatty", "secret").await.unwrap();
+        let backdate = |state: SharedState| async move {
+            let mut state = state.write().await;
+            state.get_user_mut("chatty").unwrap().last_seen -= Duration::from_secs(120);
+        };
+
+        // A client that never pings still counts as alive while it sends anything
+        backdate(state.clone()).await;
+        client
+            .send(&ServerRequest::SetStatus {
+                status: UserStatus::Away,
+            })
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.read().await.get_user("chatty").unwrap().status != UserStatus::Away {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(
+            reap_stale_sessions(&state, Duration::from_secs(60), max_depth)
+                .await
+                .is_empty()
+        );
+
+        // Once reaped, the socket is closed instead of lingering without a session
+        backdate(state.clone()).await;
+        assert_eq!(
+            reap_stale_sessions(&state, Duration::from_secs(60), max_depth).await,
+            vec!["chatty".to_string()]
+        );
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("connection task kept running")
+            .unwrap()
+            .unwrap();
+        let (mut stream, _) = client.into_parts();
+        let mut rest = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut rest)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_global_room_feed_mirrors_public_rooms() {
+        let mut server = ServerState::new();
+        let (speaker_tx, _speaker_rx) = mpsc::unbounded_channel();
+        let (follower_tx, mut follower_rx) = mpsc::unbounded_channel();
+        server.add_user(user_session(1, "speaker", speaker_tx));
+        server.add_user(user_session(2, "follower", follower_tx));
+        join(&mut server, "speaker", "lobby");
+        join(&mut server, "speaker", "secret");
+        server.rooms.get_mut("secret").unwrap().is_private = true;
+        server.global_room_users.insert("follower".into());
+
+        let state: SharedState = Arc::new(RwLock::new(server));
+        handle_say_chatroom("speaker", "secret", "hidden", &state).await.unwrap();
+        handle_say_chatroom("speaker", "lobby", "hello", &state).await.unwrap();
+
+        let mut expected = BytesMut::new();
+        ServerResponse::GlobalRoomMessage {
+            room: "lobby".into(),
+            username: "speaker".into(),
+            message: "hello".into(),
+        }
+        .write_message(&mut expected).unwrap();
+        assert_eq!(follower_rx.try_recv().unwrap(), expected);
+        assert!(follower_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_room_name_cant_be_created() {
+        let mut server = ServerState::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        server.add_user(user_session(1, "founder", tx.clone()));
+        let state: SharedState = Arc::new(RwLock::new(server));
+
+        let too_long = "r".repeat(MAX_ROOM_NAME_LEN + 1);
+        for name in [
+            "",
+            " lobby",
+            "lobby ",
+            "two  spaces",
+            "tab\there",
+            "café",
+            too_long.as_str(),
+        ] {
+            handle_join_room("founder", name, false, &tx, &state).await.unwrap();
+
+            let mut expected = BytesMut::new();
+            ServerResponse::CantCreateRoom { room: name.into() }
+                .write_message(&mut expected)
+                .unwrap();
+            assert_eq!(rx.try_recv().unwrap(), expected, "room name {name:?}");
+            assert!(rx.try_recv().is_err());
+        }
+        assert!(state.read().await.rooms.is_empty());
+
+        handle_join_room("founder", "indie rock", false, &tx, &state).await.unwrap();
+        let mut msg = rx.try_recv().unwrap();
+        assert!(matches!(
+            read_server_message(&mut msg),
+            Ok(ServerResponse::JoinRoom { room, .. }) if room == "indie rock"
+        ));
+        let state = state.read().await;
+        assert!(state.rooms["indie rock"].users.contains("founder"));
+    }
+
+    #[test]
+    fn test_connect_to_offline_peer_is_refused() {
+        let mut server = ServerState::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        server.add_user(user_session(1, "requester", tx));
+
+        forward_connect_to_peer(&server, "requester", "gone", 42, ConnectionType::Peer).unwrap();
+
+        let mut expected = BytesMut::new();
+        ServerResponse::CantConnectToPeer {
+            token: 42,
+            username: "gone".into(),
+        }
+        .write_message(&mut expected).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), expected);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_private_message_held_until_acked() {
+        let mut server = ServerState::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        server.add_user(user_session(1, "bob", tx));
+        let state: SharedState = Arc::new(RwLock::new(server));
+
+        handle_private_message("alice", "bob", "first", &state).await.unwrap();
+        handle_private_message("alice", "bob", "second", &state).await.unwrap();
+        handle_private_message("alice", "nobody", "lost", &state).await.unwrap();
+
+        let mut ids = Vec::new();
+        for expected in ["first", "second"] {
+            let mut msg = rx.try_recv().unwrap();
+            match read_server_message(&mut msg).unwrap() {
+                ServerResponse::MessageUser {
+                    id,
+                    username,
+                    message,
+                    new_message,
+                    ..
+                } => {
+                    assert_eq!(username, "alice");
+                    assert_eq!(message, expected);
+                    assert!(new_message);
+                    ids.push(id);
+                }
+                other => panic!("unexpected response: {other:?}"),
+            }
+        }
+        assert_ne!(ids[0], ids[1]);
+
+        let mut server = state.write().await;
+        assert!(!server.ack_message("alice", ids[0]));
+        assert!(server.ack_message("bob", ids[0]));
+        assert!(!server.ack_message("bob", ids[0]));
+
+        let pending = server.pending_messages_for("bob");
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message, "second");
+        assert!(server.pending_messages_for("nobody").is_empty());
+    }
+
+    #[test]
+    fn test_pending_messages_capped_per_recipient() {
+        let mut server = ServerState::new();
+        for i in 0..MAX_PENDING_MESSAGES_PER_USER + 2 {
+            server.queue_private_message("alice", "bob", &i.to_string());
+        }
+        server.queue_private_message("alice", "carol", "hi");
+
+        let pending = server.pending_messages_for("bob");
+        assert_eq!(pending.len(), MAX_PENDING_MESSAGES_PER_USER);
+        assert_eq!(pending[0].message, "2");
+        assert_eq!(server.pending_messages_for("carol").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_message_users_reaches_every_online_user() {
+        let mut server = ServerState::new();
+        let mut receivers = Vec::new();
+        for (id, name) in [(1, "bob"), (2, "carol")] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            server.add_user(user_session(id, name, tx));
+            receivers.push(rx);
+        }
+        let state: SharedState = Arc::new(RwLock::new(server));
+
+        let usernames = ["bob", "offline", "carol"].map(String::from);
+        handle_message_users("alice", &usernames, "hello all", &state).await.unwrap();
+
+        for rx in &mut receivers {
+            let mut msg = rx.try_recv().unwrap();
+            assert!(matches!(
+                read_server_message(&mut msg).unwrap(),
+                ServerResponse::MessageUser { username, message, new_message: true, .. }
+                    if username == "alice" && message == "hello all"
+            ));
+            assert!(rx.try_recv().is_err());
+        }
+        assert!(state.read().await.pending_messages_for("offline").is_empty());
+    }
+
+    fn drain(rx: &mut mpsc::UnboundedReceiver<BytesMut>) -> Vec<ServerResponse> {
+        let mut responses = Vec::new();
+        while let Ok(mut msg) = rx.try_recv() {
+            responses.push(read_server_message(&mut msg).unwrap());
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_upload_speed_is_averaged() {
+        let (state, mut rx) = online_users(&["uploader", "asker"]);
+        let config = Config::default();
+
+        for speed in [100, 200, 600] {
+            handle_client_message(
+                ServerRequest::SendUploadSpeed { speed },
+                session_info(&state, "uploader").await,
+                &state,
+                &config,
+            )
+            .await
+            .unwrap();
+        }
+        {
+            let mut server = state.write().await;
+            let user = server.get_user_mut("uploader").unwrap();
+            assert_eq!(user.avg_speed, 300);
+            assert_eq!(user.upload_count, 3);
+
+            // The count saturates rather than wrapping back to zero
+            user.upload_count = u32::MAX - 1;
+            user.record_upload_speed(300);
+            user.record_upload_speed(300);
+            assert_eq!(user.upload_count, u32::MAX);
+            assert_eq!(user.avg_speed, 300);
+        }
+
+        handle_client_message(
+            ServerRequest::GetUserStats {
+                username: "uploader".to_string(),
+            },
+            session_info(&state, "asker").await,
+            &state,
+            &config,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            drain(rx.get_mut("asker").unwrap()).as_slice(),
+            [ServerResponse::GetUserStats { stats, .. }]
+                if stats.avg_speed == 300 && stats.upload_num == u32::MAX
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_owner_adds_private_room_member() {
+        let (state, mut rx) = online_users(&["owner", "guest"]);
+        let owner_tx = state.read().await.get_user("owner").unwrap().tx.clone();
+        let guest_tx = state.read().await.get_user("guest").unwrap().tx.clone();
+
+        handle_join_room("owner", "secret", true, &owner_tx, &state).await.unwrap();
+        assert!(matches!(
+            drain(rx.get_mut("owner").unwrap()).as_slice(),
+            [ServerResponse::JoinRoom { owner: Some(owner), .. }] if owner == "owner"
+        ));
+
+        // Not a member yet, so the room can't be joined
+        handle_join_room("guest", "secret", false, &guest_tx, &state).await.unwrap();
+        assert!(matches!(
+            drain(rx.get_mut("guest").unwrap()).as_slice(),
+            [ServerResponse::CantCreateRoom { .. }]
+        ));
+
+        handle_add_room_member("owner", "secret", "guest", &state).await.unwrap();
+        assert!(matches!(
+            drain(rx.get_mut("guest").unwrap()).as_slice(),
+            [ServerResponse::RoomMembershipGranted { room }] if room == "secret"
+        ));
+        assert!(matches!(
+            drain(rx.get_mut("owner").unwrap()).as_slice(),
+            [ServerResponse::AddRoomMember { room, username }]
+                if room == "secret" && username == "guest"
+        ));
+
+        handle_join_room("guest", "secret", false, &guest_tx, &state).await.unwrap();
+        assert!(matches!(
+            drain(rx.get_mut("guest").unwrap()).as_slice(),
+            [ServerResponse::JoinRoom { .. }]
+        ));
+
+        let list = state.read().await.private_rooms_for("guest");
+        assert_eq!(list.member, vec![("secret".to_string(), 2)]);
+        assert!(list.owned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_non_owner_cant_add_private_room_member() {
+        let (state, mut rx) = online_users(&["owner", "member", "outsider"]);
+        let owner_tx = state.read().await.get_user("owner").unwrap().tx.clone();
+        handle_join_room("owner", "secret", true, &owner_tx, &state).await.unwrap();
+        handle_add_room_member("owner", "secret", "member", &state).await.unwrap();
+        for rx in rx.values_mut() {
+            drain(rx);
+        }
+
+        handle_add_room_member("member", "secret", "outsider", &state).await.unwrap();
+        handle_add_room_member("outsider", "secret", "outsider", &state).await.unwrap();
+        handle_remove_room_member("member", "secret", "owner", &state).await.unwrap();
+
+        assert!(rx.values_mut().all(|rx| drain(rx).is_empty()));
+        let state = state.read().await;
+        let room = &state.rooms["secret"];
+        assert!(!room.members.contains("outsider"));
+        assert!(room.is_owner("owner"));
+    }
+
+    #[tokio::test]
+    async fn test_removed_member_is_notified_and_ejected() {
+        let (state, mut rx) = online_users(&["owner", "guest"]);
+        let owner_tx = state.read().await.get_user("owner").unwrap().tx.clone();
+        let guest_tx = state.read().await.get_user("guest").unwrap().tx.clone();
+        handle_join_room("owner", "secret", true, &owner_tx, &state).await.unwrap();
+        handle_add_room_member("owner", "secret", "guest", &state).await.unwrap();
+        handle_join_room("guest", "secret", false, &guest_tx, &state).await.unwrap();
+        for rx in rx.values_mut() {
+            drain(rx);
+        }
+
+        handle_remove_room_member("owner", "secret", "guest", &state).await.unwrap();
+        assert!(matches!(
+            drain(rx.get_mut("guest").unwrap()).as_slice(),
+            [
+                ServerResponse::RoomMembershipRevoked { room },
+                ServerResponse::LeaveRoom { .. },
+            ] if room == "secret"
+        ));
+        let owner_saw = drain(rx.get_mut("owner").unwrap());
+        assert!(matches!(
+            owner_saw.as_slice(),
+            [
+                ServerResponse::RemoveRoomMember { username, .. },
+                ServerResponse::UserLeftRoom { .. },
+            ] if username == "guest"
+        ));
+
+        let state = state.read().await;
+        let room = &state.rooms["secret"];
+        assert!(!room.users.contains("guest"));
+        assert!(!room.admits("guest"));
+    }
+
+    #[tokio::test]
+    async fn test_file_search_reaches_branch_roots() {
+        let (state, mut rx) = online_users(&["searcher", "sharer", "leaf"]);
+        let config = Config::default();
+
+        // Only the sharer is a branch root; the leaf hears searches from its parent
+        handle_client_message(
+            ServerRequest::BranchLevel { level: 0 },
+            session_info(&state, "sharer").await,
+            &state,
+            &config,
+        )
+        .await
+        .unwrap();
+        // The searcher has no listen port, so the local index is left alone
+        handle_client_message(
+            ServerRequest::FileSearch {
+                token: 77,
+                query: "aphex windowlicker".to_string(),
+            },
+            session_info(&state, "searcher").await,
+            &state,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let (code, data) = match drain(rx.get_mut("sharer").unwrap()).as_slice() {
+            [ServerResponse::EmbeddedMessage { code, data }] => (*code, data.clone()),
+            other => panic!("unexpected responses: {other:?}"),
+        };
+        match decode_embedded(code, &data).unwrap() {
+            DistributedMessage::Search {
+                username,
+                token,
+                query,
+                ..
+            } => {
+                assert_eq!(username, "searcher");
+                assert_eq!(token, 77);
+                assert!(matches_query(&query, "Music\\Aphex Twin\\Windowlicker.flac"));
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(drain(rx.get_mut("searcher").unwrap()).is_empty());
+        assert!(drain(rx.get_mut("leaf").unwrap()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_child_branch_level_updates_parent_tree() {
+        let (state, _rx) = online_users(&["root", "child"]);
+        let config = Config {
+            max_distributed_depth: 3,
+            ..Config::default()
+        };
+
+        for request in [
+            ServerRequest::BranchLevel { level: 0 },
+            ServerRequest::AcceptChildren { accept: true },
+        ] {
+            let session = session_info(&state, "root").await;
+            handle_client_message(request, session, &state, &config)
+                .await
+                .unwrap();
+        }
+        for request in [
+            ServerRequest::BranchRoot {
+                root: "root".to_string(),
+            },
+            ServerRequest::BranchLevel { level: 3 },
+        ] {
+            let session = session_info(&state, "child").await;
+            handle_client_message(request, session, &state, &config)
+                .await
+                .unwrap();
+        }
+
+        {
+            let state = state.read().await;
+            assert!(state.branch_roots.contains("root"));
+            assert!(!state.branch_roots.contains("child"));
+            assert!(state.branch_members["root"].contains("child"));
+            assert_eq!(state.get_user("root").unwrap().child_depth, 3);
+            // A branch three levels deep fills the depth limit
+            assert!(state.potential_parents.is_empty());
+        }
+
+        // The child moving up a level frees space in the branch
+        handle_client_message(
+            ServerRequest::BranchLevel { level: 1 },
+            session_info(&state, "child").await,
+            &state,
+            &config,
+        )
+        .await
+        .unwrap();
+        {
+            let state = state.read().await;
+            assert_eq!(state.get_user("root").unwrap().child_depth, 1);
+            let parents: Vec<_> = state.potential_parents.iter().map(|p| &p.username).collect();
+            assert_eq!(parents, ["root"]);
+        }
+
+        // Once the child disconnects the root's branch is empty again
+        disconnect_user(&mut *state.write().await, "child", config.max_distributed_depth);
+        let state = state.read().await;
+        assert!(!state.branch_members.contains_key("root"));
+        assert_eq!(state.get_user("root").unwrap().child_depth, 0);
+    }
+
+    #[tokio::test]
+    async fn test_branch_members_count_depth_below_them() {
+        let (state, _rx) = online_users(&["root", "mid", "leaf"]);
+        let config = Config {
+            max_distributed_depth: 3,
+            ..Config::default()
+        };
+        let report = |username: &'static str, level: u32| {
+            let state = state.clone();
+            let config = config.clone();
+            async move {
+                let mut requests = vec![
+                    ServerRequest::AcceptChildren { accept: true },
+                    ServerRequest::BranchLevel { level },
+                ];
+                if level > 0 {
+                    requests.push(ServerRequest::BranchRoot {
+                        root: "root".to_string(),
+                    });
+                }
+                for request in requests {
+                    let session = session_info(&state, username).await;
+                    handle_client_message(request, session, &state, &config)
+                        .await
+                        .unwrap();
+                }
+            }
+        };
+        report("root", 0).await;
+        report("mid", 1).await;
+        report("leaf", 3).await;
+
+        {
+            let state = state.read().await;
+            assert_eq!(state.get_user("root").unwrap().child_depth, 3);
+            assert_eq!(state.get_user("mid").unwrap().child_depth, 2);
+            assert_eq!(state.get_user("leaf").unwrap().child_depth, 0);
+            // The branch is full below the middle member as well as below the root
+            assert!(state.potential_parents.is_empty());
+        }
+
+        // The deepest member leaving opens the branch up again straight away
+        disconnect_user(&mut *state.write().await, "leaf", config.max_distributed_depth);
+        let state = state.read().await;
+        assert_eq!(state.get_user("mid").unwrap().child_depth, 0);
+        let parents: Vec<_> = state.potential_parents.iter().map(|p| &p.username).collect();
+        assert_eq!(parents, ["root", "mid"]);
+    }
 }
@@ -12,15 +12,20 @@ mod handlers;
 mod state;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
+use slsk_rs::db::{Database, SharedDatabase};
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 
 use config::Config;
 use connection::handle_connection;
+use handlers::reap_stale_sessions;
 use state::ServerState;
 
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
@@ -34,11 +39,32 @@ async fn main() -> Result<()> {
     println!("║ Max users: {:<28}║", config.max_users);
     println!("╚════════════════════════════════════════╝");
 
-    let state = Arc::new(RwLock::new(ServerState::new()));
+    let mut server_state = ServerState::new();
+    let db_path = std::env::var("SLSK_INDEX_DB").unwrap_or_else(|_| "slsk_index.db".to_string());
+    match Database::open(&db_path) {
+        Ok(db) => server_state.index = Some(SharedDatabase::new(db)),
+        Err(e) => eprintln!("Search index {} unavailable, searches go unanswered: {}", db_path, e),
+    }
+
+    let state = Arc::new(RwLock::new(server_state));
     let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
 
     println!("Listening on 0.0.0.0:{}", config.port);
 
+    // Reap sessions whose connection died without a clean disconnect
+    let reaper_state = state.clone();
+    let session_timeout = Duration::from_secs(config.session_timeout_secs);
+    let max_depth = config.max_distributed_depth;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            for username in reap_stale_sessions(&reaper_state, session_timeout, max_depth).await {
+                println!("Reaped stale session: {}", username);
+            }
+        }
+    });
+
     loop {
         let (stream, addr) = listener.accept().await?;
         let state = state.clone();
@@ -4,10 +4,12 @@ use std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use slsk_rs::constants::UserStatus;
-use tokio::sync::{RwLock, mpsc};
+use slsk_rs::db::SharedDatabase;
+use tokio::sync::{Notify, RwLock, mpsc};
 
 static CONNECTION_ID: AtomicU32 = AtomicU32::new(1);
 
@@ -15,12 +17,26 @@ pub fn next_connection_id() -> u32 {
     CONNECTION_ID.fetch_add(1, Ordering::SeqCst)
 }
 
+/// Unacknowledged private messages held per recipient; the oldest are dropped first.
+pub const MAX_PENDING_MESSAGES_PER_USER: usize = 100;
+
+/// Longest room name the server will create.
+pub const MAX_ROOM_NAME_LEN: usize = 24;
+
+/// Room names must be printable ASCII without leading, trailing or doubled spaces.
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+        && name.trim() == name
+        && !name.contains("  ")
+}
+
 /// A connected user session
 #[derive(Debug)]
 pub struct UserSession {
     pub id: u32,
     pub username: String,
-    pub password_hash: String,
     pub status: UserStatus,
     pub ip: Ipv4Addr,
     pub port: u32,
@@ -29,6 +45,9 @@ pub struct UserSession {
     /// Channel to send messages to this user
     pub tx: mpsc::UnboundedSender<BytesMut>,
 
+    /// Tells the connection task to hang up, e.g. when the session is reaped
+    pub close: Arc<Notify>,
+
     /// User statistics
     pub avg_speed: u32,
     pub upload_count: u32,
@@ -49,25 +68,27 @@ pub struct UserSession {
 
     /// Users being watched
     pub watched_users: HashSet<String>,
+
+    /// Last time the client pinged us
+    pub last_seen: Instant,
 }
 
 impl UserSession {
     pub fn new(
         id: u32,
         username: String,
-        password_hash: String,
         ip: Ipv4Addr,
         tx: mpsc::UnboundedSender<BytesMut>,
     ) -> Self {
         Self {
             id,
             username,
-            password_hash,
             status: UserStatus::Online,
             ip,
             port: 0,
             obfuscated_port: None,
             tx,
+            close: Arc::new(Notify::new()),
             avg_speed: 0,
             upload_count: 0,
             shared_files: 0,
@@ -79,11 +100,25 @@ impl UserSession {
             privileged: false,
             joined_rooms: HashSet::new(),
             watched_users: HashSet::new(),
+            last_seen: Instant::now(),
         }
     }
 
-    pub fn send(&self, msg: BytesMut) -> bool {
-        self.tx.send(msg).is_ok()
+    /// Fold a reported upload speed into the running mean over all uploads
+    pub fn record_upload_speed(&mut self, speed: u32) {
+        let count = u64::from(self.upload_count);
+        let total = u64::from(self.avg_speed) * count + u64::from(speed);
+        self.avg_speed = (total / (count + 1)) as u32;
+        self.upload_count = self.upload_count.saturating_add(1);
+    }
+
+    pub fn touch(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Whether the connection is gone or has not pinged within `timeout`
+    pub fn is_stale(&self, timeout: Duration) -> bool {
+        self.tx.is_closed() || self.last_seen.elapsed() > timeout
     }
 }
 
@@ -106,6 +141,33 @@ impl Room {
             ..Default::default()
         }
     }
+
+    pub fn is_owner(&self, username: &str) -> bool {
+        self.owner.as_deref() == Some(username)
+    }
+
+    /// Anyone may join a public room; only the owner and members a private one
+    pub fn admits(&self, username: &str) -> bool {
+        !self.is_private || self.is_owner(username) || self.members.contains(username)
+    }
+
+    /// The owner and operators of a private room manage its members
+    pub fn can_manage_members(&self, username: &str) -> bool {
+        self.is_private && (self.is_owner(username) || self.operators.contains(username))
+    }
+
+    /// Owner and members, who hear about membership changes
+    pub fn member_audience(&self) -> impl Iterator<Item = &String> {
+        self.owner.iter().chain(self.members.iter())
+    }
+}
+
+/// Private rooms as listed in a user's `RoomList`
+#[derive(Debug, Default)]
+pub struct PrivateRoomList {
+    pub owned: Vec<(String, u32)>,
+    pub member: Vec<(String, u32)>,
+    pub operated: Vec<String>,
 }
 
 /// Distributed network node for parent selection
@@ -117,10 +179,19 @@ pub struct DistributedNode {
     pub branch_level: i32,
 }
 
+/// A private message the recipient has not acknowledged yet
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    pub id: u32,
+    pub timestamp: u32,
+    pub from: String,
+    pub to: String,
+    pub message: String,
+}
+
 /// Registered user (persisted)
 #[derive(Debug, Clone)]
 pub struct RegisteredUser {
-    pub username: String,
     pub password_hash: String,
     pub privileged: bool,
 }
@@ -143,23 +214,76 @@ pub struct ServerState {
     /// Branch roots (level 0 users)
     pub branch_roots: HashSet<String>,
 
+    /// Users below each branch root, by root
+    pub branch_members: HashMap<String, HashSet<String>>,
+
     /// Users who accept children
     pub potential_parents: Vec<DistributedNode>,
 
-    /// Search token counter
-    search_token: AtomicU32,
+    /// Users following public chat from all rooms
+    pub global_room_users: HashSet<String>,
+
+    /// Private messages awaiting MessageAcked, by message ID
+    pub pending_messages: HashMap<u32, PendingMessage>,
+
+    /// File index answering searches, opened once at startup
+    pub index: Option<SharedDatabase>,
+
+    /// Private message ID counter
+    message_id: AtomicU32,
 }
 
 impl ServerState {
     pub fn new() -> Self {
         Self {
-            search_token: AtomicU32::new(1),
+            message_id: AtomicU32::new(1),
             ..Default::default()
         }
     }
 
-    pub fn next_search_token(&self) -> u32 {
-        self.search_token.fetch_add(1, Ordering::SeqCst)
+    /// Hold a private message until its recipient acknowledges it
+    pub fn queue_private_message(&mut self, from: &str, to: &str, message: &str) -> PendingMessage {
+        let pending = PendingMessage {
+            id: self.message_id.fetch_add(1, Ordering::SeqCst),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0),
+            from: from.to_string(),
+            to: to.to_string(),
+            message: message.to_string(),
+        };
+        self.pending_messages.insert(pending.id, pending.clone());
+
+        // A client that never acks must not grow the queue without bound
+        let held: Vec<u32> = self.pending_messages_for(to).iter().map(|m| m.id).collect();
+        let excess = held.len().saturating_sub(MAX_PENDING_MESSAGES_PER_USER);
+        for id in &held[..excess] {
+            self.pending_messages.remove(id);
+        }
+        pending
+    }
+
+    /// Drop an acknowledged message. Only its recipient may acknowledge it.
+    pub fn ack_message(&mut self, username: &str, id: u32) -> bool {
+        match self.pending_messages.get(&id) {
+            Some(pending) if pending.to == username => {
+                self.pending_messages.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Unacknowledged messages for a user, oldest first
+    pub fn pending_messages_for(&self, username: &str) -> Vec<&PendingMessage> {
+        let mut messages: Vec<_> = self
+            .pending_messages
+            .values()
+            .filter(|m| m.to == username)
+            .collect();
+        messages.sort_by_key(|m| m.id);
+        messages
     }
 
     pub fn add_user(&mut self, session: UserSession) {
@@ -173,7 +297,12 @@ impl ServerState {
         if let Some(session) = self.users.remove(username) {
             self.connections.remove(&session.id);
             self.branch_roots.remove(username);
+            self.branch_members.remove(username);
+            if let Some(root) = self.leave_branch(username) {
+                self.update_child_depth(&root);
+            }
             self.potential_parents.retain(|p| p.username != username);
+            self.global_room_users.remove(username);
 
             for room in self.rooms.values_mut() {
                 room.users.remove(username);
@@ -185,6 +314,15 @@ impl ServerState {
         }
     }
 
+    /// Usernames of sessions that should be reaped
+    pub fn stale_users(&self, timeout: Duration) -> Vec<String> {
+        self.users
+            .values()
+            .filter(|u| u.is_stale(timeout))
+            .map(|u| u.username.clone())
+            .collect()
+    }
+
     pub fn get_user(&self, username: &str) -> Option<&UserSession> {
         self.users.get(username)
     }
@@ -201,11 +339,155 @@ impl ServerState {
         self.users.len() as u32
     }
 
-    pub fn get_or_create_room(&mut self, name: &str) -> &mut Room {
+    /// Look up a room, creating it if the name is valid. `None` if it can't be created.
+    pub fn get_or_create_room(&mut self, name: &str) -> Option<&mut Room> {
         if !self.rooms.contains_key(name) {
+            if !is_valid_room_name(name) {
+                return None;
+            }
             self.rooms.insert(name.to_string(), Room::new(name.to_string()));
         }
-        self.rooms.get_mut(name).unwrap()
+        self.rooms.get_mut(name)
+    }
+
+    /// Look up a room for `username` to join, creating it if needed.
+    ///
+    /// A room created with `private` is owned by `username`. `None` if the name is
+    /// invalid or the room is private and `username` isn't its owner or a member.
+    pub fn join_room(&mut self, name: &str, username: &str, private: bool) -> Option<&mut Room> {
+        if !self.rooms.contains_key(name) {
+            let room = self.get_or_create_room(name)?;
+            if private {
+                room.is_private = true;
+                room.owner = Some(username.to_string());
+            }
+        }
+        self.rooms.get_mut(name).filter(|room| room.admits(username))
+    }
+
+    /// Let `by` add `member` to a private room. False if not allowed or already a member.
+    pub fn add_room_member(&mut self, room: &str, by: &str, member: &str) -> bool {
+        let known = self.is_online(member) || self.registered.contains_key(member);
+        let Some(room) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        if !known || !room.can_manage_members(by) || room.is_owner(member) {
+            return false;
+        }
+        room.members.insert(member.to_string())
+    }
+
+    /// Let `by` remove `member` from a private room. Only the owner may remove operators.
+    pub fn remove_room_member(&mut self, room: &str, by: &str, member: &str) -> bool {
+        let Some(room) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        if !room.can_manage_members(by) || (room.operators.contains(member) && !room.is_owner(by)) {
+            return false;
+        }
+        room.operators.remove(member);
+        room.members.remove(member)
+    }
+
+    /// A member gives up their membership of a private room
+    pub fn cancel_room_membership(&mut self, room: &str, username: &str) -> bool {
+        let Some(room) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        room.operators.remove(username);
+        room.members.remove(username)
+    }
+
+    /// Private rooms `username` owns, is a member of, or operates, sorted by name
+    pub fn private_rooms_for(&self, username: &str) -> PrivateRoomList {
+        let mut list = PrivateRoomList::default();
+        for room in self.rooms.values().filter(|r| r.is_private) {
+            let entry = (room.name.clone(), room.users.len() as u32);
+            if room.is_owner(username) {
+                list.owned.push(entry);
+            } else if room.members.contains(username) {
+                list.member.push(entry);
+            }
+            if room.operators.contains(username) {
+                list.operated.push(room.name.clone());
+            }
+        }
+        list.owned.sort();
+        list.member.sort();
+        list.operated.sort();
+        list
+    }
+
+    /// Re-file a user in the distributed tree after they report a new branch level or root.
+    ///
+    /// Level 0 users are branch roots; anyone deeper is counted under their reported
+    /// root. The root and every member get a `child_depth` reaching down to the
+    /// deepest member of the branch.
+    pub fn update_branch(&mut self, username: &str) {
+        let Some(user) = self.users.get(username) else {
+            return;
+        };
+        let level = user.branch_level;
+        let root = user.branch_root.clone();
+
+        if let Some(old_root) = self.leave_branch(username) {
+            self.update_child_depth(&old_root);
+        }
+        if level == 0 {
+            self.branch_roots.insert(username.to_string());
+        } else {
+            self.branch_roots.remove(username);
+        }
+        // Drop any depth left from the branch they were in before
+        self.update_child_depth(username);
+        if let Some(root) = root.filter(|root| level > 0 && root != username) {
+            self.branch_members
+                .entry(root.clone())
+                .or_default()
+                .insert(username.to_string());
+            self.update_child_depth(&root);
+        }
+    }
+
+    /// Take a user out of whichever branch they were under, returning its root
+    fn leave_branch(&mut self, username: &str) -> Option<String> {
+        let root = self
+            .branch_members
+            .iter()
+            .find(|(_, members)| members.contains(username))
+            .map(|(root, _)| root.clone())?;
+        if let Some(members) = self.branch_members.get_mut(&root) {
+            members.remove(username);
+            if members.is_empty() {
+                self.branch_members.remove(&root);
+            }
+        }
+        Some(root)
+    }
+
+    /// Set how far the tree reaches below `root` and each member of its branch.
+    ///
+    /// Members only report their level and root, not their parent, so any member
+    /// may be above the deepest one and is counted as reaching down to it.
+    fn update_child_depth(&mut self, root: &str) {
+        let members: Vec<String> = self
+            .branch_members
+            .get(root)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let deepest = members
+            .iter()
+            .filter_map(|member| self.users.get(member))
+            .map(|member| member.branch_level.max(0) as u32)
+            .max()
+            .unwrap_or(0);
+        for username in members.iter().map(String::as_str).chain([root]) {
+            if let Some(user) = self.users.get_mut(username) {
+                user.child_depth = deepest.saturating_sub(user.branch_level.max(0) as u32);
+            }
+        }
     }
 
     pub fn update_potential_parents(&mut self, max_depth: u32) {
@@ -245,7 +527,6 @@ impl ServerState {
             self.registered.insert(
                 username.to_string(),
                 RegisteredUser {
-                    username: username.to_string(),
                     password_hash: password_hash.to_string(),
                     privileged: false,
                 },
@@ -256,3 +537,45 @@ impl ServerState {
 }
 
 pub type SharedState = Arc<RwLock<ServerState>>;
+
+/// Fixtures shared by the server's tests.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::connection::SessionInfo;
+
+    /// A session for `username` connecting from localhost.
+    pub fn user_session(
+        id: u32,
+        username: &str,
+        tx: mpsc::UnboundedSender<BytesMut>,
+    ) -> UserSession {
+        UserSession::new(id, username.to_string(), Ipv4Addr::LOCALHOST, tx)
+    }
+
+    /// Online users with their receivers, sharing one state.
+    pub fn online_users(
+        names: &[&str],
+    ) -> (SharedState, HashMap<String, mpsc::UnboundedReceiver<BytesMut>>) {
+        let mut server = ServerState::new();
+        let mut receivers = HashMap::new();
+        for (id, name) in names.iter().enumerate() {
+            let (tx, rx) = mpsc::unbounded_channel();
+            server.add_user(user_session(id as u32 + 1, name, tx));
+            receivers.insert(name.to_string(), rx);
+        }
+        (Arc::new(RwLock::new(server)), receivers)
+    }
+
+    /// Connection info for requests from `username`, who must be online in `state`.
+    pub async fn session_info(state: &SharedState, username: &str) -> SessionInfo {
+        let tx = state.read().await.get_user(username).unwrap().tx.clone();
+        SessionInfo {
+            connection_id: 0,
+            ip: Ipv4Addr::LOCALHOST,
+            tx,
+            close: Arc::new(Notify::new()),
+            username: Some(username.to_string()),
+        }
+    }
+}
@@ -1,7 +1,15 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
-use slsk_rs::peer::{SearchResultFile, SharedDirectory};
+use slsk_rs::constants::UserStatus;
+use slsk_rs::db::{Database, DownloadRecord, DownloadState};
+use slsk_rs::peer::{QUERY_STOPWORDS, SearchResultFile, SharedDirectory, filename_to_query};
 use tokio::sync::mpsc;
 
+use crate::search::{
+    AlbumGroup, SEARCH_HISTORY_LIMIT, SearchHistory, collapse_to_best_quality, group_by_directory,
+};
 use crate::spotify::{MatchedFile, SoulseekPlaylist, SpotifyClient, SpotifyResource};
 
 #[derive(Debug, Clone)]
@@ -25,11 +33,12 @@ pub enum DownloadStatus {
 }
 
 const MAX_RETRY_ATTEMPTS: u32 = 3;
+/// Progress is written to the queue database once per this many bytes.
+const PROGRESS_SAVE_INTERVAL: u64 = 1024 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct Download {
     pub id: u32,
-    #[allow(dead_code)]
     pub username: String,
     pub filename: String,
     pub size: u64,
@@ -41,7 +50,7 @@ pub struct Download {
 
 #[derive(Debug)]
 pub enum AppEvent {
-    Connected,
+    Connecting,
     LoginSuccess {
         username: String,
     },
@@ -72,6 +81,9 @@ pub enum AppEvent {
         id: u32,
         reason: String,
     },
+    DownloadCancelled {
+        id: u32,
+    },
     SpotifyLoaded(SoulseekPlaylist),
     SpotifyError(String),
     SpotifyTrackSearching {
@@ -81,6 +93,10 @@ pub enum AppEvent {
         track_index: usize,
         matched_file: MatchedFile,
     },
+    SpotifyTrackFailed {
+        track_index: usize,
+        reason: String,
+    },
     RetryDownloadMatched {
         download_id: u32,
         matched_file: MatchedFile,
@@ -88,6 +104,20 @@ pub enum AppEvent {
     RetryDownloadFailed {
         download_id: u32,
     },
+    SharesUpdated {
+        dirs: u32,
+        files: u32,
+    },
+    UserStatus {
+        username: String,
+        status: UserStatus,
+    },
+    AdminMessage(String),
+    PrivateMessage {
+        username: String,
+        message: String,
+    },
+    Relogged,
 }
 
 #[derive(Debug, Clone)]
@@ -100,6 +130,8 @@ pub enum ClientCommand {
         filename: String,
         size: u64,
     },
+    /// Download the file named by a `slsk://user/path` link.
+    DownloadFromUrl(String),
     FetchSpotify(String),
     SearchSpotifyTrack {
         track_index: usize,
@@ -118,6 +150,14 @@ pub enum ClientCommand {
         filename: String,
         size: u64,
     },
+    CancelDownload {
+        id: u32,
+    },
+    Shares(Vec<PathBuf>),
+    /// Re-queue downloads restored from a previous session, keeping their ids.
+    ResumeDownloads(Vec<DownloadRecord>),
+    /// Say goodbye to the server and stop.
+    Quit,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -144,6 +184,8 @@ pub struct App {
     pub status: String,
     pub logged_in_user: Option<String>,
     pub search_results: Vec<SearchResult>,
+    /// Each result's files grouped by folder, kept in step with `search_results`.
+    pub result_albums: Vec<Vec<AlbumGroup>>,
     pub selected_result: usize,
     pub selected_file: usize,
     pub current_user_files: Option<(String, Vec<SharedDirectory>)>,
@@ -154,6 +196,14 @@ pub struct App {
     pub spotify_playlist: Option<SoulseekPlaylist>,
     pub selected_playlist_track: usize,
     pub spotify_searching_track: Option<usize>,
+    pub user_statuses: HashMap<String, UserStatus>,
+    /// Show only the best-quality version of tracks offered in several formats.
+    pub best_only: bool,
+    /// Persists the download queue and search history.
+    pub db: Option<Database>,
+    pub search_history: SearchHistory,
+    /// Position in `search_history` while recalling past queries.
+    history_index: Option<usize>,
 }
 
 impl App {
@@ -167,6 +217,7 @@ impl App {
             status: "Connecting...".to_string(),
             logged_in_user: None,
             search_results: Vec::new(),
+            result_albums: Vec::new(),
             selected_result: 0,
             selected_file: 0,
             current_user_files: None,
@@ -177,6 +228,101 @@ impl App {
             spotify_playlist: None,
             selected_playlist_track: 0,
             spotify_searching_track: None,
+            user_statuses: HashMap::new(),
+            best_only: false,
+            db: None,
+            search_history: SearchHistory::default(),
+            history_index: None,
+        }
+    }
+
+    /// Show unfinished downloads from a previous session and resume them.
+    pub fn restore_downloads(&mut self, records: Vec<DownloadRecord>) {
+        for record in &records {
+            self.downloads.push(Download {
+                id: record.id,
+                username: record.username.clone(),
+                filename: record
+                    .filename
+                    .rsplit(['/', '\\'])
+                    .next()
+                    .unwrap_or(&record.filename)
+                    .to_string(),
+                size: record.size,
+                downloaded: record.bytes_done,
+                status: DownloadStatus::Queued,
+                retry_count: 0,
+                original_filename: record.filename.clone(),
+            });
+        }
+
+        if !records.is_empty() {
+            self.status = format!("Resuming {} downloads", records.len());
+            let _ = self.cmd_tx.send(ClientCommand::ResumeDownloads(records));
+        }
+    }
+
+    /// Write a download's current state to the queue database.
+    ///
+    /// `requeue` replaces the stored entry, for a newly queued or re-sourced download.
+    fn record_download(&mut self, id: u32, requeue: bool) {
+        let Some(db) = &self.db else {
+            return;
+        };
+        let Some(dl) = self.downloads.iter().find(|d| d.id == id) else {
+            return;
+        };
+        let result = if requeue {
+            db.queue_download(id, &dl.username, &dl.original_filename, dl.size)
+        } else {
+            let state = match dl.status {
+                DownloadStatus::Queued => DownloadState::Queued,
+                DownloadStatus::Connecting | DownloadStatus::Downloading => {
+                    DownloadState::InProgress
+                }
+                DownloadStatus::Completed => DownloadState::Completed,
+                DownloadStatus::Failed(_) => DownloadState::Failed,
+            };
+            db.mark_download_status(id, state, dl.downloaded)
+        };
+        if let Err(e) = result {
+            self.status = format!("Failed to save download queue: {e}");
+        }
+    }
+
+    fn clear_search_results(&mut self) {
+        self.search_results.clear();
+        self.result_albums.clear();
+        self.selected_result = 0;
+    }
+
+    fn record_search(&mut self, query: &str) {
+        self.search_history.push(query);
+        if let Some(db) = &self.db
+            && let Err(e) = db.record_search(query.trim(), SEARCH_HISTORY_LIMIT)
+        {
+            self.status = format!("Failed to save search history: {e}");
+        }
+    }
+
+    /// Replace the input with a past query, `older` stepping back in time.
+    fn recall_search(&mut self, older: bool) {
+        let index = match (self.history_index, older) {
+            (None, true) => 0,
+            (Some(i), true) if i + 1 < self.search_history.len() => i + 1,
+            (Some(i), true) => i,
+            (Some(0), false) | (None, false) => {
+                self.history_index = None;
+                self.search_input.clear();
+                self.cursor_position = 0;
+                return;
+            }
+            (Some(i), false) => i - 1,
+        };
+        if let Some(query) = self.search_history.get(index) {
+            self.search_input = query.to_string();
+            self.cursor_position = self.search_input.len();
+            self.history_index = Some(index);
         }
     }
 
@@ -185,9 +331,28 @@ impl App {
     }
 
     pub fn handle_app_event(&mut self, event: AppEvent) {
+        let record = match &event {
+            AppEvent::DownloadQueued { id, .. } => Some((*id, true)),
+            AppEvent::RetryDownloadMatched { download_id, .. } => Some((*download_id, true)),
+            AppEvent::DownloadStarted { id }
+            | AppEvent::DownloadCompleted { id }
+            | AppEvent::DownloadFailed { id, .. }
+            | AppEvent::DownloadCancelled { id } => Some((*id, false)),
+            AppEvent::RetryDownloadFailed { download_id } => Some((*download_id, false)),
+            AppEvent::DownloadProgress { id, downloaded } => self
+                .downloads
+                .iter()
+                .find(|d| d.id == *id)
+                .filter(|d| {
+                    d.downloaded / PROGRESS_SAVE_INTERVAL != downloaded / PROGRESS_SAVE_INTERVAL
+                })
+                .map(|_| (*id, false)),
+            _ => None,
+        };
+
         match event {
-            AppEvent::Connected => {
-                self.status = "Connected, logging in...".to_string();
+            AppEvent::Connecting => {
+                self.status = "Connecting and logging in...".to_string();
             }
             AppEvent::LoginSuccess { username } => {
                 self.logged_in_user = Some(username.clone());
@@ -197,6 +362,8 @@ impl App {
                 self.status = format!("Login failed: {reason}");
             }
             AppEvent::SearchResult(result) => {
+                self.result_albums
+                    .push(group_by_directory(std::slice::from_ref(&result)));
                 self.search_results.push(result);
                 self.status = format!(
                     "{} results from {} users",
@@ -282,6 +449,13 @@ impl App {
                     }
                 }
             }
+            AppEvent::DownloadCancelled { id } => {
+                if let Some(dl) = self.downloads.iter_mut().find(|d| d.id == id) {
+                    // Failed rather than removed, so it can still be retried
+                    dl.status = DownloadStatus::Failed("Cancelled".to_string());
+                    self.status = format!("Cancelled: {}", dl.filename);
+                }
+            }
             AppEvent::SpotifyLoaded(playlist) => {
                 let count = playlist.tracks.len();
                 let name = playlist.name.clone();
@@ -324,6 +498,15 @@ impl App {
                 }
                 self.spotify_searching_track = None;
             }
+            AppEvent::SpotifyTrackFailed {
+                track_index,
+                reason,
+            } => {
+                if self.spotify_searching_track == Some(track_index) {
+                    self.spotify_searching_track = None;
+                }
+                self.status = reason;
+            }
             AppEvent::RetryDownloadMatched {
                 download_id,
                 matched_file,
@@ -352,6 +535,26 @@ impl App {
                     self.status = format!("No alternatives found for: {}", dl.filename);
                 }
             }
+            AppEvent::SharesUpdated { dirs, files } => {
+                self.status = format!("Sharing {} files in {} folders", files, dirs);
+            }
+            AppEvent::UserStatus { username, status } => {
+                self.user_statuses.insert(username, status);
+            }
+            AppEvent::AdminMessage(message) => {
+                self.status = format!("Server message: {message}");
+            }
+            AppEvent::PrivateMessage { username, message } => {
+                self.status = format!("Message from {username}: {message}");
+            }
+            AppEvent::Relogged => {
+                self.logged_in_user = None;
+                self.status = "Disconnected: logged in from another location".to_string();
+            }
+        }
+
+        if let Some((id, requeue)) = record {
+            self.record_download(id, requeue);
         }
     }
 
@@ -366,8 +569,16 @@ impl App {
         match key.code {
             KeyCode::Enter => {
                 self.input_mode = InputMode::Normal;
+                self.history_index = None;
                 if !self.search_input.is_empty() {
-                    if let Some(resource) = SpotifyClient::parse_spotify_url(&self.search_input) {
+                    if self.search_input.trim().starts_with("slsk://") {
+                        let url = std::mem::take(&mut self.search_input);
+                        self.cursor_position = 0;
+                        self.status = "Queueing download from link...".to_string();
+                        let _ = self.cmd_tx.send(ClientCommand::DownloadFromUrl(url));
+                    } else if let Some(resource) =
+                        SpotifyClient::parse_spotify_url(&self.search_input)
+                    {
                         let url = self.search_input.clone();
                         self.search_input.clear();
                         self.cursor_position = 0;
@@ -381,8 +592,9 @@ impl App {
                             }
                         }
                     } else {
-                        self.search_results.clear();
-                        self.selected_result = 0;
+                        let query = self.search_input.clone();
+                        self.record_search(&query);
+                        self.clear_search_results();
                         self.status = format!("Searching for '{}'...", self.search_input);
                         let _ = self
                             .cmd_tx
@@ -392,38 +604,41 @@ impl App {
             }
             KeyCode::Esc => {
                 self.input_mode = InputMode::Normal;
+                self.history_index = None;
+            }
+            KeyCode::Up => self.recall_search(true),
+            KeyCode::Down => self.recall_search(false),
+            KeyCode::Tab => {
+                if let Some(query) = self.search_history.complete(&self.search_input) {
+                    self.search_input = query.to_string();
+                    self.cursor_position = self.search_input.len();
+                }
             }
             KeyCode::Char(c) => {
                 self.search_input.insert(self.cursor_position, c);
                 self.cursor_position += 1;
             }
-            KeyCode::Backspace if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                if self.cursor_position > 0 {
-                    let text = &self.search_input[..self.cursor_position];
-                    let new_pos = text
-                        .trim_end()
-                        .rfind(|c: char| c.is_whitespace())
-                        .map(|i| i + 1)
-                        .unwrap_or(0);
-                    self.search_input.drain(new_pos..self.cursor_position);
-                    self.cursor_position = new_pos;
-                }
-            }
-            KeyCode::Backspace => {
-                if self.cursor_position > 0 {
-                    self.cursor_position -= 1;
-                    self.search_input.remove(self.cursor_position);
-                }
-            }
-            KeyCode::Left => {
-                if self.cursor_position > 0 {
-                    self.cursor_position -= 1;
-                }
-            }
-            KeyCode::Right => {
-                if self.cursor_position < self.search_input.len() {
-                    self.cursor_position += 1;
-                }
+            KeyCode::Backspace
+                if key.modifiers.contains(KeyModifiers::CONTROL) && self.cursor_position > 0 =>
+            {
+                let text = &self.search_input[..self.cursor_position];
+                let new_pos = text
+                    .trim_end()
+                    .rfind(|c: char| c.is_whitespace())
+                    .map(|i| i + 1)
+                    .unwrap_or(0);
+                self.search_input.drain(new_pos..self.cursor_position);
+                self.cursor_position = new_pos;
+            }
+            KeyCode::Backspace if self.cursor_position > 0 => {
+                self.cursor_position -= 1;
+                self.search_input.remove(self.cursor_position);
+            }
+            KeyCode::Left if self.cursor_position > 0 => {
+                self.cursor_position -= 1;
+            }
+            KeyCode::Right if self.cursor_position < self.search_input.len() => {
+                self.cursor_position += 1;
             }
             KeyCode::Home => {
                 self.cursor_position = 0;
@@ -539,21 +754,34 @@ impl App {
             KeyCode::Char('G') => self.jump_to_end(),
             KeyCode::Home => self.jump_to_start(),
             KeyCode::End => self.jump_to_end(),
-            KeyCode::Enter | KeyCode::Char('b') if self.focus == Focus::Results => {
-                if !self.search_results.is_empty() {
-                    let result = &self.search_results[self.selected_result];
-                    let username = result.username.clone();
-                    let files = result.files.clone();
-                    self.current_search_files = Some((username.clone(), files));
-                    self.focus = Focus::Files;
-                    self.selected_file = 0;
-                    self.file_scroll = 0;
-                    self.status = format!(
-                        "Showing {} matching files from {}",
-                        result.files.len(),
-                        username
-                    );
-                }
+            KeyCode::Enter | KeyCode::Char('b')
+                if self.focus == Focus::Results && !self.search_results.is_empty() =>
+            {
+                let result = &self.search_results[self.selected_result];
+                let username = result.username.clone();
+                let files = if self.best_only {
+                    collapse_to_best_quality(std::slice::from_ref(result)).remove(0).files
+                } else {
+                    result.files.clone()
+                };
+                let count = files.len();
+                self.current_search_files = Some((username.clone(), files));
+                self.focus = Focus::Files;
+                self.selected_file = 0;
+                self.file_scroll = 0;
+                self.status = format!(
+                    "Showing {} matching files from {}",
+                    count,
+                    username
+                );
+            }
+            KeyCode::Char('f') if self.focus == Focus::Results => {
+                self.best_only = !self.best_only;
+                self.status = if self.best_only {
+                    "Showing only the best version of each track".to_string()
+                } else {
+                    "Showing every version of each track".to_string()
+                };
             }
             KeyCode::Char('d') if self.focus == Focus::Files => {
                 self.download_selected_file();
@@ -573,6 +801,9 @@ impl App {
             KeyCode::Char('r') if self.focus == Focus::Downloads => {
                 self.retry_failed_download();
             }
+            KeyCode::Char('x') if self.focus == Focus::Downloads => {
+                self.cancel_selected_download();
+            }
             _ => {}
         }
     }
@@ -581,12 +812,11 @@ impl App {
         if self.selected_download < self.downloads.len() {
             let download = &self.downloads[self.selected_download];
             if matches!(download.status, DownloadStatus::Failed(_)) {
-                let query = Self::filename_to_search_query(&download.filename);
+                let query = filename_to_query(&download.filename, QUERY_STOPWORDS);
                 self.search_input = query.clone();
                 self.cursor_position = query.len();
                 let _ = self.cmd_tx.send(ClientCommand::Search(query.clone()));
-                self.search_results.clear();
-                self.selected_result = 0;
+                self.clear_search_results();
                 self.focus = Focus::Results;
                 self.status = format!("Re-searching for: {}", query);
             } else {
@@ -595,33 +825,17 @@ impl App {
         }
     }
 
-    fn filename_to_search_query(filename: &str) -> String {
-        let name = std::path::Path::new(filename)
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or(filename);
-
-        name.replace(['_', '-', '.'], " ")
-            .split_whitespace()
-            .filter(|word| {
-                let lower = word.to_lowercase();
-                !matches!(
-                    lower.as_str(),
-                    "flac"
-                        | "mp3"
-                        | "wav"
-                        | "ogg"
-                        | "m4a"
-                        | "320"
-                        | "256"
-                        | "128"
-                        | "192"
-                        | "24bit"
-                        | "16bit"
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(" ")
+    fn cancel_selected_download(&mut self) {
+        if let Some(download) = self.downloads.get(self.selected_download) {
+            if matches!(
+                download.status,
+                DownloadStatus::Completed | DownloadStatus::Failed(_)
+            ) {
+                self.status = "Can only cancel unfinished downloads".to_string();
+            } else {
+                let _ = self.cmd_tx.send(ClientCommand::CancelDownload { id: download.id });
+            }
+        }
     }
 
     fn download_selected_file(&mut self) {
@@ -1,5 +1,7 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
 use std::net::Ipv4Addr;
+use std::ops::ControlFlow;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
@@ -7,28 +9,49 @@ use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use slsk_rs::constants::{
-    ConnectionType, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, TransferDirection,
+    ConnectionType, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, ObfuscationType, TransferDirection,
+    TransferRejectionReason, UploadPermission, UserStatus,
+};
+use slsk_rs::db::DownloadRecord;
+use slsk_rs::distributed::{
+    DistributedMessage, DistributedState, decode_embedded, write_distributed_message,
+};
+use slsk_rs::file::{DownloadConfig, FileOffset, FileTransferInit};
+use slsk_rs::net::{Connector, TransferTimeouts};
+use slsk_rs::peer::{
+    FileAttributes, PEER_IDLE_TIMEOUT, PeerConnectionPool, PeerMessage, QUERY_STOPWORDS,
+    RankOptions, SharedDirectory, connect_to_peer_and_browse, filename_to_query, parse_slsk_url,
+    rank_search_results, read_peer_message, search_shares, send_pooled,
 };
-use slsk_rs::db::Database;
-use slsk_rs::file::{FileOffset, FileTransferInit};
-use slsk_rs::peer::{PeerMessage, SearchResultFile, SharedDirectory, read_peer_message};
 use slsk_rs::peer_init::{
     PeerInitMessage, peer_init_message_size, read_peer_init_message, write_peer_init_message,
 };
 use slsk_rs::protocol::MessageWrite;
-use slsk_rs::server::{ServerRequest, ServerResponse, read_server_message};
+use slsk_rs::server::{
+    AddressCache, BackoffConfig, PeerAddress, PeerConnector, PeerSearchResult,
+    SEARCH_SESSION_TIMEOUT, SearchFilter, SearchPeers, SearchRateLimiter, SearchSession,
+    RoomState, ServerConnection, ServerProfile, ServerRequest, ServerResponse, TokenRegistry,
+    connect_with_backoff, drain_messages,
+};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, mpsc, oneshot, watch};
 
 use crate::app::{AppEvent, ClientCommand, SearchResult};
+use crate::shares::{Shares, scan_shares, share_counts};
 use crate::spotify::{MatchedFile, SoulseekPlaylist, SpotifyClient, SpotifyResource};
 
-const SEARCH_AGGREGATION_TIMEOUT: Duration = Duration::from_secs(5);
+const BROWSE_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// How long a sent search keeps accepting late results before its token is forgotten.
+const SEARCH_TOKEN_TTL: Duration = Duration::from_secs(600);
 
-const SEARCH_RATE_LIMIT_MAX: usize = 34;
-const SEARCH_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(220);
+/// How long quitting waits for the goodbye requests to reach the server.
+pub const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Obfuscated framings we can speak. None yet, so peers are dialled on their plain port.
+const SUPPORTED_OBFUSCATION: &[ObfuscationType] = &[];
 
 #[derive(Debug, Clone)]
 enum QueuedSearch {
@@ -37,78 +60,20 @@ enum QueuedSearch {
     RetryDownload { download_id: u32, original_filename: String, query: String },
 }
 
-#[derive(Debug)]
-struct SearchRateLimiter {
-    search_timestamps: VecDeque<Instant>,
-    queued_searches: VecDeque<QueuedSearch>,
-}
-
-impl SearchRateLimiter {
-    fn new() -> Self {
-        Self {
-            search_timestamps: VecDeque::new(),
-            queued_searches: VecDeque::new(),
-        }
-    }
-
-    fn prune_old_searches(&mut self) {
-        let cutoff = Instant::now() - SEARCH_RATE_LIMIT_WINDOW;
-        while let Some(&ts) = self.search_timestamps.front() {
-            if ts < cutoff {
-                self.search_timestamps.pop_front();
-            } else {
-                break;
-            }
-        }
-    }
-
-    fn can_search(&mut self) -> bool {
-        self.prune_old_searches();
-        self.search_timestamps.len() < SEARCH_RATE_LIMIT_MAX
-    }
-
-    fn record_search(&mut self) {
-        self.search_timestamps.push_back(Instant::now());
-    }
-
-    fn time_until_next_slot(&mut self) -> Option<Duration> {
-        self.prune_old_searches();
-        if self.search_timestamps.len() < SEARCH_RATE_LIMIT_MAX {
-            return None;
+impl QueuedSearch {
+    fn query(&self) -> &str {
+        match self {
+            QueuedSearch::Regular { query }
+            | QueuedSearch::SpotifyTrack { query, .. }
+            | QueuedSearch::RetryDownload { query, .. } => query,
         }
-        self.search_timestamps
-            .front()
-            .map(|&ts| (ts + SEARCH_RATE_LIMIT_WINDOW).saturating_duration_since(Instant::now()))
-    }
-
-    fn searches_remaining(&mut self) -> usize {
-        self.prune_old_searches();
-        SEARCH_RATE_LIMIT_MAX.saturating_sub(self.search_timestamps.len())
     }
-
-    fn queue_search(&mut self, search: QueuedSearch) {
-        self.queued_searches.push_back(search);
-    }
-
-    fn pop_queued(&mut self) -> Option<QueuedSearch> {
-        self.queued_searches.pop_front()
-    }
-
-    fn queued_count(&self) -> usize {
-        self.queued_searches.len()
-    }
-}
-
-#[derive(Debug, Clone)]
-struct AccumulatedResult {
-    username: String,
-    file: SearchResultFile,
 }
 
 #[derive(Debug)]
 struct PendingSpotifySearch {
     track_index: usize,
-    results: Vec<AccumulatedResult>,
+    peers: SearchPeers,
 }
 
 #[derive(Debug)]
@@ -116,7 +81,15 @@ struct PendingRetrySearch {
     download_id: u32,
     #[allow(dead_code)]
     original_filename: String,
-    results: Vec<AccumulatedResult>,
+    peers: SearchPeers,
+}
+
+/// A search sent to the server, by what its results are for.
+#[derive(Debug)]
+enum ActiveSearch {
+    Regular,
+    SpotifyTrack(PendingSpotifySearch),
+    RetryDownload(PendingRetrySearch),
 }
 
 static TOKEN_COUNTER: AtomicU32 = AtomicU32::new(1);
@@ -125,26 +98,220 @@ fn next_token() -> u32 {
     TOKEN_COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
+/// Make sure tokens issued from now on never collide with `token`.
+fn reserve_token(token: u32) {
+    TOKEN_COUNTER.fetch_max(token.wrapping_add(1), Ordering::SeqCst);
+}
+
 #[derive(Debug, Clone)]
 struct PendingDownload {
     id: u32,
-    #[allow(dead_code)]
     username: String,
     filename: String,
     size: u64,
     token: u32,
+    /// Bytes already downloaded in an earlier session
+    offset: u64,
 }
 
 struct ClientState {
     username: String,
-    pending_searches: HashMap<u32, String>,
+    searches: TokenRegistry<ActiveSearch>,
     pending_browse: HashMap<String, ()>,
+    browse_cache: HashMap<String, (Instant, Vec<SharedDirectory>)>,
     pending_downloads: HashMap<String, Vec<PendingDownload>>,
     active_download_users: std::collections::HashSet<String>,
     spotify_playlist: Option<SoulseekPlaylist>,
-    spotify_track_searches: HashMap<u32, PendingSpotifySearch>,
-    retry_searches: HashMap<u32, PendingRetrySearch>,
-    rate_limiter: SearchRateLimiter,
+    rate_limiter: SearchRateLimiter<QueuedSearch>,
+    /// Phrases the server refuses to search for.
+    search_filter: SearchFilter,
+    shares: Shares,
+    pending_uploads: HashMap<u32, PathBuf>,
+    pending_search_replies: HashMap<String, Vec<PeerMessage>>,
+    user_statuses: HashMap<String, UserStatus>,
+    accept_children: bool,
+    branch: DistributedState,
+    distributed_children: HashMap<String, mpsc::UnboundedSender<BytesMut>>,
+    download_config: DownloadConfig,
+    timeouts: TransferTimeouts,
+    /// Shown to peers who request our user info.
+    description: String,
+    uploads_completed: u32,
+    /// Connections our search replies go out on, shared so sends don't hold the state lock.
+    peer_pool: Arc<Mutex<PeerConnectionPool>>,
+    /// Running downloads by id, signalled to stop them early.
+    download_cancels: HashMap<u32, watch::Sender<bool>>,
+    /// Downloads handed to a peer's download task but not started yet.
+    queued_downloads: HashSet<u32>,
+    /// Peer addresses looked up recently, to skip repeat `GetPeerAddress` round-trips.
+    addresses: AddressCache,
+    /// Opens download connections, asking the server for an indirect one if dialling fails.
+    peers: PeerConnector,
+    /// The `ConnectToPeer` requests `peers` makes, until the session forwards them.
+    peer_requests: Option<mpsc::UnboundedReceiver<ServerRequest>>,
+    /// Rooms we are in, left on quit.
+    rooms: RoomState,
+}
+
+impl ClientState {
+    fn new(username: &str) -> Self {
+        let (peer_requests_tx, peer_requests) = mpsc::unbounded_channel();
+        Self {
+            username: username.to_string(),
+            searches: TokenRegistry::new(SEARCH_TOKEN_TTL),
+            pending_browse: HashMap::new(),
+            browse_cache: HashMap::new(),
+            pending_downloads: HashMap::new(),
+            active_download_users: std::collections::HashSet::new(),
+            spotify_playlist: None,
+            rate_limiter: SearchRateLimiter::new(),
+            search_filter: SearchFilter::new(),
+            shares: Shares::default(),
+            pending_uploads: HashMap::new(),
+            pending_search_replies: HashMap::new(),
+            user_statuses: HashMap::new(),
+            accept_children: false,
+            branch: DistributedState::new(username),
+            distributed_children: HashMap::new(),
+            download_config: DownloadConfig::default(),
+            timeouts: TransferTimeouts::default(),
+            description: String::new(),
+            uploads_completed: 0,
+            peer_pool: Arc::new(Mutex::new(PeerConnectionPool::new())),
+            download_cancels: HashMap::new(),
+            queued_downloads: HashSet::new(),
+            addresses: AddressCache::new(),
+            peers: PeerConnector::new(username, peer_requests_tx),
+            peer_requests: Some(peer_requests),
+            rooms: RoomState::new(),
+        }
+    }
+
+    /// Replace the shared directories, returning the report to send to the server.
+    fn set_shares(&mut self, shares: Shares) -> ServerRequest {
+        let (dirs, files) = share_counts(&shares.directories);
+        self.shares = shares;
+        ServerRequest::SharedFoldersFiles { dirs, files }
+    }
+
+    /// Our reply to someone else's search, if any shared files match.
+    fn search_response(&self, token: u32, query: &str) -> Option<PeerMessage> {
+        let results = search_shares(&self.shares.directories, query);
+        if results.is_empty() {
+            return None;
+        }
+        Some(PeerMessage::FileSearchResponse {
+            username: self.username.clone(),
+            token,
+            results,
+            slot_free: true,
+            avg_speed: 0,
+            queue_length: self.pending_uploads.len() as u32,
+            private_results: vec![],
+        })
+    }
+
+    /// Our reply to a peer's `UserInfoRequest`.
+    fn user_info(&self) -> PeerMessage {
+        PeerMessage::UserInfoResponse {
+            description: self.description.clone(),
+            picture: None,
+            total_uploads: self.uploads_completed,
+            queue_size: self.pending_uploads.len() as u32,
+            slots_free: true,
+            upload_permitted: Some(UploadPermission::Everyone),
+        }
+    }
+
+    /// Share list from a recent browse of `username`, if still fresh.
+    fn cached_browse(&self, username: &str) -> Option<&Vec<SharedDirectory>> {
+        self.browse_cache
+            .get(username)
+            .filter(|(fetched, _)| fetched.elapsed() < BROWSE_CACHE_TTL)
+            .map(|(_, dirs)| dirs)
+    }
+
+    fn cache_browse(&mut self, username: &str, directories: Vec<SharedDirectory>) {
+        self.browse_cache
+            .retain(|_, (fetched, _)| fetched.elapsed() < BROWSE_CACHE_TTL);
+        self.browse_cache
+            .insert(username.to_string(), (Instant::now(), directories));
+    }
+
+    /// Track a download that is about to run so it can be cancelled.
+    fn register_download(&mut self, id: u32) -> watch::Receiver<bool> {
+        let (tx, rx) = watch::channel(false);
+        self.download_cancels.insert(id, tx);
+        rx
+    }
+
+    /// Start download `id` from a download task's queue, unless it was cancelled while waiting.
+    fn start_queued_download(&mut self, id: u32) -> Option<watch::Receiver<bool>> {
+        self.queued_downloads
+            .remove(&id)
+            .then(|| self.register_download(id))
+    }
+
+    /// Stop download `id`, returning true if it hadn't started yet.
+    ///
+    /// A running download reports its own cancellation once it has stopped.
+    fn cancel_download(&mut self, id: u32) -> bool {
+        if let Some(cancel) = self.download_cancels.get(&id) {
+            let _ = cancel.send(true);
+            return false;
+        }
+        if self.queued_downloads.remove(&id) {
+            return true;
+        }
+        let mut found = false;
+        self.pending_downloads.retain(|_, downloads| {
+            let before = downloads.len();
+            downloads.retain(|d| d.id != id);
+            found |= downloads.len() != before;
+            !downloads.is_empty()
+        });
+        found
+    }
+
+    /// Resolve a remote `dir\file` request to a local shared path and size.
+    fn find_shared_file(&self, filename: &str) -> Option<(PathBuf, u64)> {
+        let (dir, name) = filename.rsplit_once('\\')?;
+        let size = self
+            .shares
+            .directories
+            .iter()
+            .filter(|d| d.path == dir)
+            .flat_map(|d| &d.files)
+            .find(|f| f.filename == name)?
+            .size;
+        Some((self.shares.local_path(dir, name)?, size))
+    }
+
+    /// Branch info a new distributed child needs to place itself in our branch.
+    fn branch_info(&self) -> Vec<DistributedMessage> {
+        self.branch.branch_info()
+    }
+
+    /// Send a distributed message to every child, dropping children that have gone away.
+    fn relay_to_children(&mut self, msg: &DistributedMessage) {
+        let mut buf = BytesMut::new();
+        if write_distributed_message(msg, &mut buf).is_err() {
+            return;
+        }
+        self.distributed_children
+            .retain(|_, tx| tx.send(buf.clone()).is_ok());
+    }
+}
+
+/// Encode `request` and queue it for the server connection.
+fn send_to_server(
+    request: &ServerRequest,
+    write_tx: &mpsc::UnboundedSender<BytesMut>,
+) -> slsk_rs::Result<()> {
+    let mut buf = BytesMut::new();
+    request.write_message(&mut buf)?;
+    let _ = write_tx.send(buf);
+    Ok(())
 }
 
 async fn execute_search(
@@ -158,16 +325,18 @@ async fn execute_search(
         QueuedSearch::Regular { query } => {
             {
                 let mut st = state.lock().await;
-                st.pending_searches.insert(token, query.clone());
-                st.rate_limiter.record_search();
+                st.searches.expire();
+                st.searches.register(token, ActiveSearch::Regular);
+                st.rate_limiter.record();
             }
             let req = ServerRequest::FileSearch {
                 token,
                 query: query.clone(),
             };
-            let mut buf = BytesMut::new();
-            req.write_message(&mut buf);
-            let _ = write_tx.send(buf);
+            if let Err(e) = send_to_server(&req, write_tx) {
+                let _ = event_tx.send(AppEvent::Error(format!("Search failed: {e}")));
+                return;
+            }
 
             let remaining = {
                 let mut st = state.lock().await;
@@ -181,24 +350,23 @@ async fn execute_search(
         QueuedSearch::SpotifyTrack { track_index, query } => {
             {
                 let mut st = state.lock().await;
-                st.pending_searches.insert(token, query.clone());
-                st.spotify_track_searches.insert(
+                let peers = collect_search(token, state, event_tx);
+                st.searches.expire();
+                st.searches.register(
                     token,
-                    PendingSpotifySearch {
-                        track_index,
-                        results: Vec::new(),
-                    },
+                    ActiveSearch::SpotifyTrack(PendingSpotifySearch { track_index, peers }),
                 );
-                st.rate_limiter.record_search();
+                st.rate_limiter.record();
             }
             let _ = event_tx.send(AppEvent::SpotifyTrackSearching { track_index });
             let req = ServerRequest::FileSearch {
                 token,
                 query: query.clone(),
             };
-            let mut buf = BytesMut::new();
-            req.write_message(&mut buf);
-            let _ = write_tx.send(buf);
+            if let Err(e) = send_to_server(&req, write_tx) {
+                let _ = event_tx.send(AppEvent::Error(format!("Search failed: {e}")));
+                return;
+            }
 
             let remaining = {
                 let mut st = state.lock().await;
@@ -212,24 +380,26 @@ async fn execute_search(
         QueuedSearch::RetryDownload { download_id, original_filename, query } => {
             {
                 let mut st = state.lock().await;
-                st.pending_searches.insert(token, query.clone());
-                st.retry_searches.insert(
+                let peers = collect_search(token, state, event_tx);
+                st.searches.expire();
+                st.searches.register(
                     token,
-                    PendingRetrySearch {
+                    ActiveSearch::RetryDownload(PendingRetrySearch {
                         download_id,
                         original_filename,
-                        results: Vec::new(),
-                    },
+                        peers,
+                    }),
                 );
-                st.rate_limiter.record_search();
+                st.rate_limiter.record();
             }
             let req = ServerRequest::FileSearch {
                 token,
                 query: query.clone(),
             };
-            let mut buf = BytesMut::new();
-            req.write_message(&mut buf);
-            let _ = write_tx.send(buf);
+            if let Err(e) = send_to_server(&req, write_tx) {
+                let _ = event_tx.send(AppEvent::Error(format!("Search failed: {e}")));
+                return;
+            }
 
             let remaining = {
                 let mut st = state.lock().await;
@@ -252,10 +422,32 @@ async fn try_execute_or_queue_search(
 ) {
     let (can_search, wait_time, queued_count) = {
         let mut st = state.lock().await;
+        if let Some(phrase) = st.search_filter.excluded_phrase(search.query()) {
+            // The server would drop it without a word, so don't spend a search slot
+            let reason = format!(
+                "Search '{}' not sent: the server excludes \"{}\"",
+                search.query(),
+                phrase
+            );
+            match search {
+                QueuedSearch::Regular { .. } => {}
+                QueuedSearch::SpotifyTrack { track_index, .. } => {
+                    let _ = event_tx.send(AppEvent::SpotifyTrackFailed {
+                        track_index,
+                        reason: reason.clone(),
+                    });
+                }
+                QueuedSearch::RetryDownload { download_id, .. } => {
+                    let _ = event_tx.send(AppEvent::RetryDownloadFailed { download_id });
+                }
+            }
+            let _ = event_tx.send(AppEvent::StatusMessage(reason));
+            return;
+        }
         let can = st.rate_limiter.can_search();
         let wait = st.rate_limiter.time_until_next_slot();
         if !can {
-            st.rate_limiter.queue_search(search.clone());
+            st.rate_limiter.queue(search.clone());
         }
         (can, wait, st.rate_limiter.queued_count())
     };
@@ -272,25 +464,6 @@ async fn try_execute_or_queue_search(
     }
 }
 
-fn filename_to_search_query(filename: &str) -> String {
-    let name = std::path::Path::new(filename)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or(filename);
-
-    name.replace(['_', '-', '.'], " ")
-        .split_whitespace()
-        .filter(|word| {
-            let lower = word.to_lowercase();
-            !matches!(
-                lower.as_str(),
-                "flac" | "mp3" | "wav" | "ogg" | "m4a" | "320" | "256" | "128" | "192" | "24bit" | "16bit"
-            )
-        })
-        .collect::<Vec<_>>()
-        .join(" ")
-}
-
 pub async fn run_client(
     username: &str,
     password: &str,
@@ -306,111 +479,92 @@ pub async fn run_client(
         .ok()
         .and_then(|p| p.parse().ok())
         .unwrap_or(DEFAULT_SERVER_PORT);
-    let mut stream = TcpStream::connect((&*server_host, server_port)).await?;
-    stream.set_nodelay(true)?;
-    let _ = event_tx.send(AppEvent::Connected);
-
-    let login = ServerRequest::Login {
-        username: username.to_string(),
-        password: password.to_string(),
-        version: 160,
-        minor_version: 3,
+    let profile = ServerProfile {
+        host: server_host,
+        port: server_port,
+        ..ServerProfile::default()
+    };
+    let _ = event_tx.send(AppEvent::Connecting);
+    let backoff = BackoffConfig::default();
+    let login = connect_with_backoff(&profile, username, password, &backoff, |n, e, delay| {
+        let _ = event_tx.send(AppEvent::StatusMessage(format!(
+            "Connect attempt {}/{} failed: {}. Retrying in {}s...",
+            n,
+            backoff.max_attempts,
+            e,
+            delay.as_secs()
+        )));
+    })
+    .await;
+    let (mut stream, server_buf) = match login {
+        Ok((conn, _)) => conn.into_parts(),
+        Err(slsk_rs::Error::LoginFailed { reason, detail }) => {
+            let _ = event_tx.send(AppEvent::LoginFailed {
+                reason: format!("{:?}: {}", reason, detail.unwrap_or_default()),
+            });
+            return Err("Login failed".into());
+        }
+        Err(e) => return Err(e.into()),
     };
+    let _ = event_tx.send(AppEvent::LoginSuccess {
+        username: username.to_string(),
+    });
 
+    // Send SetStatus and SetWaitPort after successful login
     let mut buf = BytesMut::new();
-    login.write_message(&mut buf);
+    for request in ServerConnection::announce_requests(listen_port, UserStatus::Online) {
+        request.write_message(&mut buf)?;
+    }
     stream.write_all(&buf).await?;
-    stream.flush().await?;
-
-    // Wait for login response before proceeding
-    let mut read_buf = BytesMut::with_capacity(65536);
-    loop {
-        let n = stream.read_buf(&mut read_buf).await?;
-        if n == 0 {
-            return Err("Connection closed before login response".into());
-        }
-
-        if read_buf.len() >= 4 {
-            let msg_len =
-                u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]]) as usize;
-
-            if read_buf.len() >= 4 + msg_len {
-                let mut msg_buf = read_buf.split_to(4 + msg_len);
 
-                match read_server_message(&mut msg_buf) {
-                    Ok(ServerResponse::LoginSuccess { .. }) => {
-                        let _ = event_tx.send(AppEvent::LoginSuccess {
-                            username: username.to_string(),
-                        });
-                        break;
-                    }
-                    Ok(ServerResponse::LoginFailure { reason, detail }) => {
-                        let _ = event_tx.send(AppEvent::LoginFailed {
-                            reason: format!("{:?}: {}", reason, detail.unwrap_or_default()),
-                        });
-                        return Err("Login failed".into());
-                    }
-                    Ok(_) => {
-                        // Ignore other messages during login
-                    }
-                    Err(e) => {
-                        return Err(format!("Failed to parse login response: {e}").into());
-                    }
-                }
-            }
+    let accept_children = std::env::var("SOULSEEK_ACCEPT_CHILDREN").is_ok_and(|v| v == "1");
+    if accept_children {
+        // Without a parent we act as our own branch root
+        let requests = [
+            ServerRequest::HaveNoParent { no_parent: true },
+            ServerRequest::BranchRoot {
+                root: username.to_string(),
+            },
+            ServerRequest::BranchLevel { level: 0 },
+            ServerRequest::AcceptChildren { accept: true },
+        ];
+        for req in requests {
+            buf.clear();
+            req.write_message(&mut buf)?;
+            stream.write_all(&buf).await?;
         }
     }
-
-    // Send SetStatus and SetWaitPort after successful login
-    buf.clear();
-    let set_status = ServerRequest::SetStatus {
-        status: slsk_rs::constants::UserStatus::Online,
-    };
-    set_status.write_message(&mut buf);
-    stream.write_all(&buf).await?;
-
-    buf.clear();
-    let set_port = ServerRequest::SetWaitPort {
-        port: listen_port as u32,
-        obfuscation_type: None,
-        obfuscated_port: None,
-    };
-    set_port.write_message(&mut buf);
-    stream.write_all(&buf).await?;
     stream.flush().await?;
 
-    let state = Arc::new(Mutex::new(ClientState {
-        username: username.to_string(),
-        pending_searches: HashMap::new(),
-        pending_browse: HashMap::new(),
-        pending_downloads: HashMap::new(),
-        active_download_users: std::collections::HashSet::new(),
-        spotify_playlist: None,
-        spotify_track_searches: HashMap::new(),
-        retry_searches: HashMap::new(),
-        rate_limiter: SearchRateLimiter::new(),
-    }));
+    let mut client_state = ClientState::new(username);
+    client_state.accept_children = accept_children;
+    if let Some(dir) = std::env::var_os("SOULSEEK_DOWNLOAD_DIR") {
+        client_state.download_config.base_dir = PathBuf::from(dir);
+    }
+    client_state.download_config.preserve_remote_dirs =
+        std::env::var("SOULSEEK_PRESERVE_DIRS").is_ok_and(|v| v == "1");
+    client_state.download_config.keep_cancelled =
+        std::env::var("SOULSEEK_KEEP_CANCELLED").is_ok_and(|v| v == "1");
+    client_state.description = std::env::var("SOULSEEK_DESCRIPTION").unwrap_or_default();
+    let mut peer_requests = client_state.peer_requests.take().expect("fresh client state");
+    let state = Arc::new(Mutex::new(client_state));
 
     let (write_tx, mut write_rx) = mpsc::unbounded_channel::<BytesMut>();
-    let (search_timeout_tx, mut search_timeout_rx) = mpsc::unbounded_channel::<u32>();
     let (rate_limit_tx, mut rate_limit_rx) = mpsc::unbounded_channel::<()>();
+    let (quit_tx, mut quit_rx) = mpsc::unbounded_channel::<()>();
+    let (close_tx, mut close_rx) = oneshot::channel::<()>();
     let (read_stream, mut write_stream) = stream.into_split();
 
     let state_for_listener = state.clone();
     let event_tx_for_listener = event_tx.clone();
-    let search_timeout_tx_for_listener = search_timeout_tx.clone();
     let listen_handle = tokio::spawn(async move {
         loop {
             match listener.accept().await {
                 Ok((stream, _addr)) => {
                     let state = state_for_listener.clone();
                     let event_tx = event_tx_for_listener.clone();
-                    let search_timeout_tx = search_timeout_tx_for_listener.clone();
                     tokio::spawn(async move {
-                        if let Err(e) =
-                            handle_incoming_peer(stream, &state, &event_tx, &search_timeout_tx)
-                                .await
-                        {
+                        if let Err(e) = handle_incoming_peer(stream, &state, &event_tx).await {
                             let _ =
                                 event_tx.send(AppEvent::Error(format!("Incoming peer error: {e}")));
                         }
@@ -423,19 +577,46 @@ pub async fn run_client(
         }
     });
 
-    let write_handle = tokio::spawn(async move {
-        while let Some(data) = write_rx.recv().await {
-            if let Err(e) = write_stream.write_all(&data).await {
-                eprintln!("Write error: {e}");
-                break;
-            }
-            if let Err(e) = write_stream.flush().await {
-                eprintln!("Flush error: {e}");
-                break;
+    let mut write_handle = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                // Everything queued is written before a close is honoured
+                biased;
+                Some(data) = write_rx.recv() => {
+                    if let Err(e) = write_stream.write_all(&data).await {
+                        eprintln!("Write error: {e}");
+                        break;
+                    }
+                    if let Err(e) = write_stream.flush().await {
+                        eprintln!("Flush error: {e}");
+                        break;
+                    }
+                }
+                _ = &mut close_rx => {
+                    let _ = write_stream.shutdown().await;
+                    break;
+                }
             }
         }
     });
 
+    let write_tx_for_peers = write_tx.clone();
+    let peer_requests_handle = tokio::spawn(async move {
+        while let Some(request) = peer_requests.recv().await {
+            let _ = send_to_server(&request, &write_tx_for_peers);
+        }
+    });
+
+    // Pooled connections are otherwise only closed when another is put back
+    let idle_pool = state.lock().await.peer_pool.clone();
+    let idle_handle = tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(PEER_IDLE_TIMEOUT);
+        loop {
+            ticks.tick().await;
+            idle_pool.lock().await.close_idle();
+        }
+    });
+
     let state_for_cmd = state.clone();
     let write_tx_for_cmd = write_tx.clone();
     let event_tx_for_cmd = event_tx.clone();
@@ -443,6 +624,10 @@ pub async fn run_client(
     let cmd_handle = tokio::spawn(async move {
         while let Some(cmd) = cmd_rx.recv().await {
             match cmd {
+                ClientCommand::Quit => {
+                    let _ = quit_tx.send(());
+                    break;
+                }
                 ClientCommand::Search(query) => {
                     try_execute_or_queue_search(
                         QueuedSearch::Regular { query },
@@ -454,54 +639,47 @@ pub async fn run_client(
                     .await;
                 }
                 ClientCommand::BrowseUser(username) => {
-                    {
-                        let mut st = state_for_cmd.lock().await;
-                        st.pending_browse.insert(username.clone(), ());
-                    }
-                    let req = ServerRequest::GetPeerAddress { username };
-                    let mut buf = BytesMut::new();
-                    req.write_message(&mut buf);
-                    let _ = write_tx_for_cmd.send(buf);
+                    browse_user(
+                        username,
+                        &state_for_cmd,
+                        &event_tx_for_cmd,
+                        &write_tx_for_cmd,
+                    )
+                    .await;
                 }
                 ClientCommand::DownloadFile {
                     username,
                     filename,
                     size,
                 } => {
-                    let download_id = next_token();
-                    let transfer_token = next_token();
-
-                    let download = PendingDownload {
-                        id: download_id,
-                        username: username.clone(),
-                        filename: filename.clone(),
-                        size,
-                        token: transfer_token,
-                    };
-
-                    let should_request_address = {
-                        let mut st = state_for_cmd.lock().await;
-                        st.pending_downloads
-                            .entry(username.clone())
-                            .or_default()
-                            .push(download);
-                        !st.active_download_users.contains(&username)
-                    };
-
-                    let _ = event_tx_for_cmd.send(AppEvent::DownloadQueued {
-                        id: download_id,
-                        username: username.clone(),
-                        filename: filename.clone(),
+                    queue_download(
+                        username,
+                        filename,
                         size,
-                    });
-
-                    if should_request_address {
-                        let req = ServerRequest::GetPeerAddress { username };
-                        let mut buf = BytesMut::new();
-                        req.write_message(&mut buf);
-                        let _ = write_tx_for_cmd.send(buf);
-                    }
+                        &state_for_cmd,
+                        &write_tx_for_cmd,
+                        &event_tx_for_cmd,
+                    )
+                    .await;
                 }
+                ClientCommand::DownloadFromUrl(url) => match parse_slsk_url(&url) {
+                    // Without a size in the link, it is filled in once the peer
+                    // offers the transfer
+                    Ok(link) => {
+                        queue_download(
+                            link.username,
+                            link.path,
+                            link.size.unwrap_or(0),
+                            &state_for_cmd,
+                            &write_tx_for_cmd,
+                            &event_tx_for_cmd,
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        let _ = event_tx_for_cmd.send(AppEvent::Error(e.to_string()));
+                    }
+                },
                 ClientCommand::FetchSpotify(url) => {
                     let event_tx = event_tx_for_cmd.clone();
                     let state = state_for_cmd.clone();
@@ -549,6 +727,7 @@ pub async fn run_client(
                             filename: matched.filename.clone(),
                             size: matched.size,
                             token: transfer_token,
+                            offset: 0,
                         };
 
                         let should_request_address = {
@@ -568,12 +747,13 @@ pub async fn run_client(
                         });
 
                         if should_request_address {
-                            let req = ServerRequest::GetPeerAddress {
-                                username: matched.username.clone(),
-                            };
-                            let mut buf = BytesMut::new();
-                            req.write_message(&mut buf);
-                            let _ = write_tx_for_cmd.send(buf);
+                            request_peer_address(
+                                matched.username.clone(),
+                                &state_for_cmd,
+                                &event_tx_for_cmd,
+                                &write_tx_for_cmd,
+                            )
+                            .await;
                         }
                     }
                 }
@@ -581,7 +761,7 @@ pub async fn run_client(
                     download_id,
                     original_filename,
                 } => {
-                    let query = filename_to_search_query(&original_filename);
+                    let query = filename_to_query(&original_filename, QUERY_STOPWORDS);
                     try_execute_or_queue_search(
                         QueuedSearch::RetryDownload {
                             download_id,
@@ -609,6 +789,7 @@ pub async fn run_client(
                         filename: filename.clone(),
                         size,
                         token: transfer_token,
+                        offset: 0,
                     };
 
                     let should_request_address = {
@@ -621,20 +802,60 @@ pub async fn run_client(
                     };
 
                     if should_request_address {
-                        let req = ServerRequest::GetPeerAddress { username };
-                        let mut buf = BytesMut::new();
-                        req.write_message(&mut buf);
-                        let _ = write_tx_for_cmd.send(buf);
+                        request_peer_address(
+                            username,
+                            &state_for_cmd,
+                            &event_tx_for_cmd,
+                            &write_tx_for_cmd,
+                        )
+                        .await;
+                    }
+                }
+                ClientCommand::CancelDownload { id } => {
+                    let queued = state_for_cmd.lock().await.cancel_download(id);
+                    if queued {
+                        let _ = event_tx_for_cmd.send(AppEvent::DownloadCancelled { id });
+                    }
+                }
+                ClientCommand::ResumeDownloads(queued) => {
+                    resume_downloads(queued, &state_for_cmd, &event_tx_for_cmd, &write_tx_for_cmd)
+                        .await;
+                }
+                ClientCommand::Shares(roots) => {
+                    let scanned = tokio::task::spawn_blocking(move || scan_shares(&roots)).await;
+                    let shares = match scanned {
+                        Ok(Ok(shares)) => shares,
+                        Ok(Err(e)) => {
+                            let _ = event_tx_for_cmd
+                                .send(AppEvent::Error(format!("Failed to scan shares: {e}")));
+                            continue;
+                        }
+                        Err(e) => {
+                            let _ = event_tx_for_cmd
+                                .send(AppEvent::Error(format!("Failed to scan shares: {e}")));
+                            continue;
+                        }
+                    };
+
+                    let (dirs, files) = share_counts(&shares.directories);
+                    let req = {
+                        let mut st = state_for_cmd.lock().await;
+                        st.set_shares(shares)
+                    };
+                    let _ = event_tx_for_cmd.send(AppEvent::SharesUpdated { dirs, files });
+                    if let Err(e) = send_to_server(&req, &write_tx_for_cmd) {
+                        let _ = event_tx_for_cmd
+                            .send(AppEvent::Error(format!("Failed to report shares: {e}")));
                     }
                 }
             }
         }
     });
 
-    let mut read_buf = BytesMut::with_capacity(65536);
+    let mut read_buf = server_buf;
     let mut read_stream = read_stream;
 
-    loop {
+    'session: loop {
         tokio::select! {
             result = read_stream.read_buf(&mut read_buf) => {
                 let n = result?;
@@ -642,27 +863,19 @@ pub async fn run_client(
                     break;
                 }
 
-                while read_buf.len() >= 4 {
-                    let msg_len = u32::from_le_bytes([
-                        read_buf[0], read_buf[1], read_buf[2], read_buf[3]
-                    ]) as usize;
-
-                    if read_buf.len() < 4 + msg_len {
-                        break;
-                    }
-
-                    let mut msg_buf = read_buf.split_to(4 + msg_len);
-
-                    match read_server_message(&mut msg_buf) {
+                for message in drain_messages(&mut read_buf) {
+                    match message {
                         Ok(response) => {
-                            handle_server_response(
+                            let flow = handle_server_response(
                                 response,
                                 &state,
                                 &event_tx,
                                 &write_tx,
                                 listen_port,
-                                &search_timeout_tx,
                             ).await;
+                            if flow.is_break() {
+                                break 'session;
+                            }
                         }
                         Err(e) => {
                             let _ = event_tx.send(AppEvent::Error(format!("Parse error: {e}")));
@@ -670,10 +883,15 @@ pub async fn run_client(
                     }
                 }
             }
-            Some(token) = search_timeout_rx.recv() => {
-                let mut st = state.lock().await;
-                finalize_search(token, &mut st, &event_tx);
-                finalize_retry_search(token, &mut st, &event_tx);
+            Some(()) = quit_rx.recv() => {
+                let requests = {
+                    let st = state.lock().await;
+                    ServerConnection::shutdown_requests(st.rooms.rooms())
+                };
+                for request in requests {
+                    let _ = send_to_server(&request, &write_tx);
+                }
+                break;
             }
             Some(()) = rate_limit_rx.recv() => {
                 let wait_time = {
@@ -740,7 +958,11 @@ pub async fn run_client(
         }
     }
 
+    let _ = close_tx.send(());
+    let _ = tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, &mut write_handle).await;
     write_handle.abort();
+    peer_requests_handle.abort();
+    idle_handle.abort();
     cmd_handle.abort();
     listen_handle.abort();
 
@@ -751,185 +973,509 @@ async fn handle_server_response(
     response: ServerResponse,
     state: &Arc<Mutex<ClientState>>,
     event_tx: &mpsc::UnboundedSender<AppEvent>,
-    _tx_to_server: &mpsc::UnboundedSender<BytesMut>,
+    tx_to_server: &mpsc::UnboundedSender<BytesMut>,
     _listen_port: u16,
-    search_timeout_tx: &mpsc::UnboundedSender<u32>,
-) {
+) -> ControlFlow<()> {
+    state.lock().await.rooms.apply(&response);
     match response {
         ServerResponse::LoginSuccess { .. } | ServerResponse::LoginFailure { .. } => {
             // Already handled before main loop
         }
         ServerResponse::GetPeerAddress {
-            username, ip, port, ..
+            username,
+            ip,
+            port,
+            obfuscation_type,
+            obfuscated_port,
         } => {
-            let (should_browse, downloads_for_user) = {
-                let mut st = state.lock().await;
-                let browse = st.pending_browse.contains_key(&username);
-                let downloads = st.pending_downloads.remove(&username).unwrap_or_default();
-                (browse, downloads)
+            let address = PeerAddress {
+                ip,
+                port,
+                obfuscation_type,
+                obfuscated_port,
             };
-
-            if should_browse {
+            state.lock().await.addresses.insert(&username, address);
+            reach_peer(username, address, state, event_tx).await;
+        }
+        ServerResponse::ConnectToPeer {
+            username,
+            connection_type: ConnectionType::Peer,
+            ip,
+            port,
+            token,
+            ..
+        } => {
+            let state_clone = state.clone();
+            let event_tx_clone = event_tx.clone();
+
+            tokio::spawn(async move {
+                let _ = handle_peer_connection(
+                    &username,
+                    ip,
+                    port,
+                    token,
+                    &state_clone,
+                    &event_tx_clone,
+                )
+                .await;
+            });
+        }
+        ServerResponse::ConnectToPeer {
+            username,
+            connection_type: ConnectionType::Distributed,
+            ip,
+            port,
+            token,
+            ..
+        } => {
+            let accept = {
+                let st = state.lock().await;
+                st.accept_children
+            };
+            if accept {
                 let state_clone = state.clone();
                 let event_tx_clone = event_tx.clone();
-                let username_clone = username.clone();
-
                 tokio::spawn(async move {
-                    match connect_to_peer_and_browse(&username_clone, ip, port, &state_clone).await
+                    if let Err(e) =
+                        connect_to_distributed_child(&username, ip, port, token, &state_clone).await
                     {
-                        Ok(dirs) => {
-                            let _ = event_tx_clone.send(AppEvent::UserFiles(username_clone, dirs));
-                        }
-                        Err(e) => {
-                            let _ = event_tx_clone.send(AppEvent::Error(format!(
-                                "Failed to browse {username_clone}: {e}"
-                            )));
-                        }
+                        let _ = event_tx_clone.send(AppEvent::Error(format!(
+                            "Failed to accept child {username}: {e}"
+                        )));
                     }
                 });
-
+            }
+        }
+        ServerResponse::GetUserStatus {
+            username, status, ..
+        } => {
+            // Pushed by the server whenever a watched user's status changes
+            {
                 let mut st = state.lock().await;
-                st.pending_browse.remove(&username);
+                st.user_statuses.insert(username.clone(), status);
             }
+            let _ = event_tx.send(AppEvent::UserStatus { username, status });
+        }
+        ServerResponse::EmbeddedMessage { code, data } => {
+            // We only receive these as a branch root; children unpack them
+            let replying_to = {
+                let mut st = state.lock().await;
+                let search = decode_embedded(code, &data);
+                st.relay_to_children(&DistributedMessage::EmbeddedMessage { code, data });
 
-            if !downloads_for_user.is_empty() {
-                let state_clone = state.clone();
-                let event_tx_clone = event_tx.clone();
-                let username_for_task = username.clone();
-
+                if let Ok(DistributedMessage::Search {
+                    username,
+                    token,
+                    query,
+                    ..
+                }) = search
+                    && username != st.username
+                    && let Some(response) = st.search_response(token, &query)
                 {
-                    let mut st = state.lock().await;
-                    st.active_download_users.insert(username_for_task.clone());
+                    st.pending_search_replies
+                        .entry(username.clone())
+                        .or_default()
+                        .push(response);
+                    Some(username)
+                } else {
+                    None
                 }
+            };
+            if let Some(username) = replying_to {
+                request_peer_address(username, state, event_tx, tx_to_server).await;
+            }
+        }
+        ServerResponse::CantConnectToPeer { token, username } => {
+            // Neither side could open a connection, so the download waiting on it fails
+            let mut st = state.lock().await;
+            st.addresses.invalidate(&username);
+            st.peers.cant_connect(token);
+        }
+        ServerResponse::AdminMessage { message } => {
+            let _ = event_tx.send(AppEvent::AdminMessage(message));
+        }
+        ServerResponse::MessageUser { id, username, message, .. } => {
+            // The server redelivers on every login until we ack
+            let ack = ServerRequest::MessageAcked { message_id: id };
+            if let Err(e) = send_to_server(&ack, tx_to_server) {
+                let _ = event_tx.send(AppEvent::Error(format!("Failed to ack message: {e}")));
+            }
+            let _ = event_tx.send(AppEvent::PrivateMessage { username, message });
+        }
+        ServerResponse::ExcludedSearchPhrases { .. } => {
+            state.lock().await.search_filter.apply(&response);
+        }
+        ServerResponse::Relogged => {
+            // Someone logged in with our name elsewhere; the server is done with us
+            let _ = event_tx.send(AppEvent::Relogged);
+            return ControlFlow::Break(());
+        }
+        _ => {}
+    }
+    ControlFlow::Continue(())
+}
 
-                tokio::spawn(async move {
-                    let mut downloads_queue = downloads_for_user;
-
-                    loop {
-                        for download in downloads_queue {
-                            if let Err(e) = connect_to_peer_and_download(
-                                ip,
-                                port,
-                                download.clone(),
-                                &state_clone,
-                                &event_tx_clone,
-                            )
-                            .await
-                            {
-                                let _ = event_tx_clone.send(AppEvent::DownloadFailed {
-                                    id: download.id,
-                                    reason: e.to_string(),
-                                });
-                            }
-                        }
+/// Ask the server where `username` listens, or act on their address at once if it is cached.
+async fn request_peer_address(
+    username: String,
+    state: &Arc<Mutex<ClientState>>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+    write_tx: &mpsc::UnboundedSender<BytesMut>,
+) {
+    let cached = state.lock().await.addresses.get(&username);
+    match cached {
+        Some(address) => reach_peer(username, address, state, event_tx).await,
+        None => {
+            let request = ServerRequest::GetPeerAddress { username };
+            if let Err(e) = send_to_server(&request, write_tx) {
+                let _ = event_tx.send(AppEvent::Error(format!("Failed to find peer: {e}")));
+            }
+        }
+    }
+}
 
-                        let more_downloads = {
-                            let mut st = state_clone.lock().await;
-                            st.pending_downloads
-                                .remove(&username_for_task)
-                                .unwrap_or_default()
-                        };
+/// Start the browse, downloads and search replies that were waiting on `username`'s address.
+async fn reach_peer(
+    username: String,
+    address: PeerAddress,
+    state: &Arc<Mutex<ClientState>>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+) {
+    let ip = address.ip;
+    let (port, _framing) = address.select(SUPPORTED_OBFUSCATION);
+    let (should_browse, downloads_for_user, search_replies) = {
+        let mut st = state.lock().await;
+        let browse = st.pending_browse.contains_key(&username);
+        let downloads = st.pending_downloads.remove(&username).unwrap_or_default();
+        let replies = st
+            .pending_search_replies
+            .remove(&username)
+            .unwrap_or_default();
+        (browse, downloads, replies)
+    };
 
-                        if more_downloads.is_empty() {
-                            let mut st = state_clone.lock().await;
-                            st.active_download_users.remove(&username_for_task);
-                            break;
-                        }
+    // Nothing can reach an offline peer, so fail what was waiting on it now
+    if address.is_offline() {
+        if should_browse {
+            state.lock().await.pending_browse.remove(&username);
+            let _ = event_tx.send(AppEvent::Error(format!(
+                "Failed to browse {username}: user is offline"
+            )));
+        }
+        for download in downloads_for_user {
+            let _ = event_tx.send(AppEvent::DownloadFailed {
+                id: download.id,
+                reason: format!("{username} is offline"),
+            });
+        }
+        return;
+    }
+
+    if !search_replies.is_empty() {
+        let state_clone = state.clone();
+        let username_clone = username.clone();
+        tokio::spawn(async move {
+            let _ = send_search_replies(
+                &username_clone,
+                ip,
+                port,
+                search_replies,
+                &state_clone,
+            )
+            .await;
+        });
+    }
+
+    if should_browse {
+        let state_clone = state.clone();
+        let event_tx_clone = event_tx.clone();
+        let username_clone = username.clone();
 
-                        downloads_queue = more_downloads;
+        tokio::spawn(async move {
+            let (my_username, timeouts) = {
+                let st = state_clone.lock().await;
+                (st.username.clone(), st.timeouts)
+            };
+            let addr = format!("{}:{}", ip, port);
+            let token = next_token();
+            match connect_to_peer_and_browse(&my_username, token, &addr, &timeouts).await {
+                Ok(dirs) => {
+                    {
+                        let mut st = state_clone.lock().await;
+                        st.cache_browse(&username_clone, dirs.clone());
                     }
-                });
+                    let _ = event_tx_clone.send(AppEvent::UserFiles(username_clone, dirs));
+                }
+                Err(e) => {
+                    state_clone.lock().await.addresses.invalidate(&username_clone);
+                    let _ = event_tx_clone.send(AppEvent::Error(format!(
+                        "Failed to browse {username_clone}: {e}"
+                    )));
+                }
             }
+        });
+
+        let mut st = state.lock().await;
+        st.pending_browse.remove(&username);
+    }
+
+    if !downloads_for_user.is_empty() {
+        let state_clone = state.clone();
+        let event_tx_clone = event_tx.clone();
+        let username_for_task = username.clone();
+
+        {
+            let mut st = state.lock().await;
+            st.active_download_users.insert(username_for_task.clone());
         }
-        ServerResponse::ConnectToPeer {
-            username,
-            connection_type,
-            ip,
-            port,
-            token,
-            ..
-        } => {
-            if connection_type == ConnectionType::Peer {
-                let state_clone = state.clone();
-                let event_tx_clone = event_tx.clone();
-                let search_timeout_tx_clone = search_timeout_tx.clone();
 
-                tokio::spawn(async move {
-                    let _ = handle_peer_connection(
-                        &username,
+        tokio::spawn(async move {
+            let mut downloads_queue = downloads_for_user;
+
+            loop {
+                state_clone
+                    .lock()
+                    .await
+                    .queued_downloads
+                    .extend(downloads_queue.iter().map(|d| d.id));
+                for download in downloads_queue {
+                    let id = download.id;
+                    // Cancelled while queued behind another download from this peer
+                    let Some(cancel) = state_clone.lock().await.start_queued_download(id)
+                    else {
+                        continue;
+                    };
+                    if let Err(e) = connect_to_peer_and_download(
                         ip,
                         port,
-                        token,
+                        download,
+                        cancel,
                         &state_clone,
                         &event_tx_clone,
-                        &search_timeout_tx_clone,
                     )
-                    .await;
-                });
+                    .await
+                    {
+                        let _ = event_tx_clone.send(AppEvent::DownloadFailed {
+                            id,
+                            reason: e.to_string(),
+                        });
+                    }
+                    state_clone.lock().await.download_cancels.remove(&id);
+                }
+
+                let more_downloads = {
+                    let mut st = state_clone.lock().await;
+                    st.pending_downloads
+                        .remove(&username_for_task)
+                        .unwrap_or_default()
+                };
+
+                if more_downloads.is_empty() {
+                    let mut st = state_clone.lock().await;
+                    st.active_download_users.remove(&username_for_task);
+                    break;
+                }
+
+                downloads_queue = more_downloads;
             }
-        }
-        _ => {}
+        });
     }
 }
 
-async fn connect_to_peer_and_browse(
-    _username: &str,
+/// Deliver our search results to the peer that searched.
+async fn send_search_replies(
+    username: &str,
     ip: Ipv4Addr,
     port: u32,
+    replies: Vec<PeerMessage>,
     state: &Arc<Mutex<ClientState>>,
-) -> Result<Vec<SharedDirectory>, Box<dyn std::error::Error + Send + Sync>> {
-    let my_username = {
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (my_username, timeouts, pool) = {
         let st = state.lock().await;
-        st.username.clone()
+        (st.username.clone(), st.timeouts, st.peer_pool.clone())
     };
-
-    let addr = format!("{}:{}", ip, port);
-    let mut stream = TcpStream::connect(&addr).await?;
-
-    let token = next_token();
     let init = PeerInitMessage::PeerInit {
         username: my_username,
         connection_type: ConnectionType::Peer,
-        token,
+        token: next_token(),
+    };
+
+    // Someone who keeps matching our shares gets all replies over one connection,
+    // taken out of the pool so an unreachable peer doesn't hold up replies to others
+    let addr = format!("{}:{}", ip, port);
+    let pooled = pool.lock().await.take(username);
+    let conn = match send_pooled(pooled, &timeouts.connector(), &addr, init, &replies).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            state.lock().await.addresses.invalidate(username);
+            return Err(e.into());
+        }
     };
+    pool.lock().await.put_back(username, conn);
+    Ok(())
+}
+
+/// Answer a would-be child's indirect connection request and adopt it.
+async fn connect_to_distributed_child(
+    username: &str,
+    ip: Ipv4Addr,
+    port: u32,
+    token: u32,
+    state: &Arc<Mutex<ClientState>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let timeouts = state.lock().await.timeouts;
+    let addr = format!("{}:{}", ip, port);
+    let mut stream = timeouts.connector().connect(&addr).await?;
+
+    let pierce = PeerInitMessage::PierceFirewall { token };
     let mut buf = BytesMut::new();
-    write_peer_init_message(&init, &mut buf);
+    write_peer_init_message(&pierce, &mut buf)?;
     stream.write_all(&buf).await?;
 
-    buf.clear();
-    let request = PeerMessage::SharedFileListRequest;
-    request.write_message(&mut buf);
-    stream.write_all(&buf).await?;
+    accept_distributed_child(username, stream, state).await
+}
+
+/// Send our branch info to a new child and register it for search relaying.
+async fn accept_distributed_child(
+    username: &str,
+    stream: TcpStream,
+    state: &Arc<Mutex<ClientState>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut read_stream, mut write_stream) = stream.into_split();
+    let (child_tx, mut child_rx) = mpsc::unbounded_channel::<BytesMut>();
+
+    let branch_info = {
+        let mut st = state.lock().await;
+        st.distributed_children
+            .insert(username.to_string(), child_tx);
+        st.branch_info()
+    };
+
+    let mut buf = BytesMut::new();
+    for msg in &branch_info {
+        write_distributed_message(msg, &mut buf)?;
+    }
+    write_stream.write_all(&buf).await?;
 
-    let mut read_buf = BytesMut::with_capacity(1024 * 1024);
+    let writer = tokio::spawn(async move {
+        while let Some(data) = child_rx.recv().await {
+            if write_stream.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    });
 
+    // Children only ping us; drain until they disconnect
+    let mut read_buf = BytesMut::with_capacity(1024);
     loop {
-        let n = stream.read_buf(&mut read_buf).await?;
-        if n == 0 {
-            return Err("Connection closed".into());
+        match read_stream.read_buf(&mut read_buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => read_buf.clear(),
         }
+    }
 
-        if read_buf.len() >= 4 {
-            let msg_len =
-                u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]]) as usize;
+    writer.abort();
+    let mut st = state.lock().await;
+    st.distributed_children.remove(username);
 
-            if read_buf.len() >= 4 + msg_len {
-                let mut msg_buf = read_buf.split_to(4 + msg_len);
+    Ok(())
+}
 
-                match read_peer_message(&mut msg_buf) {
-                    Ok(PeerMessage::SharedFileListResponse { directories, .. }) => {
-                        return Ok(directories);
-                    }
-                    Ok(_) => {
-                        continue;
-                    }
-                    Err(e) => {
-                        return Err(format!("Failed to parse peer message: {e}").into());
-                    }
-                }
+/// Queue a new download and look up the peer unless we're already downloading from them.
+async fn queue_download(
+    username: String,
+    filename: String,
+    size: u64,
+    state: &Arc<Mutex<ClientState>>,
+    write_tx: &mpsc::UnboundedSender<BytesMut>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+) {
+    let download_id = next_token();
+    let transfer_token = next_token();
+
+    let download = PendingDownload {
+        id: download_id,
+        username: username.clone(),
+        filename: filename.clone(),
+        size,
+        token: transfer_token,
+        offset: 0,
+    };
+
+    let should_request_address = {
+        let mut st = state.lock().await;
+        st.pending_downloads
+            .entry(username.clone())
+            .or_default()
+            .push(download);
+        !st.active_download_users.contains(&username)
+    };
+
+    let _ = event_tx.send(AppEvent::DownloadQueued {
+        id: download_id,
+        username: username.clone(),
+        filename,
+        size,
+    });
+
+    if should_request_address {
+        request_peer_address(username, state, event_tx, write_tx).await;
+    }
+}
+
+/// Re-queue restored downloads under their original ids and look up their peers.
+///
+/// Each picks up from the progress recorded for it.
+async fn resume_downloads(
+    queued: Vec<DownloadRecord>,
+    state: &Arc<Mutex<ClientState>>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+    write_tx: &mpsc::UnboundedSender<BytesMut>,
+) {
+    let mut users = Vec::new();
+    {
+        let mut st = state.lock().await;
+        for q in queued {
+            reserve_token(q.id);
+            if !users.contains(&q.username) && !st.active_download_users.contains(&q.username) {
+                users.push(q.username.clone());
             }
+            st.pending_downloads
+                .entry(q.username.clone())
+                .or_default()
+                .push(PendingDownload {
+                    id: q.id,
+                    username: q.username,
+                    filename: q.filename,
+                    size: q.size,
+                    token: next_token(),
+                    offset: q.bytes_done,
+                });
+        }
+    }
+
+    for username in users {
+        request_peer_address(username, state, event_tx, write_tx).await;
+    }
+}
+
+/// Browse a user's shares, answering from the cache when it is still fresh.
+async fn browse_user(
+    username: String,
+    state: &Arc<Mutex<ClientState>>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+    write_tx: &mpsc::UnboundedSender<BytesMut>,
+) {
+    {
+        let mut st = state.lock().await;
+        if let Some(dirs) = st.cached_browse(&username) {
+            let _ = event_tx.send(AppEvent::UserFiles(username, dirs.clone()));
+            return;
         }
+        st.pending_browse.insert(username.clone(), ());
     }
+
+    request_peer_address(username, state, event_tx, write_tx).await;
 }
 
 async fn handle_peer_connection(
@@ -939,15 +1485,15 @@ async fn handle_peer_connection(
     token: u32,
     state: &Arc<Mutex<ClientState>>,
     event_tx: &mpsc::UnboundedSender<AppEvent>,
-    search_timeout_tx: &mpsc::UnboundedSender<u32>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let timeouts = state.lock().await.timeouts;
     let addr = format!("{}:{}", ip, port);
-    let mut stream = TcpStream::connect(&addr).await?;
+    let mut stream = timeouts.connector().connect(&addr).await?;
 
     // Send PierceFirewall - we're responding to ConnectToPeer (indirect connection)
     let pierce = PeerInitMessage::PierceFirewall { token };
     let mut buf = BytesMut::new();
-    write_peer_init_message(&pierce, &mut buf);
+    write_peer_init_message(&pierce, &mut buf)?;
     stream.write_all(&buf).await?;
 
     let mut read_buf = BytesMut::with_capacity(65536);
@@ -969,59 +1515,8 @@ async fn handle_peer_connection(
             let mut msg_buf = read_buf.split_to(4 + msg_len);
 
             match read_peer_message(&mut msg_buf) {
-                Ok(PeerMessage::FileSearchResponse {
-                    username: result_user,
-                    token,
-                    results,
-                    slot_free,
-                    avg_speed,
-                    queue_length,
-                    ..
-                }) => {
-                    let pending = {
-                        let st = state.lock().await;
-                        st.pending_searches.contains_key(&token)
-                    };
-
-                    if pending && !results.is_empty() {
-                        let (is_spotify_search, is_retry_search) = {
-                            let st = state.lock().await;
-                            (
-                                st.spotify_track_searches.contains_key(&token),
-                                st.retry_searches.contains_key(&token),
-                            )
-                        };
-
-                        if is_spotify_search {
-                            accumulate_search_results(
-                                token,
-                                &result_user,
-                                results,
-                                state,
-                                event_tx,
-                                search_timeout_tx,
-                            )
-                            .await;
-                        } else if is_retry_search {
-                            accumulate_retry_results(
-                                token,
-                                &result_user,
-                                results,
-                                state,
-                                event_tx,
-                                search_timeout_tx,
-                            )
-                            .await;
-                        } else {
-                            let _ = event_tx.send(AppEvent::SearchResult(SearchResult {
-                                username: result_user,
-                                slot_free,
-                                avg_speed,
-                                queue_length,
-                                files: results,
-                            }));
-                        }
-                    }
+                Ok(response @ PeerMessage::FileSearchResponse { .. }) => {
+                    deliver_search_response(response, state, event_tx).await;
                 }
                 Ok(_) => {}
                 Err(_) => {}
@@ -1032,46 +1527,65 @@ async fn handle_peer_connection(
     Ok(())
 }
 
+/// Resolves once the download is cancelled, or never if it can no longer be.
+async fn cancelled(cancel: &mut watch::Receiver<bool>) {
+    while !*cancel.borrow_and_update() {
+        if cancel.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
 async fn connect_to_peer_and_download(
     ip: Ipv4Addr,
     port: u32,
     download: PendingDownload,
+    mut cancel: watch::Receiver<bool>,
     state: &Arc<Mutex<ClientState>>,
     event_tx: &mpsc::UnboundedSender<AppEvent>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let my_username = {
+    let (peers, timeouts) = {
         let st = state.lock().await;
-        st.username.clone()
+        (st.peers.clone(), st.timeouts)
     };
 
-    let addr = format!("{}:{}", ip, port);
-    let mut stream = TcpStream::connect(&addr).await?;
-
-    let init = PeerInitMessage::PeerInit {
-        username: my_username,
-        connection_type: ConnectionType::Peer,
-        token: download.token,
+    let connected = peers
+        .connect(&download.username, ip, port, download.token, ConnectionType::Peer)
+        .await;
+    let mut stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => {
+            // The peer may have moved since we looked it up
+            state.lock().await.addresses.invalidate(&download.username);
+            return Err(e.into());
+        }
     };
-    let mut buf = BytesMut::new();
-    write_peer_init_message(&init, &mut buf);
-    stream.write_all(&buf).await?;
 
-    buf.clear();
+    let mut buf = BytesMut::new();
     let queue_msg = PeerMessage::QueueUpload {
         filename: download.filename.clone(),
     };
-    queue_msg.write_message(&mut buf);
+    queue_msg.write_message(&mut buf)?;
     stream.write_all(&buf).await?;
 
     let _ = event_tx.send(AppEvent::DownloadStarted { id: download.id });
 
     let mut read_buf = BytesMut::with_capacity(65536);
+    let stall = timeouts.transfer_stall;
     let transfer_started = false;
     let mut file_size = download.size;
     let mut transfer_token: Option<u32> = None;
 
+    // No stall timeout here: a peer may keep us queued for as long as it likes
+    // before sending its TransferRequest
     loop {
-        let n = stream.read_buf(&mut read_buf).await?;
+        let n = tokio::select! {
+            result = stream.read_buf(&mut read_buf) => result?,
+            _ = cancelled(&mut cancel) => {
+                let _ = event_tx.send(AppEvent::DownloadCancelled { id: download.id });
+                return Ok(());
+            }
+        };
         if n == 0 {
             if transfer_started {
                 break;
@@ -1109,7 +1623,7 @@ async fn connect_to_peer_and_download(
                             file_size: None,
                             reason: None,
                         };
-                        response.write_message(&mut buf);
+                        response.write_message(&mut buf)?;
                         stream.write_all(&buf).await?;
                     }
                 }
@@ -1145,48 +1659,63 @@ async fn connect_to_peer_and_download(
 
     drop(stream);
 
-    let addr = format!("{}:{}", ip, port);
-    let mut file_stream = TcpStream::connect(&addr).await?;
-
-    let file_init = PeerInitMessage::PeerInit {
-        username: {
-            let st = state.lock().await;
-            st.username.clone()
-        },
-        connection_type: ConnectionType::File,
-        token: download.token,
-    };
-    let mut buf = BytesMut::new();
-    write_peer_init_message(&file_init, &mut buf);
-    file_stream.write_all(&buf).await?;
+    let mut file_stream = peers
+        .connect(&download.username, ip, port, download.token, ConnectionType::File)
+        .await?;
 
-    buf.clear();
+    let mut buf = BytesMut::new();
     let transfer_init = FileTransferInit::new(token);
     transfer_init.write_to(&mut buf);
     file_stream.write_all(&buf).await?;
 
-    buf.clear();
-    let offset = FileOffset::new(0);
-    offset.write_to(&mut buf);
-    file_stream.write_all(&buf).await?;
+    let (file_path, keep_cancelled) = {
+        let st = state.lock().await;
+        let config = &st.download_config;
+        (config.local_path(&download.filename), config.keep_cancelled)
+    };
+    if let Some(parent) = file_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
 
-    let download_dir = PathBuf::from("downloads");
-    tokio::fs::create_dir_all(&download_dir).await?;
+    // Recorded progress only counts as far as the partial file on disk goes
+    let on_disk = tokio::fs::metadata(&file_path)
+        .await
+        .map_or(0, |metadata| metadata.len());
+    let offset = download.offset.min(on_disk);
 
-    let filename = download
-        .filename
-        .rsplit(['/', '\\'])
-        .next()
-        .unwrap_or(&download.filename);
-    let file_path = download_dir.join(filename);
+    buf.clear();
+    FileOffset::new(offset).write_to(&mut buf);
+    file_stream.write_all(&buf).await?;
 
-    let mut file = File::create(&file_path).await?;
-    let mut downloaded: u64 = 0;
+    let mut file = if offset > 0 {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file
+    } else {
+        File::create(&file_path).await?
+    };
+    let mut downloaded = offset;
     let mut file_buf = vec![0u8; 65536];
     let mut last_progress_update = std::time::Instant::now();
 
     loop {
-        let n = file_stream.read(&mut file_buf).await?;
+        let n = tokio::select! {
+            result = tokio::time::timeout(stall, file_stream.read(&mut file_buf)) => {
+                result.map_err(|_| "Transfer stalled")??
+            }
+            _ = cancelled(&mut cancel) => {
+                drop(file);
+                if !keep_cancelled {
+                    tokio::fs::remove_file(&file_path).await?;
+                }
+                let _ = event_tx.send(AppEvent::DownloadCancelled { id: download.id });
+                return Ok(());
+            }
+        };
         if n == 0 {
             break;
         }
@@ -1206,6 +1735,7 @@ async fn connect_to_peer_and_download(
             break;
         }
     }
+    file.flush().await?;
 
     let _ = event_tx.send(AppEvent::DownloadCompleted { id: download.id });
 
@@ -1228,203 +1758,148 @@ async fn fetch_spotify_playlist(
             let playlist = client.get_playlist(&id).await?;
             Ok(SoulseekPlaylist::from_spotify_playlist(playlist))
         }
-        SpotifyResource::Album(_) => Err("Album support not yet implemented".into()),
+        SpotifyResource::Album(id) => {
+            let album = client.get_album(&id).await?;
+            Ok(SoulseekPlaylist::from_spotify_playlist(album))
+        }
     }
 }
 
-fn get_bitrate(attributes: &[slsk_rs::peer::FileAttribute]) -> Option<u32> {
-    attributes.iter().find(|a| a.code == 0).map(|a| a.value)
+fn pick_best_file(results: &[PeerSearchResult]) -> Option<&PeerSearchResult> {
+    rank_search_results(results, &RankOptions::default())
+        .into_iter()
+        .next()
 }
 
-fn pick_best_file(results: &[AccumulatedResult]) -> Option<&AccumulatedResult> {
-    let audio_exts = [
-        ".mp3", ".flac", ".m4a", ".ogg", ".opus", ".wav", ".aac", ".wma", ".ape", ".alac", ".aiff",
-        ".aif", ".wv", ".mpc",
-    ];
-
-    let mut candidates: Vec<_> = results
-        .iter()
-        .filter(|r| {
-            let lower = r.file.filename.to_lowercase();
-            audio_exts.iter().any(|ext| lower.ends_with(ext))
-        })
-        .collect();
-
-    if candidates.is_empty() {
-        return None;
+/// Hand a peer's search response to the search it answers.
+///
+/// Track and retry searches gather results for their session; the rest are shown as they come.
+async fn deliver_search_response(
+    response: PeerMessage,
+    state: &Arc<Mutex<ClientState>>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+) {
+    let PeerMessage::FileSearchResponse { token, results, .. } = &response else {
+        return;
+    };
+    if results.is_empty() {
+        return;
     }
 
-    candidates.sort_by(|a, b| {
-        let a_bitrate_opt = get_bitrate(&a.file.attributes);
-        let b_bitrate_opt = get_bitrate(&b.file.attributes);
-
-        let a_is_flac = a.file.filename.to_lowercase().ends_with(".flac");
-        let b_is_flac = b.file.filename.to_lowercase().ends_with(".flac");
-
-        let a_has_bitrate = a_bitrate_opt.is_some() || a_is_flac;
-        let b_has_bitrate = b_bitrate_opt.is_some() || b_is_flac;
-        if a_has_bitrate != b_has_bitrate {
-            return b_has_bitrate.cmp(&a_has_bitrate);
+    let st = state.lock().await;
+    match st.searches.get(*token) {
+        None => {}
+        Some(ActiveSearch::SpotifyTrack(PendingSpotifySearch { peers, .. }))
+        | Some(ActiveSearch::RetryDownload(PendingRetrySearch { peers, .. })) => {
+            peers.add_response(&response);
         }
-
-        if a_is_flac != b_is_flac {
-            return b_is_flac.cmp(&a_is_flac);
+        Some(ActiveSearch::Regular) => {
+            if let PeerMessage::FileSearchResponse {
+                username,
+                results,
+                slot_free,
+                avg_speed,
+                queue_length,
+                ..
+            } = response
+            {
+                let _ = event_tx.send(AppEvent::SearchResult(SearchResult {
+                    username,
+                    slot_free,
+                    avg_speed,
+                    queue_length,
+                    files: results,
+                }));
+            }
         }
-
-        let a_bitrate = a_bitrate_opt.unwrap_or(0);
-        let b_bitrate = b_bitrate_opt.unwrap_or(0);
-        b_bitrate.cmp(&a_bitrate)
-    });
-
-    candidates.first().copied()
+    }
 }
 
-async fn accumulate_search_results(
+/// Gather peers' results for `token` for the search window, then settle the search.
+fn collect_search(
     token: u32,
-    username: &str,
-    results: Vec<SearchResultFile>,
     state: &Arc<Mutex<ClientState>>,
     event_tx: &mpsc::UnboundedSender<AppEvent>,
-    search_timeout_tx: &mpsc::UnboundedSender<u32>,
-) {
-    let should_start_timer = {
+) -> SearchPeers {
+    let (session, peers) = SearchSession::new(token, SEARCH_SESSION_TIMEOUT, usize::MAX);
+    let state = state.clone();
+    let event_tx = event_tx.clone();
+    tokio::spawn(async move {
+        let results = session.collect().await;
         let mut st = state.lock().await;
-        if let Some(pending) = st.spotify_track_searches.get_mut(&token) {
-            let was_empty = pending.results.is_empty();
-            for file in results {
-                pending.results.push(AccumulatedResult {
-                    username: username.to_string(),
-                    file,
-                });
+        match st.searches.take(token) {
+            Some(ActiveSearch::SpotifyTrack(pending)) => {
+                finalize_search(pending, &results, &mut st, &event_tx);
             }
-            was_empty
-        } else {
-            false
-        }
-    };
-
-    if should_start_timer {
-        let tx = search_timeout_tx.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(SEARCH_AGGREGATION_TIMEOUT).await;
-            let _ = tx.send(token);
-        });
-
-        let track_index = {
-            let st = state.lock().await;
-            st.spotify_track_searches.get(&token).map(|p| p.track_index)
-        };
-        if let Some(idx) = track_index {
-            let _ = event_tx.send(AppEvent::StatusMessage(format!(
-                "Collecting search results for track {} (waiting {}s)...",
-                idx + 1,
-                SEARCH_AGGREGATION_TIMEOUT.as_secs()
-            )));
+            Some(ActiveSearch::RetryDownload(pending)) => {
+                finalize_retry_search(pending, &results, &event_tx);
+            }
+            Some(ActiveSearch::Regular) | None => {}
         }
-    }
+    });
+    peers
 }
 
 fn finalize_search(
-    token: u32,
+    pending: PendingSpotifySearch,
+    results: &[PeerSearchResult],
     state: &mut ClientState,
     event_tx: &mpsc::UnboundedSender<AppEvent>,
 ) {
-    if let Some(pending) = state.spotify_track_searches.remove(&token) {
-        let track_index = pending.track_index;
-        let result_count = pending.results.len();
-
-        if let Some(best) = pick_best_file(&pending.results) {
-            let matched = MatchedFile {
-                username: best.username.clone(),
-                filename: best.file.filename.clone(),
-                size: best.file.size,
-                bitrate: get_bitrate(&best.file.attributes),
-            };
+    let track_index = pending.track_index;
+    let result_count = results.len();
+
+    if let Some(best) = pick_best_file(results) {
+        let matched = MatchedFile {
+            username: best.username.clone(),
+            filename: best.file.filename.clone(),
+            size: best.file.size,
+            bitrate: FileAttributes::new(&best.file.attributes).bitrate(),
+        };
 
-            if let Some(playlist) = &mut state.spotify_playlist
-                && let Some(track) = playlist.tracks.get_mut(track_index)
-            {
-                track.matched_file = Some(matched.clone());
-            }
+        if let Some(playlist) = &mut state.spotify_playlist
+            && let Some(track) = playlist.tracks.get_mut(track_index)
+        {
+            track.matched_file = Some(matched.clone());
+        }
 
-            let _ = event_tx.send(AppEvent::SpotifyTrackMatched {
-                track_index,
-                matched_file: matched,
-            });
-        } else {
-            let _ = event_tx.send(AppEvent::StatusMessage(format!(
+        let _ = event_tx.send(AppEvent::SpotifyTrackMatched {
+            track_index,
+            matched_file: matched,
+        });
+    } else {
+        let _ = event_tx.send(AppEvent::SpotifyTrackFailed {
+            track_index,
+            reason: format!(
                 "No audio match found for track {} ({} results checked)",
                 track_index + 1,
                 result_count
-            )));
-        }
-
-        state.pending_searches.remove(&token);
-    }
-}
-
-async fn accumulate_retry_results(
-    token: u32,
-    username: &str,
-    results: Vec<SearchResultFile>,
-    state: &Arc<Mutex<ClientState>>,
-    event_tx: &mpsc::UnboundedSender<AppEvent>,
-    search_timeout_tx: &mpsc::UnboundedSender<u32>,
-) {
-    let should_start_timer = {
-        let mut st = state.lock().await;
-        if let Some(pending) = st.retry_searches.get_mut(&token) {
-            let was_empty = pending.results.is_empty();
-            for file in results {
-                pending.results.push(AccumulatedResult {
-                    username: username.to_string(),
-                    file,
-                });
-            }
-            was_empty
-        } else {
-            false
-        }
-    };
-
-    if should_start_timer {
-        let tx = search_timeout_tx.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(SEARCH_AGGREGATION_TIMEOUT).await;
-            let _ = tx.send(token);
+            ),
         });
-
-        let _ = event_tx.send(AppEvent::StatusMessage(
-            "Finding alternative sources...".to_string(),
-        ));
     }
 }
 
 fn finalize_retry_search(
-    token: u32,
-    state: &mut ClientState,
+    pending: PendingRetrySearch,
+    results: &[PeerSearchResult],
     event_tx: &mpsc::UnboundedSender<AppEvent>,
 ) {
-    if let Some(pending) = state.retry_searches.remove(&token) {
-        let download_id = pending.download_id;
-
-        if let Some(best) = pick_best_file(&pending.results) {
-            let matched = MatchedFile {
-                username: best.username.clone(),
-                filename: best.file.filename.clone(),
-                size: best.file.size,
-                bitrate: get_bitrate(&best.file.attributes),
-            };
-
-            let _ = event_tx.send(AppEvent::RetryDownloadMatched {
-                download_id,
-                matched_file: matched,
-            });
-        } else {
-            let _ = event_tx.send(AppEvent::RetryDownloadFailed { download_id });
-        }
+    let download_id = pending.download_id;
+
+    if let Some(best) = pick_best_file(results) {
+        let matched = MatchedFile {
+            username: best.username.clone(),
+            filename: best.file.filename.clone(),
+            size: best.file.size,
+            bitrate: FileAttributes::new(&best.file.attributes).bitrate(),
+        };
 
-        state.pending_searches.remove(&token);
+        let _ = event_tx.send(AppEvent::RetryDownloadMatched {
+            download_id,
+            matched_file: matched,
+        });
+    } else {
+        let _ = event_tx.send(AppEvent::RetryDownloadFailed { download_id });
     }
 }
 
@@ -1432,7 +1907,6 @@ async fn handle_incoming_peer(
     mut stream: TcpStream,
     state: &Arc<Mutex<ClientState>>,
     event_tx: &mpsc::UnboundedSender<AppEvent>,
-    search_timeout_tx: &mpsc::UnboundedSender<u32>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut read_buf = BytesMut::with_capacity(65536);
 
@@ -1447,13 +1921,27 @@ async fn handle_incoming_peer(
     let init_msg = read_peer_init_message(&mut read_buf)?;
 
     match init_msg {
-        PeerInitMessage::PierceFirewall { .. } => {
-            // Firewall pierce - not needed for basic functionality
+        PeerInitMessage::PierceFirewall { token } => {
+            // A peer answering one of our indirect connection requests
+            let peers = state.lock().await.peers.clone();
+            let _ = peers.pierce_firewall(token, stream);
         }
         PeerInitMessage::PeerInit {
-            connection_type, ..
+            username,
+            connection_type,
+            ..
         } => {
-            if connection_type == ConnectionType::Peer {
+            if connection_type == ConnectionType::File {
+                return handle_incoming_upload(stream, read_buf, state, event_tx).await;
+            } else if connection_type == ConnectionType::Distributed {
+                let accept = {
+                    let st = state.lock().await;
+                    st.accept_children
+                };
+                if accept {
+                    return accept_distributed_child(&username, stream, state).await;
+                }
+            } else if connection_type == ConnectionType::Peer {
                 // Process any data already in buffer, then read more
                 loop {
                     // First process any complete messages in the buffer
@@ -1472,47 +1960,58 @@ async fn handle_incoming_peer(
                         let mut msg_buf = read_buf.split_to(4 + msg_len);
 
                         match read_peer_message(&mut msg_buf) {
-                            Ok(PeerMessage::FileSearchResponse {
-                                username: result_user,
-                                token,
-                                results,
-                                slot_free,
-                                avg_speed,
-                                queue_length,
-                                ..
-                            }) => {
-                                let pending = {
+                            Ok(response @ PeerMessage::FileSearchResponse { .. }) => {
+                                deliver_search_response(response, state, event_tx).await;
+                            }
+                            Ok(PeerMessage::SharedFileListRequest) => {
+                                let directories = {
                                     let st = state.lock().await;
-                                    st.pending_searches.contains_key(&token)
+                                    st.shares.directories.clone()
                                 };
-
-                                if pending && !results.is_empty() {
-                                    let is_spotify_search = {
-                                        let st = state.lock().await;
-                                        st.spotify_track_searches.contains_key(&token)
-                                    };
-
-                                    if is_spotify_search {
-                                        accumulate_search_results(
+                                let response = PeerMessage::SharedFileListResponse {
+                                    directories,
+                                    private_directories: Vec::new(),
+                                };
+                                let mut buf = BytesMut::new();
+                                response.write_message(&mut buf)?;
+                                stream.write_all(&buf).await?;
+                            }
+                            Ok(PeerMessage::UserInfoRequest) => {
+                                let response = {
+                                    let st = state.lock().await;
+                                    st.user_info()
+                                };
+                                let mut buf = BytesMut::new();
+                                response.write_message(&mut buf)?;
+                                stream.write_all(&buf).await?;
+                            }
+                            Ok(PeerMessage::QueueUpload { filename }) => {
+                                let shared = {
+                                    let st = state.lock().await;
+                                    st.find_shared_file(&filename)
+                                };
+                                let response = match shared {
+                                    Some((path, size)) => {
+                                        let token = next_token();
+                                        {
+                                            let mut st = state.lock().await;
+                                            st.pending_uploads.insert(token, path);
+                                        }
+                                        PeerMessage::TransferRequest {
+                                            direction: TransferDirection::Upload,
                                             token,
-                                            &result_user,
-                                            results,
-                                            state,
-                                            event_tx,
-                                            search_timeout_tx,
-                                        )
-                                        .await;
-                                    } else {
-                                        let _ =
-                                            event_tx.send(AppEvent::SearchResult(SearchResult {
-                                                username: result_user,
-                                                slot_free,
-                                                avg_speed,
-                                                queue_length,
-                                                files: results,
-                                            }));
+                                            filename,
+                                            file_size: Some(size),
+                                        }
                                     }
-                                }
+                                    None => PeerMessage::UploadDenied {
+                                        filename,
+                                        reason: TransferRejectionReason::FileNotShared,
+                                    },
+                                };
+                                let mut buf = BytesMut::new();
+                                response.write_message(&mut buf)?;
+                                stream.write_all(&buf).await?;
                             }
                             Ok(_) => {}
                             Err(_) => {}
@@ -1531,3 +2030,1023 @@ async fn handle_incoming_peer(
 
     Ok(())
 }
+
+/// Serve a file to a peer that opened an F connection for a transfer we offered.
+async fn handle_incoming_upload(
+    mut stream: TcpStream,
+    mut read_buf: BytesMut,
+    state: &Arc<Mutex<ClientState>>,
+    event_tx: &mpsc::UnboundedSender<AppEvent>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // FileTransferInit (u32 token) followed by FileOffset (u64)
+    while read_buf.len() < 12 {
+        let n = stream.read_buf(&mut read_buf).await?;
+        if n == 0 {
+            return Err("Connection closed before upload handshake".into());
+        }
+    }
+
+    let init = FileTransferInit::read_from(&mut read_buf)?;
+    let offset = FileOffset::read_from(&mut read_buf)?;
+
+    let path = {
+        let mut st = state.lock().await;
+        st.pending_uploads.remove(&init.token)
+    }
+    .ok_or("Unknown upload token")?;
+
+    let mut file = File::open(&path).await?;
+    file.seek(SeekFrom::Start(offset.offset)).await?;
+    let sent = tokio::io::copy(&mut file, &mut stream).await?;
+    stream.shutdown().await?;
+    state.lock().await.uploads_completed += 1;
+
+    let _ = event_tx.send(AppEvent::StatusMessage(format!(
+        "Uploaded {} ({} bytes)",
+        path.display(),
+        sent
+    )));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slsk_rs::constants::ObfuscationType;
+    use slsk_rs::distributed::read_distributed_message;
+    use slsk_rs::peer::SharedFile;
+    use slsk_rs::protocol::FrameDecoder;
+    use slsk_rs::server::read_server_request;
+
+    #[test]
+    fn test_set_shares_updates_state_and_reports() {
+        let mut state = ClientState::new("me");
+        let directories = vec![SharedDirectory {
+            path: "Music\\Album".to_string(),
+            files: vec![
+                SharedFile {
+                    filename: "01.mp3".to_string(),
+                    size: 1000,
+                    extension: "mp3".to_string(),
+                    attributes: vec![],
+                },
+                SharedFile {
+                    filename: "02.mp3".to_string(),
+                    size: 2000,
+                    extension: "mp3".to_string(),
+                    attributes: vec![],
+                },
+            ],
+        }];
+
+        let req = state.set_shares(Shares {
+            directories,
+            roots: HashMap::new(),
+        });
+        assert_eq!(state.shares.directories.len(), 1);
+        assert_eq!(state.shares.directories[0].files.len(), 2);
+
+        let mut buf = BytesMut::new();
+        req.write_message(&mut buf).unwrap();
+        match read_server_request(&mut buf).unwrap() {
+            ServerRequest::SharedFoldersFiles { dirs, files } => {
+                assert_eq!(dirs, 1);
+                assert_eq!(files, 2);
+            }
+            other => panic!("unexpected request: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_distributed_child_receives_branch_info() {
+        let child = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = child.local_addr().unwrap().port() as u32;
+
+        let mut parent = ClientState::new("parent");
+        parent.accept_children = true;
+        // We never take a parent ourselves, so place this one in a branch directly
+        parent.branch.handle_parent_message(&DistributedMessage::BranchLevel { level: 1 });
+        parent.branch.handle_parent_message(&DistributedMessage::BranchRoot {
+            root: "root".to_string(),
+        });
+        let state = Arc::new(Mutex::new(parent));
+
+        let state_clone = state.clone();
+        let parent_task = tokio::spawn(async move {
+            connect_to_distributed_child("child", Ipv4Addr::LOCALHOST, port, 99, &state_clone).await
+        });
+
+        let (mut stream, _) = child.accept().await.unwrap();
+        let mut read_buf = BytesMut::new();
+        while peer_init_message_size(&read_buf).is_none() {
+            stream.read_buf(&mut read_buf).await.unwrap();
+        }
+        match read_peer_init_message(&mut read_buf).unwrap() {
+            PeerInitMessage::PierceFirewall { token } => assert_eq!(token, 99),
+            other => panic!("unexpected init message: {other:?}"),
+        }
+
+        let mut messages = Vec::new();
+        while messages.len() < 2 {
+            if read_buf.len() >= 4 {
+                let msg_len =
+                    u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]])
+                        as usize;
+                if read_buf.len() >= 4 + msg_len {
+                    let mut msg_buf = read_buf.split_to(4 + msg_len);
+                    messages.push(read_distributed_message(&mut msg_buf).unwrap());
+                    continue;
+                }
+            }
+            stream.read_buf(&mut read_buf).await.unwrap();
+        }
+        assert!(matches!(
+            messages[0],
+            DistributedMessage::BranchLevel { level: 2 }
+        ));
+        assert!(matches!(&messages[1], DistributedMessage::BranchRoot { root } if root == "root"));
+
+        // Searches reaching us as branch root are relayed down to the child
+        {
+            let mut st = state.lock().await;
+            assert!(st.distributed_children.contains_key("child"));
+            st.relay_to_children(&DistributedMessage::EmbeddedMessage {
+                code: 3,
+                data: vec![7, 7],
+            });
+        }
+        while read_buf.len() < 8 {
+            stream.read_buf(&mut read_buf).await.unwrap();
+        }
+        match read_distributed_message(&mut read_buf).unwrap() {
+            DistributedMessage::EmbeddedMessage { code, data } => {
+                assert_eq!(code, 3);
+                assert_eq!(data, vec![7, 7]);
+            }
+            other => panic!("unexpected distributed message: {other:?}"),
+        }
+
+        drop(stream);
+        parent_task.await.unwrap().unwrap();
+        assert!(state.lock().await.distributed_children.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_download_queued_behind_another() {
+        let mut state = ClientState::new("me");
+        state.queued_downloads.extend([1, 2]);
+
+        let _first = state.start_queued_download(1).unwrap();
+        // Waiting in the peer's download task, so it is cancelled straight away
+        assert!(state.cancel_download(2));
+        assert!(state.start_queued_download(2).is_none());
+        // The running one reports its own cancellation
+        assert!(!state.cancel_download(1));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_running_download() {
+        let dir = std::env::temp_dir().join(format!("slsk-cancel-{}", std::process::id()));
+        let mut client = ClientState::new("me");
+        client.download_config.base_dir = dir.clone();
+        let file_path = client.download_config.local_path("Music\\slow.flac");
+        let state = Arc::new(Mutex::new(client));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sending_tx, sending_rx) = tokio::sync::oneshot::channel();
+        let peer = tokio::spawn(async move {
+            // Agree to upload over the P connection
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = FrameDecoder::new();
+            let mut received = 0;
+            while received < 2 {
+                stream.read_buf(frames.buffer_mut()).await.unwrap();
+                while frames.next_frame().is_some() {
+                    received += 1;
+                }
+            }
+            let mut buf = BytesMut::new();
+            PeerMessage::TransferRequest {
+                direction: TransferDirection::Upload,
+                token: 9,
+                filename: "Music\\slow.flac".to_string(),
+                file_size: Some(1_000_000),
+            }
+            .write_message(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+
+            // Then send a little of the file and stall
+            let (mut file_stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            while peer_init_message_size(&buf).is_none_or(|size| buf.len() < size + 12) {
+                file_stream.read_buf(&mut buf).await.unwrap();
+            }
+            file_stream.write_all(&[0; 10]).await.unwrap();
+            sending_tx.send(()).unwrap();
+            // Until the downloader hangs up, which may come as a reset
+            let _ = file_stream.read_to_end(&mut Vec::new()).await;
+        });
+
+        let download = PendingDownload {
+            id: 3,
+            username: "friend".to_string(),
+            filename: "Music\\slow.flac".to_string(),
+            size: 1_000_000,
+            token: 30,
+            offset: 0,
+        };
+        let cancel = state.lock().await.register_download(3);
+        let state_clone = state.clone();
+        let downloader = tokio::spawn(async move {
+            connect_to_peer_and_download(
+                Ipv4Addr::LOCALHOST,
+                port as u32,
+                download,
+                cancel,
+                &state_clone,
+                &event_tx,
+            )
+            .await
+            .map_err(|e| e.to_string())
+        });
+
+        sending_rx.await.unwrap();
+        // Running, so the download reports the cancellation itself
+        assert!(!state.lock().await.cancel_download(3));
+        tokio::time::timeout(Duration::from_secs(1), downloader)
+            .await
+            .expect("download did not stop")
+            .unwrap()
+            .unwrap();
+        peer.await.unwrap();
+
+        let mut last = None;
+        while let Ok(event) = event_rx.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(AppEvent::DownloadCancelled { id: 3 })));
+        assert!(!file_path.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_resumed_download_continues_from_offset() {
+        let dir = std::env::temp_dir().join(format!("slsk-resume-{}", std::process::id()));
+        let mut client = ClientState::new("me");
+        client.download_config.base_dir = dir.clone();
+        let file_path = client.download_config.local_path("Music\\part.flac");
+        std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        // Two bytes past the recorded progress never made it into the database
+        std::fs::write(&file_path, b"abcdXX").unwrap();
+        let state = Arc::new(Mutex::new(client));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = FrameDecoder::new();
+            let mut received = 0;
+            while received < 2 {
+                stream.read_buf(frames.buffer_mut()).await.unwrap();
+                while frames.next_frame().is_some() {
+                    received += 1;
+                }
+            }
+            let mut buf = BytesMut::new();
+            PeerMessage::TransferRequest {
+                direction: TransferDirection::Upload,
+                token: 9,
+                filename: "Music\\part.flac".to_string(),
+                file_size: Some(8),
+            }
+            .write_message(&mut buf)
+            .unwrap();
+            stream.write_all(&buf).await.unwrap();
+
+            let (mut file_stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            while peer_init_message_size(&buf).is_none_or(|size| buf.len() < size + 12) {
+                file_stream.read_buf(&mut buf).await.unwrap();
+            }
+            let size = peer_init_message_size(&buf).unwrap();
+            let offset = u64::from_le_bytes(buf[size + 4..size + 12].try_into().unwrap());
+            file_stream.write_all(&b"abcdefgh"[offset as usize..]).await.unwrap();
+            offset
+        });
+
+        let download = PendingDownload {
+            id: 4,
+            username: "friend".to_string(),
+            filename: "Music\\part.flac".to_string(),
+            size: 8,
+            token: 40,
+            offset: 4,
+        };
+        let cancel = state.lock().await.register_download(4);
+        connect_to_peer_and_download(
+            Ipv4Addr::LOCALHOST,
+            port as u32,
+            download,
+            cancel,
+            &state,
+            &event_tx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(peer.await.unwrap(), 4);
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"abcdefgh");
+        let mut last = None;
+        while let Ok(event) = event_rx.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(AppEvent::DownloadCompleted { id: 4 })));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_incoming_file_connection_uploads() {
+        let dir = std::env::temp_dir().join(format!("slsk-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("track.mp3");
+        std::fs::write(&path, b"0123456789abcdef").unwrap();
+
+        let mut client = ClientState::new("me");
+        client.pending_uploads.insert(555, path);
+        let state = Arc::new(Mutex::new(client));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (event_tx, _event_rx) = mpsc::unbounded_channel();
+
+        let state_clone = state.clone();
+        let uploader = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_incoming_peer(stream, &state_clone, &event_tx).await
+        });
+
+        let mut downloader = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        write_peer_init_message(
+            &PeerInitMessage::PeerInit {
+                username: "downloader".to_string(),
+                connection_type: ConnectionType::File,
+                token: 1,
+            },
+            &mut buf,
+        ).unwrap();
+        FileTransferInit::new(555).write_to(&mut buf);
+        FileOffset::new(10).write_to(&mut buf);
+        downloader.write_all(&buf).await.unwrap();
+
+        let mut received = Vec::new();
+        downloader.read_to_end(&mut received).await.unwrap();
+        uploader.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(received, b"abcdef");
+        assert!(state.lock().await.pending_uploads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_user_info_request_answered() {
+        let mut client = ClientState::new("me");
+        client.description = "Mostly jazz".to_string();
+        client.uploads_completed = 12;
+        client.pending_uploads.insert(1, PathBuf::from("a.flac"));
+        let state = Arc::new(Mutex::new(client));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (event_tx, _event_rx) = mpsc::unbounded_channel();
+
+        let state_clone = state.clone();
+        let responder = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_incoming_peer(stream, &state_clone, &event_tx).await
+        });
+
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        write_peer_init_message(
+            &PeerInitMessage::PeerInit {
+                username: "curious".to_string(),
+                connection_type: ConnectionType::Peer,
+                token: 1,
+            },
+            &mut buf,
+        ).unwrap();
+        PeerMessage::UserInfoRequest.write_message(&mut buf).unwrap();
+        peer.write_all(&buf).await.unwrap();
+        peer.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        responder.await.unwrap().unwrap();
+
+        let mut expected = BytesMut::new();
+        PeerMessage::UserInfoResponse {
+            description: "Mostly jazz".to_string(),
+            picture: None,
+            total_uploads: 12,
+            queue_size: 1,
+            slots_free: true,
+            upload_permitted: Some(UploadPermission::Everyone),
+        }
+        .write_message(&mut expected).unwrap();
+        assert_eq!(received, expected.to_vec());
+
+        let mut received = BytesMut::from(&received[..]);
+        match read_peer_message(&mut received).unwrap() {
+            PeerMessage::UserInfoResponse {
+                description,
+                total_uploads,
+                queue_size,
+                ..
+            } => {
+                assert_eq!(description, "Mostly jazz");
+                assert_eq!(total_uploads, 12);
+                assert_eq!(queue_size, 1);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cant_connect_to_peer_fails_pending_download() {
+        let mut client = ClientState::new("me");
+        let mut peer_requests = client.peer_requests.take().unwrap();
+        let state = Arc::new(Mutex::new(client));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+
+        // A port nobody listens on stands in for a firewalled peer
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        queue_download(
+            "friend".to_string(),
+            "Music\\8.mp3".to_string(),
+            1000,
+            &state,
+            &write_tx,
+            &event_tx,
+        )
+        .await;
+        let id = match event_rx.try_recv().unwrap() {
+            AppEvent::DownloadQueued { id, .. } => id,
+            other => panic!("unexpected event: {other:?}"),
+        };
+        let mut request = write_rx.try_recv().unwrap();
+        assert!(matches!(
+            read_server_request(&mut request).unwrap(),
+            ServerRequest::GetPeerAddress { .. }
+        ));
+
+        let flow = handle_server_response(
+            ServerResponse::GetPeerAddress {
+                username: "friend".to_string(),
+                ip: Ipv4Addr::LOCALHOST,
+                port: port as u32,
+                obfuscation_type: ObfuscationType::None,
+                obfuscated_port: 0,
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        // Dialling fails, so the server is asked to have the peer connect to us
+        let request = tokio::time::timeout(Duration::from_secs(5), peer_requests.recv())
+            .await
+            .unwrap();
+        let Some(ServerRequest::ConnectToPeer {
+            token,
+            username,
+            connection_type: ConnectionType::Peer,
+        }) = request
+        else {
+            panic!("unexpected request: {request:?}");
+        };
+        assert_eq!(username, "friend");
+
+        let flow = handle_server_response(
+            ServerResponse::CantConnectToPeer {
+                token,
+                username: "friend".to_string(),
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        let event = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        match event {
+            Some(AppEvent::DownloadFailed { id: failed, reason }) => {
+                assert_eq!(failed, id);
+                assert!(reason.contains("friend"), "{reason}");
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        assert!(state.lock().await.addresses.get("friend").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin_message_and_relogged_surface_events() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+
+        let flow = handle_server_response(
+            ServerResponse::AdminMessage {
+                message: "Maintenance at noon".to_string(),
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+        match event_rx.try_recv().unwrap() {
+            AppEvent::AdminMessage(message) => assert_eq!(message, "Maintenance at noon"),
+            other => panic!("unexpected event: {other:?}"),
+        }
+
+        // Being relogged ends the session
+        let flow = handle_server_response(
+            ServerResponse::Relogged,
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_break());
+        assert!(matches!(event_rx.try_recv().unwrap(), AppEvent::Relogged));
+    }
+
+    #[tokio::test]
+    async fn test_joined_rooms_are_left_on_quit() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, _event_rx) = mpsc::unbounded_channel();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+
+        let flow = handle_server_response(
+            ServerResponse::JoinRoom {
+                room: "indie".to_string(),
+                users: vec![],
+                owner: None,
+                operators: vec![],
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        let st = state.lock().await;
+        let requests = ServerConnection::shutdown_requests(st.rooms.rooms());
+        assert!(matches!(
+            requests.as_slice(),
+            [
+                ServerRequest::SetStatus { .. },
+                ServerRequest::LeaveRoom { room },
+            ] if room == "indie"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_private_message_is_acked() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+
+        let flow = handle_server_response(
+            ServerResponse::MessageUser {
+                id: 42,
+                timestamp: 0,
+                username: "alice".to_string(),
+                message: "hi".to_string(),
+                new_message: false,
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        let mut buf = write_rx.try_recv().unwrap();
+        assert!(matches!(
+            read_server_request(&mut buf).unwrap(),
+            ServerRequest::MessageAcked { message_id: 42 }
+        ));
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::PrivateMessage { username, message } if username == "alice" && message == "hi"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_excluded_search_phrase_is_not_sent() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+        let (rate_limit_tx, _rate_limit_rx) = mpsc::unbounded_channel();
+
+        let flow = handle_server_response(
+            ServerResponse::ExcludedSearchPhrases {
+                phrases: vec!["Banned Band".to_string()],
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        let search = |query: &str| QueuedSearch::Regular {
+            query: query.to_string(),
+        };
+        try_execute_or_queue_search(
+            search("the BANNED band live"),
+            &state,
+            &write_tx,
+            &event_tx,
+            &rate_limit_tx,
+        )
+        .await;
+        assert!(write_rx.try_recv().is_err());
+        match event_rx.try_recv().unwrap() {
+            AppEvent::StatusMessage(message) => assert!(message.contains("banned band")),
+            other => panic!("unexpected event: {other:?}"),
+        }
+        let remaining = state.lock().await.rate_limiter.searches_remaining();
+        assert_eq!(remaining, slsk_rs::server::SEARCH_RATE_LIMIT_MAX);
+
+        // Searches made on behalf of a download or track fail them rather than leave them waiting
+        try_execute_or_queue_search(
+            QueuedSearch::RetryDownload {
+                download_id: 7,
+                original_filename: "Music\\banned band.mp3".to_string(),
+                query: "banned band".to_string(),
+            },
+            &state,
+            &write_tx,
+            &event_tx,
+            &rate_limit_tx,
+        )
+        .await;
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::RetryDownloadFailed { download_id: 7 }
+        ));
+        assert!(matches!(event_rx.try_recv().unwrap(), AppEvent::StatusMessage(_)));
+        try_execute_or_queue_search(
+            QueuedSearch::SpotifyTrack {
+                track_index: 2,
+                query: "banned band hit".to_string(),
+            },
+            &state,
+            &write_tx,
+            &event_tx,
+            &rate_limit_tx,
+        )
+        .await;
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::SpotifyTrackFailed { track_index: 2, .. }
+        ));
+        assert!(matches!(event_rx.try_recv().unwrap(), AppEvent::StatusMessage(_)));
+        assert!(write_rx.try_recv().is_err());
+
+        try_execute_or_queue_search(
+            search("aphex twin"),
+            &state,
+            &write_tx,
+            &event_tx,
+            &rate_limit_tx,
+        )
+        .await;
+        assert!(write_rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_search_responses_routed_by_token() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let token = state.lock().await.searches.issue(ActiveSearch::Regular);
+        let response = |token| PeerMessage::FileSearchResponse {
+            username: "alice".to_string(),
+            token,
+            results: vec![slsk_rs::peer::SearchResultFile {
+                filename: "Music\\a.flac".to_string(),
+                size: 1000,
+                extension: "flac".to_string(),
+                attributes: vec![],
+            }],
+            slot_free: true,
+            avg_speed: 100,
+            queue_length: 0,
+            private_results: vec![],
+        };
+
+        // Nothing we asked for
+        deliver_search_response(response(token + 1), &state, &event_tx).await;
+        assert!(event_rx.try_recv().is_err());
+
+        deliver_search_response(response(token), &state, &event_tx).await;
+        match event_rx.try_recv().unwrap() {
+            AppEvent::SearchResult(result) => {
+                assert_eq!(result.username, "alice");
+                assert_eq!(result.files.len(), 1);
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_user_status_updates_cache() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+
+        for status in [UserStatus::Online, UserStatus::Away] {
+            let flow = handle_server_response(
+                ServerResponse::GetUserStatus {
+                    username: "friend".to_string(),
+                    status,
+                    privileged: false,
+                },
+                &state,
+                &event_tx,
+                &write_tx,
+                0,
+                )
+            .await;
+            assert!(flow.is_continue());
+        }
+
+        assert_eq!(
+            state.lock().await.user_statuses.get("friend"),
+            Some(&UserStatus::Away)
+        );
+        match event_rx.try_recv().unwrap() {
+            AppEvent::UserStatus { username, status } => {
+                assert_eq!(username, "friend");
+                assert_eq!(status, UserStatus::Online);
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        assert!(matches!(
+            event_rx.try_recv().unwrap(),
+            AppEvent::UserStatus {
+                status: UserStatus::Away,
+                ..
+            }
+        ));
+        // Nothing is sent back to the server for a pushed update
+        assert!(write_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_browse_offline_user_fails_without_connecting() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+
+        browse_user("gone".to_string(), &state, &event_tx, &write_tx).await;
+        let flow = handle_server_response(
+            ServerResponse::GetPeerAddress {
+                username: "gone".to_string(),
+                ip: Ipv4Addr::UNSPECIFIED,
+                port: 0,
+                obfuscation_type: ObfuscationType::None,
+                obfuscated_port: 0,
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+        match event_rx.try_recv().unwrap() {
+            AppEvent::Error(message) => assert!(message.contains("offline"), "{message}"),
+            other => panic!("unexpected event: {other:?}"),
+        }
+        assert!(!state.lock().await.pending_browse.contains_key("gone"));
+    }
+
+    #[tokio::test]
+    async fn test_repeat_browse_served_from_cache() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            PeerMessage::SharedFileListResponse {
+                directories: vec![SharedDirectory {
+                    path: "Music".to_string(),
+                    files: vec![],
+                }],
+                private_directories: vec![],
+            }
+            .write_message(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+            listener
+        });
+
+        browse_user("friend".to_string(), &state, &event_tx, &write_tx).await;
+        let mut request = write_rx.try_recv().unwrap();
+        assert!(matches!(
+            read_server_request(&mut request).unwrap(),
+            ServerRequest::GetPeerAddress { .. }
+        ));
+
+        let flow = handle_server_response(
+            ServerResponse::GetPeerAddress {
+                username: "friend".to_string(),
+                ip: Ipv4Addr::LOCALHOST,
+                port: port as u32,
+                obfuscation_type: ObfuscationType::None,
+                obfuscated_port: 0,
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+        let listener = peer.await.unwrap();
+        let first = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        match first {
+            Some(AppEvent::UserFiles(user, dirs)) => {
+                assert_eq!(user, "friend");
+                assert_eq!(dirs.len(), 1);
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+
+        // Second browse is answered locally: no server request, no peer connection
+        browse_user("friend".to_string(), &state, &event_tx, &write_tx).await;
+        match event_rx.try_recv().unwrap() {
+            AppEvent::UserFiles(user, dirs) => {
+                assert_eq!(user, "friend");
+                assert_eq!(dirs[0].path, "Music");
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        assert!(write_rx.try_recv().is_err());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), listener.accept())
+                .await
+                .is_err()
+        );
+
+        // Once the share list is stale, the cached address still saves the lookup
+        state.lock().await.browse_cache.clear();
+        browse_user("friend".to_string(), &state, &event_tx, &write_tx).await;
+        assert!(write_rx.try_recv().is_err());
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        PeerMessage::SharedFileListResponse {
+            directories: vec![],
+            private_directories: vec![],
+        }
+        .write_message(&mut buf).unwrap();
+        stream.write_all(&buf).await.unwrap();
+        let refreshed = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            refreshed,
+            Some(AppEvent::UserFiles(user, dirs)) if user == "friend" && dirs.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_embedded_search_answered_from_shares() {
+        let state = Arc::new(Mutex::new(ClientState::new("me")));
+        state.lock().await.set_shares(Shares {
+            directories: vec![SharedDirectory {
+                path: "Music\\Artist".to_string(),
+                files: vec![SharedFile {
+                    filename: "Song.flac".to_string(),
+                    size: 1234,
+                    extension: "flac".to_string(),
+                    attributes: vec![],
+                }],
+            }],
+            roots: HashMap::from([("Music".to_string(), PathBuf::from("/srv/music"))]),
+        });
+        let (event_tx, _event_rx) = mpsc::unbounded_channel();
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+
+        let search = DistributedMessage::Search {
+            unknown: 0,
+            username: "searcher".to_string(),
+            token: 77,
+            query: "artist song".to_string(),
+        };
+        let DistributedMessage::EmbeddedMessage { code, data } = DistributedMessage::embed(&search)
+        else {
+            unreachable!();
+        };
+        let flow = handle_server_response(
+            ServerResponse::EmbeddedMessage { code, data },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        let mut request = write_rx.try_recv().unwrap();
+        match read_server_request(&mut request).unwrap() {
+            ServerRequest::GetPeerAddress { username } => assert_eq!(username, "searcher"),
+            other => panic!("unexpected request: {other:?}"),
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let flow = handle_server_response(
+            ServerResponse::GetPeerAddress {
+                username: "searcher".to_string(),
+                ip: Ipv4Addr::LOCALHOST,
+                port: port as u32,
+                obfuscation_type: ObfuscationType::None,
+                obfuscated_port: 0,
+            },
+            &state,
+            &event_tx,
+            &write_tx,
+            0,
+        )
+        .await;
+        assert!(flow.is_continue());
+
+        // The connection is pooled for later replies, so read frames rather than to EOF
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut frames = FrameDecoder::new();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            stream.read_buf(frames.buffer_mut()).await.unwrap();
+            while let Some(frame) = frames.next_frame() {
+                received.push(frame.unwrap());
+            }
+        }
+
+        assert!(matches!(
+            read_peer_init_message(&mut received[0]).unwrap(),
+            PeerInitMessage::PeerInit {
+                connection_type: ConnectionType::Peer,
+                ..
+            }
+        ));
+        match read_peer_message(&mut received[1]).unwrap() {
+            PeerMessage::FileSearchResponse {
+                username,
+                token,
+                results,
+                ..
+            } => {
+                assert_eq!(username, "me");
+                assert_eq!(token, 77);
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].filename, "Music\\Artist\\Song.flac");
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(state.lock().await.pending_search_replies.is_empty());
+    }
+}
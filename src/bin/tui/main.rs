@@ -1,20 +1,26 @@
 mod app;
 mod client;
+mod search;
+mod shares;
 mod spotify;
 mod ui;
 
 use std::io;
 use std::time::Duration;
 
-use app::{App, AppEvent};
+use app::{App, AppEvent, ClientCommand};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
 use ratatui::{Terminal, prelude::CrosstermBackend};
+use search::{SEARCH_HISTORY_LIMIT, SearchHistory};
+use slsk_rs::db::Database;
 use tokio::sync::mpsc;
 
+const DB_FILE: &str = "slsk-tui.db";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
@@ -33,7 +39,24 @@ async fn main() -> anyhow::Result<()> {
 
     let mut app = App::new(cmd_tx);
 
-    let client_handle = tokio::spawn(async move {
+    let db_path = std::env::var("SOULSEEK_DB").unwrap_or_else(|_| DB_FILE.to_string());
+    match Database::open(&db_path).and_then(|db| Ok((db.load_pending_downloads()?, db))) {
+        Ok((pending, db)) => {
+            app.restore_downloads(pending);
+            if let Ok(history) = db.recent_searches(SEARCH_HISTORY_LIMIT) {
+                app.search_history = SearchHistory::with_entries(history, SEARCH_HISTORY_LIMIT);
+            }
+            app.db = Some(db);
+        }
+        Err(e) => app.status = format!("Failed to load download queue: {e}"),
+    }
+
+    if let Some(shares) = std::env::var_os("SOULSEEK_SHARES") {
+        let roots = std::env::split_paths(&shares).collect();
+        let _ = app.cmd_tx.send(ClientCommand::Shares(roots));
+    }
+
+    let mut client_handle = tokio::spawn(async move {
         if let Err(e) = client::run_client(&username, &password, event_tx, cmd_rx).await {
             eprintln!("Client error: {e}");
         }
@@ -49,6 +72,9 @@ async fn main() -> anyhow::Result<()> {
     )?;
     terminal.show_cursor()?;
 
+    // Give the client a moment to go offline and leave its rooms
+    let _ = app.cmd_tx.send(ClientCommand::Quit);
+    let _ = tokio::time::timeout(client::SHUTDOWN_FLUSH_TIMEOUT, &mut client_handle).await;
     client_handle.abort();
 
     if let Err(e) = result {
@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+
+use slsk_rs::peer::{
+    FileAttributes, RankOptions, SearchResultFile, SharedFile, rank_search_results,
+};
+
+use crate::app::SearchResult;
+
+/// Number of past searches remembered across sessions.
+pub const SEARCH_HISTORY_LIMIT: usize = 50;
+
+/// Past search queries, most recent first, without duplicates.
+#[derive(Debug, Clone)]
+pub struct SearchHistory {
+    entries: VecDeque<String>,
+    limit: usize,
+}
+
+impl SearchHistory {
+    pub fn new(limit: usize) -> Self {
+        SearchHistory {
+            entries: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Restore a history saved most recent first.
+    pub fn with_entries(entries: Vec<String>, limit: usize) -> Self {
+        let mut history = Self::new(limit);
+        for query in entries.into_iter().rev() {
+            history.push(&query);
+        }
+        history
+    }
+
+    /// Record a query as the most recent, dropping any earlier copy and the oldest beyond the limit.
+    pub fn push(&mut self, query: &str) {
+        let query = query.trim();
+        if query.is_empty() {
+            return;
+        }
+        self.entries.retain(|q| q != query);
+        self.entries.push_front(query.to_string());
+        self.entries.truncate(self.limit);
+    }
+
+    /// The `index`th most recent query.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Most recent query that extends `prefix`, ignoring case.
+    pub fn complete(&self, prefix: &str) -> Option<&str> {
+        if prefix.is_empty() {
+            return None;
+        }
+        let prefix = prefix.to_lowercase();
+        self.entries
+            .iter()
+            .find(|q| q.len() > prefix.len() && q.to_lowercase().starts_with(&prefix))
+            .map(String::as_str)
+    }
+}
+
+impl Default for SearchHistory {
+    fn default() -> Self {
+        Self::new(SEARCH_HISTORY_LIMIT)
+    }
+}
+
+/// Search results from one uploader that share a containing directory.
+#[derive(Debug, Clone)]
+pub struct AlbumGroup {
+    pub username: String,
+    pub directory: String,
+    pub files: Vec<SearchResultFile>,
+}
+
+impl AlbumGroup {
+    /// Last path component of the directory, usually the album name.
+    pub fn name(&self) -> &str {
+        self.directory
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or(&self.directory)
+    }
+}
+
+/// Group search result files by uploader and containing directory, keeping first-seen order.
+pub fn group_by_directory(results: &[SearchResult]) -> Vec<AlbumGroup> {
+    let mut groups: Vec<AlbumGroup> = Vec::new();
+
+    for result in results {
+        for file in &result.files {
+            let directory = file
+                .filename
+                .rsplit_once(['/', '\\'])
+                .map(|(dir, _)| dir)
+                .unwrap_or("");
+
+            match groups
+                .iter_mut()
+                .find(|g| g.username == result.username && g.directory == directory)
+            {
+                Some(group) => group.files.push(file.clone()),
+                None => groups.push(AlbumGroup {
+                    username: result.username.clone(),
+                    directory: directory.to_string(),
+                    files: vec![file.clone()],
+                }),
+            }
+        }
+    }
+
+    groups
+}
+
+/// Short audio quality summary for a result file, e.g. `320 kbps, 4:05`.
+///
+/// Lossless files rarely report a bitrate, so they show their format and
+/// sample rate instead. Returns `None` when the uploader sent no attributes.
+pub fn quality_summary(file: &SearchResultFile) -> Option<String> {
+    let attrs = FileAttributes::new(&file.attributes);
+    let mut parts = Vec::new();
+
+    if let Some(bitrate) = attrs.bitrate() {
+        let vbr = if attrs.is_vbr() { " VBR" } else { "" };
+        parts.push(format!("{} kbps{}", bitrate, vbr));
+    } else if let Some(rate) = attrs.sample_rate() {
+        let mut extension = SharedFile::infer_extension(&file.filename);
+        if extension.is_empty() {
+            extension.clone_from(&file.extension);
+        }
+        parts.push(format!("{} {} kHz", extension.to_uppercase(), rate as f64 / 1000.0));
+    }
+
+    if let Some(duration) = attrs.duration() {
+        parts.push(format!("{}:{:02}", duration / 60, duration % 60));
+    }
+
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Split a remote path into its directory and lowercased file stem.
+fn track_key(filename: &str) -> (&str, String) {
+    let (directory, basename) = filename.rsplit_once(['/', '\\']).unwrap_or(("", filename));
+    let stem = basename
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(basename);
+    (directory, stem.to_lowercase())
+}
+
+/// Keep only the best-quality file for each track an uploader has in several formats.
+///
+/// Files are the same track when they share a directory and file stem; the
+/// survivor is picked by [`rank_search_results`] and keeps the first copy's position.
+pub fn collapse_to_best_quality(results: &[SearchResult]) -> Vec<SearchResult> {
+    let options = RankOptions {
+        allowed_extensions: Vec::new(),
+        ..RankOptions::default()
+    };
+
+    results
+        .iter()
+        .map(|result| {
+            let mut tracks: Vec<((&str, String), Vec<&SearchResultFile>)> = Vec::new();
+            for file in &result.files {
+                let key = track_key(&file.filename);
+                match tracks.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, versions)) => versions.push(file),
+                    None => tracks.push((key, vec![file])),
+                }
+            }
+
+            let files = tracks
+                .into_iter()
+                .filter_map(|(_, versions)| {
+                    rank_search_results(versions, &options)
+                        .into_iter()
+                        .next()
+                        .cloned()
+                })
+                .collect();
+
+            SearchResult {
+                files,
+                ..result.clone()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slsk_rs::peer::FileAttribute;
+
+    fn file(filename: &str) -> SearchResultFile {
+        SearchResultFile {
+            filename: filename.to_string(),
+            size: 1000,
+            extension: "mp3".to_string(),
+            attributes: vec![],
+        }
+    }
+
+    fn result(username: &str, files: &[&str]) -> SearchResult {
+        SearchResult {
+            username: username.to_string(),
+            slot_free: true,
+            avg_speed: 0,
+            queue_length: 0,
+            files: files.iter().map(|f| file(f)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_group_by_directory() {
+        let results = vec![
+            result(
+                "alice",
+                &[
+                    "Music\\Artist\\Album\\01 One.mp3",
+                    "Music\\Artist\\Other\\01 Intro.mp3",
+                    "Music\\Artist\\Album\\02 Two.mp3",
+                ],
+            ),
+            result("bob", &["Music\\Artist\\Album\\01 One.mp3"]),
+        ];
+
+        let groups = group_by_directory(&results);
+        assert_eq!(groups.len(), 3);
+
+        assert_eq!(groups[0].username, "alice");
+        assert_eq!(groups[0].directory, "Music\\Artist\\Album");
+        assert_eq!(groups[0].name(), "Album");
+        assert_eq!(groups[0].files.len(), 2);
+        assert_eq!(
+            groups[0].files[1].filename,
+            "Music\\Artist\\Album\\02 Two.mp3"
+        );
+
+        assert_eq!(groups[1].name(), "Other");
+        // Same directory from a different uploader is a separate group
+        assert_eq!(groups[2].username, "bob");
+        assert_eq!(groups[2].files.len(), 1);
+    }
+
+    #[test]
+    fn test_group_files_without_directory() {
+        let groups = group_by_directory(&[result("carol", &["loose.mp3"])]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].directory, "");
+    }
+
+    #[test]
+    fn test_search_history_dedup_and_cap() {
+        let mut history = SearchHistory::new(3);
+        for query in ["one", "two", "three", "two ", "four", ""] {
+            history.push(query);
+        }
+
+        let entries: Vec<&str> = (0..history.len()).filter_map(|i| history.get(i)).collect();
+        assert_eq!(entries, vec!["four", "two", "three"]);
+
+        let restored = SearchHistory::with_entries(vec!["b".into(), "a".into(), "b".into()], 3);
+        assert_eq!(restored.get(0), Some("b"));
+        assert_eq!(restored.len(), 2);
+    }
+
+    #[test]
+    fn test_search_history_complete() {
+        let mut history = SearchHistory::default();
+        history.push("Boards of Canada");
+        history.push("Bonobo");
+
+        assert_eq!(history.complete("bo"), Some("Bonobo"));
+        assert_eq!(history.complete("boa"), Some("Boards of Canada"));
+        assert_eq!(history.complete("Bonobo"), None);
+        assert_eq!(history.complete(""), None);
+    }
+
+    #[test]
+    fn test_collapse_mp3_and_flac_to_flac() {
+        let mut mp3 = file("Music\\Artist\\Album\\01 One.mp3");
+        mp3.attributes = vec![FileAttribute {
+            code: 0,
+            value: 320,
+        }];
+        let mut flac = file("Music\\Artist\\Album\\01 One.flac");
+        flac.extension = "flac".to_string();
+        let other = file("Music\\Artist\\Album\\02 Two.mp3");
+
+        let mut results = vec![result("alice", &[])];
+        results[0].files = vec![mp3, other, flac];
+
+        let collapsed = collapse_to_best_quality(&results);
+        let names: Vec<&str> = collapsed[0]
+            .files
+            .iter()
+            .map(|f| f.filename.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "Music\\Artist\\Album\\01 One.flac",
+                "Music\\Artist\\Album\\02 Two.mp3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collapse_keeps_same_stem_in_other_directories() {
+        let results = vec![result(
+            "bob",
+            &["Music\\A\\01 Intro.mp3", "Music\\B\\01 Intro.mp3"],
+        )];
+        assert_eq!(collapse_to_best_quality(&results)[0].files.len(), 2);
+    }
+
+    #[test]
+    fn test_quality_summary() {
+        let mut flac = file("Music\\Artist\\01 One.flac");
+        flac.attributes = vec![
+            FileAttribute { code: 1, value: 301 },
+            FileAttribute { code: 4, value: 44_100 },
+        ];
+        assert_eq!(quality_summary(&flac).as_deref(), Some("FLAC 44.1 kHz, 5:01"));
+
+        let mut mp3 = file("Music\\Artist\\01 One.mp3");
+        mp3.attributes = vec![
+            FileAttribute { code: 0, value: 320 },
+            FileAttribute { code: 1, value: 245 },
+        ];
+        assert_eq!(quality_summary(&mp3).as_deref(), Some("320 kbps, 4:05"));
+
+        mp3.attributes = vec![
+            FileAttribute { code: 0, value: 245 },
+            FileAttribute { code: 2, value: 1 },
+        ];
+        assert_eq!(quality_summary(&mp3).as_deref(), Some("245 kbps VBR"));
+
+        assert_eq!(quality_summary(&file("Music\\cover.jpg")), None);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use slsk_rs::peer::{SharedDirectory, SharedFile};
+
+/// Shared folders as peers see them, and where they live on disk.
+#[derive(Debug, Default)]
+pub struct Shares {
+    /// Listing with virtual `share\sub\dir` paths, so peers never see our local layout.
+    pub directories: Vec<SharedDirectory>,
+    /// The local folder behind each share name.
+    pub roots: HashMap<String, PathBuf>,
+}
+
+impl Shares {
+    /// Map a virtual directory and a file in it back to the local file.
+    pub fn local_path(&self, dir: &str, filename: &str) -> Option<PathBuf> {
+        let mut parts = dir.split('\\');
+        let mut path = self.roots.get(parts.next()?)?.clone();
+        path.extend(parts);
+        path.push(filename);
+        Some(path)
+    }
+}
+
+/// Recursively scan share roots into the directory listing peers browse.
+///
+/// Each root is shared under its folder name, numbered if two roots share one.
+pub fn scan_shares(roots: &[PathBuf]) -> io::Result<Shares> {
+    let mut shares = Shares::default();
+    for root in roots {
+        let base = root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "Share".to_string());
+        let mut name = base.clone();
+        let mut n = 1;
+        while shares.roots.contains_key(&name) {
+            n += 1;
+            name = format!("{base} ({n})");
+        }
+        scan_directory(root, &name, &mut shares.directories)?;
+        shares.roots.insert(name, root.clone());
+    }
+    Ok(shares)
+}
+
+fn scan_directory(
+    dir: &Path,
+    virtual_path: &str,
+    directories: &mut Vec<SharedDirectory>,
+) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    let mut files = Vec::new();
+    let mut subdirs = Vec::new();
+
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            subdirs.push((path, entry.file_name().to_string_lossy().into_owned()));
+        } else if file_type.is_file() {
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default()
+                .to_lowercase();
+            files.push(SharedFile {
+                filename: entry.file_name().to_string_lossy().into_owned(),
+                size: entry.metadata()?.len(),
+                extension,
+                attributes: Vec::new(),
+            });
+        }
+    }
+
+    directories.push(SharedDirectory {
+        path: virtual_path.to_string(),
+        files,
+    });
+
+    for (subdir, name) in subdirs {
+        scan_directory(&subdir, &format!("{virtual_path}\\{name}"), directories)?;
+    }
+
+    Ok(())
+}
+
+/// Folder and file counts as reported to the server in `SharedFoldersFiles`.
+pub fn share_counts(directories: &[SharedDirectory]) -> (u32, u32) {
+    let files: usize = directories.iter().map(|d| d.files.len()).sum();
+    (directories.len() as u32, files as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_shares() {
+        let root = std::env::temp_dir().join(format!("slsk-shares-{}", std::process::id()));
+        let album = root.join("Album");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(root.join("notes.txt"), b"hello").unwrap();
+        fs::write(album.join("01 Track.MP3"), vec![0u8; 10]).unwrap();
+
+        let shares = scan_shares(std::slice::from_ref(&root)).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let dirs = &shares.directories;
+        let name = root.file_name().unwrap().to_string_lossy();
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].path, name);
+        assert_eq!(dirs[0].files[0].filename, "notes.txt");
+        assert_eq!(dirs[0].files[0].size, 5);
+        // Only the share name is advertised, never the local path
+        assert_eq!(dirs[1].path, format!("{name}\\Album"));
+        assert_eq!(dirs[1].files[0].extension, "mp3");
+        assert_eq!(share_counts(dirs), (2, 2));
+        assert_eq!(
+            shares.local_path(&dirs[1].path, "01 Track.MP3"),
+            Some(album.join("01 Track.MP3"))
+        );
+    }
+}
@@ -92,6 +92,51 @@ struct PlaylistResponse {
     tracks: PlaylistTracksResponse,
 }
 
+#[derive(Debug, Deserialize)]
+struct AlbumTrack {
+    id: String,
+    name: String,
+    artists: Vec<SpotifyArtist>,
+    duration_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlbumTracksResponse {
+    items: Vec<AlbumTrack>,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlbumResponse {
+    id: String,
+    name: String,
+    tracks: AlbumTracksResponse,
+}
+
+impl AlbumResponse {
+    /// Combine the first page of tracks with any further pages into a playlist.
+    fn into_playlist(self, pages: Vec<AlbumTracksResponse>) -> SpotifyPlaylist {
+        let album = self.name;
+        let tracks = std::iter::once(self.tracks)
+            .chain(pages)
+            .flat_map(|page| page.items)
+            .map(|t| SpotifyTrack {
+                id: t.id,
+                name: t.name,
+                artists: t.artists.into_iter().map(|a| a.name).collect(),
+                album: album.clone(),
+                duration_ms: t.duration_ms,
+            })
+            .collect();
+
+        SpotifyPlaylist {
+            id: self.id,
+            name: album,
+            tracks,
+        }
+    }
+}
+
 pub struct SpotifyClient {
     client: Client,
     client_id: String,
@@ -219,6 +264,42 @@ impl SpotifyClient {
         })
     }
 
+    pub async fn get_album(&mut self, album_id: &str) -> Result<SpotifyPlaylist> {
+        let token = self.ensure_token().await?;
+        let url = format!("{API_BASE}/albums/{album_id}");
+
+        let resp: AlbumResponse = self
+            .client
+            .get(&url)
+            .header("Authorization", format!("Bearer {token}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // Albums embed the first 50 tracks; the rest come from /albums/{id}/tracks
+        let mut pages = Vec::new();
+        let mut next_url = resp.tracks.next.clone();
+        while let Some(url) = next_url {
+            let token = self.ensure_token().await?;
+            let page: AlbumTracksResponse = self
+                .client
+                .get(&url)
+                .header("Authorization", format!("Bearer {token}"))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            next_url = page.next.clone();
+            pages.push(page);
+        }
+
+        Ok(resp.into_playlist(pages))
+    }
+
     pub fn parse_spotify_url(url: &str) -> Option<SpotifyResource> {
         let url = url.trim();
 
@@ -494,4 +575,48 @@ mod tests {
         assert_eq!(playlist.matched_count(), 2);
         assert_eq!(playlist.unmatched_tracks().count(), 0);
     }
+
+    #[test]
+    fn test_album_response_collects_all_pages() {
+        let first: AlbumResponse = serde_json::from_str(
+            r#"{
+                "id": "1DFixLWuPkv3KT3TnV35m3",
+                "name": "Double Album",
+                "album_type": "album",
+                "tracks": {
+                    "href": "https://api.spotify.com/v1/albums/1DFixLWuPkv3KT3TnV35m3/tracks?offset=0&limit=2",
+                    "items": [
+                        {"id": "t1", "name": "One", "artists": [{"id": "a", "name": "Band"}], "duration_ms": 1000, "track_number": 1},
+                        {"id": "t2", "name": "Two", "artists": [{"id": "a", "name": "Band"}, {"id": "b", "name": "Guest"}], "duration_ms": 2000, "track_number": 2}
+                    ],
+                    "limit": 2,
+                    "next": "https://api.spotify.com/v1/albums/1DFixLWuPkv3KT3TnV35m3/tracks?offset=2&limit=2",
+                    "offset": 0,
+                    "total": 3
+                }
+            }"#,
+        )
+        .unwrap();
+        let second: AlbumTracksResponse = serde_json::from_str(
+            r#"{
+                "items": [
+                    {"id": "t3", "name": "Three", "artists": [{"id": "a", "name": "Band"}], "duration_ms": 3000, "track_number": 3}
+                ],
+                "limit": 2,
+                "next": null,
+                "offset": 2,
+                "total": 3
+            }"#,
+        )
+        .unwrap();
+        assert!(first.tracks.next.is_some());
+        assert!(second.next.is_none());
+
+        let playlist = first.into_playlist(vec![second]);
+        assert_eq!(playlist.name, "Double Album");
+        let names: Vec<&str> = playlist.tracks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["One", "Two", "Three"]);
+        assert_eq!(playlist.tracks[1].artists, vec!["Band", "Guest"]);
+        assert!(playlist.tracks.iter().all(|t| t.album == "Double Album"));
+    }
 }
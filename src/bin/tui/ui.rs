@@ -7,6 +7,7 @@ use ratatui::{
 };
 
 use crate::app::{App, DownloadStatus, Focus, InputMode};
+use crate::search::quality_summary;
 
 const ACCENT: Color = Color::Rgb(138, 180, 248);
 const DIM: Color = Color::Rgb(128, 128, 128);
@@ -198,6 +199,11 @@ fn draw_results(f: &mut Frame, app: &App, area: Rect) {
             let is_selected = i == app.selected_result && is_focused;
             let speed_mb = result.avg_speed as f64 / 1_000_000.0;
             let file_count = result.files.len();
+            let folders = match app.result_albums.get(i).map(Vec::as_slice) {
+                Some([album]) if !album.name().is_empty() => album.name().to_string(),
+                Some(albums) => format!("{} folders", albums.len()),
+                None => String::new(),
+            };
 
             let slot_char = if result.slot_free { "●" } else { "○" };
             let slot_color = if result.slot_free { SUCCESS } else { WARNING };
@@ -206,7 +212,7 @@ fn draw_results(f: &mut Frame, app: &App, area: Rect) {
                 Span::styled(format!(" {} ", slot_char), Style::default().fg(slot_color)),
                 Span::styled(&result.username, Style::default().fg(TEXT).bold()),
                 Span::styled(
-                    format!("  {} files", file_count),
+                    format!("  {} files in {}", file_count, folders),
                     Style::default().fg(TEXT_DIM),
                 ),
             ];
@@ -281,11 +287,14 @@ fn draw_files(f: &mut Frame, app: &App, area: Rect) {
                     path.to_string()
                 };
 
-                let spans = vec![
+                let mut spans = vec![
                     Span::styled("  ", Style::default()),
                     Span::styled(filename, Style::default().fg(TEXT)),
                     Span::styled(format!("  {}", size_str), Style::default().fg(TEXT_DIM)),
                 ];
+                if let Some(quality) = quality_summary(file) {
+                    spans.push(Span::styled(format!("  {}", quality), Style::default().fg(DIM)));
+                }
 
                 let style = if is_selected {
                     Style::default().bg(SURFACE_BRIGHT)
@@ -307,7 +316,10 @@ fn draw_files(f: &mut Frame, app: &App, area: Rect) {
         (title, items)
     } else if let Some((username, dirs)) = &app.current_user_files {
         let total: usize = dirs.iter().map(|d| d.files.len()).sum();
-        let title = format!(" {} ({} files) ", username, total);
+        let title = match app.user_statuses.get(username) {
+            Some(status) => format!(" {} [{}] ({} files) ", username, status, total),
+            None => format!(" {} ({} files) ", username, total),
+        };
         let flat_files = app.get_current_files_flat();
         let items: Vec<ListItem> = flat_files
             .iter()
@@ -518,6 +530,14 @@ fn draw_status_bar(f: &mut Frame, app: &App, area: Rect) {
             ("/", "search"),
             ("↑↓", "nav"),
             ("d/⏎", "download"),
+            (
+                "f",
+                if app.best_only {
+                    "all versions"
+                } else {
+                    "best only"
+                },
+            ),
             ("esc", "back"),
         ]
     };
@@ -1,5 +1,7 @@
 //! Protocol constants and enumerations.
 
+use std::fmt;
+
 use crate::{Error, Result};
 
 /// Connection types used in the protocol.
@@ -42,6 +44,27 @@ pub enum UserStatus {
     Online = 2,
 }
 
+impl UserStatus {
+    /// Every status, in wire value order.
+    pub fn all() -> [UserStatus; 3] {
+        [UserStatus::Offline, UserStatus::Away, UserStatus::Online]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Offline => "Offline",
+            UserStatus::Away => "Away",
+            UserStatus::Online => "Online",
+        }
+    }
+}
+
+impl fmt::Display for UserStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl TryFrom<u32> for UserStatus {
     type Error = Error;
 
@@ -254,6 +277,9 @@ impl LoginRejectionReason {
 /// Default client version (matches Nicotine+).
 pub const CLIENT_VERSION: u32 = 160;
 
+/// Default client minor version.
+pub const CLIENT_MINOR_VERSION: u32 = 3;
+
 /// Default listen port for peers.
 pub const DEFAULT_PEER_PORT: u16 = 2234;
 
@@ -1,8 +1,17 @@
 //! SQLite database for the file index.
 
-use rusqlite::{Connection, params};
-use crate::peer::SharedDirectory;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Row, params};
+use crate::peer::{SharedDirectory, SharedFile};
+use crate::protocol::SearchQuery;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub struct Database {
     conn: Connection,
@@ -12,6 +21,56 @@ pub struct SearchResult {
     pub username: String,
     pub filename: String,
     pub size: u64,
+    /// Byte ranges of `filename` matched by the query terms, sorted and merged.
+    pub matches: Vec<Range<usize>>,
+}
+
+/// Lifecycle of a queued download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadState {
+    Queued,
+    InProgress,
+    Completed,
+    Failed,
+}
+
+impl DownloadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadState::Queued => "queued",
+            DownloadState::InProgress => "in_progress",
+            DownloadState::Completed => "completed",
+            DownloadState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "queued" => Some(DownloadState::Queued),
+            "in_progress" => Some(DownloadState::InProgress),
+            "completed" => Some(DownloadState::Completed),
+            "failed" => Some(DownloadState::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A download persisted in the queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadRecord {
+    pub id: u32,
+    pub username: String,
+    pub filename: String,
+    pub size: u64,
+    pub state: DownloadState,
+    pub bytes_done: u64,
+}
+
+/// Rows changed by [`Database::index_user_merge`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeCounts {
+    pub added: u32,
+    pub removed: u32,
 }
 
 pub struct IndexStats {
@@ -24,6 +83,17 @@ impl Database {
     pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let conn = Connection::open(path.as_ref())?;
 
+        // LIKE only folds ASCII case, but SearchQuery::matches folds all of Unicode
+        conn.create_scalar_function(
+            "unicode_lower",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| match ctx.get_raw(0) {
+                ValueRef::Text(text) => Ok(Some(String::from_utf8_lossy(text).to_lowercase())),
+                _ => Ok(None),
+            },
+        )?;
+
         conn.execute_batch(
             "
             CREATE TABLE IF NOT EXISTS users (
@@ -43,6 +113,21 @@ impl Database {
                 FOREIGN KEY (user_id) REFERENCES users(id)
             );
 
+            CREATE TABLE IF NOT EXISTS downloads (
+                position INTEGER PRIMARY KEY AUTOINCREMENT,
+                id INTEGER UNIQUE NOT NULL,
+                username TEXT NOT NULL,
+                filename TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                bytes_done INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS search_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                query TEXT UNIQUE NOT NULL
+            );
+
             CREATE INDEX IF NOT EXISTS idx_files_filename ON files(filename);
             CREATE INDEX IF NOT EXISTS idx_files_extension ON files(extension);
             CREATE INDEX IF NOT EXISTS idx_files_full_path ON files(full_path);
@@ -54,10 +139,8 @@ impl Database {
     }
 
     pub fn index_user(&self, username: &str, directories: &[SharedDirectory]) -> anyhow::Result<()> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .map(|d| d.as_secs() as i64)
-            .unwrap_or(0);
+        let username = storable_text(username);
+        let now = unix_now();
 
         // Delete existing data for this user
         self.conn.execute(
@@ -88,23 +171,12 @@ impl Database {
 
         for dir in directories {
             for file in &dir.files {
-                let filename = file
-                    .filename
-                    .rsplit(['/', '\\'])
-                    .next()
-                    .unwrap_or(&file.filename);
-
-                let extension = filename
-                    .rsplit('.')
-                    .next()
-                    .filter(|ext| ext.len() <= 10)
-                    .map(|s| s.to_lowercase());
-
+                let (filename, extension) = split_filename(&file.filename);
                 stmt.execute(params![
                     user_id,
-                    dir.path,
-                    filename,
-                    file.filename,
+                    storable_text(&dir.path),
+                    storable_text(filename),
+                    storable_text(&file.filename),
                     file.size as i64,
                     extension,
                 ])?;
@@ -118,16 +190,15 @@ impl Database {
     }
     
     pub fn index_users_batch(&mut self, users: Vec<(String, Vec<SharedDirectory>)>) -> anyhow::Result<(u32, u32)> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .map(|d| d.as_secs() as i64)
-            .unwrap_or(0);
+        let now = unix_now();
 
         let tx = self.conn.transaction()?;
         let mut success = 0u32;
         let mut failed = 0u32;
 
         for (username, directories) in users {
+            let username = storable_text(&username);
+
             // Delete existing data for this user
             tx.execute(
                 "DELETE FROM files WHERE user_id = (SELECT id FROM users WHERE username = ?)",
@@ -155,23 +226,12 @@ impl Database {
 
             for dir in &directories {
                 for file in &dir.files {
-                    let filename = file
-                        .filename
-                        .rsplit(['/', '\\'])
-                        .next()
-                        .unwrap_or(&file.filename);
-
-                    let extension = filename
-                        .rsplit('.')
-                        .next()
-                        .filter(|ext| ext.len() <= 10)
-                        .map(|s| s.to_lowercase());
-
+                    let (filename, extension) = split_filename(&file.filename);
                     if stmt.execute(params![
                         user_id,
-                        dir.path,
-                        filename,
-                        file.filename,
+                        storable_text(&dir.path),
+                        storable_text(filename),
+                        storable_text(&file.filename),
                         file.size as i64,
                         extension,
                     ]).is_err() {
@@ -188,17 +248,98 @@ impl Database {
         Ok((success, failed))
     }
 
+    /// Bring a user's indexed files in line with `directories` without rewriting them all.
+    ///
+    /// Files are matched on (full path, size): unseen ones are inserted, ones no longer
+    /// shared are removed, and the user's `indexed_at` is bumped either way.
+    pub fn index_user_merge(
+        &mut self,
+        username: &str,
+        directories: &[SharedDirectory],
+    ) -> anyhow::Result<MergeCounts> {
+        let username = storable_text(username);
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO users (username, indexed_at) VALUES (?, ?)
+             ON CONFLICT(username) DO UPDATE SET indexed_at = excluded.indexed_at",
+            params![&username, unix_now()],
+        )?;
+        let user_id: i64 = tx.query_row(
+            "SELECT id FROM users WHERE username = ?",
+            params![&username],
+            |row| row.get(0),
+        )?;
+
+        // Row ids per (full_path, size); duplicates are kept one-for-one
+        let mut existing: HashMap<(String, i64), Vec<i64>> = HashMap::new();
+        {
+            let mut stmt = tx.prepare("SELECT id, full_path, size FROM files WHERE user_id = ?")?;
+            let rows = stmt.query_map(params![user_id], |row| {
+                Ok((row.get::<_, i64>(0)?, lossy_text(row, 1)?, row.get::<_, i64>(2)?))
+            })?;
+            for row in rows {
+                let (id, full_path, size) = row?;
+                existing.entry((full_path, size)).or_default().push(id);
+            }
+        }
+
+        let mut counts = MergeCounts::default();
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO files (user_id, directory, filename, full_path, size, extension)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )?;
+            for dir in directories {
+                for file in &dir.files {
+                    let full_path = storable_text(&file.filename);
+                    let key = (full_path.into_owned(), file.size as i64);
+                    if existing.get_mut(&key).and_then(|ids| ids.pop()).is_some() {
+                        continue;
+                    }
+
+                    let (filename, extension) = split_filename(&file.filename);
+                    insert.execute(params![
+                        user_id,
+                        storable_text(&dir.path),
+                        storable_text(filename),
+                        key.0,
+                        key.1,
+                        extension,
+                    ])?;
+                    counts.added += 1;
+                }
+            }
+
+            let mut delete = tx.prepare_cached("DELETE FROM files WHERE id = ?")?;
+            for id in existing.into_values().flatten() {
+                delete.execute(params![id])?;
+                counts.removed += 1;
+            }
+        }
+
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    /// Search full paths the way [`SearchQuery::matches`] would, largest files first.
     pub fn search(&self, query: &str, limit: usize) -> anyhow::Result<Vec<SearchResult>> {
-        // Split query into words and search for all of them
-        let words: Vec<&str> = query.split_whitespace().collect();
-        if words.is_empty() {
+        let query = SearchQuery::parse(query);
+        if query.is_empty() {
             return Ok(vec![]);
         }
 
-        // Build WHERE clause for all words
-        let conditions: Vec<String> = words
+        // Every required part must appear and no excluded word may
+        let required: Vec<&str> = query.required().collect();
+        let conditions: Vec<&str> = required
             .iter()
-            .map(|_| "full_path LIKE ?".to_string())
+            .map(|_| "unicode_lower(full_path) LIKE ? ESCAPE '\\'")
+            .chain(
+                query
+                    .excluded
+                    .iter()
+                    .map(|_| "unicode_lower(full_path) NOT LIKE ? ESCAPE '\\'"),
+            )
             .collect();
         let where_clause = conditions.join(" AND ");
 
@@ -215,7 +356,12 @@ impl Database {
         let mut stmt = self.conn.prepare(&sql)?;
 
         // Bind parameters
-        let patterns: Vec<String> = words.iter().map(|w| format!("%{}%", w)).collect();
+        let patterns: Vec<String> = required
+            .iter()
+            .copied()
+            .chain(query.excluded.iter().map(String::as_str))
+            .map(|part| format!("%{}%", escape_like(&part.to_lowercase())))
+            .collect();
         let mut params_vec: Vec<&dyn rusqlite::ToSql> = patterns
             .iter()
             .map(|s| s as &dyn rusqlite::ToSql)
@@ -226,21 +372,117 @@ impl Database {
         let results = stmt
             .query_map(rusqlite::params_from_iter(params_vec), |row| {
                 Ok(SearchResult {
-                    username: row.get(0)?,
-                    filename: row.get(1)?,
+                    username: lossy_text(row, 0)?,
+                    filename: lossy_text(row, 1)?,
                     size: row.get::<_, i64>(2)? as u64,
+                    matches: Vec::new(),
                 })
             })?
             .filter_map(|r| r.ok())
+            .map(|mut result: SearchResult| {
+                result.matches = match_spans(&result.filename, &required);
+                result
+            })
             .collect();
 
         Ok(results)
     }
 
+    /// Record a download as queued, replacing any earlier entry with the same id.
+    ///
+    /// A re-queued download keeps its original place in the queue.
+    pub fn queue_download(&self, id: u32, username: &str, filename: &str, size: u64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO downloads (id, username, filename, size, status, bytes_done)
+             VALUES (?1, ?2, ?3, ?4, ?5, 0)
+             ON CONFLICT(id) DO UPDATE SET
+                username = excluded.username,
+                filename = excluded.filename,
+                size = excluded.size,
+                status = excluded.status,
+                bytes_done = 0",
+            params![id, username, filename, size as i64, DownloadState::Queued.as_str()],
+        )?;
+        Ok(())
+    }
+
+    pub fn mark_download_status(&self, id: u32, state: DownloadState, bytes_done: u64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE downloads SET status = ?2, bytes_done = ?3 WHERE id = ?1",
+            params![id, state.as_str(), bytes_done as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Every download that was queued or running when the client stopped, in the order
+    /// it was queued. Completed and failed downloads are left alone.
+    pub fn load_pending_downloads(&self) -> anyhow::Result<Vec<DownloadRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, username, filename, size, status, bytes_done
+             FROM downloads
+             WHERE status IN (?1, ?2)
+             ORDER BY position",
+        )?;
+        let interrupted = params![
+            DownloadState::Queued.as_str(),
+            DownloadState::InProgress.as_str()
+        ];
+        let downloads = stmt
+            .query_map(interrupted, |row| {
+                let status: String = row.get(4)?;
+                Ok(DownloadRecord {
+                    id: row.get(0)?,
+                    username: lossy_text(row, 1)?,
+                    filename: lossy_text(row, 2)?,
+                    size: row.get::<_, i64>(3)? as u64,
+                    state: DownloadState::parse(&status).unwrap_or(DownloadState::Queued),
+                    bytes_done: row.get::<_, i64>(5)? as u64,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(downloads)
+    }
+
+    /// Record a search as the most recent, keeping only the newest `keep` queries.
+    pub fn record_search(&self, query: &str, keep: usize) -> anyhow::Result<()> {
+        self.conn.execute("DELETE FROM search_history WHERE query = ?1", params![query])?;
+        self.conn.execute("INSERT INTO search_history (query) VALUES (?1)", params![query])?;
+        self.conn.execute(
+            "DELETE FROM search_history WHERE id NOT IN
+             (SELECT id FROM search_history ORDER BY id DESC LIMIT ?1)",
+            params![keep as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Up to `limit` past searches, most recent first.
+    pub fn recent_searches(&self, limit: usize) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT query FROM search_history ORDER BY id DESC LIMIT ?1")?;
+        let queries = stmt
+            .query_map(params![limit as i64], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(queries)
+    }
+
     pub fn get_indexed_users(&self) -> anyhow::Result<Vec<String>> {
         let mut stmt = self.conn.prepare("SELECT username FROM users")?;
         let users = stmt
-            .query_map([], |row| row.get(0))?
+            .query_map([], |row| lossy_text(row, 0))?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(users)
+    }
+
+    /// Users whose index is older than `max_age`, oldest first.
+    pub fn get_stale_users(&self, max_age: Duration) -> anyhow::Result<Vec<String>> {
+        let cutoff = unix_now() - max_age.as_secs() as i64;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT username FROM users WHERE indexed_at < ? ORDER BY indexed_at")?;
+        let users = stmt
+            .query_map(params![cutoff], |row| lossy_text(row, 0))?
             .filter_map(|r| r.ok())
             .collect();
         Ok(users)
@@ -268,4 +510,568 @@ impl Database {
             db_size_bytes: (page_count * page_size) as u64,
         })
     }
+
+    /// Reclaim space left by deleted rows and refresh the query planner's statistics.
+    pub fn vacuum(&self) -> anyhow::Result<()> {
+        self.conn.execute_batch("VACUUM; ANALYZE;")?;
+        Ok(())
+    }
+
+    /// Indexed files per extension, most common first and ties by name.
+    ///
+    /// Files without a stored extension are counted under `""`.
+    pub fn extension_histogram(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT COALESCE(extension, ''), COUNT(*) FROM files
+             GROUP BY 1 ORDER BY 2 DESC, 1",
+        )?;
+        let histogram = stmt
+            .query_map([], |row| Ok((lossy_text(row, 0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<Result<_, _>>()?;
+        Ok(histogram)
+    }
+
+    /// Users sharing the most indexed files, with their file counts.
+    pub fn top_sharers(&self, limit: usize) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT u.username, COUNT(*) FROM files f
+             JOIN users u ON f.user_id = u.id
+             GROUP BY u.id
+             ORDER BY 2 DESC, u.username
+             LIMIT ?",
+        )?;
+        let sharers = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok((lossy_text(row, 0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(sharers)
+    }
+
+    /// The largest indexed files across all users.
+    pub fn largest_files(&self, limit: usize) -> anyhow::Result<Vec<SearchResult>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT u.username, f.full_path, f.size
+             FROM files f
+             JOIN users u ON f.user_id = u.id
+             ORDER BY f.size DESC, f.full_path
+             LIMIT ?",
+        )?;
+        let files = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok(SearchResult {
+                    username: lossy_text(row, 0)?,
+                    filename: lossy_text(row, 1)?,
+                    size: row.get::<_, i64>(2)? as u64,
+                    matches: Vec::new(),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(files)
+    }
+}
+
+/// A [`Database`] that can be cloned into many tasks.
+///
+/// Queries run one at a time on the blocking thread pool, so concurrent callers
+/// share a single connection instead of contending for the SQLite file lock.
+#[derive(Clone)]
+pub struct SharedDatabase {
+    db: Arc<Mutex<Database>>,
+}
+
+impl SharedDatabase {
+    pub fn new(db: Database) -> Self {
+        SharedDatabase {
+            db: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    /// Run `f` against the database without blocking the async runtime.
+    pub async fn with<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> anyhow::Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let db = db.lock().map_err(|_| anyhow::anyhow!("database lock poisoned"))?;
+            f(&db)
+        })
+        .await?
+    }
+
+    pub async fn search(&self, query: &str, limit: usize) -> anyhow::Result<Vec<SearchResult>> {
+        let query = query.to_string();
+        self.with(move |db| db.search(&query, limit)).await
+    }
+}
+
+impl fmt::Debug for SharedDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedDatabase").finish_non_exhaustive()
+    }
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Escape `LIKE` wildcards so `part` only matches itself, using `\` as the escape.
+fn escape_like(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Split a shared path into its bare filename and lowercased extension.
+///
+/// Files without an extension, or with one too long to be real, get `None`.
+fn split_filename(path: &str) -> (&str, Option<String>) {
+    let filename = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let extension = Some(SharedFile::infer_extension(filename))
+        .filter(|ext| !ext.is_empty() && ext.len() <= 10)
+        .map(|ext| ext.to_lowercase());
+    (filename, extension)
+}
+
+/// Text as it is stored in the index.
+///
+/// SQLite's string functions stop at NUL, so `LIKE` would miss anything after
+/// one; they are replaced so a name never ends up partly unsearchable. Names
+/// from the network must go through this before being compared with stored ones.
+pub fn storable_text(text: &str) -> Cow<'_, str> {
+    if text.contains('\0') {
+        Cow::Owned(text.replace('\0', "\u{FFFD}"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Read a text column, replacing invalid UTF-8 instead of failing the row.
+///
+/// Indexes written by older or foreign tools may hold raw bytes in text columns.
+fn lossy_text(row: &Row<'_>, idx: usize) -> rusqlite::Result<String> {
+    match row.get_ref(idx)? {
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => {
+            Ok(String::from_utf8_lossy(bytes).into_owned())
+        }
+        ValueRef::Null => Ok(String::new()),
+        ValueRef::Integer(i) => Ok(i.to_string()),
+        ValueRef::Real(f) => Ok(f.to_string()),
+    }
+}
+
+/// Find the byte ranges of `text` matched by any of `terms`.
+///
+/// Case is folded like the `unicode_lower` SQL function, and the returned
+/// ranges always fall on character boundaries of the original text.
+pub fn match_spans(text: &str, terms: &[&str]) -> Vec<Range<usize>> {
+    let haystack = text.to_lowercase();
+    // Lowercasing can change a character's length, so note which character
+    // of `text` each byte of `haystack` came from
+    let mut origin: Vec<Range<usize>> = Vec::with_capacity(haystack.len());
+    for (start, ch) in text.char_indices() {
+        let folded = ch.to_lowercase().map(char::len_utf8).sum();
+        origin.extend(std::iter::repeat_n(start..start + ch.len_utf8(), folded));
+    }
+    let mut spans: Vec<Range<usize>> = Vec::new();
+
+    for term in terms.iter().filter(|t| !t.is_empty()) {
+        let needle = term.to_lowercase();
+        spans.extend(
+            haystack
+                .match_indices(&needle)
+                .map(|(start, m)| origin[start].start..origin[start + m.len() - 1].end),
+        );
+    }
+
+    spans.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(spans.len());
+    for span in spans {
+        match merged.last_mut() {
+            Some(last) if span.start <= last.end => last.end = last.end.max(span.end),
+            _ => merged.push(span),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::SharedFile;
+
+    #[test]
+    fn test_match_spans_multi_term() {
+        let text = "Music\\Daft Punk\\Discovery\\01 One More Time.flac";
+        let spans = match_spans(text, &["daft", "TIME", "more"]);
+
+        let matched: Vec<&str> = spans.iter().map(|r| &text[r.clone()]).collect();
+        assert_eq!(matched, vec!["Daft", "More", "Time"]);
+    }
+
+    #[test]
+    fn test_match_spans_fold_unicode_case() {
+        // 'İ' grows by a byte when lowercased, shifting everything after it
+        let text = "Music\\İstanbul\\ÉTÉ À Paris.mp3";
+        let spans = match_spans(text, &["été", "paris", "i̇st"]);
+
+        let matched: Vec<&str> = spans.iter().map(|r| &text[r.clone()]).collect();
+        assert_eq!(matched, vec!["İst", "ÉTÉ", "Paris"]);
+    }
+
+    #[test]
+    fn test_match_spans_overlapping_terms_merge() {
+        let spans = match_spans("abcdef", &["abc", "cde", "zz"]);
+        assert_eq!(spans, vec![0..5]);
+    }
+
+    #[test]
+    fn test_search_reports_match_spans() {
+        let db = Database::open(":memory:").unwrap();
+        let dirs = vec![SharedDirectory {
+            path: "Music\\Artist".to_string(),
+            files: vec![SharedFile {
+                filename: "Music\\Artist\\Great Song.mp3".to_string(),
+                size: 1000,
+                extension: "mp3".to_string(),
+                attributes: vec![],
+            }],
+        }];
+        db.index_user("alice", &dirs).unwrap();
+
+        let results = db.search("artist song", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        let matched: Vec<&str> = result
+            .matches
+            .iter()
+            .map(|r| &result.filename[r.clone()])
+            .collect();
+        assert_eq!(matched, vec!["Artist", "Song"]);
+    }
+
+    #[test]
+    fn test_search_applies_exclusions_and_phrases() {
+        let db = Database::open(":memory:").unwrap();
+        db.index_user(
+            "alice",
+            &shares(&[
+                ("Come To Daddy.mp3", 3),
+                ("Come To Daddy (Remix).mp3", 2),
+                ("Daddy Come To.mp3", 1),
+                ("100%_Daddy.mp3", 4),
+            ]),
+        )
+        .unwrap();
+        let found = |query| {
+            db.search(query, 10)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.filename)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(found("\"come to daddy\" -remix"), ["Music\\Come To Daddy.mp3"]);
+        assert_eq!(found("daddy -come"), ["Music\\100%_Daddy.mp3"]);
+        // Wildcards in the query are matched literally
+        assert_eq!(found("0%_d"), ["Music\\100%_Daddy.mp3"]);
+        assert!(found("-remix").is_empty());
+    }
+
+    #[test]
+    fn test_search_folds_non_ascii_case() {
+        let db = Database::open(":memory:").unwrap();
+        db.index_user("alice", &shares(&[("Ärzte - Schrei nach Liebe.mp3", 1), ("Öl.mp3", 2)]))
+            .unwrap();
+        let found = |query| {
+            db.search(query, 10)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.filename)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(found("ärzte"), ["Music\\Ärzte - Schrei nach Liebe.mp3"]);
+        assert_eq!(found("mp3 -öl"), ["Music\\Ärzte - Schrei nach Liebe.mp3"]);
+        assert_eq!(found("ÄRZTE"), found("ärzte"));
+    }
+
+    #[test]
+    fn test_download_queue_reload() {
+        let db = Database::open(":memory:").unwrap();
+        db.queue_download(7, "alice", "Music\\a.flac", 3000).unwrap();
+        db.queue_download(3, "bob", "Music\\b.flac", 2000).unwrap();
+        db.queue_download(9, "carol", "Music\\c.flac", 1000).unwrap();
+        db.queue_download(5, "erin", "Music\\e.flac", 500).unwrap();
+
+        db.mark_download_status(7, DownloadState::InProgress, 1500).unwrap();
+        db.mark_download_status(3, DownloadState::Completed, 2000).unwrap();
+        db.mark_download_status(5, DownloadState::Failed, 100).unwrap();
+
+        let pending = db.load_pending_downloads().unwrap();
+        let ids: Vec<u32> = pending.iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![7, 9]);
+        assert_eq!(
+            pending[0],
+            DownloadRecord {
+                id: 7,
+                username: "alice".to_string(),
+                filename: "Music\\a.flac".to_string(),
+                size: 3000,
+                state: DownloadState::InProgress,
+                bytes_done: 1500,
+            }
+        );
+
+        // Re-queueing from another source keeps the queue position
+        db.queue_download(7, "dave", "Other\\a.flac", 3100).unwrap();
+        let pending = db.load_pending_downloads().unwrap();
+        assert_eq!(pending[0].username, "dave");
+        assert_eq!(pending[0].state, DownloadState::Queued);
+        assert_eq!(pending[0].bytes_done, 0);
+    }
+
+    #[test]
+    fn test_search_history_persists_recent_first() {
+        let db = Database::open(":memory:").unwrap();
+        for query in ["aphex twin", "boards of canada", "aphex twin", "autechre"] {
+            db.record_search(query, 2).unwrap();
+        }
+
+        assert_eq!(db.recent_searches(10).unwrap(), vec!["autechre", "aphex twin"]);
+        assert_eq!(db.recent_searches(1).unwrap(), vec!["autechre"]);
+    }
+
+    #[test]
+    fn test_index_and_search_non_ascii_user() {
+        let db = Database::open(":memory:").unwrap();
+        let dirs = vec![SharedDirectory {
+            path: "Música\\Sigur Rós".to_string(),
+            files: vec![SharedFile {
+                filename: "Música\\Sigur Rós\\Ágætis byrjun.flac".to_string(),
+                size: 4000,
+                extension: "flac".to_string(),
+                attributes: vec![],
+            }],
+        }];
+        db.index_user("ユーザー\0名", &dirs).unwrap();
+
+        let results = db.search("Rós Ágætis", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].username, "ユーザー\u{FFFD}名");
+        assert_eq!(results[0].filename, "Música\\Sigur Rós\\Ágætis byrjun.flac");
+        assert_eq!(db.get_indexed_users().unwrap(), vec!["ユーザー\u{FFFD}名"]);
+    }
+
+    #[test]
+    fn test_search_tolerates_invalid_utf8_rows() {
+        let db = Database::open(":memory:").unwrap();
+        db.conn
+            .execute_batch(
+                "INSERT INTO users (id, username, indexed_at) VALUES (1, CAST(X'6AFF65' AS TEXT), 0);
+                 INSERT INTO files (user_id, directory, filename, full_path, size)
+                 VALUES (1, 'Music', 'song.mp3', CAST(X'4D757369635C736F6E67FE2E6D7033' AS TEXT), 10);",
+            )
+            .unwrap();
+
+        let results = db.search("song", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].username, "j\u{FFFD}e");
+        assert_eq!(results[0].filename, "Music\\song\u{FFFD}.mp3");
+    }
+
+    fn shares(files: &[(&str, u64)]) -> Vec<SharedDirectory> {
+        vec![SharedDirectory {
+            path: "Music".to_string(),
+            files: files
+                .iter()
+                .map(|(name, size)| SharedFile {
+                    filename: format!("Music\\{name}"),
+                    size: *size,
+                    extension: String::new(),
+                    attributes: vec![],
+                })
+                .collect(),
+        }]
+    }
+
+    fn indexed_files(db: &Database) -> Vec<(String, u64)> {
+        let mut stmt = db
+            .conn
+            .prepare("SELECT filename, size FROM files ORDER BY filename")
+            .unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_extension_histogram() {
+        let db = Database::open(":memory:").unwrap();
+        db.index_user("alice", &shares(&[("a.mp3", 1), ("b.FLAC", 2), ("c.flac", 3)]))
+            .unwrap();
+        db.index_user("bob", &shares(&[("d.mp3", 4), ("e.ogg", 5), ("f.flac", 6)]))
+            .unwrap();
+        db.index_user("carol", &shares(&[("README", 7)])).unwrap();
+
+        assert_eq!(
+            db.extension_histogram().unwrap(),
+            vec![
+                ("flac".to_string(), 3),
+                ("mp3".to_string(), 2),
+                ("".to_string(), 1),
+                ("ogg".to_string(), 1),
+            ]
+        );
+        assert!(Database::open(":memory:").unwrap().extension_histogram().unwrap().is_empty());
+    }
+
+    fn seeded_index() -> Database {
+        let db = Database::open(":memory:").unwrap();
+        db.index_user("alice", &shares(&[("a.mp3", 5), ("b.mp3", 40)])).unwrap();
+        db.index_user("bob", &shares(&[("c.mp3", 30), ("d.mp3", 10), ("e.mp3", 20)]))
+            .unwrap();
+        db.index_user("carol", &shares(&[("f.mp3", 40), ("g.mp3", 1)])).unwrap();
+        db.index_user("dave", &[]).unwrap();
+        db
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shared_database_concurrent_searches() {
+        let index = SharedDatabase::new(seeded_index());
+
+        let searches: Vec<_> = ["a.mp3", "c.mp3", "mp3", "missing"]
+            .iter()
+            .cycle()
+            .take(32)
+            .map(|query| {
+                let index = index.clone();
+                tokio::spawn(async move { index.search(query, 10).await })
+            })
+            .collect();
+
+        let mut counts = Vec::new();
+        for search in searches {
+            counts.push(search.await.unwrap().unwrap().len());
+        }
+        assert_eq!(counts.len(), 32);
+        assert!(counts.chunks(4).all(|chunk| chunk == [1, 1, 7, 0]));
+    }
+
+    #[test]
+    fn test_vacuum_after_removing_user() {
+        let db = seeded_index();
+        db.conn
+            .execute_batch(
+                "DELETE FROM files WHERE user_id = (SELECT id FROM users WHERE username = 'bob');
+                 DELETE FROM users WHERE username = 'bob';",
+            )
+            .unwrap();
+
+        db.vacuum().unwrap();
+
+        let stats = db.get_stats().unwrap();
+        assert_eq!(stats.user_count, 3);
+        assert_eq!(stats.file_count, 4);
+        assert!(stats.db_size_bytes > 0);
+        assert_eq!(db.search("b.mp3", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_top_sharers() {
+        let db = seeded_index();
+        let sharers = |limit| db.top_sharers(limit).unwrap();
+
+        assert_eq!(
+            sharers(10),
+            vec![("bob".to_string(), 3), ("alice".to_string(), 2), ("carol".to_string(), 2)]
+        );
+        assert_eq!(sharers(1), vec![("bob".to_string(), 3)]);
+        assert!(sharers(0).is_empty());
+    }
+
+    #[test]
+    fn test_largest_files() {
+        let db = seeded_index();
+        let largest = |limit| {
+            db.largest_files(limit)
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.username, r.filename, r.size))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            largest(3),
+            vec![
+                ("alice".to_string(), "Music\\b.mp3".to_string(), 40),
+                ("carol".to_string(), "Music\\f.mp3".to_string(), 40),
+                ("bob".to_string(), "Music\\c.mp3".to_string(), 30),
+            ]
+        );
+        assert_eq!(largest(100).len(), 7);
+    }
+
+    #[test]
+    fn test_index_user_merge_adds_new_files() {
+        let mut db = Database::open(":memory:").unwrap();
+        db.index_user("alice", &shares(&[("a.mp3", 1)])).unwrap();
+        db.conn.execute("UPDATE users SET indexed_at = 0", []).unwrap();
+        assert_eq!(db.get_stale_users(Duration::from_secs(60)).unwrap(), vec!["alice"]);
+
+        let counts = db
+            .index_user_merge("alice", &shares(&[("a.mp3", 1), ("b.mp3", 2), ("c.mp3", 3)]))
+            .unwrap();
+        assert_eq!(counts, MergeCounts { added: 2, removed: 0 });
+        assert_eq!(
+            indexed_files(&db),
+            vec![("a.mp3".to_string(), 1), ("b.mp3".to_string(), 2), ("c.mp3".to_string(), 3)]
+        );
+        // The refresh bumps indexed_at even though old rows were kept
+        assert!(db.get_stale_users(Duration::from_secs(60)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_index_user_merge_removes_unshared_files() {
+        let mut db = Database::open(":memory:").unwrap();
+        db.index_user("alice", &shares(&[("a.mp3", 1), ("b.mp3", 2)])).unwrap();
+        db.index_user("bob", &shares(&[("a.mp3", 1)])).unwrap();
+
+        let counts = db.index_user_merge("alice", &shares(&[("b.mp3", 2)])).unwrap();
+        assert_eq!(counts, MergeCounts { added: 0, removed: 1 });
+        let results = db.search("a.mp3", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].username, "bob");
+    }
+
+    #[test]
+    fn test_index_user_merge_mixed_delta() {
+        let mut db = Database::open(":memory:").unwrap();
+        db.index_user("alice", &shares(&[("a.mp3", 1), ("b.mp3", 2), ("b.mp3", 2)]))
+            .unwrap();
+
+        // A size change counts as a new file replacing the old one
+        let counts = db
+            .index_user_merge("alice", &shares(&[("a.mp3", 10), ("b.mp3", 2), ("d.mp3", 4)]))
+            .unwrap();
+        assert_eq!(counts, MergeCounts { added: 2, removed: 2 });
+        assert_eq!(
+            indexed_files(&db),
+            vec![("a.mp3".to_string(), 10), ("b.mp3".to_string(), 2), ("d.mp3".to_string(), 4)]
+        );
+
+        let counts = db.index_user_merge("carol", &shares(&[("e.mp3", 5)])).unwrap();
+        assert_eq!(counts, MergeCounts { added: 1, removed: 0 });
+    }
 }
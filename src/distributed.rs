@@ -1,10 +1,20 @@
 //! Distributed network messages sent over D connections.
 //!
 //! These messages are used for the distributed search network.
+//!
+//! A branch root receives searches from the server as an `EmbeddedMessage`
+//! and passes them to its children unchanged. Every other parent unpacks the
+//! embedded message and forwards the plain `Search` to its own children; see
+//! [`DistributedMessage::downstream`].
+
+use std::collections::HashMap;
 
 use bytes::{Buf, BufMut};
 
-use crate::protocol::{MessageRead, MessageWrite, ProtocolRead, ProtocolWrite};
+use crate::protocol::{
+    FramedRead, MessageRead, MessageWrite, ProtocolRead, ProtocolWrite, SearchQuery, read_framed,
+};
+use crate::server::{PossibleParent, ServerRequest, ServerResponse};
 use crate::{Error, Result};
 
 /// Distributed message codes.
@@ -68,6 +78,32 @@ pub enum DistributedMessage {
     EmbeddedMessage { code: u8, data: Vec<u8> },
 }
 
+impl DistributedMessage {
+    /// Wrap a distributed message the way a branch root tunnels it to children.
+    pub fn embed(inner: &DistributedMessage) -> Self {
+        let mut data = Vec::new();
+        inner.write_payload(&mut data);
+        DistributedMessage::EmbeddedMessage {
+            code: inner.code().into(),
+            data,
+        }
+    }
+
+    /// The message a relaying parent sends to its children after receiving this one.
+    ///
+    /// Searches are forwarded as-is and embedded messages are unpacked first.
+    /// Branch info is per-node and is never forwarded verbatim, so `None` is returned.
+    pub fn downstream(&self) -> Result<Option<DistributedMessage>> {
+        match self {
+            DistributedMessage::Search { .. } => Ok(Some(self.clone())),
+            DistributedMessage::EmbeddedMessage { code, data } => {
+                decode_embedded(*code, data).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
 impl MessageWrite for DistributedMessage {
     type Code = DistributedCode;
 
@@ -158,14 +194,289 @@ impl MessageRead for DistributedMessage {
 
 /// Read a distributed message from a buffer (including length prefix).
 pub fn read_distributed_message<B: Buf>(buf: &mut B) -> Result<DistributedMessage> {
-    let _len = u32::read_from(buf)?;
-    let code = DistributedCode::try_from(u8::read_from(buf)?)?;
-    DistributedMessage::read_with_code(code, buf)
+    read_framed(buf, |buf| {
+        let code = DistributedCode::try_from(u8::read_from(buf)?)?;
+        DistributedMessage::read_with_code(code, buf)
+    })
+}
+
+impl FramedRead for DistributedMessage {
+    fn read_frame<B: Buf>(buf: &mut B) -> Result<Self> {
+        read_distributed_message(buf)
+    }
+}
+
+/// Decode the payload of a server `EmbeddedMessage` into a distributed message.
+pub fn decode_embedded(code: u8, data: &[u8]) -> Result<DistributedMessage> {
+    let code = DistributedCode::try_from(code)?;
+    let mut buf = data;
+    DistributedMessage::read_with_code(code, &mut buf)
+}
+
+/// Check whether a filename matches a search query, as parsed by [`SearchQuery`].
+pub fn matches_query(query: &str, filename: &str) -> bool {
+    SearchQuery::parse(query).matches(filename)
+}
+
+/// Messages to send after our place in the distributed network changes.
+#[derive(Debug, Clone, Default)]
+pub struct BranchUpdate {
+    /// Requests for the server.
+    pub server: Vec<ServerRequest>,
+    /// Messages for every distributed child.
+    pub children: Vec<DistributedMessage>,
+}
+
+impl BranchUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.server.is_empty() && self.children.is_empty()
+    }
+}
+
+/// Our position in the distributed search tree.
+///
+/// Without a parent we are our own branch root at level 0. Once a parent
+/// tells us its level and root, we sit one level below it and pass the
+/// update on to our children.
+///
+/// The handshake with the server goes: take a candidate from `PossibleParents`
+/// with [`DistributedState::next_candidate`], connect to it, then call
+/// [`DistributedState::parent_connected`]. Messages from the parent go to
+/// [`DistributedState::handle_from_parent`], which reports our new branch to
+/// the server and relays it, along with searches, to our children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistributedState {
+    username: String,
+    level: i32,
+    root: String,
+    parent: Option<String>,
+    candidates: Vec<PossibleParent>,
+}
+
+impl DistributedState {
+    /// State for a node with no parent, acting as its own branch root.
+    pub fn new(username: impl Into<String>) -> Self {
+        let username = username.into();
+        Self {
+            level: 0,
+            root: username.clone(),
+            username,
+            parent: None,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Username of our parent, if we are connected to one.
+    pub fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
+    /// Our level in the branch; 0 means we are the branch root.
+    pub fn branch_level(&self) -> i32 {
+        self.level
+    }
+
+    /// Username of the root of our branch.
+    pub fn branch_root(&self) -> &str {
+        &self.root
+    }
+
+    /// Branch info a new child needs to place itself in our branch.
+    pub fn branch_info(&self) -> Vec<DistributedMessage> {
+        vec![
+            DistributedMessage::BranchLevel { level: self.level },
+            DistributedMessage::BranchRoot {
+                root: self.root.clone(),
+            },
+        ]
+    }
+
+    /// Apply a message from our parent, returning what to relay to our children.
+    ///
+    /// Only `BranchLevel` and `BranchRoot` change our position, and nothing is
+    /// relayed when the position is unchanged.
+    pub fn handle_parent_message(&mut self, msg: &DistributedMessage) -> Vec<DistributedMessage> {
+        match msg {
+            DistributedMessage::BranchLevel { level } => {
+                let level = level.saturating_add(1);
+                if level == self.level {
+                    return Vec::new();
+                }
+                self.level = level;
+                vec![DistributedMessage::BranchLevel { level }]
+            }
+            DistributedMessage::BranchRoot { root } => {
+                if *root == self.root {
+                    return Vec::new();
+                }
+                self.root = root.clone();
+                vec![DistributedMessage::BranchRoot { root: root.clone() }]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Forget our parent and become our own branch root again.
+    ///
+    /// Returns the branch info to relay to our children.
+    pub fn clear_parent(&mut self) -> Vec<DistributedMessage> {
+        self.parent = None;
+        self.level = 0;
+        self.root = self.username.clone();
+        self.branch_info()
+    }
+
+    /// Apply a server response that concerns the distributed network.
+    ///
+    /// `PossibleParents` is remembered while we have no parent; `ResetDistributed`
+    /// drops our parent and candidates and makes us a branch root again.
+    pub fn handle_server_message(&mut self, response: &ServerResponse) -> BranchUpdate {
+        match response {
+            ServerResponse::PossibleParents { parents } if self.parent.is_none() => {
+                self.candidates = parents.clone();
+                BranchUpdate::default()
+            }
+            ServerResponse::ResetDistributed => {
+                self.candidates.clear();
+                self.parent_lost()
+            }
+            _ => BranchUpdate::default(),
+        }
+    }
+
+    /// Next parent to try connecting to, in the order the server offered them.
+    pub fn next_candidate(&mut self) -> Option<PossibleParent> {
+        if self.parent.is_some() || self.candidates.is_empty() {
+            return None;
+        }
+        Some(self.candidates.remove(0))
+    }
+
+    /// We connected to `username` as our parent.
+    ///
+    /// The server stops sending `PossibleParents` once we say we have a parent.
+    /// Our branch level and root follow from the parent's first messages.
+    pub fn parent_connected(&mut self, username: &str) -> BranchUpdate {
+        self.parent = Some(username.to_string());
+        self.candidates.clear();
+        BranchUpdate {
+            server: vec![ServerRequest::HaveNoParent { no_parent: false }],
+            children: Vec::new(),
+        }
+    }
+
+    /// Our parent disconnected or the server reset us; we become a branch root.
+    pub fn parent_lost(&mut self) -> BranchUpdate {
+        let children = self.clear_parent();
+        let mut server = vec![ServerRequest::HaveNoParent { no_parent: true }];
+        server.extend(children.iter().filter_map(server_report));
+        BranchUpdate { server, children }
+    }
+
+    /// Apply a message from our parent.
+    ///
+    /// Branch changes are reported to the server and relayed to children, and
+    /// searches are passed on to children as [`DistributedMessage::downstream`] says.
+    pub fn handle_from_parent(&mut self, msg: &DistributedMessage) -> Result<BranchUpdate> {
+        let children = match msg.downstream()? {
+            Some(search) => vec![search],
+            None => self.handle_parent_message(msg),
+        };
+        Ok(BranchUpdate {
+            server: children.iter().filter_map(server_report).collect(),
+            children,
+        })
+    }
+}
+
+/// The server request reporting a branch change we relay to children.
+fn server_report(msg: &DistributedMessage) -> Option<ServerRequest> {
+    match msg {
+        DistributedMessage::BranchLevel { level } => Some(ServerRequest::BranchLevel {
+            level: (*level).max(0) as u32,
+        }),
+        DistributedMessage::BranchRoot { root } => {
+            Some(ServerRequest::BranchRoot { root: root.clone() })
+        }
+        _ => None,
+    }
+}
+
+/// Our distributed children and the depth of the subtree below us.
+///
+/// A node without children has depth 0; otherwise its depth is one more than
+/// the deepest child's. Each child reports its own depth with `ChildDepth`,
+/// and every change to ours yields a `ChildDepth` to send to our parent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BranchTree {
+    /// Last depth reported by each child.
+    children: HashMap<String, u32>,
+    /// Depth last handed out for our parent.
+    reported: u32,
+}
+
+impl BranchTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Depth of the subtree rooted at us.
+    pub fn depth(&self) -> u32 {
+        self.children
+            .values()
+            .max()
+            .map_or(0, |deepest| deepest.saturating_add(1))
+    }
+
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn has_child(&self, username: &str) -> bool {
+        self.children.contains_key(username)
+    }
+
+    /// A child attached below us; it has no children of its own until it says so.
+    pub fn add_child(&mut self, username: &str) -> Option<DistributedMessage> {
+        self.children.entry(username.to_string()).or_insert(0);
+        self.update()
+    }
+
+    /// A child disconnected, taking its subtree with it.
+    pub fn remove_child(&mut self, username: &str) -> Option<DistributedMessage> {
+        self.children.remove(username)?;
+        self.update()
+    }
+
+    /// Apply a message from one of our children.
+    ///
+    /// Only `ChildDepth` matters here; messages from unknown children are ignored.
+    pub fn handle_child_message(
+        &mut self,
+        username: &str,
+        msg: &DistributedMessage,
+    ) -> Option<DistributedMessage> {
+        let DistributedMessage::ChildDepth { depth } = msg else {
+            return None;
+        };
+        *self.children.get_mut(username)? = *depth;
+        self.update()
+    }
+
+    /// The `ChildDepth` for our parent, if our depth changed since the last one.
+    fn update(&mut self) -> Option<DistributedMessage> {
+        let depth = self.depth();
+        if depth == self.reported {
+            return None;
+        }
+        self.reported = depth;
+        Some(DistributedMessage::ChildDepth { depth })
+    }
 }
 
 /// Write a distributed message to a buffer (with length prefix and code).
-pub fn write_distributed_message<B: BufMut>(msg: &DistributedMessage, buf: &mut B) {
-    msg.write_message_u8(buf);
+pub fn write_distributed_message<B: BufMut>(msg: &DistributedMessage, buf: &mut B) -> Result<()> {
+    msg.write_message_u8(buf)
 }
 
 #[cfg(test)]
@@ -182,7 +493,7 @@ mod tests {
             query: "test query".to_string(),
         };
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
 
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
         match parsed {
@@ -205,7 +516,7 @@ mod tests {
     fn test_branch_level_roundtrip() {
         let msg = DistributedMessage::BranchLevel { level: 5 };
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
 
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
         match parsed {
@@ -215,4 +526,320 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+
+    #[test]
+    fn test_decode_embedded_search() {
+        use crate::server::{ServerResponse, read_server_message};
+
+        let search = DistributedMessage::Search {
+            unknown: 0x31,
+            username: "searcher".to_string(),
+            token: 777,
+            query: "some album".to_string(),
+        };
+        let mut payload = BytesMut::new();
+        search.write_payload(&mut payload);
+
+        let embedded = ServerResponse::EmbeddedMessage {
+            code: search.code().into(),
+            data: payload.to_vec(),
+        };
+        let mut buf = BytesMut::new();
+        embedded.write_message(&mut buf).unwrap();
+
+        let (code, data) = match read_server_message(&mut buf).unwrap() {
+            ServerResponse::EmbeddedMessage { code, data } => (code, data),
+            _ => panic!("Wrong message type"),
+        };
+        match decode_embedded(code, &data).unwrap() {
+            DistributedMessage::Search {
+                unknown,
+                username,
+                token,
+                query,
+            } => {
+                assert_eq!(unknown, 0x31);
+                assert_eq!(username, "searcher");
+                assert_eq!(token, 777);
+                assert_eq!(query, "some album");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_decode_embedded_invalid_code() {
+        assert!(matches!(
+            decode_embedded(42, &[]),
+            Err(Error::InvalidDistributedCode(42))
+        ));
+    }
+
+    #[test]
+    fn test_embedded_message_roundtrip() {
+        let msg = DistributedMessage::EmbeddedMessage {
+            code: 3,
+            data: vec![1, 2, 3, 4],
+        };
+        let mut buf = BytesMut::new();
+        write_distributed_message(&msg, &mut buf).unwrap();
+
+        let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
+        match parsed {
+            DistributedMessage::EmbeddedMessage { code, data } => {
+                assert_eq!(code, 3);
+                assert_eq!(data, vec![1, 2, 3, 4]);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_relay_embedded_search() {
+        let search = DistributedMessage::Search {
+            unknown: 0,
+            username: "searcher".to_string(),
+            token: 42,
+            query: "relay me".to_string(),
+        };
+        let embedded = DistributedMessage::embed(&search);
+
+        let mut buf = BytesMut::new();
+        write_distributed_message(&embedded, &mut buf).unwrap();
+        let received = read_distributed_message(&mut buf.freeze()).unwrap();
+
+        let relayed = received.downstream().unwrap().unwrap();
+        let mut expected = BytesMut::new();
+        write_distributed_message(&search, &mut expected).unwrap();
+        let mut actual = BytesMut::new();
+        write_distributed_message(&relayed, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+
+        assert!(search.downstream().unwrap().is_some());
+        assert!(
+            DistributedMessage::BranchLevel { level: 1 }
+                .downstream()
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_branch_level_increments_below_parent() {
+        let mut state = DistributedState::new("me");
+        assert_eq!(state.branch_level(), 0);
+        assert_eq!(state.branch_root(), "me");
+
+        let relayed = state.handle_parent_message(&DistributedMessage::BranchLevel { level: 2 });
+        assert_eq!(state.branch_level(), 3);
+        assert!(matches!(
+            relayed.as_slice(),
+            [DistributedMessage::BranchLevel { level: 3 }]
+        ));
+
+        let relayed = state.handle_parent_message(&DistributedMessage::BranchRoot {
+            root: "root".to_string(),
+        });
+        assert_eq!(state.branch_root(), "root");
+        assert!(matches!(
+            relayed.as_slice(),
+            [DistributedMessage::BranchRoot { root }] if root == "root"
+        ));
+
+        // Repeats from the parent don't need relaying
+        assert!(
+            state
+                .handle_parent_message(&DistributedMessage::BranchLevel { level: 2 })
+                .is_empty()
+        );
+        assert!(
+            state
+                .handle_parent_message(&DistributedMessage::Ping)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_branch_level_propagates_down_the_tree() {
+        let mut nodes: Vec<DistributedState> = ["a", "b", "c"]
+            .into_iter()
+            .map(DistributedState::new)
+            .collect();
+
+        // "a" is the root; each node hands its branch info to the next one down
+        let mut pending = nodes[0].branch_info();
+        for node in nodes.iter_mut().skip(1) {
+            pending = pending
+                .iter()
+                .flat_map(|msg| node.handle_parent_message(msg))
+                .collect();
+        }
+        assert_eq!(nodes[1].branch_level(), 1);
+        assert_eq!(nodes[2].branch_level(), 2);
+        assert!(nodes.iter().all(|n| n.branch_root() == "a"));
+        assert_eq!(pending.len(), 2);
+
+        // "b" loses its parent and becomes a root; "c" follows
+        let relayed = nodes[1].clear_parent();
+        for msg in &relayed {
+            nodes[2].handle_parent_message(msg);
+        }
+        assert_eq!(nodes[2].branch_level(), 1);
+        assert_eq!(nodes[2].branch_root(), "b");
+    }
+
+    #[test]
+    fn test_parent_assignment_reports_branch_level() {
+        use std::net::Ipv4Addr;
+
+        let mut state = DistributedState::new("me");
+        let candidate = |username: &str| PossibleParent {
+            username: username.to_string(),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 2234,
+        };
+        let update = state.handle_server_message(&ServerResponse::PossibleParents {
+            parents: vec![candidate("p1"), candidate("p2")],
+        });
+        assert!(update.is_empty());
+
+        // The first candidate is unreachable, the second accepts us
+        assert_eq!(state.next_candidate(), Some(candidate("p1")));
+        assert_eq!(state.next_candidate(), Some(candidate("p2")));
+        let update = state.parent_connected("p2");
+        assert!(matches!(
+            update.server.as_slice(),
+            [ServerRequest::HaveNoParent { no_parent: false }]
+        ));
+        assert_eq!(state.parent(), Some("p2"));
+
+        // More candidates are ignored while we have a parent
+        state.handle_server_message(&ServerResponse::PossibleParents {
+            parents: vec![candidate("p3")],
+        });
+        assert_eq!(state.next_candidate(), None);
+
+        let update = state
+            .handle_from_parent(&DistributedMessage::BranchLevel { level: 1 })
+            .unwrap();
+        assert!(matches!(
+            update.server.as_slice(),
+            [ServerRequest::BranchLevel { level: 2 }]
+        ));
+        assert!(matches!(
+            update.children.as_slice(),
+            [DistributedMessage::BranchLevel { level: 2 }]
+        ));
+        let update = state
+            .handle_from_parent(&DistributedMessage::BranchRoot {
+                root: "root".to_string(),
+            })
+            .unwrap();
+        assert!(matches!(
+            update.server.as_slice(),
+            [ServerRequest::BranchRoot { root }] if root == "root"
+        ));
+
+        // Searches go to children only
+        let search = DistributedMessage::Search {
+            unknown: 0,
+            username: "searcher".to_string(),
+            token: 1,
+            query: "q".to_string(),
+        };
+        let update = state.handle_from_parent(&search).unwrap();
+        assert!(update.server.is_empty());
+        assert_eq!(update.children.len(), 1);
+    }
+
+    #[test]
+    fn test_reset_distributed_makes_us_root() {
+        let mut state = DistributedState::new("me");
+        state.parent_connected("p");
+        state.handle_parent_message(&DistributedMessage::BranchLevel { level: 3 });
+
+        let update = state.handle_server_message(&ServerResponse::ResetDistributed);
+        assert_eq!(state.parent(), None);
+        assert_eq!(state.branch_level(), 0);
+        assert!(matches!(
+            update.server.as_slice(),
+            [
+                ServerRequest::HaveNoParent { no_parent: true },
+                ServerRequest::BranchLevel { level: 0 },
+                ServerRequest::BranchRoot { root },
+            ] if root == "me"
+        ));
+        assert_eq!(update.children.len(), 2);
+    }
+
+    #[test]
+    fn test_child_depth_grows_with_nested_children() {
+        // a -> b -> c, with each node's upstream ChildDepth fed to its parent
+        let mut a = BranchTree::new();
+        let mut b = BranchTree::new();
+        let mut c = BranchTree::new();
+        assert_eq!(a.depth(), 0);
+
+        let up = a.add_child("b");
+        assert!(matches!(up, Some(DistributedMessage::ChildDepth { depth: 1 })));
+        assert!(b.add_child("c").is_some());
+        let depth = b.depth();
+        let up = a.handle_child_message("b", &DistributedMessage::ChildDepth { depth });
+        assert!(matches!(up, Some(DistributedMessage::ChildDepth { depth: 2 })));
+
+        let up = c.add_child("d").unwrap();
+        let up = b.handle_child_message("c", &up).unwrap();
+        let up = a.handle_child_message("b", &up);
+        assert!(matches!(up, Some(DistributedMessage::ChildDepth { depth: 3 })));
+
+        // A shallower sibling doesn't change the depth
+        assert!(a.add_child("e").is_none());
+        assert_eq!(a.depth(), 3);
+        assert_eq!(a.child_count(), 2);
+    }
+
+    #[test]
+    fn test_child_depth_shrinks_when_children_leave() {
+        let mut tree = BranchTree::new();
+        tree.add_child("b");
+        tree.add_child("c");
+        tree.handle_child_message("b", &DistributedMessage::ChildDepth { depth: 2 });
+        assert_eq!(tree.depth(), 3);
+
+        let up = tree.remove_child("b");
+        assert!(matches!(up, Some(DistributedMessage::ChildDepth { depth: 1 })));
+        assert!(tree.remove_child("b").is_none());
+        let up = tree.remove_child("c");
+        assert!(matches!(up, Some(DistributedMessage::ChildDepth { depth: 0 })));
+
+        // Reports from children we don't have are ignored
+        assert!(
+            tree.handle_child_message("x", &DistributedMessage::ChildDepth { depth: 5 })
+                .is_none()
+        );
+        assert!(!tree.has_child("x"));
+    }
+
+    #[test]
+    fn test_matches_query_all_terms() {
+        let filename = "Music\\Pink Floyd\\The Wall\\Comfortably Numb.flac";
+        assert!(matches_query("pink numb", filename));
+        assert!(matches_query("floyd wall flac", filename));
+        assert!(!matches_query("pink zeppelin", filename));
+    }
+
+    #[test]
+    fn test_matches_query_case_insensitive() {
+        assert!(matches_query("RADIOHEAD", "radiohead - creep.mp3"));
+        assert!(matches_query("creep", "RADIOHEAD - CREEP.MP3"));
+    }
+
+    #[test]
+    fn test_matches_query_substrings() {
+        // Terms are not anchored to word boundaries
+        assert!(matches_query("beat", "The Beatles - Help.mp3"));
+        assert!(matches_query("help mp", "The Beatles - Help.mp3"));
+        assert!(!matches_query("helps", "The Beatles - Help.mp3"));
+        assert!(!matches_query("   ", "anything.mp3"));
+    }
 }
@@ -3,6 +3,8 @@
 use std::io;
 use std::string::FromUtf8Error;
 
+use crate::constants::LoginRejectionReason;
+
 /// Result type alias for slsk-rs operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -42,6 +44,34 @@ pub enum Error {
     #[error("Invalid transfer direction: {0}")]
     InvalidTransferDirection(u32),
 
+    #[error("Login failed: {reason:?}")]
+    LoginFailed {
+        reason: LoginRejectionReason,
+        detail: Option<String>,
+    },
+
+    #[error("Unexpected message code {code} ({context})")]
+    UnexpectedCode { code: u32, context: &'static str },
+
+    #[error("Length mismatch: frame declared {declared} bytes, parser consumed {consumed}")]
+    LengthMismatch { declared: u32, consumed: usize },
+
+    #[error("Message too large: {len} bytes, limit is {max}")]
+    MessageTooLarge { len: usize, max: usize },
+
+    #[error("Invalid slsk:// link {url}: {reason}")]
+    InvalidUrl { url: String, reason: &'static str },
+
+    #[error("Config error: {0}")]
+    Config(String),
+
     #[error("Protocol error: {0}")]
     Protocol(String),
 }
+
+impl Error {
+    /// Whether this is an IO timeout, which callers can usually retry.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Io(e) if e.kind() == io::ErrorKind::TimedOut)
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! File messages don't have message codes - they are raw token/offset values.
 
+use std::path::PathBuf;
+
 use bytes::{Buf, BufMut};
 
 use crate::Result;
@@ -55,6 +57,86 @@ impl FileOffset {
     }
 }
 
+/// Where downloaded files are written locally.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Directory all downloads are placed under.
+    pub base_dir: PathBuf,
+    /// Recreate the peer's folder structure instead of flattening to the basename.
+    pub preserve_remote_dirs: bool,
+    /// Keep what was received of a cancelled download instead of deleting it.
+    pub keep_cancelled: bool,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            base_dir: PathBuf::from("downloads"),
+            preserve_remote_dirs: false,
+            keep_cancelled: false,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// Map a remote `dir\file` path to a local path that never escapes `base_dir`.
+    ///
+    /// Empty, `.` and `..` directories are dropped, as is a leading drive prefix like `C:`.
+    /// Colons elsewhere are sanitized like any other reserved character.
+    pub fn local_path(&self, remote_filename: &str) -> PathBuf {
+        let mut path = self.base_dir.clone();
+
+        if self.preserve_remote_dirs {
+            let mut dirs: Vec<&str> = remote_filename.split(['/', '\\']).collect();
+            dirs.pop();
+            if dirs.first().is_some_and(|d| is_drive_prefix(d)) {
+                dirs.remove(0);
+            }
+            path.extend(dirs.into_iter().filter_map(sanitize_component));
+        }
+
+        path.push(sanitize_filename(remote_filename));
+        path
+    }
+}
+
+/// Whether `component` is a bare Windows drive like `C:`.
+fn is_drive_prefix(component: &str) -> bool {
+    matches!(component.as_bytes(), [letter, b':'] if letter.is_ascii_alphabetic())
+}
+
+/// Characters that are invalid in filenames on at least one common platform.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Reduce a remote path to a basename that is safe to create locally.
+///
+/// Directory components are stripped and reserved characters replaced with
+/// `_`. Names that end up empty, `.` or `..` become `unnamed`.
+pub fn sanitize_filename(remote: &str) -> String {
+    let basename = remote.rsplit(['/', '\\']).next().unwrap_or(remote);
+    sanitize_component(basename).unwrap_or_else(|| "unnamed".to_string())
+}
+
+fn sanitize_component(component: &str) -> Option<String> {
+    let cleaned: String = component
+        .chars()
+        .map(|c| {
+            if c.is_control() || RESERVED_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    // Windows silently strips trailing dots and spaces
+    let cleaned = cleaned.trim_end_matches(['.', ' ']).trim_start();
+
+    match cleaned {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,4 +161,61 @@ mod tests {
         let parsed = FileOffset::read_from(&mut buf.freeze()).unwrap();
         assert_eq!(parsed.offset, 1024 * 1024 * 500);
     }
+
+    #[test]
+    fn test_download_path_flattens_by_default() {
+        let config = DownloadConfig::default();
+        assert_eq!(
+            config.local_path("@@music\\Artist\\Album\\01 Track.mp3"),
+            PathBuf::from("downloads").join("01 Track.mp3")
+        );
+    }
+
+    #[test]
+    fn test_download_path_preserves_remote_dirs() {
+        let config = DownloadConfig {
+            base_dir: PathBuf::from("/srv/music"),
+            preserve_remote_dirs: true,
+            ..DownloadConfig::default()
+        };
+        assert_eq!(
+            config.local_path("Artist\\Album\\track.mp3"),
+            PathBuf::from("/srv/music/Artist/Album/track.mp3")
+        );
+    }
+
+    #[test]
+    fn test_download_path_rejects_traversal() {
+        let config = DownloadConfig {
+            base_dir: PathBuf::from("downloads"),
+            preserve_remote_dirs: true,
+            ..DownloadConfig::default()
+        };
+        let path = config.local_path("..\\..\\etc\\passwd");
+        assert_eq!(path, PathBuf::from("downloads/etc/passwd"));
+        assert!(path.starts_with("downloads"));
+
+        let path = config.local_path("C:\\Windows\\..\\..\\x");
+        assert_eq!(path, PathBuf::from("downloads/Windows/x"));
+
+        let path = config.local_path("C:\\Music\\Vol. 1: Live\\d:\\x.mp3");
+        assert_eq!(path, PathBuf::from("downloads/Music/Vol. 1_ Live/d_/x.mp3"));
+
+        let flat = DownloadConfig::default();
+        assert_eq!(flat.local_path(".."), PathBuf::from("downloads/unnamed"));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("../../secret"), "secret");
+        assert_eq!(sanitize_filename("C:\\Windows\\x"), "x");
+        assert_eq!(sanitize_filename(""), "unnamed");
+        assert_eq!(sanitize_filename(".."), "unnamed");
+        assert_eq!(sanitize_filename("dir\\."), "unnamed");
+        assert_eq!(
+            sanitize_filename("Artist - Track.mp3"),
+            "Artist - Track.mp3"
+        );
+        assert_eq!(sanitize_filename("What? \"Live\".mp3"), "What_ _Live_.mp3");
+    }
 }
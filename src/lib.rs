@@ -12,9 +12,13 @@ pub mod protocol;
 
 pub mod distributed;
 pub mod file;
+pub mod net;
 pub mod peer;
 pub mod peer_init;
 pub mod server;
 
 pub use error::{Error, Result};
-pub use protocol::{MessageRead, MessageWrite, ProtocolRead, ProtocolWrite};
+pub use protocol::{
+    FrameDecoder, FramedRead, MessageBuffer, MessageRead, MessageWrite, ProtocolRead,
+    ProtocolWrite,
+};
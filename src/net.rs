@@ -0,0 +1,169 @@
+//! Outgoing TCP connections.
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::error::Result;
+
+/// Default limit on how long establishing a connection may take.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait on peers before giving up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferTimeouts {
+    /// Establishing a connection.
+    pub connect: Duration,
+    /// Waiting for a reply, such as a share list, on an open connection.
+    pub read: Duration,
+    /// Waiting for more file data once a transfer has started.
+    pub transfer_stall: Duration,
+    /// Waiting for a peer to start a transfer we queued with it.
+    pub transfer_wait: Duration,
+}
+
+impl TransferTimeouts {
+    pub const fn new() -> Self {
+        TransferTimeouts {
+            connect: CONNECT_TIMEOUT,
+            read: Duration::from_secs(30),
+            transfer_stall: Duration::from_secs(60),
+            transfer_wait: Duration::from_secs(60),
+        }
+    }
+
+    /// A TCP connector that gives up after the connect timeout.
+    pub const fn connector(&self) -> TimeoutConnector<TcpConnector> {
+        TimeoutConnector::new(TcpConnector, self.connect)
+    }
+}
+
+impl Default for TransferTimeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Opens outgoing connections to `host:port` addresses.
+pub trait Connector {
+    type Stream;
+
+    fn connect(&self, addr: &str) -> impl Future<Output = Result<Self::Stream>> + Send;
+}
+
+/// Plain TCP connections with `TCP_NODELAY` set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    type Stream = TcpStream;
+
+    async fn connect(&self, addr: &str) -> Result<TcpStream> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// Fails any connection that is not established within the timeout.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutConnector<C> {
+    inner: C,
+    timeout: Duration,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub const fn new(inner: C, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl Default for TimeoutConnector<TcpConnector> {
+    fn default() -> Self {
+        Self::new(TcpConnector, CONNECT_TIMEOUT)
+    }
+}
+
+impl<C: Connector + Sync> Connector for TimeoutConnector<C> {
+    type Stream = C::Stream;
+
+    async fn connect(&self, addr: &str) -> Result<C::Stream> {
+        match tokio::time::timeout(self.timeout, self.inner.connect(addr)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connect to {addr} timed out after {:?}", self.timeout),
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    /// A connector whose connect never completes.
+    struct HangingConnector;
+
+    impl Connector for HangingConnector {
+        type Stream = ();
+
+        async fn connect(&self, _addr: &str) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hanging_connect_times_out() {
+        let connector = TimeoutConnector::new(HangingConnector, Duration::from_secs(5));
+
+        match connector.connect("10.0.0.1:2234").await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_listener_hits_connect_timeout() {
+        // With a backlog of one that is already taken, further handshakes go unanswered
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut queued = Vec::new();
+        let timeouts = TransferTimeouts {
+            connect: Duration::from_millis(300),
+            ..TransferTimeouts::default()
+        };
+
+        let started = tokio::time::Instant::now();
+        let err = loop {
+            match timeouts.connector().connect(&addr).await {
+                Ok(stream) => queued.push(stream),
+                Err(e) => break e,
+            }
+            assert!(queued.len() < 16, "backlog never filled");
+        };
+        let elapsed = started.elapsed();
+
+        match err {
+            Error::Io(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("unexpected error: {other:?}"),
+        }
+        assert!(elapsed >= timeouts.connect);
+        assert!(elapsed < timeouts.connect * 3);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_within_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let stream = TimeoutConnector::default().connect(&addr).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+    }
+}
@@ -3,10 +3,25 @@
 //! These messages are sent to peers for file browsing, searching, transfers, etc.
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
-use crate::constants::{TransferDirection, TransferRejectionReason, UploadPermission};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::constants::{
+    ConnectionType, FileAttributeType, TransferDirection, TransferRejectionReason, UploadPermission,
+};
+use crate::distributed::matches_query;
+use crate::net::{Connector, TransferTimeouts};
+use crate::peer_init::{PeerInitMessage, write_peer_init_message};
 use crate::protocol::{
-    MessageRead, MessageWrite, ProtocolRead, ProtocolWrite, read_list, write_list, zlib_compress,
+    FramedRead, MessageRead, MessageWrite, ProtocolRead, ProtocolWrite, ZlibStream,
+    read_framed, read_list, split_frame, write_list, zlib_compress, zlib_compress_level,
     zlib_decompress,
 };
 use crate::{Error, Result};
@@ -64,7 +79,8 @@ impl From<PeerCode> for u32 {
 }
 
 /// File attribute (e.g., bitrate, duration).
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileAttribute {
     pub code: u32,
     pub value: u32,
@@ -84,8 +100,50 @@ impl FileAttribute {
     }
 }
 
+/// Typed view over a file's attribute list.
+///
+/// Attributes are optional on the wire: VBR MP3s often omit the bitrate and
+/// lossless files usually report sample rate and bit depth instead.
+#[derive(Debug, Clone, Copy)]
+pub struct FileAttributes<'a>(pub &'a [FileAttribute]);
+
+impl<'a> FileAttributes<'a> {
+    pub fn new(attributes: &'a [FileAttribute]) -> Self {
+        Self(attributes)
+    }
+
+    /// Value of the first attribute of the given type.
+    pub fn get(&self, kind: FileAttributeType) -> Option<u32> {
+        self.0
+            .iter()
+            .find(|a| a.code == kind as u32)
+            .map(|a| a.value)
+    }
+
+    /// Bitrate in kbps.
+    pub fn bitrate(&self) -> Option<u32> {
+        self.get(FileAttributeType::Bitrate)
+    }
+
+    /// Duration in seconds.
+    pub fn duration(&self) -> Option<u32> {
+        self.get(FileAttributeType::Duration)
+    }
+
+    /// Sample rate in Hz.
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.get(FileAttributeType::SampleRate)
+    }
+
+    /// Whether the file is flagged as variable bitrate.
+    pub fn is_vbr(&self) -> bool {
+        self.get(FileAttributeType::Vbr) == Some(1)
+    }
+}
+
 /// Shared file entry.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SharedFile {
     pub filename: String,
     pub size: u64,
@@ -94,6 +152,15 @@ pub struct SharedFile {
 }
 
 impl SharedFile {
+    /// Extension of the last path component, or empty if it has none.
+    pub fn infer_extension(filename: &str) -> String {
+        let basename = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+        basename
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_string())
+            .unwrap_or_default()
+    }
+
     pub fn read_from<B: Buf>(buf: &mut B) -> Result<Self> {
         let _code = u8::read_from(buf)?; // Always 1
         let filename = String::read_from(buf)?;
@@ -118,7 +185,8 @@ impl SharedFile {
 }
 
 /// Directory with files.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SharedDirectory {
     pub path: String,
     pub files: Vec<SharedFile>,
@@ -137,8 +205,156 @@ impl SharedDirectory {
     }
 }
 
+/// Write the zlib-compressed body of a `SharedFileListResponse`.
+fn write_shared_file_list<B: BufMut>(
+    buf: &mut B,
+    directories: &[SharedDirectory],
+    private_directories: &[SharedDirectory],
+) {
+    let mut uncompressed = BytesMut::new();
+    write_list(&mut uncompressed, directories, |b, d| d.write_to(b));
+    0u32.write_to(&mut uncompressed); // Unknown field
+    write_list(&mut uncompressed, private_directories, |b, d| d.write_to(b));
+
+    // Sent once per browse and often large, so worth the extra CPU
+    let compressed = zlib_compress_level(&uncompressed, 9).unwrap_or_default();
+    buf.put_slice(&compressed);
+}
+
+/// Builds the `SharedFileListResponse` we send when a peer browses our shares.
+#[derive(Debug, Clone, Default)]
+pub struct SharedFileList {
+    directories: Vec<SharedDirectory>,
+}
+
+impl SharedFileList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_directory(&mut self, directory: SharedDirectory) -> &mut Self {
+        self.directories.push(directory);
+        self
+    }
+
+    pub fn directories(&self) -> &[SharedDirectory] {
+        &self.directories
+    }
+
+    /// The equivalent message, with no private directories.
+    pub fn into_message(self) -> PeerMessage {
+        PeerMessage::SharedFileListResponse {
+            directories: self.directories,
+            private_directories: Vec::new(),
+        }
+    }
+}
+
+impl MessageWrite for SharedFileList {
+    type Code = PeerCode;
+
+    fn code(&self) -> PeerCode {
+        PeerCode::SharedFileListResponse
+    }
+
+    fn write_payload<B: BufMut>(&self, buf: &mut B) {
+        write_shared_file_list(buf, &self.directories, &[]);
+    }
+}
+
+/// Find shared files matching a search query, reported as `dir\\file` paths.
+pub fn search_shares(directories: &[SharedDirectory], query: &str) -> Vec<SearchResultFile> {
+    directories
+        .iter()
+        .flat_map(|dir| {
+            dir.files.iter().filter_map(move |file| {
+                let filename = format!("{}\\{}", dir.path, file.filename);
+                matches_query(query, &filename).then(|| SearchResultFile {
+                    filename,
+                    size: file.size,
+                    extension: file.extension.clone(),
+                    attributes: file.attributes.clone(),
+                })
+            })
+        })
+        .collect()
+}
+
+/// Builds the `FileSearchResponse` we send to a peer whose search matched our shares.
+///
+/// Starts with a free slot, no queue and no private results.
+#[derive(Debug, Clone)]
+pub struct SearchResponseBuilder {
+    username: String,
+    token: u32,
+    results: Vec<SearchResultFile>,
+    slot_free: bool,
+    avg_speed: u32,
+    queue_length: u32,
+}
+
+impl SearchResponseBuilder {
+    /// A response to the search with `token`.
+    pub fn for_token(token: u32) -> Self {
+        Self {
+            username: String::new(),
+            token,
+            results: Vec::new(),
+            slot_free: true,
+            avg_speed: 0,
+            queue_length: 0,
+        }
+    }
+
+    /// Our own username, which the searcher uses to reach us.
+    pub fn as_user(mut self, username: impl Into<String>) -> Self {
+        self.username = username.into();
+        self
+    }
+
+    pub fn add_file(mut self, file: SearchResultFile) -> Self {
+        self.results.push(file);
+        self
+    }
+
+    pub fn add_files(mut self, files: impl IntoIterator<Item = SearchResultFile>) -> Self {
+        self.results.extend(files);
+        self
+    }
+
+    pub fn slots_free(mut self, free: bool) -> Self {
+        self.slot_free = free;
+        self
+    }
+
+    /// Our average upload speed in bytes per second.
+    pub fn avg_speed(mut self, speed: u32) -> Self {
+        self.avg_speed = speed;
+        self
+    }
+
+    pub fn queue_length(mut self, length: u32) -> Self {
+        self.queue_length = length;
+        self
+    }
+
+    /// The response message; it is compressed when written.
+    pub fn build(self) -> PeerMessage {
+        PeerMessage::FileSearchResponse {
+            username: self.username,
+            token: self.token,
+            results: self.results,
+            slot_free: self.slot_free,
+            avg_speed: self.avg_speed,
+            queue_length: self.queue_length,
+            private_results: Vec::new(),
+        }
+    }
+}
+
 /// Search result file.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SearchResultFile {
     pub filename: String,
     pub size: u64,
@@ -170,6 +386,264 @@ impl SearchResultFile {
     }
 }
 
+/// Options controlling how search results are ranked by [`rank_search_results`].
+#[derive(Debug, Clone)]
+pub struct RankOptions {
+    /// Only keep files with these extensions (lowercase, no dot). Empty keeps everything.
+    pub allowed_extensions: Vec<String>,
+    /// Extensions ranked above everything else, best first. These count as known quality.
+    pub preferred_extensions: Vec<String>,
+    /// Drop files that report no bitrate and aren't a preferred extension.
+    pub require_bitrate: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Among files of the same format, rank free slots above higher bitrates, then
+    /// shorter queues and faster uploaders. Needs [`RankCandidate::availability`].
+    pub prefer_available: bool,
+}
+
+impl Default for RankOptions {
+    fn default() -> Self {
+        let audio = [
+            "mp3", "flac", "m4a", "ogg", "opus", "wav", "aac", "wma", "ape", "alac", "aiff", "aif",
+            "wv", "mpc",
+        ];
+        RankOptions {
+            allowed_extensions: audio.iter().map(|e| e.to_string()).collect(),
+            preferred_extensions: vec!["flac".to_string()],
+            require_bitrate: false,
+            min_size: None,
+            max_size: None,
+            prefer_available: false,
+        }
+    }
+}
+
+/// Renders as `filename (size, bitrate)`, leaving out the bitrate when unknown.
+impl fmt::Display for SearchResultFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size_mb = self.size as f64 / 1_048_576.0;
+        if size_mb >= 1.0 {
+            write!(f, "{} ({:.1} MB", self.filename, size_mb)?;
+        } else {
+            write!(f, "{} ({:.0} KB", self.filename, self.size as f64 / 1024.0)?;
+        }
+        match file_bitrate(self) {
+            Some(bitrate) => write!(f, ", {bitrate} kbps)"),
+            None => write!(f, ")"),
+        }
+    }
+}
+
+/// How busy the uploader of a search result is, from its `FileSearchResponse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAvailability {
+    pub slot_free: bool,
+    pub queue_length: u32,
+    pub avg_speed: u32,
+}
+
+/// Something [`rank_search_results`] can rank.
+pub trait RankCandidate {
+    fn file(&self) -> &SearchResultFile;
+
+    /// The uploader's availability, if known.
+    fn availability(&self) -> Option<PeerAvailability> {
+        None
+    }
+}
+
+impl RankCandidate for SearchResultFile {
+    fn file(&self) -> &SearchResultFile {
+        self
+    }
+}
+
+impl<T: RankCandidate + ?Sized> RankCandidate for &T {
+    fn file(&self) -> &SearchResultFile {
+        (**self).file()
+    }
+
+    fn availability(&self) -> Option<PeerAvailability> {
+        (**self).availability()
+    }
+}
+
+fn file_bitrate(file: &SearchResultFile) -> Option<u32> {
+    FileAttributes::new(&file.attributes).bitrate()
+}
+
+/// Filter and sort search results best-first.
+///
+/// Files with known quality (a bitrate, or a preferred extension) come first,
+/// then preferred extensions in order, then higher bitrates. With
+/// [`RankOptions::prefer_available`] uploader availability is weighed in too.
+pub fn rank_search_results<T: RankCandidate>(
+    results: impl IntoIterator<Item = T>,
+    options: &RankOptions,
+) -> Vec<T> {
+    let preference = |ext: &str| {
+        options
+            .preferred_extensions
+            .iter()
+            .position(|p| p == ext)
+            .unwrap_or(options.preferred_extensions.len())
+    };
+
+    let mut ranked: Vec<_> = results
+        .into_iter()
+        .filter_map(|r| {
+            let file = r.file();
+            let mut ext = SharedFile::infer_extension(&file.filename).to_lowercase();
+            if ext.is_empty() {
+                ext = file.extension.to_lowercase();
+            }
+            let bitrate = file_bitrate(file);
+            let preferred = preference(&ext) < options.preferred_extensions.len();
+
+            let allowed = options.allowed_extensions.is_empty()
+                || options.allowed_extensions.contains(&ext);
+            let size_ok = options.min_size.is_none_or(|min| file.size >= min)
+                && options.max_size.is_none_or(|max| file.size <= max);
+            let bitrate_ok = !options.require_bitrate || bitrate.is_some() || preferred;
+
+            (allowed && size_ok && bitrate_ok).then(|| {
+                let known = bitrate.is_some() || preferred;
+                let availability = r.availability().filter(|_| options.prefer_available);
+                let key = (
+                    !known,
+                    preference(&ext),
+                    !availability.is_none_or(|a| a.slot_free),
+                    std::cmp::Reverse(bitrate.unwrap_or(0)),
+                    availability.map_or(0, |a| a.queue_length),
+                    std::cmp::Reverse(availability.map_or(0, |a| a.avg_speed)),
+                );
+                (key, r)
+            })
+        })
+        .collect();
+
+    ranked.sort_by_key(|(key, _)| *key);
+    ranked.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Format and quality words dropped by [`filename_to_query`].
+pub const QUERY_STOPWORDS: &[&str] = &[
+    "flac", "mp3", "wav", "ogg", "m4a", "320", "256", "128", "192", "24bit", "16bit",
+];
+
+/// Turn a shared filename into a search query for finding the same track elsewhere.
+///
+/// Directories and the extension are dropped, `_`, `-` and `.` become spaces,
+/// leading track numbers such as `01 - ` are skipped and words in `stopwords`
+/// are removed, ignoring case.
+pub fn filename_to_query(filename: &str, stopwords: &[&str]) -> String {
+    let basename = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+    let stem = match basename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && looks_like_extension(ext) => stem,
+        _ => basename,
+    };
+
+    strip_track_numbers(stem)
+        .split(|c: char| matches!(c, '_' | '-' | '.') || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .filter(|word| {
+            let lower = word.to_lowercase();
+            !stopwords.iter().any(|s| s.to_lowercase() == lower)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Skip up to three digits followed by a `-`, `.` or `_` separator, as often
+/// as they repeat, so `1-07. Title` becomes `Title` but `99 Luftballons` is kept.
+fn strip_track_numbers(mut stem: &str) -> &str {
+    loop {
+        stem = stem.trim_start();
+        let digits = stem.len() - stem.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 || digits > 3 {
+            return stem;
+        }
+        match stem[digits..].trim_start().strip_prefix(['-', '.', '_']) {
+            Some(rest) => stem = rest,
+            None => return stem,
+        }
+    }
+}
+
+fn looks_like_extension(ext: &str) -> bool {
+    ext.len() <= 5
+        && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        && ext.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+/// A file link such as `slsk://user/Music/track.mp3?size=1234`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlskUrl {
+    pub username: String,
+    /// Path in the peer's `dir\\file` form.
+    pub path: String,
+    /// File size from the `size` query parameter, if given.
+    pub size: Option<u64>,
+}
+
+/// Parse a `slsk://user/path/to/file` link.
+///
+/// The user and path are percent-decoded. Query parameters other than `size`
+/// are ignored.
+pub fn parse_slsk_url(url: &str) -> Result<SlskUrl> {
+    let invalid = |reason| Error::InvalidUrl {
+        url: url.to_string(),
+        reason,
+    };
+
+    let rest = url
+        .trim()
+        .strip_prefix("slsk://")
+        .ok_or_else(|| invalid("not a slsk:// link"))?;
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (user, path) = rest.split_once('/').ok_or_else(|| invalid("no file path"))?;
+    let user = percent_decode(user).ok_or_else(|| invalid("malformed percent escape"))?;
+    let path = percent_decode(path.trim_end_matches('/'))
+        .ok_or_else(|| invalid("malformed percent escape"))?;
+    if user.is_empty() {
+        return Err(invalid("no user name"));
+    }
+    if path.is_empty() {
+        return Err(invalid("no file path"));
+    }
+
+    let mut size = None;
+    for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        if key == "size" {
+            size = Some(value.parse().map_err(|_| invalid("size is not a number"))?);
+        }
+    }
+
+    Ok(SlskUrl {
+        username: user,
+        path: path.replace('/', "\\"),
+        size,
+    })
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        if b == b'%' {
+            let hex = [input.next()?, input.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
 /// Peer messages.
 #[derive(Debug, Clone)]
 pub enum PeerMessage {
@@ -284,13 +758,7 @@ impl MessageWrite for PeerMessage {
                 directories,
                 private_directories,
             } => {
-                let mut uncompressed = BytesMut::new();
-                write_list(&mut uncompressed, directories, |b, d| d.write_to(b));
-                0u32.write_to(&mut uncompressed); // Unknown field
-                write_list(&mut uncompressed, private_directories, |b, d| d.write_to(b));
-
-                let compressed = zlib_compress(&uncompressed).unwrap_or_default();
-                buf.put_slice(&compressed);
+                write_shared_file_list(buf, directories, private_directories);
             }
             PeerMessage::FileSearchResponse {
                 username,
@@ -311,7 +779,8 @@ impl MessageWrite for PeerMessage {
                 0u32.write_to(&mut uncompressed); // Unknown field
                 write_list(&mut uncompressed, private_results, |b, f| f.write_to(b));
 
-                let compressed = zlib_compress(&uncompressed).unwrap_or_default();
+                // Small and latency sensitive; the searcher stops listening after a while
+                let compressed = zlib_compress_level(&uncompressed, 1).unwrap_or_default();
                 buf.put_slice(&compressed);
             }
             PeerMessage::UserInfoRequest => {}
@@ -583,23 +1052,509 @@ impl MessageRead for PeerMessage {
 
 /// Read a peer message from a buffer (including length prefix).
 pub fn read_peer_message<B: Buf>(buf: &mut B) -> Result<PeerMessage> {
-    let _len = u32::read_from(buf)?;
-    let code = PeerCode::try_from(u32::read_from(buf)?)?;
-    PeerMessage::read_with_code(code, buf)
+    read_framed(buf, |buf| {
+        let code = PeerCode::try_from(u32::read_from(buf)?)?;
+        PeerMessage::read_with_code(code, buf)
+    })
+}
+
+impl FramedRead for PeerMessage {
+    fn read_frame<B: Buf>(buf: &mut B) -> Result<Self> {
+        read_peer_message(buf)
+    }
+}
+
+/// How long a pooled peer connection may sit unused before it is closed.
+pub const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An open `P` connection and the init message that opened it.
+#[derive(Debug)]
+pub struct PooledConnection<S> {
+    pub stream: S,
+    init: PeerInitMessage,
+    last_used: Instant,
+}
+
+impl<S> PooledConnection<S> {
+    /// Wrap a connection whose init message has already been exchanged.
+    pub fn new(stream: S, init: PeerInitMessage) -> Self {
+        PooledConnection {
+            stream,
+            init,
+            last_used: Instant::now(),
+        }
+    }
+
+    /// The init message this connection opened with; it is never sent again.
+    pub fn init(&self) -> &PeerInitMessage {
+        &self.init
+    }
+
+    /// Whether this connection answered the peer's indirect connection request.
+    pub fn is_pierced(&self) -> bool {
+        matches!(self.init, PeerInitMessage::PierceFirewall { .. })
+    }
+}
+
+impl<S: AsyncWrite + Unpin> PooledConnection<S> {
+    /// Send `init` on a fresh connection to open it for peer messages.
+    pub async fn open(mut stream: S, init: PeerInitMessage) -> Result<Self> {
+        let mut buf = BytesMut::new();
+        write_peer_init_message(&init, &mut buf)?;
+        stream.write_all(&buf).await?;
+        Ok(PooledConnection::new(stream, init))
+    }
+
+    pub async fn send(&mut self, messages: &[PeerMessage]) -> Result<()> {
+        let mut buf = BytesMut::new();
+        for msg in messages {
+            msg.write_message(&mut buf)?;
+        }
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        self.last_used = Instant::now();
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> PooledConnection<S> {
+    /// Whether the peer has hung up, without waiting for anything to arrive.
+    ///
+    /// A write to a closed socket can still succeed, so this is checked before
+    /// reuse. Nothing is read from pooled connections, so anything the peer
+    /// sent is discarded.
+    pub async fn is_closed(&mut self) -> bool {
+        let mut scratch = [0u8; 1024];
+        std::future::poll_fn(|cx| {
+            loop {
+                let mut buf = ReadBuf::new(&mut scratch);
+                match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) if buf.filled().is_empty() => return Poll::Ready(true),
+                    Poll::Ready(Ok(())) => continue,
+                    Poll::Ready(Err(_)) => return Poll::Ready(true),
+                    Poll::Pending => return Poll::Ready(false),
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// Send `messages` over `pooled` if the peer still has it open, otherwise over
+/// a fresh connection to `addr` opened with `init`.
+///
+/// Returns the connection that carried them, for the caller to put back in its
+/// pool. Taking the connection out first means no lock on the pool is held
+/// while connecting or writing.
+pub async fn send_pooled<S, C>(
+    pooled: Option<PooledConnection<S>>,
+    connector: &C,
+    addr: &str,
+    init: PeerInitMessage,
+    messages: &[PeerMessage],
+) -> Result<PooledConnection<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Connector<Stream = S>,
+{
+    if let Some(mut conn) = pooled
+        && !conn.is_closed().await
+        && conn.send(messages).await.is_ok()
+    {
+        return Ok(conn);
+    }
+    let mut conn = PooledConnection::open(connector.connect(addr).await?, init).await?;
+    conn.send(messages).await?;
+    Ok(conn)
+}
+
+/// Live peer connections by username, so repeated messages to a peer share one connection.
+///
+/// Connections are taken out while in use and put back afterwards, which keeps
+/// the pool usable behind a lock without holding it across I/O. Only
+/// connections we write to without awaiting a reply belong here; one that is
+/// read from, like a browse, stays with whoever is reading it.
+/// [`close_idle`](Self::close_idle) should run periodically, since idle
+/// connections are otherwise only closed when another is put back.
+#[derive(Debug)]
+pub struct PeerConnectionPool<S = TcpStream> {
+    connections: HashMap<String, PooledConnection<S>>,
+    idle_timeout: Duration,
+}
+
+impl<S> Default for PeerConnectionPool<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> PeerConnectionPool<S> {
+    pub fn new() -> Self {
+        Self::with_idle_timeout(PEER_IDLE_TIMEOUT)
+    }
+
+    pub fn with_idle_timeout(idle_timeout: Duration) -> Self {
+        PeerConnectionPool {
+            connections: HashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    /// Take the connection to `username` out of the pool, unless it has gone idle.
+    pub fn take(&mut self, username: &str) -> Option<PooledConnection<S>> {
+        self.connections
+            .remove(username)
+            .filter(|conn| conn.last_used.elapsed() < self.idle_timeout)
+    }
+
+    /// Return a connection once done with it, closing any that have gone idle.
+    pub fn put_back(&mut self, username: &str, conn: PooledConnection<S>) {
+        self.connections.insert(username.to_string(), conn);
+        self.close_idle();
+    }
+
+    /// Close connections unused for longer than the idle timeout, returning whose they were.
+    pub fn close_idle(&mut self) -> Vec<String> {
+        let mut closed = Vec::new();
+        self.connections.retain(|username, conn| {
+            let idle = conn.last_used.elapsed() >= self.idle_timeout;
+            if idle {
+                closed.push(username.clone());
+            }
+            !idle
+        });
+        closed
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.connections.contains_key(username)
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerConnectionPool<S> {
+    /// Send `messages` to `username`, opening a connection to `addr` with `init` if none is pooled.
+    ///
+    /// If the pooled connection has been closed or fails to send, the messages
+    /// are sent once more over a fresh connection. A connection that fails to
+    /// send is dropped rather than pooled. This borrows the pool throughout, so
+    /// a pool shared behind a lock should use [`send_pooled`] instead.
+    pub async fn send<C: Connector<Stream = S>>(
+        &mut self,
+        connector: &C,
+        username: &str,
+        addr: &str,
+        init: PeerInitMessage,
+        messages: &[PeerMessage],
+    ) -> Result<()> {
+        let conn = send_pooled(self.take(username), connector, addr, init, messages).await?;
+        self.put_back(username, conn);
+        Ok(())
+    }
+}
+
+/// Fetch the shares of the peer listening at `addr`, bounded by `timeouts`.
+///
+/// Messages the peer sends before its share list are skipped. Fails with a
+/// timeout if the list does not arrive within `timeouts.read`.
+pub async fn connect_to_peer_and_browse(
+    our_username: &str,
+    token: u32,
+    addr: &str,
+    timeouts: &TransferTimeouts,
+) -> Result<Vec<SharedDirectory>> {
+    let options = BrowseOptions {
+        timeout: timeouts.read,
+        ..BrowseOptions::new()
+    };
+    browse_user(&timeouts.connector(), addr, our_username, token, &options).await
+}
+
+/// Limits for [`browse_user`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrowseOptions {
+    /// Most bytes of share list to accept, compressed or decompressed.
+    pub max_bytes: usize,
+    /// How long connecting and receiving the whole list may take.
+    pub timeout: Duration,
+}
+
+impl BrowseOptions {
+    pub const fn new() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            timeout: TransferTimeouts::new().read,
+        }
+    }
+}
+
+impl Default for BrowseOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a [`ShareListDecoder`] is within the decompressed list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShareListSection {
+    Count { private: bool },
+    Directories { private: bool, left: u32 },
+    Unknown,
+    Done,
+}
+
+impl ShareListSection {
+    fn after_directories(private: bool) -> Self {
+        if private { Self::Done } else { Self::Unknown }
+    }
+}
+
+/// Decodes a compressed `SharedFileListResponse` body as it arrives, one directory at a time.
+///
+/// Public directories come first, followed by any private ones.
+#[derive(Debug)]
+pub struct ShareListDecoder {
+    zlib: ZlibStream,
+    plain: BytesMut,
+    section: ShareListSection,
+}
+
+impl ShareListDecoder {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            zlib: ZlibStream::new().with_limit(max_bytes),
+            plain: BytesMut::new(),
+            section: ShareListSection::Count { private: false },
+        }
+    }
+
+    /// Feed more of the compressed body, returning the directories it completed.
+    ///
+    /// Fails with [`Error::MessageTooLarge`] once the decompressed list exceeds the cap.
+    pub fn feed(&mut self, compressed: &[u8]) -> Result<Vec<SharedDirectory>> {
+        let ended = self.zlib.feed(compressed, &mut self.plain)?;
+
+        let mut directories = Vec::new();
+        loop {
+            self.section = match self.section {
+                ShareListSection::Count { private } => match self.take(|b| u32::read_from(b))? {
+                    Some(0) => ShareListSection::after_directories(private),
+                    Some(left) => ShareListSection::Directories { private, left },
+                    None => break,
+                },
+                ShareListSection::Directories { private, left } => {
+                    match self.take(|b| SharedDirectory::read_from(b))? {
+                        Some(directory) => directories.push(directory),
+                        None => break,
+                    }
+                    match left - 1 {
+                        0 => ShareListSection::after_directories(private),
+                        left => ShareListSection::Directories { private, left },
+                    }
+                }
+                ShareListSection::Unknown => match self.take(|b| u32::read_from(b))? {
+                    Some(_) => ShareListSection::Count { private: true },
+                    None => break,
+                },
+                ShareListSection::Done => break,
+            };
+        }
+
+        if ended {
+            // Older clients end the list after the public directories
+            if self.section == ShareListSection::Unknown && self.plain.is_empty() {
+                self.section = ShareListSection::Done;
+            }
+            if !self.is_done() {
+                return Err(Error::Protocol("share list ended mid-directory".to_string()));
+            }
+        }
+        Ok(directories)
+    }
+
+    /// Read one value off the decompressed bytes, or `None` if it has not all arrived.
+    fn take<T>(&mut self, read: impl FnOnce(&mut &[u8]) -> Result<T>) -> Result<Option<T>> {
+        let mut rest = &self.plain[..];
+        match read(&mut rest) {
+            Ok(value) => {
+                let consumed = self.plain.len() - rest.len();
+                self.plain.advance(consumed);
+                Ok(Some(value))
+            }
+            Err(Error::BufferUnderflow { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether every directory in the list has been returned.
+    pub fn is_done(&self) -> bool {
+        self.section == ShareListSection::Done
+    }
+}
+
+/// Fetch every shared directory, public then private, of the peer listening at `addr`.
+///
+/// The list is decompressed and decoded as it arrives rather than buffered
+/// whole. Lists larger than `options.max_bytes` fail with
+/// [`Error::MessageTooLarge`], and a list that has not fully arrived within
+/// `options.timeout` fails with a timeout.
+pub async fn browse_user<C>(
+    connector: &C,
+    addr: &str,
+    our_username: &str,
+    token: u32,
+    options: &BrowseOptions,
+) -> Result<Vec<SharedDirectory>>
+where
+    C: Connector,
+    C::Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    let init = PeerInitMessage::PeerInit {
+        username: our_username.to_string(),
+        connection_type: ConnectionType::Peer,
+        token,
+    };
+    let browse = async {
+        let stream = connector.connect(addr).await?;
+        let mut conn = PooledConnection::open(stream, init).await?;
+        conn.send(&[PeerMessage::SharedFileListRequest]).await?;
+
+        // Skip whole messages until the share list header arrives
+        let mut buf = BytesMut::new();
+        let body_len = loop {
+            if buf.len() >= 8 {
+                let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+                let code = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                if code == u32::from(PeerCode::SharedFileListResponse) {
+                    if len > options.max_bytes {
+                        return Err(Error::MessageTooLarge {
+                            len,
+                            max: options.max_bytes,
+                        });
+                    }
+                    buf.advance(8);
+                    break len.saturating_sub(4);
+                }
+                if let Some(frame) = split_frame(&mut buf) {
+                    frame?;
+                    continue;
+                }
+            }
+            if conn.stream.read_buf(&mut buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        };
+
+        let mut decoder = ShareListDecoder::new(options.max_bytes);
+        let mut directories = Vec::new();
+        let mut left = body_len;
+        loop {
+            let chunk = buf.split_to(buf.len().min(left));
+            left -= chunk.len();
+            directories.extend(decoder.feed(&chunk)?);
+            if decoder.is_done() {
+                return Ok(directories);
+            }
+            if left == 0 {
+                return Err(Error::Protocol("share list ended mid-directory".to_string()));
+            }
+            buf.clear();
+            if conn.stream.read_buf(&mut buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    };
+    match tokio::time::timeout(options.timeout, browse).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no complete share list from {addr} after {:?}", options.timeout),
+        )
+        .into()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::net::TcpConnector;
     use bytes::BytesMut;
 
+    fn shared_file(filename: &str, size: u64, bitrate: Option<u32>) -> SharedFile {
+        SharedFile {
+            filename: filename.to_string(),
+            size,
+            extension: SharedFile::infer_extension(filename),
+            attributes: bitrate
+                .map(|value| vec![FileAttribute { code: 0, value }])
+                .unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn test_infer_extension() {
+        assert_eq!(SharedFile::infer_extension("track"), "");
+        assert_eq!(SharedFile::infer_extension("a.b/c"), "");
+        assert_eq!(SharedFile::infer_extension("Music\\v1.0\\track"), "");
+        assert_eq!(SharedFile::infer_extension("song.flac"), "flac");
+        assert_eq!(SharedFile::infer_extension("Music\\Album\\song.flac"), "flac");
+    }
+
+    #[test]
+    fn test_shared_file_list_roundtrip() {
+        let albums = SharedDirectory {
+            path: "Music\\Artist\\Album".to_string(),
+            files: vec![
+                shared_file("01 One.mp3", 4_000_000, Some(320)),
+                shared_file("02 Two.flac", 30_000_000, None),
+            ],
+        };
+        let empty = SharedDirectory {
+            path: "Music\\Empty".to_string(),
+            files: vec![],
+        };
+
+        let mut list = SharedFileList::new();
+        list.add_directory(albums.clone())
+            .add_directory(empty.clone());
+
+        let mut buf = BytesMut::new();
+        list.write_message(&mut buf).unwrap();
+
+        match read_peer_message(&mut buf).unwrap() {
+            PeerMessage::SharedFileListResponse {
+                directories,
+                private_directories,
+            } => {
+                assert_eq!(directories, vec![albums, empty]);
+                assert!(private_directories.is_empty());
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(buf.is_empty());
+
+        // Same bytes as the equivalent PeerMessage
+        let mut from_message = BytesMut::new();
+        list.clone().into_message().write_message(&mut from_message).unwrap();
+        let mut from_builder = BytesMut::new();
+        list.write_message(&mut from_builder).unwrap();
+        assert_eq!(from_message, from_builder);
+    }
+
     #[test]
     fn test_queue_upload_roundtrip() {
         let msg = PeerMessage::QueueUpload {
             filename: "Music/test.mp3".to_string(),
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
 
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
         match parsed {
@@ -619,7 +1574,7 @@ mod tests {
             file_size: Some(1024),
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
 
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
         match parsed {
@@ -637,4 +1592,741 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+
+    fn ranked_file(filename: &str, bitrate: Option<u32>) -> SearchResultFile {
+        SearchResultFile {
+            filename: filename.to_string(),
+            size: 5_000_000,
+            extension: String::new(),
+            attributes: bitrate
+                .map(|value| vec![FileAttribute { code: 0, value }])
+                .unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn test_file_attributes_cbr_mp3() {
+        let attributes = [
+            FileAttribute { code: 0, value: 320 },
+            FileAttribute { code: 1, value: 245 },
+            FileAttribute { code: 2, value: 0 },
+        ];
+        let attrs = FileAttributes::new(&attributes);
+        assert_eq!(attrs.bitrate(), Some(320));
+        assert_eq!(attrs.duration(), Some(245));
+        assert_eq!(attrs.sample_rate(), None);
+        assert!(!attrs.is_vbr());
+    }
+
+    #[test]
+    fn test_file_attributes_flac() {
+        let attributes = [
+            FileAttribute { code: 1, value: 301 },
+            FileAttribute { code: 4, value: 44_100 },
+            FileAttribute { code: 5, value: 16 },
+        ];
+        let attrs = FileAttributes::new(&attributes);
+        assert_eq!(attrs.bitrate(), None);
+        assert_eq!(attrs.duration(), Some(301));
+        assert_eq!(attrs.sample_rate(), Some(44_100));
+        assert_eq!(attrs.get(FileAttributeType::BitDepth), Some(16));
+    }
+
+    #[test]
+    fn test_file_attributes_empty() {
+        let attrs = FileAttributes::new(&[]);
+        assert_eq!(attrs.bitrate(), None);
+        assert_eq!(attrs.duration(), None);
+        assert_eq!(attrs.sample_rate(), None);
+        assert!(!attrs.is_vbr());
+    }
+
+    #[tokio::test]
+    async fn test_connection_pool_reuses_connection() {
+        use crate::net::TcpConnector;
+        use crate::peer_init::read_peer_init_message;
+        use crate::protocol::FrameDecoder;
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = FrameDecoder::new();
+            let mut received = Vec::new();
+            while received.len() < 3 {
+                stream.read_buf(frames.buffer_mut()).await.unwrap();
+                while let Some(frame) = frames.next_frame() {
+                    received.push(frame.unwrap());
+                }
+            }
+            (listener, received)
+        });
+
+        let mut pool = PeerConnectionPool::new();
+        let init = |token| PeerInitMessage::PeerInit {
+            username: "me".to_string(),
+            connection_type: crate::constants::ConnectionType::Peer,
+            token,
+        };
+        let requests = [PeerMessage::UserInfoRequest, PeerMessage::SharedFileListRequest];
+        for (token, request) in requests.into_iter().enumerate() {
+            pool.send(&TcpConnector, "friend", &addr, init(token as u32), &[request])
+                .await
+                .unwrap();
+        }
+        assert!(pool.contains("friend"));
+
+        let (listener, mut received) = peer.await.unwrap();
+        // One connection, opened once with the first init and then reused
+        assert!(matches!(
+            read_peer_init_message(&mut received[0]).unwrap(),
+            PeerInitMessage::PeerInit { token: 0, .. }
+        ));
+        assert!(matches!(
+            read_peer_message(&mut received[1]).unwrap(),
+            PeerMessage::UserInfoRequest
+        ));
+        assert!(matches!(
+            read_peer_message(&mut received[2]).unwrap(),
+            PeerMessage::SharedFileListRequest
+        ));
+        let second = tokio::time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(second.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_pool_reconnects_after_failed_send() {
+        /// Hands out in-memory connections, keeping the peer's ends.
+        #[derive(Default)]
+        struct DuplexConnector(std::sync::Mutex<Vec<tokio::io::DuplexStream>>);
+
+        impl Connector for DuplexConnector {
+            type Stream = tokio::io::DuplexStream;
+
+            async fn connect(&self, _addr: &str) -> Result<tokio::io::DuplexStream> {
+                let (ours, theirs) = tokio::io::duplex(4096);
+                self.0.lock().unwrap().push(theirs);
+                Ok(ours)
+            }
+        }
+
+        let connector = DuplexConnector::default();
+        let mut pool = PeerConnectionPool::new();
+        let init = PeerInitMessage::PeerInit {
+            username: "me".to_string(),
+            connection_type: crate::constants::ConnectionType::Peer,
+            token: 1,
+        };
+        let request = [PeerMessage::UserInfoRequest];
+        pool.send(&connector, "friend", "peer", init.clone(), &request)
+            .await
+            .unwrap();
+
+        // The peer hangs up on the pooled connection
+        connector.0.lock().unwrap().clear();
+        pool.send(&connector, "friend", "peer", init, &request)
+            .await
+            .unwrap();
+        assert_eq!(connector.0.lock().unwrap().len(), 1);
+        assert!(pool.contains("friend"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_pool_skips_connection_peer_closed() {
+        use crate::net::TcpConnector;
+        use crate::protocol::FrameDecoder;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut pool = PeerConnectionPool::new();
+        let init = PeerInitMessage::PeerInit {
+            username: "me".to_string(),
+            connection_type: crate::constants::ConnectionType::Peer,
+            token: 1,
+        };
+        let request = [PeerMessage::UserInfoRequest];
+        pool.send(&TcpConnector, "friend", &addr, init.clone(), &request)
+            .await
+            .unwrap();
+
+        // The peer hangs up, which a write alone would not notice
+        let (first, _) = listener.accept().await.unwrap();
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        pool.send(&TcpConnector, "friend", &addr, init, &request)
+            .await
+            .unwrap();
+        let (mut second, _) = tokio::time::timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .expect("no fresh connection")
+            .unwrap();
+        let mut frames = FrameDecoder::new();
+        let mut received = 0;
+        while received < 2 {
+            second.read_buf(frames.buffer_mut()).await.unwrap();
+            while frames.next_frame().is_some() {
+                received += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection_pool_closes_idle() {
+        let pierced = || PooledConnection::new((), PeerInitMessage::PierceFirewall { token: 1 });
+        let mut pool = PeerConnectionPool::with_idle_timeout(Duration::ZERO);
+        pool.put_back("friend", pierced());
+        assert!(pool.is_empty());
+
+        let mut pool = PeerConnectionPool::new();
+        pool.put_back("friend", pierced());
+        let conn = pool.take("friend").unwrap();
+        assert!(conn.is_pierced());
+        assert!(pool.take("friend").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_browse_skips_messages_before_share_list() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Both messages land in a single read on the browsing side
+            let mut buf = BytesMut::new();
+            PeerMessage::UserInfoRequest.write_message(&mut buf).unwrap();
+            PeerMessage::SharedFileListResponse {
+                directories: vec![SharedDirectory {
+                    path: "Music".to_string(),
+                    files: vec![],
+                }],
+                private_directories: vec![],
+            }
+            .write_message(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream
+        });
+
+        let directories = tokio::time::timeout(
+            Duration::from_secs(5),
+            connect_to_peer_and_browse("me", 1, &addr, &TransferTimeouts::new()),
+        )
+        .await
+        .expect("second buffered message was not processed")
+        .unwrap();
+        assert_eq!(directories.len(), 1);
+        assert_eq!(directories[0].path, "Music");
+        drop(peer.await.unwrap());
+    }
+
+    /// Serve `message` to one browsing peer in small writes, so it arrives in pieces.
+    async fn serve_in_chunks(message: BytesMut) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for chunk in message.chunks(1500) {
+                if stream.write_all(chunk).await.is_err() {
+                    return;
+                }
+                stream.flush().await.unwrap();
+            }
+            // Hold the connection open until the browser hangs up
+            let _ = stream.read_u8().await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_browse_user_decodes_streamed_list() {
+        let directories: Vec<SharedDirectory> = (0..500)
+            .map(|d| SharedDirectory {
+                path: format!("Music\\Artist {d}\\Album {}", d * 7919 % 1000),
+                files: (0..8)
+                    .map(|f| {
+                        let name = format!("{f:02} Track {}.flac", d * f);
+                        shared_file(&name, d * 1000 + f, None)
+                    })
+                    .collect(),
+            })
+            .collect();
+        let private = vec![SharedDirectory {
+            path: "Private\\Demos".to_string(),
+            files: vec![shared_file("demo.mp3", 1234, Some(192))],
+        }];
+        let mut message = BytesMut::new();
+        PeerMessage::UserInfoRequest.write_message(&mut message).unwrap();
+        PeerMessage::SharedFileListResponse {
+            directories: directories.clone(),
+            private_directories: private.clone(),
+        }
+        .write_message(&mut message).unwrap();
+        assert!(message.len() > 10 * 1500, "list should span many reads");
+
+        let addr = serve_in_chunks(message.clone()).await;
+        let browsed = browse_user(&TcpConnector, &addr, "me", 1, &BrowseOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(browsed.len(), directories.len() + 1);
+        for (got, want) in browsed.iter().zip(directories.iter().chain(&private)) {
+            assert_eq!(got.path, want.path);
+            assert_eq!(got.files.len(), want.files.len());
+            assert_eq!(got.files.last().unwrap().size, want.files.last().unwrap().size);
+        }
+
+        // The same list fails cleanly under a smaller cap instead of being truncated
+        let addr = serve_in_chunks(message).await;
+        let options = BrowseOptions {
+            max_bytes: 64 * 1024,
+            ..BrowseOptions::new()
+        };
+        match browse_user(&TcpConnector, &addr, "me", 2, &options).await {
+            Err(Error::MessageTooLarge { max, .. }) => assert_eq!(max, 64 * 1024),
+            other => panic!("expected the cap to be hit, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_slsk_url() {
+        assert_eq!(
+            parse_slsk_url("slsk://friend/Music/Artist/track.mp3").unwrap(),
+            SlskUrl {
+                username: "friend".to_string(),
+                path: "Music\\Artist\\track.mp3".to_string(),
+                size: None,
+            }
+        );
+        assert_eq!(
+            parse_slsk_url("slsk://friend/Music/track.flac?size=1234&from=web").unwrap(),
+            SlskUrl {
+                username: "friend".to_string(),
+                path: "Music\\track.flac".to_string(),
+                size: Some(1234),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_slsk_url_percent_encoded() {
+        let link =
+            parse_slsk_url("slsk://dj%20friend/Music/Caf%C3%A9%20Del%20Mar/01%20Intro.mp3")
+                .unwrap();
+        assert_eq!(link.username, "dj friend");
+        assert_eq!(link.path, "Music\\Café Del Mar\\01 Intro.mp3");
+    }
+
+    #[test]
+    fn test_parse_slsk_url_malformed() {
+        for url in [
+            "https://friend/track.mp3",
+            "slsk://friend",
+            "slsk:///track.mp3",
+            "slsk://friend/",
+            "slsk://friend/bad%zzpath",
+            "slsk://friend/cut%2",
+            "slsk://friend/signed%+5",
+            "slsk://friend/track.mp3?size=big",
+            "slsk://friend/track.mp3?size=-1",
+        ] {
+            assert!(
+                matches!(parse_slsk_url(url), Err(Error::InvalidUrl { .. })),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_response_builder_roundtrip() {
+        let msg = SearchResponseBuilder::for_token(99)
+            .as_user("uploader")
+            .add_file(SearchResultFile {
+                filename: "Music\\a.flac".to_string(),
+                size: 1000,
+                extension: "flac".to_string(),
+                attributes: vec![],
+            })
+            .slots_free(false)
+            .avg_speed(2048)
+            .queue_length(5)
+            .build();
+        let mut buf = BytesMut::new();
+        msg.write_message(&mut buf).unwrap();
+
+        match read_peer_message(&mut buf.freeze()).unwrap() {
+            PeerMessage::FileSearchResponse {
+                username,
+                token,
+                results,
+                slot_free,
+                avg_speed,
+                queue_length,
+                private_results,
+            } => {
+                assert_eq!(username, "uploader");
+                assert_eq!(token, 99);
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].filename, "Music\\a.flac");
+                assert!(!slot_free);
+                assert_eq!(avg_speed, 2048);
+                assert_eq!(queue_length, 5);
+                assert!(private_results.is_empty());
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_file_search_response_roundtrip() {
+        let file = |filename: &str, size, attributes| SearchResultFile {
+            filename: filename.to_string(),
+            size,
+            extension: "mp3".to_string(),
+            attributes,
+        };
+        let msg = PeerMessage::FileSearchResponse {
+            username: "uploader".to_string(),
+            token: 4242,
+            results: vec![
+                file(
+                    "Music\\Artist\\01 Song.mp3",
+                    5_000_000,
+                    vec![FileAttribute {
+                        code: FileAttributeType::Bitrate as u32,
+                        value: 320,
+                    }],
+                ),
+                file("Music\\Artist\\02 Song.mp3", 6_000_000, vec![]),
+            ],
+            slot_free: true,
+            avg_speed: 1024,
+            queue_length: 3,
+            private_results: vec![file("Private\\03 Song.mp3", 7_000_000, vec![])],
+        };
+        let mut buf = BytesMut::new();
+        msg.write_message(&mut buf).unwrap();
+
+        match read_peer_message(&mut buf.freeze()).unwrap() {
+            PeerMessage::FileSearchResponse {
+                username,
+                token,
+                results,
+                slot_free,
+                avg_speed,
+                queue_length,
+                private_results,
+            } => {
+                assert_eq!(username, "uploader");
+                assert_eq!(token, 4242);
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0].filename, "Music\\Artist\\01 Song.mp3");
+                assert_eq!(results[0].size, 5_000_000);
+                assert_eq!(results[0].attributes[0].value, 320);
+                assert_eq!(results[1].size, 6_000_000);
+                assert!(slot_free);
+                assert_eq!(avg_speed, 1024);
+                assert_eq!(queue_length, 3);
+                assert_eq!(private_results.len(), 1);
+                assert_eq!(private_results[0].filename, "Private\\03 Song.mp3");
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_search_shares() {
+        let shared = |filename: &str| SharedFile {
+            filename: filename.to_string(),
+            size: 100,
+            extension: "flac".to_string(),
+            attributes: vec![],
+        };
+        let directories = vec![
+            SharedDirectory {
+                path: "Music\\Pink Floyd".to_string(),
+                files: vec![shared("Time.flac"), shared("Money.flac")],
+            },
+            SharedDirectory {
+                path: "Music\\Other".to_string(),
+                files: vec![shared("Time.flac")],
+            },
+        ];
+
+        let results = search_shares(&directories, "floyd time");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, "Music\\Pink Floyd\\Time.flac");
+        assert!(search_shares(&directories, "").is_empty());
+    }
+
+    #[test]
+    fn test_filename_to_query_separators() {
+        assert_eq!(
+            filename_to_query("Daft_Punk-One.More.Time.mp3", QUERY_STOPWORDS),
+            "Daft Punk One More Time"
+        );
+        assert_eq!(
+            filename_to_query("Artist  -  Title .flac", QUERY_STOPWORDS),
+            "Artist Title"
+        );
+    }
+
+    #[test]
+    fn test_filename_to_query_strips_directories_and_extension() {
+        assert_eq!(
+            filename_to_query("@@music\\Artist\\Album\\Song.flac", QUERY_STOPWORDS),
+            "Song"
+        );
+        assert_eq!(
+            filename_to_query("music/Artist/Album/Song.ogg", QUERY_STOPWORDS),
+            "Song"
+        );
+        // A dot followed by a word with spaces is not an extension.
+        assert_eq!(
+            filename_to_query("Mr. Brightside", QUERY_STOPWORDS),
+            "Mr Brightside"
+        );
+    }
+
+    #[test]
+    fn test_filename_to_query_removes_codec_and_bitrate() {
+        assert_eq!(
+            filename_to_query("Artist - Title FLAC 24bit.flac", QUERY_STOPWORDS),
+            "Artist Title"
+        );
+        assert_eq!(
+            filename_to_query("Artist - Title - MP3 - 320.mp3", QUERY_STOPWORDS),
+            "Artist Title"
+        );
+        assert_eq!(
+            filename_to_query("Artist_Title_16BIT_wav.wav", QUERY_STOPWORDS),
+            "Artist Title"
+        );
+    }
+
+    #[test]
+    fn test_filename_to_query_track_numbers() {
+        assert_eq!(
+            filename_to_query("01 - Artist - Title.mp3", QUERY_STOPWORDS),
+            "Artist Title"
+        );
+        assert_eq!(
+            filename_to_query("1-07. Artist - Title.flac", QUERY_STOPWORDS),
+            "Artist Title"
+        );
+        // Numbers inside the title are kept.
+        assert_eq!(
+            filename_to_query("03 - The Smashing Pumpkins - 1979.mp3", QUERY_STOPWORDS),
+            "The Smashing Pumpkins 1979"
+        );
+        // As are leading numbers that aren't followed by a separator
+        assert_eq!(
+            filename_to_query("99 Luftballons.mp3", QUERY_STOPWORDS),
+            "99 Luftballons"
+        );
+        assert_eq!(
+            filename_to_query("07 - 99 Luftballons.mp3", QUERY_STOPWORDS),
+            "99 Luftballons"
+        );
+    }
+
+    #[test]
+    fn test_filename_to_query_preserves_unicode() {
+        assert_eq!(
+            filename_to_query("02_Sigur_Rós_-_Hoppípolla.flac", QUERY_STOPWORDS),
+            "Sigur Rós Hoppípolla"
+        );
+        assert_eq!(
+            filename_to_query("坂本龍一 - 戦場のメリークリスマス.mp3", QUERY_STOPWORDS),
+            "坂本龍一 戦場のメリークリスマス"
+        );
+    }
+
+    #[test]
+    fn test_filename_to_query_custom_stopwords() {
+        assert_eq!(
+            filename_to_query("Artist - Title (Remastered) flac.flac", &["(remastered)"]),
+            "Artist Title flac"
+        );
+        assert_eq!(
+            filename_to_query("Artist - Title 320.mp3", &[]),
+            "Artist Title 320"
+        );
+    }
+
+    #[test]
+    fn test_search_result_file_display() {
+        let mut file = ranked_file("Music\\Artist\\01 - Song.mp3", Some(320));
+        file.size = 8_388_608;
+        assert_eq!(file.to_string(), "Music\\Artist\\01 - Song.mp3 (8.0 MB, 320 kbps)");
+
+        let cover = SearchResultFile {
+            size: 51_200,
+            ..ranked_file("cover.jpg", None)
+        };
+        assert_eq!(cover.to_string(), "cover.jpg (50 KB)");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_shared_directory_json_roundtrip() {
+        let dir = SharedDirectory {
+            path: "Music\\Björk".to_string(),
+            files: vec![SharedFile {
+                filename: "Jóga.flac".to_string(),
+                size: 31_000_000,
+                extension: "flac".to_string(),
+                attributes: vec![FileAttribute { code: 1, value: 305 }],
+            }],
+        };
+
+        let json = serde_json::to_string(&dir).unwrap();
+        let back: SharedDirectory = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, dir);
+    }
+
+    #[test]
+    fn test_rank_prefers_flac_over_mp3() {
+        let results = vec![
+            ranked_file("a\\song.mp3", Some(320)),
+            ranked_file("b\\song.flac", None),
+            ranked_file("c\\song.mp3", Some(192)),
+        ];
+        let ranked = rank_search_results(results, &RankOptions::default());
+        let names: Vec<_> = ranked.iter().map(|f| f.filename.as_str()).collect();
+        assert_eq!(names, vec!["b\\song.flac", "a\\song.mp3", "c\\song.mp3"]);
+    }
+
+    #[test]
+    fn test_rank_unknown_bitrate_last() {
+        let results = vec![
+            ranked_file("unknown.mp3", None),
+            ranked_file("low.mp3", Some(128)),
+            ranked_file("cover.jpg", Some(999)),
+        ];
+        let ranked = rank_search_results(&results, &RankOptions::default());
+        let names: Vec<_> = ranked.iter().map(|f| f.filename.as_str()).collect();
+        assert_eq!(names, vec!["low.mp3", "unknown.mp3"]);
+
+        let options = RankOptions {
+            require_bitrate: true,
+            ..RankOptions::default()
+        };
+        assert_eq!(rank_search_results(&results, &options).len(), 1);
+    }
+
+    struct Offer {
+        file: SearchResultFile,
+        availability: PeerAvailability,
+    }
+
+    impl RankCandidate for Offer {
+        fn file(&self) -> &SearchResultFile {
+            &self.file
+        }
+
+        fn availability(&self) -> Option<PeerAvailability> {
+            Some(self.availability)
+        }
+    }
+
+    fn offer(
+        filename: &str,
+        bitrate: u32,
+        slot_free: bool,
+        queue_length: u32,
+        avg_speed: u32,
+    ) -> Offer {
+        Offer {
+            file: ranked_file(filename, Some(bitrate)),
+            availability: PeerAvailability {
+                slot_free,
+                queue_length,
+                avg_speed,
+            },
+        }
+    }
+
+    #[test]
+    fn test_rank_prefers_free_slot_when_enabled() {
+        let offers = vec![
+            offer("queued.mp3", 320, false, 40, 900_000),
+            offer("free.mp3", 256, true, 0, 100_000),
+        ];
+
+        let ranked = rank_search_results(&offers, &RankOptions::default());
+        assert_eq!(ranked[0].file.filename, "queued.mp3");
+
+        let options = RankOptions {
+            prefer_available: true,
+            ..RankOptions::default()
+        };
+        let ranked = rank_search_results(&offers, &options);
+        let names: Vec<_> = ranked.iter().map(|o| o.file.filename.as_str()).collect();
+        assert_eq!(names, vec!["free.mp3", "queued.mp3"]);
+    }
+
+    #[test]
+    fn test_rank_availability_tie_breaks() {
+        let offers = vec![
+            offer("long-queue.mp3", 320, false, 50, 900_000),
+            offer("short-queue.mp3", 320, false, 2, 100_000),
+            offer("slow.mp3", 320, true, 0, 50_000),
+            offer("fast.mp3", 320, true, 0, 500_000),
+            offer("low.mp3", 192, true, 0, 900_000),
+        ];
+        let options = RankOptions {
+            prefer_available: true,
+            ..RankOptions::default()
+        };
+
+        let ranked = rank_search_results(&offers, &options);
+        let names: Vec<_> = ranked.iter().map(|o| o.file.filename.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "fast.mp3",
+                "slow.mp3",
+                "low.mp3",
+                "short-queue.mp3",
+                "long-queue.mp3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rank_format_outranks_availability() {
+        let offers = vec![
+            offer("free.mp3", 320, true, 0, 500_000),
+            Offer {
+                file: ranked_file("queued.flac", None),
+                availability: PeerAvailability {
+                    slot_free: false,
+                    queue_length: 10,
+                    avg_speed: 0,
+                },
+            },
+        ];
+        let options = RankOptions {
+            prefer_available: true,
+            ..RankOptions::default()
+        };
+
+        let ranked = rank_search_results(&offers, &options);
+        assert_eq!(ranked[0].file.filename, "queued.flac");
+    }
+
+    #[test]
+    fn test_rank_size_limits() {
+        let mut small = ranked_file("small.mp3", Some(320));
+        small.size = 100;
+        let results = vec![small, ranked_file("big.mp3", Some(128))];
+        let options = RankOptions {
+            min_size: Some(1000),
+            max_size: Some(10_000_000),
+            ..RankOptions::default()
+        };
+        let ranked = rank_search_results(results, &options);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].filename, "big.mp3");
+    }
 }
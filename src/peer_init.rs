@@ -5,7 +5,9 @@
 use bytes::{Buf, BufMut};
 
 use crate::constants::ConnectionType;
-use crate::protocol::{MessageRead, MessageWrite, ProtocolRead, ProtocolWrite};
+use crate::protocol::{
+    FramedRead, MessageRead, MessageWrite, ProtocolRead, ProtocolWrite, read_framed,
+};
 use crate::{Error, Result};
 
 /// Peer init message codes.
@@ -103,14 +105,21 @@ impl MessageRead for PeerInitMessage {
 
 /// Read a peer init message from a buffer (including length prefix).
 pub fn read_peer_init_message<B: Buf>(buf: &mut B) -> Result<PeerInitMessage> {
-    let _len = u32::read_from(buf)?;
-    let code = PeerInitCode::try_from(u8::read_from(buf)?)?;
-    PeerInitMessage::read_with_code(code, buf)
+    read_framed(buf, |buf| {
+        let code = PeerInitCode::try_from(u8::read_from(buf)?)?;
+        PeerInitMessage::read_with_code(code, buf)
+    })
+}
+
+impl FramedRead for PeerInitMessage {
+    fn read_frame<B: Buf>(buf: &mut B) -> Result<Self> {
+        read_peer_init_message(buf)
+    }
 }
 
 /// Write a peer init message to a buffer (with length prefix and code).
-pub fn write_peer_init_message<B: BufMut>(msg: &PeerInitMessage, buf: &mut B) {
-    msg.write_message_u8(buf);
+pub fn write_peer_init_message<B: BufMut>(msg: &PeerInitMessage, buf: &mut B) -> Result<()> {
+    msg.write_message_u8(buf)
 }
 
 /// Check if the buffer contains a complete peer init message.
@@ -141,7 +150,7 @@ mod tests {
     fn test_pierce_firewall_roundtrip() {
         let msg = PeerInitMessage::PierceFirewall { token: 12345 };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
 
         let parsed = read_peer_init_message(&mut buf.freeze()).unwrap();
         match parsed {
@@ -158,7 +167,7 @@ mod tests {
             token: 0,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
 
         let parsed = read_peer_init_message(&mut buf.freeze()).unwrap();
         match parsed {
@@ -192,7 +201,7 @@ mod tests {
             token: 12345,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
 
         // Truncate to only have length + partial payload
         buf.truncate(6);
@@ -209,7 +218,7 @@ mod tests {
             token: 42,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
         // Append trailing "garbage" (simulating start of next message)
         buf.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0x02, 0x03, 0x04]);
 
@@ -237,7 +246,7 @@ mod tests {
             token: 1,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
         let complete_len = buf.len();
 
         // Empty buffer
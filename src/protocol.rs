@@ -4,10 +4,14 @@
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::io::{Read, Write};
+use std::marker::PhantomData;
 use std::net::Ipv4Addr;
 
 use crate::{Error, Result};
 
+/// Largest message frame we are willing to send, excluding the length prefix.
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
 /// Trait for reading protocol primitives from a buffer.
 pub trait ProtocolRead: Sized {
     fn read_from<B: Buf>(buf: &mut B) -> Result<Self>;
@@ -44,8 +48,19 @@ pub trait MessageWrite {
     /// Write the message contents (without length prefix or code).
     fn write_payload<B: BufMut>(&self, buf: &mut B);
 
+    /// Get the message code as a raw `u32`, e.g. for logging.
+    fn code_u32(&self) -> u32
+    where
+        Self::Code: Into<u32>,
+    {
+        self.code().into()
+    }
+
     /// Write a complete message with length prefix and code.
-    fn write_message<B: BufMut>(&self, buf: &mut B)
+    ///
+    /// Frames larger than [`MAX_MESSAGE_LEN`] are refused with
+    /// [`Error::MessageTooLarge`], and nothing is written to `buf`.
+    fn write_message<B: BufMut>(&self, buf: &mut B) -> Result<()>
     where
         Self::Code: Into<u32> + Copy,
     {
@@ -53,14 +68,17 @@ pub trait MessageWrite {
         self.write_payload(&mut payload);
 
         let code: u32 = self.code().into();
-        let total_len = 4 + payload.len(); // code (4 bytes) + payload
-        buf.put_u32_le(total_len as u32);
+        let total_len = frame_len(4 + payload.len())?; // code (4 bytes) + payload
+        buf.put_u32_le(total_len);
         buf.put_u32_le(code);
         buf.put_slice(&payload);
+        Ok(())
     }
 
     /// Write a complete message with u8 code (for peer init/distributed).
-    fn write_message_u8<B: BufMut>(&self, buf: &mut B)
+    ///
+    /// Size limits are the same as for [`MessageWrite::write_message`].
+    fn write_message_u8<B: BufMut>(&self, buf: &mut B) -> Result<()>
     where
         Self::Code: Into<u8> + Copy,
     {
@@ -68,10 +86,172 @@ pub trait MessageWrite {
         self.write_payload(&mut payload);
 
         let code: u8 = self.code().into();
-        let total_len = 1 + payload.len(); // code (1 byte) + payload
-        buf.put_u32_le(total_len as u32);
+        let total_len = frame_len(1 + payload.len())?; // code (1 byte) + payload
+        buf.put_u32_le(total_len);
         buf.put_u8(code);
         buf.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// Check an outgoing frame length against [`MAX_MESSAGE_LEN`].
+fn frame_len(len: usize) -> Result<u32> {
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::MessageTooLarge {
+            len,
+            max: MAX_MESSAGE_LEN,
+        });
+    }
+    Ok(len as u32)
+}
+
+/// Messages that can be parsed from a complete length-prefixed frame.
+pub trait FramedRead: Sized {
+    fn read_frame<B: Buf>(buf: &mut B) -> Result<Self>;
+}
+
+/// Splits a byte stream into length-prefixed frames without doing any IO itself.
+///
+/// Bytes can come from any source, blocking or async, so the decoder works with
+/// any runtime. Each frame is returned with its length prefix, ready for the
+/// `read_*_message` functions. Frames whose declared length exceeds
+/// [`MAX_MESSAGE_LEN`] are reported as [`Error::MessageTooLarge`] and the
+/// buffered bytes are discarded, since the stream cannot be resynchronised after that.
+///
+/// ```
+/// use std::io::Read;
+///
+/// use slsk_rs::MessageWrite;
+/// use slsk_rs::protocol::FrameDecoder;
+/// use slsk_rs::server::{ServerRequest, read_server_request};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut wire = Vec::new();
+/// ServerRequest::ServerPing.write_message(&mut wire)?;
+/// ServerRequest::SendUploadSpeed { speed: 1000 }.write_message(&mut wire)?;
+///
+/// // Any blocking reader will do; here a few bytes arrive at a time
+/// let mut reader = std::io::Cursor::new(wire);
+/// let mut decoder = FrameDecoder::new();
+/// let mut chunk = [0u8; 5];
+/// let mut requests = Vec::new();
+/// loop {
+///     let n = reader.read(&mut chunk)?;
+///     if n == 0 {
+///         break;
+///     }
+///     decoder.feed(&chunk[..n]);
+///     while let Some(frame) = decoder.next_frame() {
+///         requests.push(read_server_request(&mut frame?)?);
+///     }
+/// }
+/// assert_eq!(requests.len(), 2);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from bytes already read from the stream, e.g. after a handshake.
+    pub fn with_buffer(buf: BytesMut) -> Self {
+        Self { buf }
+    }
+
+    /// Append bytes received from the connection.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The underlying buffer, for reading into directly.
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Number of bytes buffered but not yet returned as a frame.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Give back any bytes not yet returned as a frame.
+    pub fn into_inner(self) -> BytesMut {
+        self.buf
+    }
+
+    /// Take the next complete frame, or `None` if more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Result<Bytes>> {
+        split_frame(&mut self.buf).map(|frame| frame.map(BytesMut::freeze))
+    }
+}
+
+/// Split the first complete frame, length prefix included, off the front of `buf`.
+///
+/// Returns `None` until the whole frame is buffered. A frame longer than
+/// [`MAX_MESSAGE_LEN`] is an error and clears `buf`, as [`FrameDecoder`] does.
+pub fn split_frame(buf: &mut BytesMut) -> Option<Result<BytesMut>> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len > MAX_MESSAGE_LEN {
+        buf.clear();
+        return Some(Err(Error::MessageTooLarge {
+            len,
+            max: MAX_MESSAGE_LEN,
+        }));
+    }
+    if buf.len() < 4 + len {
+        return None;
+    }
+    Some(Ok(buf.split_to(4 + len)))
+}
+
+/// Accumulates raw bytes and yields complete messages, for callers doing their own IO.
+///
+/// Framing is done by a [`FrameDecoder`], so oversized frames are reported the same way.
+#[derive(Debug)]
+pub struct MessageBuffer<T> {
+    frames: FrameDecoder,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> MessageBuffer<T> {
+    pub fn new() -> Self {
+        Self {
+            frames: FrameDecoder::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Append bytes received from the connection.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.frames.feed(data);
+    }
+
+    /// Number of bytes buffered but not yet returned as a message.
+    pub fn buffered(&self) -> usize {
+        self.frames.buffered()
+    }
+}
+
+impl<T: FramedRead> MessageBuffer<T> {
+    /// Take the next complete message, or `None` if more bytes are needed.
+    pub fn poll(&mut self) -> Option<Result<T>> {
+        self.frames
+            .next_frame()
+            .map(|frame| frame.and_then(|mut frame| T::read_frame(&mut frame)))
+    }
+}
+
+impl<T> Default for MessageBuffer<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -258,12 +438,22 @@ impl ProtocolWrite for Ipv4Addr {
     }
 }
 
-/// Compress data using zlib.
+/// zlib level used by [`zlib_compress`], balancing speed and size.
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// Compress data using zlib at the default level.
 pub fn zlib_compress(data: &[u8]) -> Result<Vec<u8>> {
+    zlib_compress_level(data, DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// Compress data using zlib at `level`, from 0 (store only) to 9 (smallest).
+///
+/// Levels above 9 are treated as 9.
+pub fn zlib_compress_level(data: &[u8], level: u32) -> Result<Vec<u8>> {
     use flate2::Compression;
     use flate2::write::ZlibEncoder;
 
-    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level.min(9)));
     encoder
         .write_all(data)
         .map_err(|e| Error::Compression(e.to_string()))?;
@@ -284,6 +474,89 @@ pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>> {
     Ok(decompressed)
 }
 
+/// Incremental zlib decompression, for bodies that arrive in pieces.
+pub struct ZlibStream {
+    inner: flate2::Decompress,
+    finished: bool,
+    max_out: Option<usize>,
+}
+
+impl ZlibStream {
+    pub fn new() -> Self {
+        Self {
+            inner: flate2::Decompress::new(true),
+            finished: false,
+            max_out: None,
+        }
+    }
+
+    /// Fail with [`Error::MessageTooLarge`] as soon as more than `max_out` bytes are produced.
+    pub fn with_limit(mut self, max_out: usize) -> Self {
+        self.max_out = Some(max_out);
+        self
+    }
+
+    /// Decompress `input`, appending the output to `out`.
+    ///
+    /// Returns whether the end of the zlib stream was reached; anything after
+    /// it in `input` is ignored.
+    pub fn feed(&mut self, mut input: &[u8], out: &mut BytesMut) -> Result<bool> {
+        use flate2::{FlushDecompress, Status};
+
+        let mut chunk = [0u8; 32 * 1024];
+        while !self.finished {
+            let (in_before, out_before) = (self.inner.total_in(), self.inner.total_out());
+            let status = self
+                .inner
+                .decompress(input, &mut chunk, FlushDecompress::None)
+                .map_err(|e| Error::Decompression(e.to_string()))?;
+            let consumed = (self.inner.total_in() - in_before) as usize;
+            let produced = (self.inner.total_out() - out_before) as usize;
+            input = &input[consumed..];
+            if let Some(max) = self.max_out
+                && self.inner.total_out() > max as u64
+            {
+                return Err(Error::MessageTooLarge {
+                    len: self.inner.total_out() as usize,
+                    max,
+                });
+            }
+            out.extend_from_slice(&chunk[..produced]);
+
+            match status {
+                Status::StreamEnd => self.finished = true,
+                _ if produced < chunk.len() && (input.is_empty() || consumed == 0) => break,
+                _ => {}
+            }
+        }
+        Ok(self.finished)
+    }
+
+    /// Total decompressed bytes produced so far.
+    pub fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Default for ZlibStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for ZlibStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZlibStream")
+            .field("total_out", &self.total_out())
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
 /// Generate MD5 hash of username + password for login.
 pub fn login_hash(username: &str, password: &str) -> String {
     let input = format!("{}{}", username, password);
@@ -291,6 +564,28 @@ pub fn login_hash(username: &str, password: &str) -> String {
     format!("{:x}", digest)
 }
 
+/// Read a length-prefixed message, checking the parser stayed within the frame.
+pub fn read_framed<B, T, F>(buf: &mut B, read_fn: F) -> Result<T>
+where
+    B: Buf,
+    F: FnOnce(&mut B) -> Result<T>,
+{
+    let declared = u32::read_from(buf)?;
+    let before = buf.remaining();
+    if before < declared as usize {
+        return Err(Error::BufferUnderflow {
+            needed: declared as usize,
+            available: before,
+        });
+    }
+    let msg = read_fn(buf)?;
+    let consumed = before - buf.remaining();
+    if consumed > declared as usize {
+        return Err(Error::LengthMismatch { declared, consumed });
+    }
+    Ok(msg)
+}
+
 /// Read a list of items from a buffer.
 pub fn read_list<B, T, F>(buf: &mut B, read_fn: F) -> Result<Vec<T>>
 where
@@ -317,6 +612,65 @@ where
     }
 }
 
+/// A search query split into what matching filenames must and must not contain.
+///
+/// Plain words are required terms, `-word` excludes filenames containing the word
+/// and `"quoted words"` must appear together as written. Parts keep their case
+/// but match as substrings ignoring it, so `beat` matches `Beatles`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub excluded: Vec<String>,
+    pub phrases: Vec<String>,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Self {
+        let mut parsed = SearchQuery::default();
+        let mut rest = query.trim_start();
+        while !rest.is_empty() {
+            if let Some(quoted) = rest.strip_prefix('"') {
+                // An unclosed quote runs to the end of the query
+                let (phrase, after) = quoted.split_once('"').unwrap_or((quoted, ""));
+                let phrase = phrase.trim();
+                if !phrase.is_empty() {
+                    parsed.phrases.push(phrase.to_string());
+                }
+                rest = after.trim_start();
+                continue;
+            }
+
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (word, after) = rest.split_at(end);
+            match word.strip_prefix('-') {
+                Some("") => {}
+                Some(excluded) => parsed.excluded.push(excluded.to_string()),
+                None => parsed.terms.push(word.to_string()),
+            }
+            rest = after.trim_start();
+        }
+        parsed
+    }
+
+    /// Whether nothing is required, in which case the query matches no filename.
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty() && self.phrases.is_empty()
+    }
+
+    /// The terms and phrases a matching filename must all contain.
+    pub fn required(&self) -> impl Iterator<Item = &str> {
+        self.terms.iter().chain(&self.phrases).map(String::as_str)
+    }
+
+    pub fn matches(&self, filename: &str) -> bool {
+        let filename = filename.to_lowercase();
+        let contains = |part: &str| filename.contains(&part.to_lowercase());
+        !self.is_empty()
+            && self.required().all(contains)
+            && !self.excluded.iter().any(|word| contains(word))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -358,4 +712,157 @@ mod tests {
         let decompressed = zlib_decompress(&compressed).unwrap();
         assert_eq!(decompressed, original);
     }
+
+    #[test]
+    fn test_zlib_stream_fed_byte_by_byte() {
+        let original: Vec<u8> = (0..100_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let compressed = zlib_compress(&original).unwrap();
+
+        let mut stream = ZlibStream::new();
+        let mut out = BytesMut::new();
+        let (last, body) = compressed.split_last().unwrap();
+        for byte in body {
+            assert!(!stream.feed(std::slice::from_ref(byte), &mut out).unwrap());
+        }
+        assert!(stream.feed(&[*last], &mut out).unwrap());
+        assert_eq!(&out[..], &original[..]);
+        assert_eq!(stream.total_out(), original.len() as u64);
+
+        // A limit stops inflation part way through a single large input
+        let mut stream = ZlibStream::new().with_limit(1000);
+        let mut out = BytesMut::new();
+        assert!(matches!(
+            stream.feed(&compressed, &mut out),
+            Err(Error::MessageTooLarge { max: 1000, .. })
+        ));
+        assert!(out.len() <= 1000);
+    }
+
+    #[test]
+    fn test_code_u32_matches_code() {
+        use crate::peer::PeerMessage;
+        use crate::server::{ServerRequest, ServerResponse};
+
+        let requests = [
+            ServerRequest::ServerPing,
+            ServerRequest::RoomList,
+            ServerRequest::FileSearch {
+                token: 1,
+                query: "q".to_string(),
+            },
+            ServerRequest::CantConnectToPeer {
+                token: 1,
+                username: "u".to_string(),
+            },
+        ];
+        for req in &requests {
+            assert_eq!(req.code_u32(), req.code() as u32);
+        }
+
+        let response = ServerResponse::CheckPrivileges { time_left: 0 };
+        assert_eq!(response.code_u32(), response.code() as u32);
+
+        let peer = PeerMessage::QueueUpload {
+            filename: "f".to_string(),
+        };
+        assert_eq!(peer.code_u32(), peer.code() as u32);
+        assert_eq!(PeerMessage::SharedFileListRequest.code_u32(), 4);
+    }
+
+    #[test]
+    fn test_frame_decoder_varied_chunk_sizes() {
+        use crate::server::{ServerRequest, read_server_request};
+
+        let requests = [
+            ServerRequest::ServerPing,
+            ServerRequest::FileSearch {
+                token: 7,
+                query: "boards of canada".to_string(),
+            },
+            ServerRequest::SharedFoldersFiles { dirs: 2, files: 30 },
+            ServerRequest::RoomList,
+        ];
+        let mut wire = BytesMut::new();
+        for req in &requests {
+            req.write_message(&mut wire).unwrap();
+        }
+
+        for chunk_size in [1, 3, 4, 7, 16, wire.len()] {
+            let mut decoder = FrameDecoder::new();
+            let mut frames = Vec::new();
+            for chunk in wire.chunks(chunk_size) {
+                decoder.feed(chunk);
+                while let Some(frame) = decoder.next_frame() {
+                    frames.push(frame.unwrap());
+                }
+            }
+            assert_eq!(decoder.buffered(), 0, "chunk size {chunk_size}");
+            assert_eq!(frames.len(), requests.len(), "chunk size {chunk_size}");
+
+            for (frame, req) in frames.iter().zip(&requests) {
+                let mut expected = BytesMut::new();
+                req.write_message(&mut expected).unwrap();
+                assert_eq!(frame[..], expected[..]);
+
+                let decoded = read_server_request(&mut frame.clone()).unwrap();
+                assert_eq!(decoded.code(), req.code());
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_decoder_partial_and_oversized() {
+        let mut decoder = FrameDecoder::with_buffer(BytesMut::from(&[8u8, 0, 0][..]));
+        assert!(decoder.next_frame().is_none());
+        decoder.feed(&[0, 1, 2, 3, 4]);
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(decoder.buffered(), 8);
+        decoder.feed(&[5, 6, 7, 8]);
+        assert_eq!(
+            decoder.next_frame().unwrap().unwrap()[..],
+            [8, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        decoder.feed(&(MAX_MESSAGE_LEN as u32 + 1).to_le_bytes());
+        assert!(matches!(
+            decoder.next_frame(),
+            Some(Err(Error::MessageTooLarge { .. }))
+        ));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_search_query_plain_terms() {
+        let query = SearchQuery::parse("  Aphex   windowlicker ");
+        assert_eq!(query.terms, ["Aphex", "windowlicker"]);
+        assert!(query.excluded.is_empty() && query.phrases.is_empty());
+
+        assert!(query.matches("Music\\Aphex Twin\\Windowlicker.flac"));
+        assert!(!query.matches("Music\\Aphex Twin\\Xtal.flac"));
+        assert!(!SearchQuery::parse("   ").matches("anything"));
+    }
+
+    #[test]
+    fn test_search_query_excluded_terms() {
+        let query = SearchQuery::parse("windowlicker -Remix -");
+        assert_eq!(query.terms, ["windowlicker"]);
+        assert_eq!(query.excluded, ["Remix"]);
+
+        assert!(query.matches("Windowlicker.flac"));
+        assert!(!query.matches("Windowlicker (RMX REMIX).flac"));
+        // Exclusions alone match nothing
+        assert!(!SearchQuery::parse("-remix").matches("Windowlicker.flac"));
+    }
+
+    #[test]
+    fn test_search_query_phrases() {
+        let query = SearchQuery::parse("\"Aphex Twin\" -live \"come to");
+        assert_eq!(query.phrases, ["Aphex Twin", "come to"]);
+        assert_eq!(query.excluded, ["live"]);
+        assert!(query.terms.is_empty());
+
+        assert!(query.matches("Aphex Twin - Come To Daddy.mp3"));
+        assert!(!query.matches("Twin Aphex - Come To Daddy.mp3"));
+        assert!(!query.matches("Aphex Twin - Come To Daddy (Live).mp3"));
+    }
 }
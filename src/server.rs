@@ -2,12 +2,33 @@
 //!
 //! Server messages are used by clients to interface with the Soulseek server.
 
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, BytesMut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::constants::{ConnectionType, LoginRejectionReason, ObfuscationType, UserStatus};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{
+    CLIENT_MINOR_VERSION, CLIENT_VERSION, ConnectionType, DEFAULT_SERVER_HOST,
+    DEFAULT_SERVER_PORT, LoginRejectionReason, ObfuscationType, UserStatus,
+};
+use crate::net::{Connector, TcpConnector, TimeoutConnector};
+use crate::peer::{
+    PeerAvailability, PeerMessage, RankCandidate, SearchResultFile, read_peer_message,
+};
+use crate::peer_init::{PeerInitMessage, write_peer_init_message};
 use crate::protocol::{
-    MessageRead, MessageWrite, ProtocolRead, ProtocolWrite, login_hash, read_list, write_list,
+    FrameDecoder, FramedRead, MessageRead, MessageWrite, ProtocolRead, ProtocolWrite, login_hash,
+    read_framed, read_list, split_frame, write_list,
 };
 use crate::{Error, Result};
 
@@ -222,7 +243,7 @@ pub struct RoomUser {
 }
 
 /// A possible parent for the distributed network.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PossibleParent {
     pub username: String,
     pub ip: Ipv4Addr,
@@ -246,11 +267,10 @@ pub enum ServerRequest {
         version: u32,
         minor_version: u32,
     },
-    /// Set the port we're listening on.
+    /// Set the port we're listening on, plus an obfuscated port if we have one.
     SetWaitPort {
         port: u32,
-        obfuscation_type: Option<ObfuscationType>,
-        obfuscated_port: Option<u32>,
+        obfuscation: Option<(ObfuscationType, u32)>,
     },
     /// Get a peer's address.
     GetPeerAddress { username: String },
@@ -440,13 +460,9 @@ impl MessageWrite for ServerRequest {
                 login_hash(username, password).write_to(buf);
                 minor_version.write_to(buf);
             }
-            ServerRequest::SetWaitPort {
-                port,
-                obfuscation_type,
-                obfuscated_port,
-            } => {
+            ServerRequest::SetWaitPort { port, obfuscation } => {
                 port.write_to(buf);
-                if let (Some(obs_type), Some(obs_port)) = (obfuscation_type, obfuscated_port) {
+                if let Some((obs_type, obs_port)) = obfuscation {
                     (*obs_type as u32).write_to(buf);
                     obs_port.write_to(buf);
                 }
@@ -596,6 +612,7 @@ pub enum ServerResponse {
         exists: bool,
         status: Option<UserStatus>,
         stats: Option<UserStats>,
+        /// `None` when the server didn't send one, whatever the user's status.
         country_code: Option<String>,
     },
     /// User status update.
@@ -784,9 +801,9 @@ impl MessageRead for ServerResponse {
                 } else {
                     let reason_str = String::read_from(buf)?;
                     let reason = LoginRejectionReason::from_string(reason_str.clone());
-                    let detail = if matches!(reason, LoginRejectionReason::InvalidUsername)
-                        && buf.has_remaining()
-                    {
+                    // Only INVALIDUSERNAME carries a detail in practice, but accept it
+                    // after any reason so what we write always reads back.
+                    let detail = if buf.has_remaining() {
                         Some(String::read_from(buf)?)
                     } else {
                         None
@@ -814,7 +831,7 @@ impl MessageRead for ServerResponse {
                 if exists {
                     let status = UserStatus::try_from(u32::read_from(buf)?)?;
                     let stats = UserStats::read_from(buf)?;
-                    let country_code = if status != UserStatus::Offline && buf.has_remaining() {
+                    let country_code = if buf.has_remaining() {
                         Some(String::read_from(buf)?)
                     } else {
                         None
@@ -924,8 +941,14 @@ impl MessageRead for ServerResponse {
                 let port = u32::read_from(buf)?;
                 let token = u32::read_from(buf)?;
                 let privileged = bool::read_from(buf)?;
-                let obfuscation_type = ObfuscationType::try_from(u32::read_from(buf)?)?;
-                let obfuscated_port = u32::read_from(buf)?;
+                // Older servers end the message before the obfuscation fields
+                let (obfuscation_type, obfuscated_port) = if buf.has_remaining() {
+                    let obs = ObfuscationType::try_from(u32::read_from(buf)?)?;
+                    let obs_port = u32::read_from(buf)?;
+                    (obs, obs_port)
+                } else {
+                    (ObfuscationType::None, 0)
+                };
                 Ok(ServerResponse::ConnectToPeer {
                     username,
                     connection_type,
@@ -1223,27 +1246,49 @@ impl MessageRead for ServerResponse {
             | ServerCode::MessageUsers
             | ServerCode::JoinGlobalRoom
             | ServerCode::LeaveGlobalRoom
-            | ServerCode::MessageAcked => Err(Error::Protocol(format!(
-                "Server code {:?} is send-only, not expected in response",
-                code
-            ))),
+            | ServerCode::MessageAcked => Err(Error::UnexpectedCode {
+                code: code as u32,
+                context: "send-only server code in response",
+            }),
         }
     }
 }
 
 /// Read a server message from a buffer (including length prefix).
 pub fn read_server_message<B: Buf>(buf: &mut B) -> Result<ServerResponse> {
-    let _len = u32::read_from(buf)?;
-    let code = ServerCode::try_from(u32::read_from(buf)?)?;
-    ServerResponse::read_with_code(code, buf)
+    read_framed(buf, |buf| {
+        let code = ServerCode::try_from(u32::read_from(buf)?)?;
+        ServerResponse::read_with_code(code, buf)
+    })
+}
+
+/// Parse every complete message in `buf`, leaving a trailing partial message buffered.
+///
+/// Each message is split off as the iterator advances, so stopping early keeps
+/// the rest in `buf` for later.
+pub fn drain_messages(buf: &mut BytesMut) -> impl Iterator<Item = Result<ServerResponse>> + '_ {
+    std::iter::from_fn(move || split_frame(buf).map(|frame| read_server_message(&mut frame?)))
+}
+
+impl FramedRead for ServerResponse {
+    fn read_frame<B: Buf>(buf: &mut B) -> Result<Self> {
+        read_server_message(buf)
+    }
 }
 
 /// Read a server request from a buffer (including length prefix).
 /// Used by server implementations to parse client messages.
 pub fn read_server_request<B: Buf>(buf: &mut B) -> Result<ServerRequest> {
-    let _len = u32::read_from(buf)?;
-    let code = ServerCode::try_from(u32::read_from(buf)?)?;
-    ServerRequest::read_with_code(code, buf)
+    read_framed(buf, |buf| {
+        let code = ServerCode::try_from(u32::read_from(buf)?)?;
+        ServerRequest::read_with_code(code, buf)
+    })
+}
+
+impl FramedRead for ServerRequest {
+    fn read_frame<B: Buf>(buf: &mut B) -> Result<Self> {
+        read_server_request(buf)
+    }
 }
 
 impl MessageRead for ServerRequest {
@@ -1266,18 +1311,14 @@ impl MessageRead for ServerRequest {
             }
             ServerCode::SetWaitPort => {
                 let port = u32::read_from(buf)?;
-                let (obfuscation_type, obfuscated_port) = if buf.has_remaining() {
+                let obfuscation = if buf.has_remaining() {
                     let obs = ObfuscationType::try_from(u32::read_from(buf)?)?;
                     let obs_port = u32::read_from(buf)?;
-                    (Some(obs), Some(obs_port))
+                    Some((obs, obs_port))
                 } else {
-                    (None, None)
+                    None
                 };
-                Ok(ServerRequest::SetWaitPort {
-                    port,
-                    obfuscation_type,
-                    obfuscated_port,
-                })
+                Ok(ServerRequest::SetWaitPort { port, obfuscation })
             }
             ServerCode::GetPeerAddress => {
                 let username = String::read_from(buf)?;
@@ -1486,10 +1527,10 @@ impl MessageRead for ServerRequest {
                 Ok(ServerRequest::CantConnectToPeer { token, username })
             }
             // Response-only codes
-            _ => Err(Error::Protocol(format!(
-                "Server code {:?} is response-only, not expected in request",
-                code
-            ))),
+            _ => Err(Error::UnexpectedCode {
+                code: code as u32,
+                context: "response-only server code in request",
+            }),
         }
     }
 }
@@ -1688,18 +1729,11 @@ impl MessageWrite for ServerResponse {
                 write_list(buf, hates, |b, h| h.write_to(b));
             }
             ServerResponse::RoomList { rooms, owned_private_rooms, private_rooms, operated_private_rooms } => {
-                write_list(buf, rooms, |b, (name, count)| {
-                    name.write_to(b);
-                    count.write_to(b);
-                });
-                write_list(buf, owned_private_rooms, |b, (name, count)| {
-                    name.write_to(b);
-                    count.write_to(b);
-                });
-                write_list(buf, private_rooms, |b, (name, count)| {
-                    name.write_to(b);
-                    count.write_to(b);
-                });
+                // Names and user counts are sent as two parallel lists
+                for list in [rooms, owned_private_rooms, private_rooms] {
+                    write_list(buf, list, |b, (name, _)| name.write_to(b));
+                    write_list(buf, list, |b, (_, count)| count.write_to(b));
+                }
                 write_list(buf, operated_private_rooms, |b, name| name.write_to(b));
             }
             ServerResponse::AdminMessage { message } => {
@@ -1826,36 +1860,4080 @@ impl MessageWrite for ServerResponse {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bytes::BytesMut;
+/// Where a peer listens, as reported by a `GetPeerAddress` response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddress {
+    pub ip: Ipv4Addr,
+    pub port: u32,
+    pub obfuscation_type: ObfuscationType,
+    pub obfuscated_port: u16,
+}
 
-    #[test]
-    fn test_login_request() {
-        let req = ServerRequest::Login {
-            username: "testuser".to_string(),
-            password: "testpass".to_string(),
-            version: 160,
-            minor_version: 1,
-        };
+impl PeerAddress {
+    /// The user and address carried by a `GetPeerAddress` response.
+    pub fn from_response(response: &ServerResponse) -> Option<(&str, PeerAddress)> {
+        match response {
+            ServerResponse::GetPeerAddress {
+                username,
+                ip,
+                port,
+                obfuscation_type,
+                obfuscated_port,
+            } => Some((
+                username,
+                PeerAddress {
+                    ip: *ip,
+                    port: *port,
+                    obfuscation_type: *obfuscation_type,
+                    obfuscated_port: *obfuscated_port,
+                },
+            )),
+            _ => None,
+        }
+    }
+
+    /// Whether the peer can't be reached: the server answers `0.0.0.0` for offline
+    /// users, and a port of 0 means the peer never told it where it listens.
+    pub fn is_offline(&self) -> bool {
+        self.ip.is_unspecified() || self.port == 0
+    }
+
+    /// Pick the port to dial and the framing to use on it.
+    ///
+    /// The obfuscated port is preferred whenever the peer advertises one with an
+    /// obfuscation type in `supported`; otherwise the plain port is used.
+    pub fn select(&self, supported: &[ObfuscationType]) -> (u32, ObfuscationType) {
+        if self.obfuscation_type != ObfuscationType::None
+            && self.obfuscated_port != 0
+            && supported.contains(&self.obfuscation_type)
+        {
+            (u32::from(self.obfuscated_port), self.obfuscation_type)
+        } else {
+            (self.port, ObfuscationType::None)
+        }
+    }
+}
+
+/// How long a looked-up peer address is trusted before asking the server again.
+pub const ADDRESS_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Recently looked-up peer addresses, to save `GetPeerAddress` round-trips.
+///
+/// Entries expire after the TTL, since users get new addresses when they
+/// reconnect. Offline answers are never cached.
+#[derive(Debug)]
+pub struct AddressCache<C = SystemClock> {
+    ttl: Duration,
+    entries: HashMap<String, (PeerAddress, Instant)>,
+    clock: C,
+}
+
+impl Default for AddressCache {
+    fn default() -> Self {
+        Self::with_ttl(ADDRESS_CACHE_TTL)
+    }
+}
+
+impl AddressCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<C: Clock> AddressCache<C> {
+    /// Read the time from `clock` instead of the system clock.
+    pub fn with_clock<D: Clock>(self, clock: D) -> AddressCache<D> {
+        AddressCache {
+            ttl: self.ttl,
+            entries: self.entries,
+            clock,
+        }
+    }
+
+    /// The cached address of `username`, if it has not expired.
+    pub fn get(&self, username: &str) -> Option<PeerAddress> {
+        self.entries
+            .get(username)
+            .filter(|(_, fetched)| self.clock.now().duration_since(*fetched) < self.ttl)
+            .map(|(address, _)| *address)
+    }
+
+    /// Remember `address` for `username`, or forget them if it says they are offline.
+    pub fn insert(&mut self, username: &str, address: PeerAddress) {
+        if address.is_offline() {
+            self.entries.remove(username);
+        } else {
+            self.entries
+                .insert(username.to_string(), (address, self.clock.now()));
+        }
+    }
+
+    /// Forget `username`'s address, e.g. after failing to connect to it.
+    pub fn invalidate(&mut self, username: &str) {
+        self.entries.remove(username);
+    }
+
+    /// Update from a server message. Returns true if it carried a peer address.
+    pub fn apply(&mut self, response: &ServerResponse) -> bool {
+        match PeerAddress::from_response(response) {
+            Some((username, address)) => {
+                self.insert(username, address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop expired entries, returning how many were removed.
+    pub fn prune(&mut self) -> usize {
+        let now = self.clock.now();
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, (_, fetched)| now.duration_since(*fetched) < self.ttl);
+        before - self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The cached address of `username`, asking the server over `conn` if it is missing or stale.
+    pub async fn get_or_fetch(
+        &mut self,
+        username: &str,
+        conn: &mut ServerConnection,
+    ) -> Result<PeerAddress> {
+        if let Some(address) = self.get(username) {
+            return Ok(address);
+        }
+        let address = conn.get_peer_address(username).await?;
+        self.insert(username, address);
+        Ok(address)
+    }
+}
+
+/// How the password hash in the login message is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum HashScheme {
+    /// MD5 of username + password, as the official server expects.
+    #[default]
+    Md5,
+    /// Empty hash, for servers that don't check it.
+    None,
+}
+
+/// Connection settings for a Soulseek-compatible server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ServerProfile {
+    pub name: String,
+    pub host: String,
+    #[cfg_attr(feature = "serde", serde(default = "default_server_port"))]
+    pub port: u16,
+    #[cfg_attr(feature = "serde", serde(default = "default_client_version"))]
+    pub version: u32,
+    #[cfg_attr(feature = "serde", serde(default = "default_client_minor_version"))]
+    pub minor_version: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hash_scheme: HashScheme,
+}
+
+#[cfg(feature = "serde")]
+fn default_server_port() -> u16 {
+    DEFAULT_SERVER_PORT
+}
+
+#[cfg(feature = "serde")]
+fn default_client_version() -> u32 {
+    CLIENT_VERSION
+}
+
+#[cfg(feature = "serde")]
+fn default_client_minor_version() -> u32 {
+    CLIENT_MINOR_VERSION
+}
+
+impl Default for ServerProfile {
+    fn default() -> Self {
+        ServerProfile {
+            name: "soulseek".to_string(),
+            host: DEFAULT_SERVER_HOST.to_string(),
+            port: DEFAULT_SERVER_PORT,
+            version: CLIENT_VERSION,
+            minor_version: CLIENT_MINOR_VERSION,
+            hash_scheme: HashScheme::Md5,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ProfileFile {
+    #[serde(default, rename = "profile")]
+    profiles: Vec<ServerProfile>,
+}
+
+impl ServerProfile {
+    /// Parse `[[profile]]` tables from a TOML config.
+    #[cfg(feature = "serde")]
+    pub fn load_profiles(toml: &str) -> Result<Vec<ServerProfile>> {
+        let file: ProfileFile = toml::from_str(toml).map_err(|e| Error::Config(e.to_string()))?;
+        Ok(file.profiles)
+    }
+
+    /// Find a profile by name.
+    pub fn find<'a>(profiles: &'a [ServerProfile], name: &str) -> Option<&'a ServerProfile> {
+        profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Write a login message using this profile's version and hash scheme.
+    pub fn write_login<B: BufMut>(&self, username: &str, password: &str, buf: &mut B) {
+        let mut payload = BytesMut::new();
+        username.write_to(&mut payload);
+        password.write_to(&mut payload);
+        self.version.write_to(&mut payload);
+        match self.hash_scheme {
+            HashScheme::Md5 => login_hash(username, password).write_to(&mut payload),
+            HashScheme::None => "".write_to(&mut payload),
+        }
+        self.minor_version.write_to(&mut payload);
+
+        buf.put_u32_le(4 + payload.len() as u32);
+        buf.put_u32_le(ServerCode::Login.into());
+        buf.put_slice(&payload);
+    }
+}
+
+/// Details returned by the server on a successful login.
+#[derive(Debug, Clone)]
+pub struct LoginSuccess {
+    pub greet: String,
+    pub own_ip: Ipv4Addr,
+    pub password_hash: String,
+    pub is_supporter: bool,
+}
+
+/// An async connection to a Soulseek server.
+///
+/// Owns the TCP stream and handles message framing.
+///
+/// ```no_run
+/// use slsk_rs::server::{ServerConnection, ServerRequest, ServerResponse};
+///
+/// # async fn run() -> slsk_rs::Result<()> {
+/// let mut conn = ServerConnection::connect("server.slsknet.org", 2242).await?;
+/// let login = conn.login("username", "password").await?;
+/// println!("{}", login.greet);
+///
+/// conn.send(&ServerRequest::RoomList).await?;
+/// loop {
+///     if let ServerResponse::RoomList { rooms, .. } = conn.next_message().await? {
+///         println!("{} rooms", rooms.len());
+///         break;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ServerConnection {
+    stream: TcpStream,
+    frames: FrameDecoder,
+    profile: ServerProfile,
+    rooms: RoomState,
+    /// Messages read while waiting for a peer address, returned before any new ones.
+    deferred: VecDeque<ServerResponse>,
+}
+
+impl ServerConnection {
+    /// Connect to a server using the default protocol version.
+    pub async fn connect(host: &str, port: u16) -> Result<Self> {
+        Self::connect_profile(ServerProfile {
+            host: host.to_string(),
+            port,
+            ..ServerProfile::default()
+        })
+        .await
+    }
+
+    /// Connect to the server described by a profile.
+    pub async fn connect_profile(profile: ServerProfile) -> Result<Self> {
+        let addr = format!("{}:{}", profile.host, profile.port);
+        let stream = TimeoutConnector::default().connect(&addr).await?;
+        Ok(Self::from_stream(stream, profile))
+    }
+
+    /// Wrap an already connected stream.
+    pub fn from_stream(stream: TcpStream, profile: ServerProfile) -> Self {
+        ServerConnection {
+            stream,
+            frames: FrameDecoder::with_buffer(BytesMut::with_capacity(65536)),
+            profile,
+            rooms: RoomState::new(),
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// Rooms joined on this connection, as seen through [`Self::next_message`].
+    pub fn rooms(&self) -> &RoomState {
+        &self.rooms
+    }
 
+    pub fn profile(&self) -> &ServerProfile {
+        &self.profile
+    }
+
+    /// Take back the stream and any bytes already buffered from it.
+    ///
+    /// Messages deferred by [`Self::get_peer_address`] are dropped.
+    pub fn into_parts(self) -> (TcpStream, BytesMut) {
+        (self.stream, self.frames.into_inner())
+    }
+
+    /// Log in using the profile's protocol version, skipping other messages until the response.
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<LoginSuccess> {
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        self.profile.write_login(username, password, &mut buf);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
 
-        // Verify it can be written without panic
-        assert!(buf.len() > 8);
+        loop {
+            match self.next_message().await? {
+                ServerResponse::LoginSuccess {
+                    greet,
+                    own_ip,
+                    password_hash,
+                    is_supporter,
+                } => {
+                    return Ok(LoginSuccess {
+                        greet,
+                        own_ip,
+                        password_hash,
+                        is_supporter,
+                    });
+                }
+                ServerResponse::LoginFailure { reason, detail } => {
+                    return Err(Error::LoginFailed { reason, detail });
+                }
+                _ => {}
+            }
+        }
     }
 
-    #[test]
-    fn test_file_search_request() {
-        let req = ServerRequest::FileSearch {
-            token: 12345,
-            query: "test query".to_string(),
-        };
+    /// Send a request to the server.
+    pub async fn send(&mut self, request: &ServerRequest) -> Result<()> {
+        let mut buf = BytesMut::new();
+        request.write_message(&mut buf)?;
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// The `SetStatus` + `SetWaitPort` pair a client sends right after logging in, in order.
+    ///
+    /// `listen_port` must be a port we have actually bound for incoming peer connections
+    /// (e.g. `listener.local_addr()?.port()` after binding port 0); the server hands it
+    /// to peers in `GetPeerAddress` and `ConnectToPeer`, so a stale port leaves us unreachable.
+    pub fn announce_requests(listen_port: u16, status: UserStatus) -> [ServerRequest; 2] {
+        [
+            ServerRequest::SetStatus { status },
+            ServerRequest::SetWaitPort {
+                port: listen_port as u32,
+                obfuscation: None,
+            },
+        ]
+    }
 
+    /// Announce our status and listen port after login. See [`Self::announce_requests`].
+    pub async fn announce(&mut self, listen_port: u16, status: UserStatus) -> Result<()> {
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
-        assert!(buf.len() > 8);
+        for request in Self::announce_requests(listen_port, status) {
+            request.write_message(&mut buf)?;
+        }
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// What to send before closing so we don't linger online or in `rooms`.
+    ///
+    /// Going offline comes first, then leaving each room in name order.
+    pub fn shutdown_requests<'a>(rooms: impl IntoIterator<Item = &'a str>) -> Vec<ServerRequest> {
+        let mut rooms: Vec<&str> = rooms.into_iter().collect();
+        rooms.sort_unstable();
+        std::iter::once(ServerRequest::SetStatus {
+            status: UserStatus::Offline,
+        })
+        .chain(rooms.into_iter().map(|room| ServerRequest::LeaveRoom {
+            room: room.to_string(),
+        }))
+        .collect()
+    }
+
+    /// Go offline, leave every joined room and close the connection.
+    ///
+    /// Dropping the connection closes it without telling the server, which then
+    /// shows us online until it notices; this can't happen in `Drop` as it needs
+    /// to await the writes.
+    pub async fn disconnect(mut self) -> Result<()> {
+        let mut buf = BytesMut::new();
+        for request in Self::shutdown_requests(self.rooms.rooms()) {
+            request.write_message(&mut buf)?;
+        }
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Start a search under a fresh token from `searches` and return the token.
+    ///
+    /// The server sends nothing back for `FileSearch` or `UserSearch`; results arrive
+    /// from peers as `FileSearchResponse`s carrying the token, to be matched with
+    /// [`TokenRegistry::match_search_response`].
+    pub async fn start_search(
+        &mut self,
+        searches: &mut TokenRegistry<PendingSearch>,
+        search: PendingSearch,
+    ) -> Result<u32> {
+        let token = searches.next_token();
+        self.send(&search.request(token)).await?;
+        searches.register(token, search);
+        Ok(token)
+    }
+
+    /// Ask the server where `username` listens.
+    ///
+    /// Other messages that arrive first are kept for [`Self::next_message`],
+    /// and frames that fail to decode are skipped. An offline user comes back as
+    /// an address with an unspecified IP; see [`PeerAddress::is_offline`]. Fails
+    /// with [`io::ErrorKind::TimedOut`] if no answer arrives within
+    /// [`PEER_ADDRESS_TIMEOUT`].
+    pub async fn get_peer_address(&mut self, username: &str) -> Result<PeerAddress> {
+        self.send(&ServerRequest::GetPeerAddress {
+            username: username.to_string(),
+        })
+        .await?;
+
+        let wait = async {
+            loop {
+                let response = match self.read_message().await {
+                    Ok(response) => response,
+                    Err(e @ Error::Io(_)) => return Err(e),
+                    // The undecodable frame was consumed, so carry on with the next one
+                    Err(_) => continue,
+                };
+                if let Some((name, address)) = PeerAddress::from_response(&response)
+                    && name == username
+                {
+                    return Ok(address);
+                }
+                self.deferred.push_back(response);
+            }
+        };
+        match tokio::time::timeout(PEER_ADDRESS_TIMEOUT, wait).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no address for {username} after {PEER_ADDRESS_TIMEOUT:?}"),
+            ))),
+        }
+    }
+
+    /// Wait for the next complete message from the server.
+    pub async fn next_message(&mut self) -> Result<ServerResponse> {
+        match self.deferred.pop_front() {
+            Some(response) => Ok(response),
+            None => self.read_message().await,
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<ServerResponse> {
+        loop {
+            if let Some(frame) = self.frames.next_frame() {
+                let response = read_server_message(&mut frame?)?;
+                self.rooms.apply(&response);
+                return Ok(response);
+            }
+
+            let n = self.stream.read_buf(self.frames.buffer_mut()).await?;
+            if n == 0 {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection",
+                )));
+            }
+        }
+    }
+}
+
+/// Server messages read from any async reader, such as the read half of a split stream.
+///
+/// Partially received frames stay buffered across calls, so a timed-out read
+/// loses nothing and the next call picks up where it left off.
+#[derive(Debug)]
+pub struct MessageStream<R> {
+    reader: R,
+    frames: FrameDecoder,
+}
+
+impl<R: AsyncRead + Unpin> MessageStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_buffer(reader, BytesMut::new())
+    }
+
+    /// Continue from bytes already read, e.g. from [`ServerConnection::into_parts`].
+    pub fn with_buffer(reader: R, buf: BytesMut) -> Self {
+        Self {
+            reader,
+            frames: FrameDecoder::with_buffer(buf),
+        }
+    }
+
+    /// Wait for the next message, or `None` once the peer closes the stream cleanly.
+    ///
+    /// A stream that ends partway through a frame is an error.
+    pub async fn next_message(&mut self) -> Result<Option<ServerResponse>> {
+        loop {
+            if let Some(frame) = self.frames.next_frame() {
+                return read_server_message(&mut frame?).map(Some);
+            }
+
+            if self.reader.read_buf(self.frames.buffer_mut()).await? == 0 {
+                if self.frames.buffered() == 0 {
+                    return Ok(None);
+                }
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream closed mid-message",
+                )));
+            }
+        }
+    }
+
+    /// Like [`next_message`](Self::next_message), failing with
+    /// [`io::ErrorKind::TimedOut`] if no complete message arrives within `timeout`.
+    pub async fn next_message_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<ServerResponse>> {
+        match tokio::time::timeout(timeout, self.next_message()).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no complete message before timeout",
+            ))),
+        }
+    }
+
+    /// Give back the reader and any bytes not yet returned as a message.
+    pub fn into_parts(self) -> (R, BytesMut) {
+        (self.reader, self.frames.into_inner())
+    }
+}
+
+/// How long [`ServerConnection::get_peer_address`] waits for the server to answer.
+pub const PEER_ADDRESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the login response before giving up on an attempt.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Retry schedule for connecting to a server.
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    pub max_attempts: u32,
+    /// Delay after the first ordinary failure, doubled on each further one.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Delay per attempt when the server appears to be rate limiting us.
+    pub rate_limit_delay: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+            rate_limit_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Delay before retrying after `attempt` (1-based) failed with `error`.
+    pub fn delay_for(&self, attempt: u32, error: &Error) -> Duration {
+        if is_rate_limited(error) {
+            self.rate_limit_delay * attempt
+        } else {
+            let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+            self.base_delay.saturating_mul(factor).min(self.max_delay)
+        }
+    }
+}
+
+/// The server drops connections without a reply when it rate limits logins.
+fn is_rate_limited(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Io(e) if matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof
+        )
+    )
+}
+
+/// Run `attempt` until it succeeds, sleeping between failures per `config`.
+///
+/// Rejected logins are returned immediately since retrying cannot fix them.
+/// `on_retry` gets the failed attempt, its error and the delay before the next
+/// one, so callers can report progress.
+pub async fn retry_with_backoff<T, F, Fut, R>(
+    config: &BackoffConfig,
+    mut attempt: F,
+    mut on_retry: R,
+) -> Result<T>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+    R: FnMut(u32, &Error, Duration),
+{
+    let mut n = 0;
+    loop {
+        n += 1;
+        match attempt(n).await {
+            Ok(value) => return Ok(value),
+            Err(e @ Error::LoginFailed { .. }) => return Err(e),
+            Err(e) if n >= config.max_attempts => return Err(e),
+            Err(e) => {
+                let delay = config.delay_for(n, &e);
+                on_retry(n, &e, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Connect and log in to the server in `profile`, retrying with backoff.
+///
+/// See [`retry_with_backoff`] for `on_retry`.
+pub async fn connect_with_backoff(
+    profile: &ServerProfile,
+    username: &str,
+    password: &str,
+    config: &BackoffConfig,
+    on_retry: impl FnMut(u32, &Error, Duration),
+) -> Result<(ServerConnection, LoginSuccess)> {
+    retry_with_backoff(
+        config,
+        |_| async {
+            let mut conn = ServerConnection::connect_profile(profile.clone()).await?;
+            let login = tokio::time::timeout(LOGIN_TIMEOUT, conn.login(username, password))
+                .await
+                .map_err(|_| {
+                    Error::Io(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for login response",
+                    ))
+                })??;
+            Ok((conn, login))
+        },
+        on_retry,
+    )
+    .await
+}
+
+/// Counts of messages handed to a [`MeteredSender`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendStats {
+    /// Messages sent since the channel was created.
+    pub messages: u64,
+    /// Bytes sent since the channel was created.
+    pub bytes: u64,
+    /// Messages sent but not yet taken by the [`MeteredReceiver`].
+    pub queued_messages: u64,
+    /// Bytes sent but not yet taken by the [`MeteredReceiver`].
+    pub queued_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct SendCounters {
+    sent_messages: AtomicU64,
+    sent_bytes: AtomicU64,
+    received_messages: AtomicU64,
+    received_bytes: AtomicU64,
+}
+
+/// An outgoing message channel that counts what passes through it.
+///
+/// Cloned senders share counters, so [`MeteredSender::stats`] covers every
+/// producer. A growing queue means the writer can't keep up with them.
+#[derive(Debug, Clone)]
+pub struct MeteredSender {
+    tx: mpsc::UnboundedSender<BytesMut>,
+    counters: Arc<SendCounters>,
+}
+
+/// The writer's end of a [`MeteredSender`].
+#[derive(Debug)]
+pub struct MeteredReceiver {
+    rx: mpsc::UnboundedReceiver<BytesMut>,
+    counters: Arc<SendCounters>,
+}
+
+impl MeteredSender {
+    /// A new metered channel.
+    pub fn channel() -> (MeteredSender, MeteredReceiver) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let counters = Arc::new(SendCounters::default());
+        (
+            MeteredSender {
+                tx,
+                counters: counters.clone(),
+            },
+            MeteredReceiver { rx, counters },
+        )
+    }
+
+    /// Queue a message; it is only counted if the receiver is still there.
+    pub fn send(
+        &self,
+        buf: BytesMut,
+    ) -> std::result::Result<(), mpsc::error::SendError<BytesMut>> {
+        let len = buf.len() as u64;
+        self.tx.send(buf)?;
+        self.counters.sent_messages.fetch_add(1, Ordering::Relaxed);
+        self.counters.sent_bytes.fetch_add(len, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    pub fn stats(&self) -> SendStats {
+        self.counters.snapshot()
+    }
+}
+
+impl MeteredReceiver {
+    /// Take the next message, or `None` once every sender is gone.
+    pub async fn recv(&mut self) -> Option<BytesMut> {
+        let buf = self.rx.recv().await?;
+        self.counters.received_messages.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .received_bytes
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Some(buf)
+    }
+
+    pub fn stats(&self) -> SendStats {
+        self.counters.snapshot()
+    }
+}
+
+impl SendCounters {
+    fn snapshot(&self) -> SendStats {
+        // Read the receive side first so queued counts never go negative
+        let received_messages = self.received_messages.load(Ordering::Relaxed);
+        let received_bytes = self.received_bytes.load(Ordering::Relaxed);
+        let messages = self.sent_messages.load(Ordering::Relaxed);
+        let bytes = self.sent_bytes.load(Ordering::Relaxed);
+        SendStats {
+            messages,
+            bytes,
+            queued_messages: messages.saturating_sub(received_messages),
+            queued_bytes: bytes.saturating_sub(received_bytes),
+        }
+    }
+}
+
+/// Searches the official server accepts per [`SEARCH_RATE_LIMIT_WINDOW`].
+pub const SEARCH_RATE_LIMIT_MAX: usize = 34;
+
+/// Window over which [`SEARCH_RATE_LIMIT_MAX`] applies.
+pub const SEARCH_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(220);
+
+/// A source of the current time, so time-based logic can be tested without sleeping.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The real clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Keeps searches under the server's limit, queueing the ones that must wait.
+///
+/// The server silently drops searches over the limit, so callers check
+/// [`SearchRateLimiter::can_search`] first and [`queue`](SearchRateLimiter::queue)
+/// what they can't send yet, retrying after [`SearchRateLimiter::time_until_next_slot`].
+#[derive(Debug)]
+pub struct SearchRateLimiter<T, C = SystemClock> {
+    max: usize,
+    window: Duration,
+    sent: VecDeque<Instant>,
+    queued: VecDeque<T>,
+    clock: C,
+}
+
+impl<T> Default for SearchRateLimiter<T> {
+    fn default() -> Self {
+        Self::with_limit(SEARCH_RATE_LIMIT_MAX, SEARCH_RATE_LIMIT_WINDOW)
+    }
+}
+
+impl<T> SearchRateLimiter<T> {
+    /// A limiter using the official server's limit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow `max` searches per `window`.
+    pub fn with_limit(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            sent: VecDeque::new(),
+            queued: VecDeque::new(),
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<T, C: Clock> SearchRateLimiter<T, C> {
+    /// Read the time from `clock` instead of the system clock.
+    pub fn with_clock<D: Clock>(self, clock: D) -> SearchRateLimiter<T, D> {
+        SearchRateLimiter {
+            max: self.max,
+            window: self.window,
+            sent: self.sent,
+            queued: self.queued,
+            clock,
+        }
+    }
+
+    /// Forget searches older than the window, returning the current time.
+    ///
+    /// A search sent exactly one window ago no longer counts, so a slot frees
+    /// the moment [`Self::time_until_next_slot`] said it would.
+    fn prune_old_searches(&mut self) -> Instant {
+        let now = self.clock.now();
+        while self
+            .sent
+            .front()
+            .is_some_and(|&sent| now.saturating_duration_since(sent) >= self.window)
+        {
+            self.sent.pop_front();
+        }
+        now
+    }
+
+    pub fn can_search(&mut self) -> bool {
+        self.prune_old_searches();
+        self.sent.len() < self.max
+    }
+
+    /// Count a search as sent now.
+    pub fn record(&mut self) {
+        let now = self.prune_old_searches();
+        self.sent.push_back(now);
+    }
+
+    /// How long until a search may be sent, or `None` if one may be sent now.
+    pub fn time_until_next_slot(&mut self) -> Option<Duration> {
+        let now = self.prune_old_searches();
+        if self.sent.len() < self.max {
+            return None;
+        }
+        // The oldest search in the window frees the next slot
+        let oldest = self.sent[self.sent.len() - self.max];
+        Some((oldest + self.window).saturating_duration_since(now))
+    }
+
+    pub fn searches_remaining(&mut self) -> usize {
+        self.prune_old_searches();
+        self.max.saturating_sub(self.sent.len())
+    }
+
+    /// Hold a search until a slot frees up.
+    pub fn queue(&mut self, search: T) {
+        self.queued.push_back(search);
+    }
+
+    /// The oldest queued search.
+    pub fn pop_queued(&mut self) -> Option<T> {
+        self.queued.pop_front()
+    }
+
+    pub fn queued_count(&self) -> usize {
+        self.queued.len()
+    }
+}
+
+/// Phrases the server bans from searches, from `ExcludedSearchPhrases`.
+///
+/// The server ignores searches containing any of them, so check
+/// [`SearchFilter::is_allowed`] before sending rather than wait for results
+/// that never come.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchFilter {
+    phrases: Vec<String>,
+}
+
+impl SearchFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a filter from the server's list; blank phrases are ignored.
+    pub fn from_excluded<I: IntoIterator<Item = String>>(phrases: I) -> Self {
+        SearchFilter {
+            phrases: phrases
+                .into_iter()
+                .map(|phrase| phrase.trim().to_lowercase())
+                .filter(|phrase| !phrase.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Replace the phrases when the server sends a new list. Returns whether it did.
+    pub fn apply(&mut self, response: &ServerResponse) -> bool {
+        match response {
+            ServerResponse::ExcludedSearchPhrases { phrases } => {
+                *self = Self::from_excluded(phrases.iter().cloned());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The first banned phrase in `query`, ignoring case.
+    pub fn excluded_phrase(&self, query: &str) -> Option<&str> {
+        let query = query.to_lowercase();
+        self.phrases
+            .iter()
+            .find(|phrase| query.contains(phrase.as_str()))
+            .map(String::as_str)
+    }
+
+    pub fn is_allowed(&self, query: &str) -> bool {
+        self.excluded_phrase(query).is_none()
+    }
+
+    pub fn phrases(&self) -> &[String] {
+        &self.phrases
+    }
+}
+
+/// Pick rooms to join from a `RoomList`, busiest first.
+///
+/// Rooms with fewer than `min_users` or named in `exclude` are skipped, and at most
+/// `max_rooms` names are returned. Ties in user count are broken by name so the
+/// selection is stable across runs.
+pub fn filter_rooms(
+    rooms: &[(String, u32)],
+    min_users: u32,
+    max_rooms: usize,
+    exclude: &[String],
+) -> Vec<String> {
+    let mut selected: Vec<&(String, u32)> = rooms
+        .iter()
+        .filter(|(name, users)| *users >= min_users && !exclude.contains(name))
+        .collect();
+    selected.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    selected
+        .into_iter()
+        .take(max_rooms)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Tracks in-flight requests by token, e.g. searches awaiting results.
+///
+/// Entries older than the TTL are treated as absent and dropped by [`TokenRegistry::expire`].
+#[derive(Debug)]
+pub struct TokenRegistry<T> {
+    entries: HashMap<u32, (Instant, T)>,
+    ttl: Duration,
+    next_token: u32,
+}
+
+impl<T> TokenRegistry<T> {
+    pub fn new(ttl: Duration) -> Self {
+        TokenRegistry {
+            entries: HashMap::new(),
+            ttl,
+            next_token: 1,
+        }
+    }
+
+    /// Issue a token that is not currently registered.
+    pub fn next_token(&mut self) -> u32 {
+        loop {
+            let token = self.next_token;
+            self.next_token = self.next_token.wrapping_add(1).max(1);
+            if !self.entries.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+
+    /// Register a payload under a fresh token and return the token.
+    pub fn issue(&mut self, value: T) -> u32 {
+        let token = self.next_token();
+        self.register(token, value);
+        token
+    }
+
+    /// Register a payload under a known token, returning any previous payload.
+    pub fn register(&mut self, token: u32, value: T) -> Option<T> {
+        self.entries
+            .insert(token, (Instant::now(), value))
+            .map(|(_, v)| v)
+    }
+
+    /// Remove and return the payload for a token if it hasn't expired.
+    pub fn take(&mut self, token: u32) -> Option<T> {
+        let (registered, value) = self.entries.remove(&token)?;
+        (registered.elapsed() < self.ttl).then_some(value)
+    }
+
+    /// Get the payload for a token if it hasn't expired.
+    pub fn get(&self, token: u32) -> Option<&T> {
+        self.entries
+            .get(&token)
+            .filter(|(registered, _)| registered.elapsed() < self.ttl)
+            .map(|(_, v)| v)
+    }
+
+    /// Get a mutable reference to the payload for a token if it hasn't expired.
+    pub fn get_mut(&mut self, token: u32) -> Option<&mut T> {
+        let ttl = self.ttl;
+        self.entries
+            .get_mut(&token)
+            .filter(|(registered, _)| registered.elapsed() < ttl)
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains(&self, token: u32) -> bool {
+        self.get(token).is_some()
+    }
+
+    /// Remove all expired entries, returning them.
+    pub fn expire(&mut self) -> Vec<(u32, T)> {
+        let expired: Vec<u32> = self
+            .entries
+            .iter()
+            .filter(|(_, (registered, _))| registered.elapsed() >= self.ttl)
+            .map(|(token, _)| *token)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|token| self.entries.remove(&token).map(|(_, v)| (token, v)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Called with a username and its new status.
+type StatusCallback = Box<dyn FnMut(&str, UserStatus) + Send>;
+
+/// Follows the online status of watched users.
+///
+/// Send the request returned by [`PresenceTracker::watch`], then feed every server
+/// response to [`PresenceTracker::handle`].
+#[derive(Default)]
+pub struct PresenceTracker {
+    watched: HashSet<String>,
+    statuses: HashMap<String, UserStatus>,
+    changes: Vec<(String, UserStatus)>,
+    on_change: Option<StatusCallback>,
+}
+
+impl fmt::Debug for PresenceTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PresenceTracker")
+            .field("watched", &self.watched)
+            .field("statuses", &self.statuses)
+            .field("changes", &self.changes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `callback` with each status change as it is handled.
+    pub fn on_change(&mut self, callback: impl FnMut(&str, UserStatus) + Send + 'static) {
+        self.on_change = Some(Box::new(callback));
+    }
+
+    /// Start tracking `username`, returning the request to send to the server.
+    pub fn watch(&mut self, username: &str) -> ServerRequest {
+        self.watched.insert(username.to_string());
+        ServerRequest::WatchUser {
+            username: username.to_string(),
+        }
+    }
+
+    /// Stop tracking `username`, returning the request to send to the server.
+    pub fn unwatch(&mut self, username: &str) -> ServerRequest {
+        self.watched.remove(username);
+        self.statuses.remove(username);
+        ServerRequest::UnwatchUser {
+            username: username.to_string(),
+        }
+    }
+
+    pub fn is_watched(&self, username: &str) -> bool {
+        self.watched.contains(username)
+    }
+
+    /// Last known status of a watched user.
+    pub fn status(&self, username: &str) -> Option<UserStatus> {
+        self.statuses.get(username).copied()
+    }
+
+    /// Update from a server response. Responses about unwatched users are ignored.
+    pub fn handle(&mut self, response: &ServerResponse) {
+        let (username, status) = match response {
+            ServerResponse::GetUserStatus {
+                username, status, ..
+            } => (username, *status),
+            // A user that doesn't exist can't be online
+            ServerResponse::WatchUser {
+                username, status, ..
+            } => (username, status.unwrap_or(UserStatus::Offline)),
+            _ => return,
+        };
+        if !self.watched.contains(username) {
+            return;
+        }
+
+        if self.statuses.insert(username.clone(), status) != Some(status) {
+            if let Some(callback) = &mut self.on_change {
+                callback(username, status);
+            }
+            self.changes.push((username.clone(), status));
+        }
+    }
+
+    /// Status changes since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<(String, UserStatus)> {
+        std::mem::take(&mut self.changes)
+    }
+}
+
+/// Who is privileged on the server, and how long our own privileges last.
+///
+/// Feed every server response to [`PrivilegeState::handle`]; the server sends
+/// `PrivilegedUsers` after login and `CheckPrivileges` in reply to a request.
+#[derive(Debug, Default)]
+pub struct PrivilegeState {
+    privileged: HashSet<String>,
+    /// Time left as last reported, and when it was reported.
+    time_left: Option<(Duration, Instant)>,
+}
+
+impl PrivilegeState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update from a server response, returning whether it was a privilege message.
+    pub fn handle(&mut self, response: &ServerResponse) -> bool {
+        match response {
+            ServerResponse::PrivilegedUsers { users } => {
+                self.privileged = users.iter().cloned().collect();
+                true
+            }
+            ServerResponse::CheckPrivileges { time_left } => {
+                self.time_left = Some((Duration::from_secs(*time_left as u64), Instant::now()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_privileged(&self, username: &str) -> bool {
+        self.privileged.contains(username)
+    }
+
+    /// Number of privileged users known.
+    pub fn privileged_count(&self) -> usize {
+        self.privileged.len()
+    }
+
+    /// Our remaining privileges, counting down from the last `CheckPrivileges`.
+    ///
+    /// Zero until the server has answered a [`ServerRequest::CheckPrivileges`].
+    pub fn time_left(&self) -> Duration {
+        self.time_left
+            .map(|(left, at)| left.saturating_sub(at.elapsed()))
+            .unwrap_or_default()
+    }
+}
+
+/// Current tickers of each joined room, folded from the ticker messages.
+///
+/// Feed every server response to [`RoomTickers::apply`]. Leaving a room forgets its tickers.
+#[derive(Debug, Default)]
+pub struct RoomTickers {
+    rooms: HashMap<String, Vec<(String, String)>>,
+}
+
+impl RoomTickers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update from a server response, returning whether any tickers changed.
+    pub fn apply(&mut self, response: &ServerResponse) -> bool {
+        match response {
+            ServerResponse::RoomTickerState { room, tickers } => {
+                let current = self.rooms.entry(room.clone()).or_default();
+                current.clear();
+                for ticker in tickers {
+                    set_ticker(current, &ticker.username, &ticker.ticker);
+                }
+                true
+            }
+            ServerResponse::RoomTickerAdd {
+                room,
+                username,
+                ticker,
+            } => {
+                set_ticker(self.rooms.entry(room.clone()).or_default(), username, ticker);
+                true
+            }
+            ServerResponse::RoomTickerRemove { room, username } => {
+                let Some(current) = self.rooms.get_mut(room) else {
+                    return false;
+                };
+                let before = current.len();
+                current.retain(|(user, _)| user != username);
+                current.len() != before
+            }
+            ServerResponse::LeaveRoom { room } => self.rooms.remove(room).is_some(),
+            _ => false,
+        }
+    }
+
+    /// `(username, ticker)` pairs for `room`, oldest first.
+    pub fn current(&self, room: &str) -> &[(String, String)] {
+        self.rooms.get(room).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+/// A user has at most one ticker per room; setting a new one moves it to the end.
+fn set_ticker(tickers: &mut Vec<(String, String)>, username: &str, ticker: &str) {
+    tickers.retain(|(user, _)| user != username);
+    tickers.push((username.to_string(), ticker.to_string()));
+}
+
+/// Chat messages kept per room by [`RoomState::new`].
+pub const DEFAULT_ROOM_HISTORY: usize = 100;
+
+/// Membership and recent chat of each joined room.
+///
+/// Feed every server response to [`RoomState::apply`]. Only rooms we have a
+/// `JoinRoom` response for are tracked, and each keeps the last few messages.
+#[derive(Debug)]
+pub struct RoomState {
+    rooms: HashMap<String, JoinedRoom>,
+    history: usize,
+}
+
+#[derive(Debug, Default)]
+struct JoinedRoom {
+    users: HashSet<String>,
+    messages: VecDeque<(String, String)>,
+}
+
+impl Default for RoomState {
+    fn default() -> Self {
+        Self::with_history(DEFAULT_ROOM_HISTORY)
+    }
+}
+
+impl RoomState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep at most `history` messages per room.
+    pub fn with_history(history: usize) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            history,
+        }
+    }
+
+    /// Update from a server response, returning whether any room changed.
+    pub fn apply(&mut self, response: &ServerResponse) -> bool {
+        match response {
+            ServerResponse::JoinRoom { room, users, .. } => {
+                let joined = self.rooms.entry(room.clone()).or_default();
+                joined.users = users.iter().map(|u| u.username.clone()).collect();
+                true
+            }
+            ServerResponse::LeaveRoom { room } => self.rooms.remove(room).is_some(),
+            ServerResponse::UserJoinedRoom { room, username, .. } => self
+                .rooms
+                .get_mut(room)
+                .is_some_and(|joined| joined.users.insert(username.clone())),
+            ServerResponse::UserLeftRoom { room, username } => self
+                .rooms
+                .get_mut(room)
+                .is_some_and(|joined| joined.users.remove(username)),
+            ServerResponse::SayChatroom {
+                room,
+                username,
+                message,
+            } => {
+                let Some(joined) = self.rooms.get_mut(room) else {
+                    return false;
+                };
+                if self.history == 0 {
+                    return false;
+                }
+                if joined.messages.len() == self.history {
+                    joined.messages.pop_front();
+                }
+                joined.messages.push_back((username.clone(), message.clone()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Rooms we are in.
+    pub fn rooms(&self) -> impl Iterator<Item = &str> {
+        self.rooms.keys().map(String::as_str)
+    }
+
+    /// Users in `room`, or `None` if we haven't joined it.
+    pub fn users(&self, room: &str) -> Option<&HashSet<String>> {
+        self.rooms.get(room).map(|joined| &joined.users)
+    }
+
+    /// `(username, message)` pairs said in `room`, oldest first.
+    pub fn recent_messages(&self, room: &str) -> impl Iterator<Item = &(String, String)> {
+        self.rooms
+            .get(room)
+            .into_iter()
+            .flat_map(|joined| joined.messages.iter())
+    }
+}
+
+/// An item (usually an interest) and its recommendation score.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recommendation {
+    pub item: String,
+    pub score: i32,
+}
+
+/// A user with interests like ours, and how alike they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimilarUser {
+    pub username: String,
+    pub rating: u32,
+}
+
+/// Recommendations and similar users collected from the server.
+///
+/// Send [`Recommendations::requests`] or [`Recommendations::request_item`] and
+/// feed every server response to [`Recommendations::apply`]. Recommendations
+/// are kept highest score first and unrecommendations lowest first.
+#[derive(Debug, Default)]
+pub struct Recommendations {
+    recommendations: Vec<Recommendation>,
+    unrecommendations: Vec<Recommendation>,
+    global_recommendations: Vec<Recommendation>,
+    global_unrecommendations: Vec<Recommendation>,
+    similar_users: Vec<SimilarUser>,
+    items: HashMap<String, Vec<Recommendation>>,
+    item_similar_users: HashMap<String, Vec<String>>,
+    pending_items: HashSet<String>,
+}
+
+impl Recommendations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask for our own and global recommendations, and users similar to us.
+    pub fn requests() -> [ServerRequest; 3] {
+        [
+            ServerRequest::GetRecommendations,
+            ServerRequest::GetGlobalRecommendations,
+            ServerRequest::GetSimilarUsers,
+        ]
+    }
+
+    /// Ask for what is recommended alongside `item`, and who likes it.
+    ///
+    /// Earlier replies for `item` are dropped, so it stays pending until both new ones arrive.
+    pub fn request_item(&mut self, item: &str) -> [ServerRequest; 2] {
+        self.items.remove(item);
+        self.item_similar_users.remove(item);
+        self.pending_items.insert(item.to_string());
+        [
+            ServerRequest::GetItemRecommendations {
+                item: item.to_string(),
+            },
+            ServerRequest::GetItemSimilarUsers {
+                item: item.to_string(),
+            },
+        ]
+    }
+
+    /// Whether either reply to [`Self::request_item`] is still outstanding.
+    pub fn is_pending(&self, item: &str) -> bool {
+        self.pending_items.contains(item)
+    }
+
+    /// Update from a server response, returning whether it was a recommendation reply.
+    pub fn apply(&mut self, response: &ServerResponse) -> bool {
+        match response {
+            ServerResponse::Recommendations {
+                recommendations,
+                unrecommendations,
+            } => {
+                self.recommendations = ranked(recommendations, false);
+                self.unrecommendations = ranked(unrecommendations, true);
+            }
+            ServerResponse::GlobalRecommendations {
+                recommendations,
+                unrecommendations,
+            } => {
+                self.global_recommendations = ranked(recommendations, false);
+                self.global_unrecommendations = ranked(unrecommendations, true);
+            }
+            ServerResponse::SimilarUsers { users } => {
+                let mut users: Vec<SimilarUser> = users
+                    .iter()
+                    .map(|(username, rating)| SimilarUser {
+                        username: username.clone(),
+                        rating: *rating,
+                    })
+                    .collect();
+                users.sort_by(|a, b| b.rating.cmp(&a.rating).then(a.username.cmp(&b.username)));
+                self.similar_users = users;
+            }
+            ServerResponse::ItemRecommendations {
+                item,
+                recommendations,
+            } => {
+                self.items.insert(item.clone(), ranked(recommendations, false));
+                self.settle_item(item);
+            }
+            ServerResponse::ItemSimilarUsers { item, users } => {
+                let mut users = users.clone();
+                users.sort();
+                self.item_similar_users.insert(item.clone(), users);
+                self.settle_item(item);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn settle_item(&mut self, item: &str) {
+        if self.items.contains_key(item) && self.item_similar_users.contains_key(item) {
+            self.pending_items.remove(item);
+        }
+    }
+
+    pub fn recommendations(&self) -> &[Recommendation] {
+        &self.recommendations
+    }
+
+    pub fn unrecommendations(&self) -> &[Recommendation] {
+        &self.unrecommendations
+    }
+
+    pub fn global_recommendations(&self) -> &[Recommendation] {
+        &self.global_recommendations
+    }
+
+    pub fn global_unrecommendations(&self) -> &[Recommendation] {
+        &self.global_unrecommendations
+    }
+
+    pub fn similar_users(&self) -> &[SimilarUser] {
+        &self.similar_users
+    }
+
+    /// What is recommended alongside `item`, once the server has replied.
+    pub fn item_recommendations(&self, item: &str) -> Option<&[Recommendation]> {
+        self.items.get(item).map(Vec::as_slice)
+    }
+
+    /// Users who like `item`, by name, once the server has replied.
+    pub fn item_similar_users(&self, item: &str) -> Option<&[String]> {
+        self.item_similar_users.get(item).map(Vec::as_slice)
+    }
+}
+
+/// The interests we have declared to the server, in the order they were added.
+///
+/// Each change returns the requests that tell the server about it, which are
+/// empty if nothing changed. An item is never both liked and hated.
+#[derive(Debug, Clone, Default)]
+pub struct InterestList {
+    likes: Vec<String>,
+    hates: Vec<String>,
+}
+
+impl InterestList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_like(&mut self, item: &str) -> Vec<ServerRequest> {
+        if self.likes.iter().any(|like| like == item) {
+            return vec![];
+        }
+        let mut requests = self.remove_hate(item);
+        self.likes.push(item.to_string());
+        requests.push(ServerRequest::InterestAdd {
+            item: item.to_string(),
+        });
+        requests
+    }
+
+    pub fn remove_like(&mut self, item: &str) -> Vec<ServerRequest> {
+        if !remove_item(&mut self.likes, item) {
+            return vec![];
+        }
+        vec![ServerRequest::InterestRemove {
+            item: item.to_string(),
+        }]
+    }
+
+    pub fn add_hate(&mut self, item: &str) -> Vec<ServerRequest> {
+        if self.hates.iter().any(|hate| hate == item) {
+            return vec![];
+        }
+        let mut requests = self.remove_like(item);
+        self.hates.push(item.to_string());
+        requests.push(ServerRequest::HatedInterestAdd {
+            item: item.to_string(),
+        });
+        requests
+    }
+
+    pub fn remove_hate(&mut self, item: &str) -> Vec<ServerRequest> {
+        if !remove_item(&mut self.hates, item) {
+            return vec![];
+        }
+        vec![ServerRequest::HatedInterestRemove {
+            item: item.to_string(),
+        }]
+    }
+
+    pub fn likes(&self) -> &[String] {
+        &self.likes
+    }
+
+    pub fn hates(&self) -> &[String] {
+        &self.hates
+    }
+}
+
+fn remove_item(items: &mut Vec<String>, item: &str) -> bool {
+    let before = items.len();
+    items.retain(|existing| existing != item);
+    items.len() != before
+}
+
+/// Highest score first, or lowest first for `ascending`, with ties by name.
+fn ranked(scores: &[(String, i32)], ascending: bool) -> Vec<Recommendation> {
+    let mut ranked: Vec<Recommendation> = scores
+        .iter()
+        .map(|(item, score)| Recommendation {
+            item: item.clone(),
+            score: *score,
+        })
+        .collect();
+    ranked.sort_by(|a, b| {
+        let by_score = if ascending {
+            a.score.cmp(&b.score)
+        } else {
+            b.score.cmp(&a.score)
+        };
+        by_score.then_with(|| a.item.cmp(&b.item))
+    });
+    ranked
+}
+
+/// Whose shares a search covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchScope {
+    /// Every user, via `FileSearch`.
+    Global,
+    /// A single user, via `UserSearch`.
+    User(String),
+}
+
+/// A search awaiting peer results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSearch {
+    pub query: String,
+    pub scope: SearchScope,
+}
+
+impl PendingSearch {
+    pub fn global(query: impl Into<String>) -> Self {
+        PendingSearch {
+            query: query.into(),
+            scope: SearchScope::Global,
+        }
+    }
+
+    pub fn user(username: impl Into<String>, query: impl Into<String>) -> Self {
+        PendingSearch {
+            query: query.into(),
+            scope: SearchScope::User(username.into()),
+        }
+    }
+
+    /// The server request that starts this search under `token`.
+    pub fn request(&self, token: u32) -> ServerRequest {
+        match &self.scope {
+            SearchScope::Global => ServerRequest::FileSearch {
+                token,
+                query: self.query.clone(),
+            },
+            SearchScope::User(username) => ServerRequest::UserSearch {
+                username: username.clone(),
+                token,
+                query: self.query.clone(),
+            },
+        }
+    }
+
+    /// Whether results from `username` can belong to this search.
+    pub fn accepts(&self, username: &str) -> bool {
+        match &self.scope {
+            SearchScope::Global => true,
+            SearchScope::User(target) => target == username,
+        }
+    }
+}
+
+impl TokenRegistry<PendingSearch> {
+    /// Find the search a `FileSearchResponse` from `username` with `token` answers.
+    ///
+    /// A user search only accepts results from the user it was sent to.
+    pub fn match_search_response(&self, token: u32, username: &str) -> Option<&PendingSearch> {
+        self.get(token).filter(|search| search.accepts(username))
+    }
+}
+
+/// A single file returned by a peer for a search.
+#[derive(Debug, Clone)]
+pub struct PeerSearchResult {
+    pub username: String,
+    pub file: SearchResultFile,
+    pub slot_free: bool,
+    pub avg_speed: u32,
+    pub queue_length: u32,
+}
+
+impl RankCandidate for PeerSearchResult {
+    fn file(&self) -> &SearchResultFile {
+        &self.file
+    }
+
+    fn availability(&self) -> Option<PeerAvailability> {
+        Some(PeerAvailability {
+            slot_free: self.slot_free,
+            queue_length: self.queue_length,
+            avg_speed: self.avg_speed,
+        })
+    }
+}
+
+/// How long a [`SearchSession`] usually waits for peers to answer.
+pub const SEARCH_SESSION_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Collects `FileSearchResponse`s for one search token from many peers.
+///
+/// Peer connections are handed to a [`SearchPeers`] handle while [`SearchSession::collect`]
+/// waits for results until the timeout elapses or enough results have arrived.
+#[derive(Debug)]
+pub struct SearchSession {
+    token: u32,
+    timeout: Duration,
+    max_results: usize,
+    results_rx: mpsc::UnboundedReceiver<Vec<PeerSearchResult>>,
+}
+
+/// Handle for feeding peer connections into a [`SearchSession`].
+#[derive(Debug, Clone)]
+pub struct SearchPeers {
+    token: u32,
+    timeout: Duration,
+    results_tx: mpsc::UnboundedSender<Vec<PeerSearchResult>>,
+}
+
+impl SearchSession {
+    pub fn new(token: u32, timeout: Duration, max_results: usize) -> (Self, SearchPeers) {
+        let (results_tx, results_rx) = mpsc::unbounded_channel();
+        let session = SearchSession {
+            token,
+            timeout,
+            max_results,
+            results_rx,
+        };
+        let peers = SearchPeers {
+            token,
+            timeout,
+            results_tx,
+        };
+        (session, peers)
+    }
+
+    pub fn token(&self) -> u32 {
+        self.token
+    }
+
+    /// Wait for results until the timeout, the result cap, or every peer handle is gone.
+    pub async fn collect(mut self) -> Vec<PeerSearchResult> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let mut results = Vec::new();
+
+        while results.len() < self.max_results {
+            match tokio::time::timeout_at(deadline, self.results_rx.recv()).await {
+                Ok(Some(batch)) => results.extend(batch),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        results.truncate(self.max_results);
+        results
+    }
+}
+
+impl SearchPeers {
+    /// Answer a `ConnectToPeer` from a peer with results, reading its response in the background.
+    pub fn connect_to_peer(&self, ip: Ipv4Addr, port: u32, token: u32) {
+        let peers = self.clone();
+        tokio::spawn(async move {
+            let _ = tokio::time::timeout(peers.timeout, async {
+                let mut stream = TimeoutConnector::default()
+                    .connect(&format!("{ip}:{port}"))
+                    .await?;
+                let mut buf = BytesMut::new();
+                write_peer_init_message(&PeerInitMessage::PierceFirewall { token }, &mut buf)?;
+                stream.write_all(&buf).await?;
+                peers.read_results(stream, BytesMut::new()).await
+            })
+            .await;
+        });
+    }
+
+    /// Add a `FileSearchResponse` the caller read itself, returning whether it answers this search.
+    ///
+    /// For clients that read their peer connections elsewhere and route responses by token.
+    pub fn add_response(&self, response: &PeerMessage) -> bool {
+        let PeerMessage::FileSearchResponse {
+            username,
+            token,
+            results,
+            slot_free,
+            avg_speed,
+            queue_length,
+            ..
+        } = response
+        else {
+            return false;
+        };
+        if *token != self.token {
+            return false;
+        }
+        let batch = results
+            .iter()
+            .map(|file| PeerSearchResult {
+                username: username.clone(),
+                file: file.clone(),
+                slot_free: *slot_free,
+                avg_speed: *avg_speed,
+                queue_length: *queue_length,
+            })
+            .collect();
+        let _ = self.results_tx.send(batch);
+        true
+    }
+
+    /// Read results from a peer that connected to us directly after its `PeerInit`.
+    pub fn add_stream(&self, stream: TcpStream, read_buf: BytesMut) {
+        let peers = self.clone();
+        tokio::spawn(async move {
+            let _ = tokio::time::timeout(peers.timeout, peers.read_results(stream, read_buf)).await;
+        });
+    }
+
+    async fn read_results(&self, mut stream: TcpStream, read_buf: BytesMut) -> Result<()> {
+        let mut frames = FrameDecoder::with_buffer(read_buf);
+        loop {
+            while let Some(frame) = frames.next_frame() {
+                if let Ok(response) = read_peer_message(&mut frame?)
+                    && self.add_response(&response)
+                {
+                    return Ok(());
+                }
+            }
+
+            if stream.read_buf(frames.buffer_mut()).await? == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// How long to wait for a firewalled peer to connect back after `ConnectToPeer`.
+pub const PIERCE_FIREWALL_TIMEOUT: Duration = Duration::from_secs(20);
+
+type PendingPierces = Arc<Mutex<HashMap<u32, oneshot::Sender<TcpStream>>>>;
+
+/// Opens connections to peers, falling back to the server for firewalled ones.
+///
+/// A direct connection is tried first and introduced with `PeerInit`. If that fails,
+/// a `ConnectToPeer` request is queued on `requests` for the server, and the
+/// connection resolves once the peer connects to our listener with a matching
+/// `PierceFirewall`, which must be handed over with [`PeerConnector::pierce_firewall`].
+#[derive(Debug, Clone)]
+pub struct PeerConnector<C = TimeoutConnector<TcpConnector>> {
+    username: String,
+    connector: C,
+    requests: mpsc::UnboundedSender<ServerRequest>,
+    pierce_timeout: Duration,
+    pending: PendingPierces,
+}
+
+impl PeerConnector {
+    /// `username` is our own, sent to peers we connect to directly.
+    pub fn new(username: &str, requests: mpsc::UnboundedSender<ServerRequest>) -> Self {
+        Self::with_connector(
+            username,
+            TimeoutConnector::default(),
+            requests,
+            PIERCE_FIREWALL_TIMEOUT,
+        )
+    }
+}
+
+impl<C> PeerConnector<C> {
+    pub fn with_connector(
+        username: &str,
+        connector: C,
+        requests: mpsc::UnboundedSender<ServerRequest>,
+        pierce_timeout: Duration,
+    ) -> Self {
+        PeerConnector {
+            username: username.to_string(),
+            connector,
+            requests,
+            pierce_timeout,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Hand over an incoming connection that opened with `PierceFirewall { token }`.
+    ///
+    /// The stream is given back if no connection attempt is waiting for the token.
+    pub fn pierce_firewall(
+        &self,
+        token: u32,
+        stream: TcpStream,
+    ) -> std::result::Result<(), TcpStream> {
+        let waiting = self.pending.lock().unwrap().remove(&token);
+        match waiting {
+            Some(tx) => tx.send(stream),
+            None => Err(stream),
+        }
+    }
+
+    /// Fail the attempt waiting on `token`, e.g. when the server sends `CantConnectToPeer`.
+    pub fn cant_connect(&self, token: u32) {
+        self.pending.lock().unwrap().remove(&token);
+    }
+}
+
+impl<C: Connector<Stream = TcpStream> + Sync> PeerConnector<C> {
+    /// Connect to `username` at its advertised address, ready for peer messages.
+    pub async fn connect(
+        &self,
+        username: &str,
+        ip: Ipv4Addr,
+        port: u32,
+        token: u32,
+        connection_type: ConnectionType,
+    ) -> Result<TcpStream> {
+        if let Ok(stream) = self.connect_direct(ip, port, token, connection_type).await {
+            return Ok(stream);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(token, tx);
+        let request = ServerRequest::ConnectToPeer {
+            token,
+            username: username.to_string(),
+            connection_type,
+        };
+        if self.requests.send(request).is_err() {
+            self.cant_connect(token);
+            return Err(
+                io::Error::new(io::ErrorKind::NotConnected, "server connection is closed").into(),
+            );
+        }
+
+        let result = tokio::time::timeout(self.pierce_timeout, rx).await;
+        self.cant_connect(token);
+        match result {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(_)) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("{username} could not connect to us"),
+            )
+            .into()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{username} did not connect back within {:?}",
+                    self.pierce_timeout
+                ),
+            )
+            .into()),
+        }
+    }
+
+    async fn connect_direct(
+        &self,
+        ip: Ipv4Addr,
+        port: u32,
+        token: u32,
+        connection_type: ConnectionType,
+    ) -> Result<TcpStream> {
+        let mut stream = self.connector.connect(&format!("{ip}:{port}")).await?;
+        let mut buf = BytesMut::new();
+        let init = PeerInitMessage::PeerInit {
+            username: self.username.clone(),
+            connection_type,
+            token,
+        };
+        write_peer_init_message(&init, &mut buf)?;
+        stream.write_all(&buf).await?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_init::read_peer_init_message;
+    use crate::protocol::{MAX_MESSAGE_LEN, MessageBuffer};
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_login_request() {
+        let req = ServerRequest::Login {
+            username: "testuser".to_string(),
+            password: "testpass".to_string(),
+            version: 160,
+            minor_version: 1,
+        };
+
+        let mut buf = BytesMut::new();
+        req.write_message(&mut buf).unwrap();
+
+        // Verify it can be written without panic
+        assert!(buf.len() > 8);
+    }
+
+    #[test]
+    fn test_file_search_request() {
+        let req = ServerRequest::FileSearch {
+            token: 12345,
+            query: "test query".to_string(),
+        };
+
+        let mut buf = BytesMut::new();
+        req.write_message(&mut buf).unwrap();
+        assert!(buf.len() > 8);
+    }
+
+    #[test]
+    fn test_send_only_code_in_response_is_unexpected() {
+        let req = ServerRequest::SendUploadSpeed { speed: 1000 };
+        let mut buf = BytesMut::new();
+        req.write_message(&mut buf).unwrap();
+
+        match read_server_message(&mut buf) {
+            Err(Error::UnexpectedCode { code, context }) => {
+                assert_eq!(code, ServerCode::SendUploadSpeed as u32);
+                assert!(context.contains("send-only"));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_response_only_code_in_request_is_unexpected() {
+        let resp = ServerResponse::Relogged;
+        let mut buf = BytesMut::new();
+        resp.write_message(&mut buf).unwrap();
+
+        assert!(matches!(
+            read_server_request(&mut buf),
+            Err(Error::UnexpectedCode {
+                code,
+                context: "response-only server code in request",
+            }) if code == ServerCode::Relogged as u32
+        ));
+    }
+
+    #[test]
+    fn test_parser_reading_past_frame_is_length_mismatch() {
+        let req = ServerRequest::GetPeerAddress {
+            username: "someone".to_string(),
+        };
+        let mut buf = BytesMut::new();
+        req.write_message(&mut buf).unwrap();
+        // Declare only the code, leaving the username outside the frame
+        buf[..4].copy_from_slice(&4u32.to_le_bytes());
+
+        match read_server_request(&mut buf) {
+            Err(Error::LengthMismatch { declared, consumed }) => {
+                assert_eq!(declared, 4);
+                assert_eq!(consumed, 4 + 4 + "someone".len());
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_server_ping_frame_without_payload() {
+        // Length 4 covers only the code
+        let mut buf = BytesMut::from(&[4u8, 0, 0, 0, 32, 0, 0, 0][..]);
+
+        let req = read_server_request(&mut buf).unwrap();
+        assert!(matches!(req, ServerRequest::ServerPing));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_empty_payload_requests_roundtrip() {
+        let requests = [
+            ServerRequest::ServerPing,
+            ServerRequest::GetRecommendations,
+            ServerRequest::GetGlobalRecommendations,
+            ServerRequest::RoomList,
+            ServerRequest::CheckPrivileges,
+            ServerRequest::GetSimilarUsers,
+            ServerRequest::JoinGlobalRoom,
+            ServerRequest::LeaveGlobalRoom,
+        ];
+
+        // Back to back, so a parser reading past its frame would eat the next one
+        let mut buf = BytesMut::new();
+        for req in &requests {
+            req.write_message(&mut buf).unwrap();
+        }
+        assert_eq!(buf.len(), requests.len() * 8);
+
+        for req in &requests {
+            let decoded = read_server_request(&mut buf).unwrap();
+            assert_eq!(decoded.code(), req.code());
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_every_request_roundtrips() {
+        let s = |v: &str| v.to_string();
+        let requests = vec![
+            ServerRequest::Login {
+                username: s("alice"),
+                password: s("secret"),
+                version: 160,
+                minor_version: 1,
+            },
+            ServerRequest::SetWaitPort {
+                port: 2234,
+                obfuscation: None,
+            },
+            ServerRequest::SetWaitPort {
+                port: 2234,
+                obfuscation: Some((ObfuscationType::Rotated, 2235)),
+            },
+            ServerRequest::GetPeerAddress { username: s("bob") },
+            ServerRequest::WatchUser { username: s("bob") },
+            ServerRequest::UnwatchUser { username: s("bob") },
+            ServerRequest::GetUserStatus { username: s("bob") },
+            ServerRequest::SayChatroom {
+                room: s("room"),
+                message: s("hi"),
+            },
+            ServerRequest::JoinRoom {
+                room: s("room"),
+                private: true,
+            },
+            ServerRequest::JoinRoom {
+                room: s("room"),
+                private: false,
+            },
+            ServerRequest::LeaveRoom { room: s("room") },
+            ServerRequest::ConnectToPeer {
+                token: 7,
+                username: s("bob"),
+                connection_type: ConnectionType::File,
+            },
+            ServerRequest::MessageUser {
+                username: s("bob"),
+                message: s("hello"),
+            },
+            ServerRequest::MessageAcked { message_id: 0xDEAD_BEEF },
+            ServerRequest::FileSearch {
+                token: 1,
+                query: s("query"),
+            },
+            ServerRequest::SetStatus {
+                status: UserStatus::Offline,
+            },
+            ServerRequest::SetStatus {
+                status: UserStatus::Away,
+            },
+            ServerRequest::SetStatus {
+                status: UserStatus::Online,
+            },
+            ServerRequest::ServerPing,
+            ServerRequest::SharedFoldersFiles { dirs: 3, files: 40 },
+            ServerRequest::GetUserStats { username: s("bob") },
+            ServerRequest::UserSearch {
+                username: s("bob"),
+                token: 2,
+                query: s("query"),
+            },
+            ServerRequest::InterestAdd { item: s("jazz") },
+            ServerRequest::InterestRemove { item: s("jazz") },
+            ServerRequest::GetRecommendations,
+            ServerRequest::GetGlobalRecommendations,
+            ServerRequest::GetUserInterests { username: s("bob") },
+            ServerRequest::RoomList,
+            ServerRequest::HaveNoParent { no_parent: true },
+            ServerRequest::CheckPrivileges,
+            ServerRequest::AcceptChildren { accept: false },
+            ServerRequest::WishlistSearch {
+                token: 3,
+                query: s("wish"),
+            },
+            ServerRequest::GetSimilarUsers,
+            ServerRequest::GetItemRecommendations { item: s("jazz") },
+            ServerRequest::GetItemSimilarUsers { item: s("jazz") },
+            ServerRequest::RoomTickerSet {
+                room: s("room"),
+                ticker: s("ticker"),
+            },
+            ServerRequest::HatedInterestAdd { item: s("noise") },
+            ServerRequest::HatedInterestRemove { item: s("noise") },
+            ServerRequest::RoomSearch {
+                room: s("room"),
+                token: 4,
+                query: s("query"),
+            },
+            ServerRequest::SendUploadSpeed { speed: 1000 },
+            ServerRequest::GivePrivileges {
+                username: s("bob"),
+                days: 30,
+            },
+            ServerRequest::BranchLevel { level: 2 },
+            ServerRequest::BranchRoot { root: s("root") },
+            ServerRequest::AddRoomMember {
+                room: s("room"),
+                username: s("bob"),
+            },
+            ServerRequest::RemoveRoomMember {
+                room: s("room"),
+                username: s("bob"),
+            },
+            ServerRequest::CancelRoomMembership { room: s("room") },
+            ServerRequest::CancelRoomOwnership { room: s("room") },
+            ServerRequest::EnableRoomInvitations { enable: true },
+            ServerRequest::ChangePassword { password: s("new") },
+            ServerRequest::AddRoomOperator {
+                room: s("room"),
+                username: s("bob"),
+            },
+            ServerRequest::RemoveRoomOperator {
+                room: s("room"),
+                username: s("bob"),
+            },
+            ServerRequest::MessageUsers {
+                usernames: vec![s("bob"), s("carol")],
+                message: s("hey"),
+            },
+            ServerRequest::JoinGlobalRoom,
+            ServerRequest::LeaveGlobalRoom,
+            ServerRequest::CantConnectToPeer {
+                token: 5,
+                username: s("bob"),
+            },
+        ];
+
+        for req in &requests {
+            let mut written = BytesMut::new();
+            req.write_message(&mut written).unwrap();
+
+            let mut buf = written.clone();
+            let decoded = read_server_request(&mut buf)
+                .unwrap_or_else(|e| panic!("{req:?} failed to decode: {e}"));
+            assert!(buf.is_empty(), "{req:?} left {} bytes unread", buf.len());
+
+            let mut rewritten = BytesMut::new();
+            decoded.write_message(&mut rewritten).unwrap();
+            assert_eq!(rewritten, written, "{req:?} decoded as {decoded:?}");
+        }
+    }
+
+    #[test]
+    fn test_set_wait_port_obfuscation_roundtrip() {
+        for obfuscation in [None, Some((ObfuscationType::Rotated, 2235))] {
+            let mut buf = BytesMut::new();
+            ServerRequest::SetWaitPort {
+                port: 2234,
+                obfuscation,
+            }
+            .write_message(&mut buf).unwrap();
+            // Length, code, port, and the optional type and port pair
+            let expected_len = if obfuscation.is_some() { 20 } else { 12 };
+            assert_eq!(buf.len(), expected_len);
+
+            match read_server_request(&mut buf).unwrap() {
+                ServerRequest::SetWaitPort {
+                    port,
+                    obfuscation: decoded,
+                } => {
+                    assert_eq!(port, 2234);
+                    assert_eq!(decoded, obfuscation);
+                }
+                other => panic!("unexpected request: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame_is_underflow() {
+        let mut buf = BytesMut::from(&[8u8, 0, 0, 0, 32, 0, 0, 0][..]);
+
+        assert!(matches!(
+            read_server_request(&mut buf),
+            Err(Error::BufferUnderflow {
+                needed: 8,
+                available: 4
+            })
+        ));
+    }
+
+    fn say_chatroom(room: &str, message: &str) -> ServerResponse {
+        ServerResponse::SayChatroom {
+            room: room.to_string(),
+            username: "alice".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_message_buffer_chunked_feed() {
+        let mut wire = BytesMut::new();
+        say_chatroom("indie", "first").write_message(&mut wire).unwrap();
+        ServerResponse::Relogged.write_message(&mut wire).unwrap();
+        say_chatroom("jazz", &"x".repeat(300)).write_message(&mut wire).unwrap();
+
+        for chunk_size in [1, 3, 7, 64, wire.len()] {
+            let mut buffer = MessageBuffer::<ServerResponse>::new();
+            let mut messages = Vec::new();
+            for chunk in wire.chunks(chunk_size) {
+                buffer.feed(chunk);
+                while let Some(msg) = buffer.poll() {
+                    messages.push(msg.unwrap());
+                }
+            }
+
+            assert_eq!(messages.len(), 3, "chunk size {chunk_size}");
+            assert!(matches!(
+                &messages[0],
+                ServerResponse::SayChatroom { room, message, .. } if room == "indie" && message == "first"
+            ));
+            assert!(matches!(messages[1], ServerResponse::Relogged));
+            assert!(matches!(
+                &messages[2],
+                ServerResponse::SayChatroom { room, message, .. } if room == "jazz" && message.len() == 300
+            ));
+            assert_eq!(buffer.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn test_message_buffer_waits_for_complete_frame() {
+        let mut wire = BytesMut::new();
+        ServerResponse::Relogged.write_message(&mut wire).unwrap();
+        say_chatroom("indie", "hello").write_message(&mut wire).unwrap();
+
+        let mut buffer = MessageBuffer::<ServerResponse>::new();
+        buffer.feed(&wire[..wire.len() - 1]);
+        assert!(matches!(buffer.poll(), Some(Ok(ServerResponse::Relogged))));
+        assert!(buffer.poll().is_none());
+
+        buffer.feed(&wire[wire.len() - 1..]);
+        assert!(matches!(
+            buffer.poll(),
+            Some(Ok(ServerResponse::SayChatroom { .. }))
+        ));
+        assert!(buffer.poll().is_none());
+    }
+
+    #[test]
+    fn test_message_buffer_rejects_oversized_frame() {
+        let mut buffer = MessageBuffer::<ServerResponse>::new();
+        buffer.feed(&(MAX_MESSAGE_LEN as u32 + 1).to_le_bytes());
+
+        assert!(matches!(
+            buffer.poll(),
+            Some(Err(Error::MessageTooLarge {
+                max: MAX_MESSAGE_LEN,
+                ..
+            }))
+        ));
+        assert_eq!(buffer.buffered(), 0);
+    }
+
+    #[test]
+    fn test_peer_address_prefers_obfuscated_port() {
+        let address = PeerAddress {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            port: 2234,
+            obfuscation_type: ObfuscationType::Rotated,
+            obfuscated_port: 2235,
+        };
+        assert!(!address.is_offline());
+
+        assert_eq!(
+            address.select(&[ObfuscationType::Rotated]),
+            (2235, ObfuscationType::Rotated)
+        );
+        // Peers can't be reached on the obfuscated port without its framing.
+        assert_eq!(address.select(&[]), (2234, ObfuscationType::None));
+    }
+
+    #[test]
+    fn test_peer_address_plain_without_obfuscation() {
+        let plain = PeerAddress {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            port: 2234,
+            obfuscation_type: ObfuscationType::None,
+            obfuscated_port: 0,
+        };
+        assert_eq!(
+            plain.select(&[ObfuscationType::Rotated]),
+            (2234, ObfuscationType::None)
+        );
+
+        let missing_port = PeerAddress {
+            obfuscation_type: ObfuscationType::Rotated,
+            ..plain
+        };
+        assert_eq!(
+            missing_port.select(&[ObfuscationType::Rotated]),
+            (2234, ObfuscationType::None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_address_cache_skips_round_trip_until_expiry() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let lookups = Arc::new(AtomicU64::new(0));
+        let server_lookups = lookups.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = FrameDecoder::new();
+            while stream.read_buf(frames.buffer_mut()).await.unwrap() > 0 {
+                while let Some(frame) = frames.next_frame() {
+                    let ServerRequest::GetPeerAddress { username } =
+                        read_server_request(&mut frame.unwrap()).unwrap()
+                    else {
+                        continue;
+                    };
+                    let n = server_lookups.fetch_add(1, Ordering::SeqCst) + 1;
+                    let mut buf = BytesMut::new();
+                    ServerResponse::AdminMessage {
+                        message: format!("lookup {n}"),
+                    }
+                    .write_message(&mut buf).unwrap();
+                    ServerResponse::GetPeerAddress {
+                        username,
+                        ip: Ipv4Addr::new(10, 0, 0, n as u8),
+                        port: 2234,
+                        obfuscation_type: ObfuscationType::None,
+                        obfuscated_port: 0,
+                    }
+                    .write_message(&mut buf).unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = ServerConnection::from_stream(stream, ServerProfile::default());
+        let clock = MockClock::new();
+        let mut cache = AddressCache::with_ttl(Duration::from_secs(60)).with_clock(clock.clone());
+
+        let first = cache.get_or_fetch("peer", &mut conn).await.unwrap();
+        assert_eq!(first.ip, Ipv4Addr::new(10, 0, 0, 1));
+        clock.advance(Duration::from_secs(59));
+        let cached = cache.get_or_fetch("peer", &mut conn).await.unwrap();
+        assert_eq!(cached, first);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // Past the TTL the user may have reconnected from elsewhere
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.get("peer"), None);
+        let refreshed = cache.get_or_fetch("peer", &mut conn).await.unwrap();
+        assert_eq!(refreshed.ip, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        // Messages that arrived ahead of each answer are still delivered, in order
+        for n in 1..=2 {
+            assert!(matches!(
+                conn.next_message().await.unwrap(),
+                ServerResponse::AdminMessage { message } if message == format!("lookup {n}")
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_address_lookup_skips_undecodable_frame() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            // A frame with a code we don't know, ahead of the answer
+            4u32.write_to(&mut buf);
+            9999u32.write_to(&mut buf);
+            ServerResponse::GetPeerAddress {
+                username: "peer".to_string(),
+                ip: Ipv4Addr::new(10, 0, 0, 2),
+                port: 2234,
+                obfuscation_type: ObfuscationType::None,
+                obfuscated_port: 0,
+            }
+            .write_message(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+            // Keep the connection open until the client is done
+            let _ = stream.read_buf(&mut buf).await;
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = ServerConnection::from_stream(stream, ServerProfile::default());
+        let address = conn.get_peer_address("peer").await.unwrap();
+        assert_eq!(address.ip, Ipv4Addr::new(10, 0, 0, 2));
+    }
+
+    #[test]
+    fn test_peer_address_offline_sentinels() {
+        let reply = |ip, port| ServerResponse::GetPeerAddress {
+            username: "peer".to_string(),
+            ip,
+            port,
+            obfuscation_type: ObfuscationType::None,
+            obfuscated_port: 0,
+        };
+
+        let offline = reply(Ipv4Addr::UNSPECIFIED, 0);
+        assert!(PeerAddress::from_response(&offline).unwrap().1.is_offline());
+        // Online, but with no listen port to connect to
+        let no_port = reply(Ipv4Addr::new(10, 0, 0, 2), 0);
+        assert!(PeerAddress::from_response(&no_port).unwrap().1.is_offline());
+
+        let online = reply(Ipv4Addr::new(10, 0, 0, 2), 2234);
+        let (name, address) = PeerAddress::from_response(&online).unwrap();
+        assert_eq!(name, "peer");
+        assert!(!address.is_offline());
+        assert_eq!(address.select(&[]), (2234, ObfuscationType::None));
+
+        assert!(PeerAddress::from_response(&ServerResponse::ResetDistributed).is_none());
+    }
+
+    fn connect_to_peer_response() -> ServerResponse {
+        ServerResponse::ConnectToPeer {
+            username: "peer".to_string(),
+            connection_type: ConnectionType::Peer,
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            port: 2234,
+            token: 77,
+            privileged: false,
+            obfuscation_type: ObfuscationType::Rotated,
+            obfuscated_port: 2235,
+        }
+    }
+
+    #[test]
+    fn test_connect_to_peer_with_obfuscation() {
+        let mut buf = BytesMut::new();
+        connect_to_peer_response().write_message(&mut buf).unwrap();
+
+        match read_server_message(&mut buf).unwrap() {
+            ServerResponse::ConnectToPeer {
+                username,
+                token,
+                obfuscation_type,
+                obfuscated_port,
+                ..
+            } => {
+                assert_eq!(username, "peer");
+                assert_eq!(token, 77);
+                assert_eq!(obfuscation_type, ObfuscationType::Rotated);
+                assert_eq!(obfuscated_port, 2235);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_connect_to_peer_without_obfuscation() {
+        let mut buf = BytesMut::new();
+        connect_to_peer_response().write_message(&mut buf).unwrap();
+        // Drop the obfuscation type and port, as older servers do
+        buf.truncate(buf.len() - 8);
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+
+        match read_server_message(&mut buf).unwrap() {
+            ServerResponse::ConnectToPeer {
+                ip,
+                port,
+                token,
+                obfuscation_type,
+                obfuscated_port,
+                ..
+            } => {
+                assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 2));
+                assert_eq!(port, 2234);
+                assert_eq!(token, 77);
+                assert_eq!(obfuscation_type, ObfuscationType::None);
+                assert_eq!(obfuscated_port, 0);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_message_users_is_rejected() {
+        let req = ServerRequest::MessageUsers {
+            usernames: (0..1000).map(|i| format!("user{i}")).collect(),
+            message: "x".repeat(MAX_MESSAGE_LEN),
+        };
+
+        let mut buf = BytesMut::new();
+        match req.write_message(&mut buf) {
+            Err(Error::MessageTooLarge { len, max }) => {
+                assert!(len > max);
+                assert_eq!(max, MAX_MESSAGE_LEN);
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(buf.is_empty());
+
+        let req = ServerRequest::MessageUsers {
+            usernames: vec!["alice".to_string()],
+            message: "hi".to_string(),
+        };
+        req.write_message(&mut buf).unwrap();
+        assert!(matches!(
+            read_server_request(&mut buf).unwrap(),
+            ServerRequest::MessageUsers { .. }
+        ));
+    }
+
+    #[test]
+    fn test_watch_user_country_code_is_optional() {
+        let cases = [
+            (UserStatus::Online, Some("LT")),
+            (UserStatus::Online, None),
+            (UserStatus::Offline, Some("DE")),
+            (UserStatus::Offline, None),
+        ];
+
+        for (status, country) in cases {
+            let mut buf = BytesMut::new();
+            ServerResponse::WatchUser {
+                username: "watched".to_string(),
+                exists: true,
+                status: Some(status),
+                stats: Some(UserStats {
+                    avg_speed: 100,
+                    upload_num: 2,
+                    unknown: 0,
+                    files: 30,
+                    dirs: 4,
+                }),
+                country_code: country.map(str::to_string),
+            }
+            .write_message(&mut buf).unwrap();
+
+            match read_server_message(&mut buf).unwrap() {
+                ServerResponse::WatchUser {
+                    status: decoded_status,
+                    stats,
+                    country_code,
+                    ..
+                } => {
+                    assert_eq!(decoded_status, Some(status));
+                    assert_eq!(stats.unwrap().files, 30);
+                    assert_eq!(country_code.as_deref(), country, "{status:?}");
+                }
+                other => panic!("unexpected response: {other:?}"),
+            }
+            assert!(buf.is_empty());
+        }
+    }
+
+    fn user_status(username: &str, status: UserStatus) -> ServerResponse {
+        ServerResponse::GetUserStatus {
+            username: username.to_string(),
+            status,
+            privileged: false,
+        }
+    }
+
+    #[test]
+    fn test_presence_tracker_changes() {
+        let mut presence = PresenceTracker::new();
+        assert!(matches!(
+            presence.watch("alice"),
+            ServerRequest::WatchUser { ref username } if username == "alice"
+        ));
+        presence.watch("bob");
+
+        presence.handle(&ServerResponse::WatchUser {
+            username: "alice".to_string(),
+            exists: true,
+            status: Some(UserStatus::Away),
+            stats: None,
+            country_code: None,
+        });
+        presence.handle(&ServerResponse::WatchUser {
+            username: "bob".to_string(),
+            exists: false,
+            status: None,
+            stats: None,
+            country_code: None,
+        });
+        presence.handle(&user_status("alice", UserStatus::Online));
+        // Repeats and unwatched users are not changes
+        presence.handle(&user_status("alice", UserStatus::Online));
+        presence.handle(&user_status("carol", UserStatus::Online));
+
+        assert_eq!(
+            presence.take_changes(),
+            vec![
+                ("alice".to_string(), UserStatus::Away),
+                ("bob".to_string(), UserStatus::Offline),
+                ("alice".to_string(), UserStatus::Online),
+            ]
+        );
+        assert!(presence.take_changes().is_empty());
+        assert_eq!(presence.status("alice"), Some(UserStatus::Online));
+        assert_eq!(presence.status("carol"), None);
+
+        presence.unwatch("alice");
+        presence.handle(&user_status("alice", UserStatus::Offline));
+        assert!(presence.take_changes().is_empty());
+        assert_eq!(presence.status("alice"), None);
+    }
+
+    #[test]
+    fn test_presence_tracker_callback() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut presence = PresenceTracker::new();
+        let sink = seen.clone();
+        presence.on_change(move |username, status| {
+            sink.lock().unwrap().push(format!("{username}={status}"));
+        });
+        presence.watch("dave");
+
+        presence.handle(&user_status("dave", UserStatus::Online));
+        presence.handle(&user_status("dave", UserStatus::Online));
+        presence.handle(&user_status("dave", UserStatus::Offline));
+
+        assert_eq!(*seen.lock().unwrap(), vec!["dave=Online", "dave=Offline"]);
+    }
+
+    #[test]
+    fn test_room_list_matches_wire_frame() {
+        // RoomList as the official server sends it: each room group is a list of
+        // names followed by a parallel list of user counts
+        #[rustfmt::skip]
+        let frame: &[u8] = &[
+            77, 0, 0, 0, 64, 0, 0, 0,
+            2, 0, 0, 0, 5, 0, 0, 0, b'l', b'o', b'b', b'b', b'y', 4, 0, 0, 0, b'j', b'a', b'z', b'z',
+            2, 0, 0, 0, 3, 0, 0, 0, 12, 0, 0, 0,
+            0, 0, 0, 0,
+            0, 0, 0, 0,
+            1, 0, 0, 0, 4, 0, 0, 0, b'c', b'l', b'u', b'b',
+            1, 0, 0, 0, 5, 0, 0, 0,
+            1, 0, 0, 0, 4, 0, 0, 0, b'c', b'l', b'u', b'b',
+        ];
+        let room_list = ServerResponse::RoomList {
+            rooms: vec![("lobby".to_string(), 3), ("jazz".to_string(), 12)],
+            owned_private_rooms: vec![],
+            private_rooms: vec![("club".to_string(), 5)],
+            operated_private_rooms: vec!["club".to_string()],
+        };
+
+        let mut buf = BytesMut::new();
+        room_list.write_message(&mut buf).unwrap();
+        assert_eq!(&buf[..], frame);
+
+        match read_server_message(&mut BytesMut::from(frame)).unwrap() {
+            ServerResponse::RoomList {
+                rooms,
+                owned_private_rooms,
+                private_rooms,
+                operated_private_rooms,
+            } => {
+                assert_eq!(rooms, vec![("lobby".to_string(), 3), ("jazz".to_string(), 12)]);
+                assert!(owned_private_rooms.is_empty());
+                assert_eq!(private_rooms, vec![("club".to_string(), 5)]);
+                assert_eq!(operated_private_rooms, vec!["club".to_string()]);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_global_room_roundtrip() {
+        let mut buf = BytesMut::new();
+        ServerRequest::JoinGlobalRoom.write_message(&mut buf).unwrap();
+        ServerRequest::LeaveGlobalRoom.write_message(&mut buf).unwrap();
+        assert!(matches!(
+            read_server_request(&mut buf).unwrap(),
+            ServerRequest::JoinGlobalRoom
+        ));
+        assert!(matches!(
+            read_server_request(&mut buf).unwrap(),
+            ServerRequest::LeaveGlobalRoom
+        ));
+
+        ServerResponse::GlobalRoomMessage {
+            room: "indie".to_string(),
+            username: "someone".to_string(),
+            message: "hi all".to_string(),
+        }
+        .write_message(&mut buf).unwrap();
+        match read_server_message(&mut buf).unwrap() {
+            ServerResponse::GlobalRoomMessage {
+                room,
+                username,
+                message,
+            } => {
+                assert_eq!(room, "indie");
+                assert_eq!(username, "someone");
+                assert_eq!(message, "hi all");
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_connection_login_and_room_list() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+
+            let mut read_buf = BytesMut::new();
+            let mut requests = Vec::new();
+            while requests.len() < 2 {
+                if read_buf.len() >= 4 {
+                    let len =
+                        u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]])
+                            as usize;
+                    if read_buf.len() >= 4 + len {
+                        let mut frame = read_buf.split_to(4 + len);
+                        let request = read_server_request(&mut frame).unwrap();
+                        let mut out = BytesMut::new();
+                        match &request {
+                            ServerRequest::Login { .. } => {
+                                // Unrelated messages may precede the login response
+                                ServerResponse::WishlistInterval { interval: 720 }
+                                    .write_message(&mut out).unwrap();
+                                ServerResponse::LoginSuccess {
+                                    greet: "hi".to_string(),
+                                    own_ip: Ipv4Addr::new(10, 0, 0, 1),
+                                    password_hash: "hash".to_string(),
+                                    is_supporter: false,
+                                }
+                                .write_message(&mut out).unwrap();
+                            }
+                            _ => {
+                                ServerResponse::RoomList {
+                                    rooms: vec![("lobby".to_string(), 3)],
+                                    owned_private_rooms: vec![],
+                                    private_rooms: vec![],
+                                    operated_private_rooms: vec![],
+                                }
+                                .write_message(&mut out).unwrap();
+                            }
+                        }
+                        writer.write_all(&out).await.unwrap();
+                        requests.push(request);
+                        continue;
+                    }
+                }
+                reader.read_buf(&mut read_buf).await.unwrap();
+            }
+            requests
+        });
+
+        let mut conn = ServerConnection::connect("127.0.0.1", port).await.unwrap();
+        let login = conn.login("user", "pass").await.unwrap();
+        assert_eq!(login.greet, "hi");
+        assert_eq!(login.own_ip, Ipv4Addr::new(10, 0, 0, 1));
+
+        conn.send(&ServerRequest::RoomList).await.unwrap();
+        match conn.next_message().await.unwrap() {
+            ServerResponse::RoomList { rooms, .. } => {
+                assert_eq!(rooms, vec![("lobby".to_string(), 3)]);
+            }
+            other => panic!("unexpected response: {other:?}"),
+        }
+
+        let requests = server.await.unwrap();
+        match &requests[0] {
+            ServerRequest::Login {
+                version,
+                minor_version,
+                ..
+            } => {
+                assert_eq!(*version, CLIENT_VERSION);
+                assert_eq!(*minor_version, CLIENT_MINOR_VERSION);
+            }
+            other => panic!("unexpected request: {other:?}"),
+        }
+
+        drop(conn);
+    }
+
+    #[test]
+    fn test_backoff_schedule() {
+        let config = BackoffConfig::default();
+        let other = Error::Protocol("boom".into());
+        let delays: Vec<u64> = (1..=5)
+            .map(|n| config.delay_for(n, &other).as_secs())
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+
+        let reset = Error::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(config.delay_for(2, &reset), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff_follows_schedule() {
+        let config = BackoffConfig {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(50),
+            rate_limit_delay: Duration::from_millis(5),
+        };
+        let mut attempts = Vec::new();
+        let mut retries = Vec::new();
+        let result = retry_with_backoff(
+            &config,
+            |n| {
+                attempts.push(Instant::now());
+                async move {
+                    if n < 4 {
+                        Err(Error::Protocol(format!("attempt {n} failed")))
+                    } else {
+                        Ok(n)
+                    }
+                }
+            },
+            |n, _, delay| retries.push((n, delay.as_millis())),
+        )
+        .await;
+        assert_eq!(result.unwrap(), 4);
+        assert_eq!(attempts.len(), 4);
+        assert_eq!(retries, [(1, 20), (2, 40), (3, 50)]);
+
+        let expected = [20, 40, 50];
+        for (gap, want) in attempts.windows(2).map(|w| w[1] - w[0]).zip(expected) {
+            assert!(gap >= Duration::from_millis(want), "{gap:?} < {want}ms");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff_gives_up() {
+        let config = BackoffConfig {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            rate_limit_delay: Duration::from_millis(1),
+        };
+        let mut calls = 0;
+        let result: Result<()> = retry_with_backoff(
+            &config,
+            |_| {
+                calls += 1;
+                async { Err(Error::Protocol("nope".into())) }
+            },
+            |_, _, _| {},
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        // Bad credentials are not retried
+        calls = 0;
+        let result: Result<()> = retry_with_backoff(
+            &config,
+            |_| {
+                calls += 1;
+                async {
+                    Err(Error::LoginFailed {
+                        reason: LoginRejectionReason::InvalidPassword,
+                        detail: None,
+                    })
+                }
+            },
+            |_, _, _| panic!("rejected logins are not retried"),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::LoginFailed { .. })));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_token_registry_take() {
+        let mut registry = TokenRegistry::new(Duration::from_secs(60));
+        let token = registry.issue("query".to_string());
+        assert!(registry.contains(token));
+        assert_eq!(registry.take(token).as_deref(), Some("query"));
+        assert_eq!(registry.take(token), None);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_token_registry_expiry() {
+        let mut registry = TokenRegistry::new(Duration::ZERO);
+        let token = registry.issue(1u32);
+        assert!(!registry.contains(token));
+        assert_eq!(registry.len(), 1);
+
+        let expired = registry.expire();
+        assert_eq!(expired, vec![(token, 1)]);
+        assert!(registry.is_empty());
+
+        registry.register(7, 2);
+        assert_eq!(registry.take(7), None);
+    }
+
+    #[test]
+    fn test_token_registry_issues_unique_tokens() {
+        let mut registry = TokenRegistry::new(Duration::from_secs(60));
+        registry.register(1, ());
+        registry.register(3, ());
+
+        let issued: Vec<u32> = (0..4).map(|_| registry.issue(())).collect();
+        assert_eq!(issued, vec![2, 4, 5, 6]);
+
+        // Wrapping past u32::MAX skips 0 and tokens still in use
+        registry.next_token = u32::MAX;
+        assert_eq!(registry.next_token(), u32::MAX);
+        assert_eq!(registry.next_token(), 7);
+    }
+
+    #[test]
+    fn test_privilege_state_tracks_privileged_users() {
+        let mut privileges = PrivilegeState::new();
+        assert!(!privileges.is_privileged("alice"));
+
+        assert!(privileges.handle(&ServerResponse::PrivilegedUsers {
+            users: vec!["alice".to_string(), "bob".to_string()],
+        }));
+        assert!(privileges.is_privileged("alice"));
+        assert!(privileges.is_privileged("bob"));
+        assert!(!privileges.is_privileged("carol"));
+
+        // A new list replaces the old one
+        privileges.handle(&ServerResponse::PrivilegedUsers {
+            users: vec!["carol".to_string()],
+        });
+        assert!(!privileges.is_privileged("alice"));
+        assert!(privileges.is_privileged("carol"));
+        assert_eq!(privileges.privileged_count(), 1);
+        assert!(!privileges.handle(&ServerResponse::Relogged));
+    }
+
+    #[test]
+    fn test_privilege_state_time_left() {
+        let mut privileges = PrivilegeState::new();
+        assert_eq!(privileges.time_left(), Duration::ZERO);
+
+        assert!(privileges.handle(&ServerResponse::CheckPrivileges { time_left: 3600 }));
+        let left = privileges.time_left();
+        assert!(left <= Duration::from_secs(3600));
+        assert!(left > Duration::from_secs(3590));
+
+        privileges.handle(&ServerResponse::CheckPrivileges { time_left: 0 });
+        assert_eq!(privileges.time_left(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_room_tickers_fold_state_and_updates() {
+        let pair = |user: &str, ticker: &str| (user.to_string(), ticker.to_string());
+        let mut tickers = RoomTickers::new();
+        assert!(tickers.current("indie").is_empty());
+
+        tickers.apply(&ServerResponse::RoomTickerState {
+            room: "indie".to_string(),
+            tickers: vec![
+                RoomTicker {
+                    username: "alice".to_string(),
+                    ticker: "hello".to_string(),
+                },
+                RoomTicker {
+                    username: "bob".to_string(),
+                    ticker: "listening to jazz".to_string(),
+                },
+            ],
+        });
+        assert_eq!(
+            tickers.current("indie"),
+            [pair("alice", "hello"), pair("bob", "listening to jazz")]
+        );
+
+        tickers.apply(&ServerResponse::RoomTickerAdd {
+            room: "indie".to_string(),
+            username: "carol".to_string(),
+            ticker: "hi all".to_string(),
+        });
+        // A second ticker from alice replaces her first
+        tickers.apply(&ServerResponse::RoomTickerAdd {
+            room: "indie".to_string(),
+            username: "alice".to_string(),
+            ticker: "back".to_string(),
+        });
+        assert!(tickers.apply(&ServerResponse::RoomTickerRemove {
+            room: "indie".to_string(),
+            username: "bob".to_string(),
+        }));
+        assert!(!tickers.apply(&ServerResponse::RoomTickerRemove {
+            room: "indie".to_string(),
+            username: "bob".to_string(),
+        }));
+        assert_eq!(
+            tickers.current("indie"),
+            [pair("carol", "hi all"), pair("alice", "back")]
+        );
+        assert!(tickers.current("jazz").is_empty());
+
+        // A fresh snapshot replaces everything, and leaving forgets the room
+        tickers.apply(&ServerResponse::RoomTickerState {
+            room: "indie".to_string(),
+            tickers: vec![],
+        });
+        assert!(tickers.current("indie").is_empty());
+        assert!(tickers.apply(&ServerResponse::LeaveRoom {
+            room: "indie".to_string(),
+        }));
+    }
+
+    fn room_user(username: &str) -> RoomUser {
+        RoomUser {
+            username: username.to_string(),
+            status: UserStatus::Online,
+            stats: UserStats::default(),
+            slots_full: false,
+            country_code: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_room_state_join_then_leave() {
+        let mut rooms = RoomState::new();
+        // Nothing is tracked for rooms we never joined
+        assert!(!rooms.apply(&ServerResponse::UserLeftRoom {
+            room: "indie".to_string(),
+            username: "alice".to_string(),
+        }));
+
+        rooms.apply(&ServerResponse::JoinRoom {
+            room: "indie".to_string(),
+            users: vec![room_user("alice"), room_user("bob")],
+            owner: None,
+            operators: vec![],
+        });
+        rooms.apply(&ServerResponse::UserJoinedRoom {
+            room: "indie".to_string(),
+            username: "carol".to_string(),
+            status: UserStatus::Online,
+            stats: UserStats::default(),
+            slots_full: false,
+            country_code: String::new(),
+        });
+        rooms.apply(&ServerResponse::UserLeftRoom {
+            room: "indie".to_string(),
+            username: "alice".to_string(),
+        });
+
+        let mut users: Vec<&str> = rooms
+            .users("indie")
+            .unwrap()
+            .iter()
+            .map(String::as_str)
+            .collect();
+        users.sort();
+        assert_eq!(users, vec!["bob", "carol"]);
+        assert_eq!(rooms.rooms().collect::<Vec<_>>(), vec!["indie"]);
+
+        assert!(rooms.apply(&ServerResponse::LeaveRoom {
+            room: "indie".to_string(),
+        }));
+        assert!(rooms.users("indie").is_none());
+        assert_eq!(rooms.recent_messages("indie").count(), 0);
+    }
+
+    #[test]
+    fn test_room_state_truncates_messages() {
+        let mut rooms = RoomState::with_history(2);
+        rooms.apply(&ServerResponse::JoinRoom {
+            room: "indie".to_string(),
+            users: vec![room_user("alice")],
+            owner: None,
+            operators: vec![],
+        });
+        for message in ["one", "two", "three"] {
+            assert!(rooms.apply(&ServerResponse::SayChatroom {
+                room: "indie".to_string(),
+                username: "alice".to_string(),
+                message: message.to_string(),
+            }));
+        }
+
+        let messages: Vec<&str> = rooms
+            .recent_messages("indie")
+            .map(|(_, message)| message.as_str())
+            .collect();
+        assert_eq!(messages, vec!["two", "three"]);
+    }
+
+    fn scores(pairs: &[(&str, i32)]) -> Vec<(String, i32)> {
+        pairs.iter().map(|(item, score)| (item.to_string(), *score)).collect()
+    }
+
+    fn items(recommendations: &[Recommendation]) -> Vec<&str> {
+        recommendations.iter().map(|r| r.item.as_str()).collect()
+    }
+
+    #[test]
+    fn test_recommendations_sorted_by_score() {
+        let mut recs = Recommendations::new();
+        assert!(recs.apply(&ServerResponse::Recommendations {
+            recommendations: scores(&[("jazz", 3), ("ambient", 9), ("dub", 3)]),
+            unrecommendations: scores(&[("pop", -1), ("country", -7)]),
+        }));
+        assert!(recs.apply(&ServerResponse::GlobalRecommendations {
+            recommendations: scores(&[("rock", 10), ("techno", 40)]),
+            unrecommendations: scores(&[("polka", -2)]),
+        }));
+        assert!(recs.apply(&ServerResponse::SimilarUsers {
+            users: vec![("bob".to_string(), 2), ("alice".to_string(), 5)],
+        }));
+        assert!(!recs.apply(&ServerResponse::Relogged));
+
+        assert_eq!(items(recs.recommendations()), vec!["ambient", "dub", "jazz"]);
+        assert_eq!(items(recs.unrecommendations()), vec!["country", "pop"]);
+        assert_eq!(items(recs.global_recommendations()), vec!["techno", "rock"]);
+        assert_eq!(recs.global_unrecommendations()[0].score, -2);
+        let similar: Vec<&str> = recs.similar_users().iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(similar, vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn test_item_recommendations_settle_pending() {
+        let mut recs = Recommendations::new();
+        let requests = recs.request_item("jazz");
+        assert!(matches!(
+            &requests[0],
+            ServerRequest::GetItemRecommendations { item } if item == "jazz"
+        ));
+        assert!(recs.is_pending("jazz"));
+        assert_eq!(recs.item_recommendations("jazz"), None);
+
+        recs.apply(&ServerResponse::ItemRecommendations {
+            item: "jazz".to_string(),
+            recommendations: scores(&[("bebop", 2), ("swing", 6)]),
+        });
+        assert!(recs.is_pending("jazz"));
+        recs.apply(&ServerResponse::ItemSimilarUsers {
+            item: "jazz".to_string(),
+            users: vec!["miles".to_string(), "duke".to_string()],
+        });
+        assert!(!recs.is_pending("jazz"));
+
+        assert_eq!(items(recs.item_recommendations("jazz").unwrap()), vec!["swing", "bebop"]);
+        assert_eq!(
+            recs.item_similar_users("jazz").unwrap(),
+            &["duke".to_string(), "miles".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_item_rerequest_waits_for_both_replies() {
+        let mut recs = Recommendations::new();
+        recs.request_item("jazz");
+        recs.apply(&ServerResponse::ItemRecommendations {
+            item: "jazz".to_string(),
+            recommendations: scores(&[("bebop", 2)]),
+        });
+        recs.apply(&ServerResponse::ItemSimilarUsers {
+            item: "jazz".to_string(),
+            users: vec!["miles".to_string()],
+        });
+        assert!(!recs.is_pending("jazz"));
+
+        recs.request_item("jazz");
+        assert_eq!(recs.item_recommendations("jazz"), None);
+        assert_eq!(recs.item_similar_users("jazz"), None);
+        recs.apply(&ServerResponse::ItemRecommendations {
+            item: "jazz".to_string(),
+            recommendations: scores(&[("swing", 6)]),
+        });
+        // The similar users from last time don't settle the new request
+        assert!(recs.is_pending("jazz"));
+        recs.apply(&ServerResponse::ItemSimilarUsers {
+            item: "jazz".to_string(),
+            users: vec!["duke".to_string()],
+        });
+        assert!(!recs.is_pending("jazz"));
+        assert_eq!(items(recs.item_recommendations("jazz").unwrap()), vec!["swing"]);
+    }
+
+    #[test]
+    fn test_interest_list_ignores_duplicate_like() {
+        let mut interests = InterestList::new();
+        assert!(matches!(
+            interests.add_like("jazz").as_slice(),
+            [ServerRequest::InterestAdd { item }] if item == "jazz"
+        ));
+        assert!(interests.add_like("jazz").is_empty());
+        assert_eq!(interests.likes(), &["jazz".to_string()]);
+
+        assert_eq!(interests.remove_like("jazz").len(), 1);
+        assert!(interests.remove_like("jazz").is_empty());
+        assert!(interests.likes().is_empty());
+    }
+
+    #[test]
+    fn test_interest_list_moves_like_to_hates() {
+        let mut interests = InterestList::new();
+        interests.add_like("jazz");
+        interests.add_like("dub");
+
+        let requests = interests.add_hate("jazz");
+        assert!(matches!(
+            requests.as_slice(),
+            [
+                ServerRequest::InterestRemove { item: removed },
+                ServerRequest::HatedInterestAdd { item: added },
+            ] if removed == "jazz" && added == "jazz"
+        ));
+        assert_eq!(interests.likes(), &["dub".to_string()]);
+        assert_eq!(interests.hates(), &["jazz".to_string()]);
+    }
+
+    fn manual_limiter(
+        max: usize,
+        window: Duration,
+    ) -> (SearchRateLimiter<&'static str, MockClock>, MockClock) {
+        let clock = MockClock::new();
+        let limiter = SearchRateLimiter::with_limit(max, window).with_clock(clock.clone());
+        (limiter, clock)
+    }
+
+    #[test]
+    fn test_drain_messages_keeps_partial_tail() {
+        let mut buf = BytesMut::new();
+        ServerResponse::Relogged.write_message(&mut buf).unwrap();
+        ServerResponse::AdminMessage {
+            message: "hello".to_string(),
+        }
+        .write_message(&mut buf).unwrap();
+        ServerResponse::ResetDistributed.write_message(&mut buf).unwrap();
+        let mut fourth = BytesMut::new();
+        ServerResponse::AdminMessage {
+            message: "cut short".to_string(),
+        }
+        .write_message(&mut fourth).unwrap();
+        buf.extend_from_slice(&fourth[..fourth.len() - 3]);
+
+        let messages: Vec<_> = drain_messages(&mut buf).map(Result::unwrap).collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                ServerResponse::Relogged,
+                ServerResponse::AdminMessage { message },
+                ServerResponse::ResetDistributed,
+            ] if message == "hello"
+        ));
+        assert_eq!(buf[..], fourth[..fourth.len() - 3]);
+
+        // The rest arrives and the fourth message comes through
+        buf.extend_from_slice(&fourth[fourth.len() - 3..]);
+        let rest: Vec<_> = drain_messages(&mut buf).map(Result::unwrap).collect();
+        assert!(matches!(
+            rest.as_slice(),
+            [ServerResponse::AdminMessage { message }] if message == "cut short"
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_search_filter_rejects_excluded_phrases() {
+        let filter = SearchFilter::from_excluded(
+            ["Taylor Swift", " banned ", ""].map(String::from),
+        );
+        assert_eq!(filter.phrases(), ["taylor swift", "banned"]);
+
+        assert!(!filter.is_allowed("taylor swift 1989"));
+        assert!(!filter.is_allowed("TAYLOR SWIFT"));
+        assert_eq!(filter.excluded_phrase("the BANNED album"), Some("banned"));
+        assert!(filter.is_allowed("taylor made swift"));
+        assert!(filter.is_allowed("aphex twin"));
+        assert!(SearchFilter::new().is_allowed("taylor swift"));
+    }
+
+    #[test]
+    fn test_search_filter_replaced_by_new_list() {
+        let mut filter = SearchFilter::from_excluded(["old".to_string()]);
+        assert!(filter.apply(&ServerResponse::ExcludedSearchPhrases {
+            phrases: vec!["New Phrase".to_string()],
+        }));
+        assert!(filter.is_allowed("old"));
+        assert!(!filter.is_allowed("a new phrase"));
+        assert!(!filter.apply(&ServerResponse::Relogged));
+    }
+
+    #[test]
+    fn test_search_rate_limiter_fills_window() {
+        let (mut limiter, _) = manual_limiter(3, Duration::from_secs(10));
+        for remaining in (1..=3).rev() {
+            assert!(limiter.can_search());
+            assert_eq!(limiter.searches_remaining(), remaining);
+            assert_eq!(limiter.time_until_next_slot(), None);
+            limiter.record();
+        }
+        assert!(!limiter.can_search());
+        assert_eq!(limiter.searches_remaining(), 0);
+
+        limiter.queue("later");
+        limiter.queue("much later");
+        assert_eq!(limiter.queued_count(), 2);
+        assert_eq!(limiter.pop_queued(), Some("later"));
+        assert_eq!(limiter.queued_count(), 1);
+    }
+
+    #[test]
+    fn test_search_rate_limiter_prunes_after_window() {
+        let (mut limiter, now) = manual_limiter(2, Duration::from_secs(10));
+        limiter.record();
+        now.advance(Duration::from_secs(4));
+        limiter.record();
+        assert!(!limiter.can_search());
+
+        // Exactly one window later the first search no longer counts
+        now.advance(Duration::from_secs(6));
+        assert!(limiter.can_search());
+        assert_eq!(limiter.searches_remaining(), 1);
+
+        now.advance(Duration::from_secs(10));
+        assert_eq!(limiter.searches_remaining(), 2);
+    }
+
+    #[test]
+    fn test_search_rate_limiter_time_until_next_slot() {
+        let (mut limiter, now) = manual_limiter(2, Duration::from_secs(10));
+        limiter.record();
+        now.advance(Duration::from_secs(3));
+        limiter.record();
+        assert_eq!(limiter.time_until_next_slot(), Some(Duration::from_secs(7)));
+
+        now.advance(Duration::from_secs(5));
+        assert_eq!(limiter.time_until_next_slot(), Some(Duration::from_secs(2)));
+
+        // Once the first search ages out, the second decides the wait
+        now.advance(Duration::from_secs(3));
+        assert_eq!(limiter.time_until_next_slot(), None);
+        limiter.record();
+        assert_eq!(limiter.time_until_next_slot(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_search_rate_limiter_frees_slots_at_cutoff() {
+        let (mut limiter, now) = manual_limiter(SEARCH_RATE_LIMIT_MAX, SEARCH_RATE_LIMIT_WINDOW);
+        for _ in 0..SEARCH_RATE_LIMIT_MAX {
+            limiter.record();
+        }
+        assert_eq!(limiter.time_until_next_slot(), Some(SEARCH_RATE_LIMIT_WINDOW));
+
+        now.advance(SEARCH_RATE_LIMIT_WINDOW - Duration::from_nanos(1));
+        assert_eq!(limiter.time_until_next_slot(), Some(Duration::from_nanos(1)));
+        assert!(!limiter.can_search());
+
+        // The whole burst ages out together, right when the wait runs out
+        now.advance(Duration::from_nanos(1));
+        assert_eq!(limiter.time_until_next_slot(), None);
+        assert!(limiter.can_search());
+        assert_eq!(limiter.searches_remaining(), SEARCH_RATE_LIMIT_MAX);
+    }
+
+    #[test]
+    fn test_search_rate_limiter_evicts_only_expired_front() {
+        let (mut limiter, now) = manual_limiter(3, Duration::from_secs(10));
+        limiter.record();
+        limiter.record();
+        now.advance(Duration::from_secs(5));
+        limiter.record();
+
+        // The two oldest expire; the newer one behind them stays
+        now.advance(Duration::from_secs(6));
+        assert_eq!(limiter.searches_remaining(), 2);
+        now.advance(Duration::from_secs(3));
+        assert_eq!(limiter.searches_remaining(), 2);
+        now.advance(Duration::from_secs(1));
+        assert_eq!(limiter.searches_remaining(), 3);
+    }
+
+    fn room_list() -> Vec<(String, u32)> {
+        [("indie", 80), ("jazz", 120), ("metal", 40), ("ambient", 120), ("pop", 60)]
+            .into_iter()
+            .map(|(name, users)| (name.to_string(), users))
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_rooms_min_users() {
+        assert_eq!(
+            filter_rooms(&room_list(), 60, usize::MAX, &[]),
+            vec!["ambient", "jazz", "indie", "pop"]
+        );
+        assert!(filter_rooms(&room_list(), 500, usize::MAX, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_filter_rooms_caps_count() {
+        assert_eq!(filter_rooms(&room_list(), 0, 2, &[]), vec!["ambient", "jazz"]);
+        assert!(filter_rooms(&room_list(), 0, 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_filter_rooms_excludes_names() {
+        let exclude = vec!["jazz".to_string(), "pop".to_string()];
+        assert_eq!(
+            filter_rooms(&room_list(), 50, 2, &exclude),
+            vec!["ambient", "indie"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metered_sender_counts_messages_and_bytes() {
+        let (tx, mut rx) = MeteredSender::channel();
+        let other = tx.clone();
+        for request in [
+            ServerRequest::ServerPing,
+            ServerRequest::SetStatus {
+                status: UserStatus::Online,
+            },
+        ] {
+            let mut buf = BytesMut::new();
+            request.write_message(&mut buf).unwrap();
+            tx.send(buf).unwrap();
+        }
+        other.send(BytesMut::from(&[0u8; 10][..])).unwrap();
+
+        // Ping is 8 bytes, SetStatus 12
+        let expected = SendStats {
+            messages: 3,
+            bytes: 30,
+            queued_messages: 3,
+            queued_bytes: 30,
+        };
+        assert_eq!(tx.stats(), expected);
+        assert_eq!(other.stats(), expected);
+
+        assert_eq!(rx.recv().await.unwrap().len(), 8);
+        assert_eq!(
+            rx.stats(),
+            SendStats {
+                queued_messages: 2,
+                queued_bytes: 22,
+                ..expected
+            }
+        );
+
+        // Nothing is counted once the writer has gone away
+        drop(rx);
+        assert!(tx.send(BytesMut::from(&b"late"[..])).is_err());
+        assert!(tx.is_closed());
+        assert_eq!(tx.stats().messages, 3);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_goes_offline_then_leaves_rooms() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            for room in ["jazz", "indie", "ambient"] {
+                ServerResponse::JoinRoom {
+                    room: room.to_string(),
+                    users: vec![],
+                    owner: None,
+                    operators: vec![],
+                }
+                .write_message(&mut buf).unwrap();
+            }
+            ServerResponse::LeaveRoom {
+                room: "ambient".to_string(),
+            }
+            .write_message(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+
+            // Read everything up to the client closing its end
+            let mut frames = FrameDecoder::new();
+            while stream.read_buf(frames.buffer_mut()).await.unwrap() > 0 {}
+            let mut requests = Vec::new();
+            while let Some(frame) = frames.next_frame() {
+                requests.push(read_server_request(&mut frame.unwrap()).unwrap());
+            }
+            requests
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = ServerConnection::from_stream(stream, ServerProfile::default());
+        for _ in 0..4 {
+            conn.next_message().await.unwrap();
+        }
+        assert!(conn.rooms().users("jazz").is_some());
+        conn.disconnect().await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(matches!(
+            requests[0],
+            ServerRequest::SetStatus {
+                status: UserStatus::Offline
+            }
+        ));
+        assert!(matches!(&requests[1], ServerRequest::LeaveRoom { room } if room == "indie"));
+        assert!(matches!(&requests[2], ServerRequest::LeaveRoom { room } if room == "jazz"));
+    }
+
+    #[tokio::test]
+    async fn test_announce_sends_status_then_wait_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frames = FrameDecoder::new();
+            let mut requests = Vec::new();
+            while requests.len() < 2 {
+                match frames.next_frame() {
+                    Some(frame) => {
+                        let mut frame = frame.unwrap();
+                        requests.push(read_server_request(&mut frame).unwrap());
+                    }
+                    None => {
+                        stream.read_buf(frames.buffer_mut()).await.unwrap();
+                    }
+                }
+            }
+            requests
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = ServerConnection::from_stream(stream, ServerProfile::default());
+        conn.announce(2234, UserStatus::Away).await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert!(matches!(
+            requests[0],
+            ServerRequest::SetStatus {
+                status: UserStatus::Away
+            }
+        ));
+        assert!(matches!(
+            requests[1],
+            ServerRequest::SetWaitPort {
+                port: 2234,
+                obfuscation: None
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_user_search_tokens_are_tracked_separately() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut read_buf = BytesMut::new();
+            let mut requests = Vec::new();
+            while requests.len() < 2 {
+                if read_buf.len() >= 4 {
+                    let len =
+                        u32::from_le_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]])
+                            as usize;
+                    if read_buf.len() >= 4 + len {
+                        let mut frame = read_buf.split_to(4 + len);
+                        requests.push(read_server_request(&mut frame).unwrap());
+                        continue;
+                    }
+                }
+                stream.read_buf(&mut read_buf).await.unwrap();
+            }
+            requests
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = ServerConnection::from_stream(stream, ServerProfile::default());
+        let mut searches = TokenRegistry::new(Duration::from_secs(60));
+        let global = conn
+            .start_search(&mut searches, PendingSearch::global("ambient"))
+            .await
+            .unwrap();
+        let user = conn
+            .start_search(&mut searches, PendingSearch::user("alice", "ambient"))
+            .await
+            .unwrap();
+        assert_ne!(global, user);
+
+        let requests = server.await.unwrap();
+        assert!(matches!(
+            &requests[0],
+            ServerRequest::FileSearch { token, .. } if *token == global
+        ));
+        assert!(matches!(
+            &requests[1],
+            ServerRequest::UserSearch { username, token, query }
+                if username == "alice" && *token == user && query == "ambient"
+        ));
+
+        let search = searches.match_search_response(user, "alice").unwrap();
+        assert_eq!(search.scope, SearchScope::User("alice".to_string()));
+        // Only alice can answer her user search; anyone can answer the global one
+        assert!(searches.match_search_response(user, "bob").is_none());
+        assert_eq!(
+            searches.match_search_response(global, "bob").unwrap().scope,
+            SearchScope::Global
+        );
+    }
+
+    async fn mock_search_peer(username: &str, token: u32, filenames: &[&str]) -> u32 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let response = PeerMessage::FileSearchResponse {
+            username: username.to_string(),
+            token,
+            results: filenames
+                .iter()
+                .map(|f| SearchResultFile {
+                    filename: f.to_string(),
+                    size: 1000,
+                    extension: "mp3".to_string(),
+                    attributes: vec![],
+                })
+                .collect(),
+            slot_free: true,
+            avg_speed: 100,
+            queue_length: 0,
+            private_results: vec![],
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            response.write_message(&mut buf).unwrap();
+            stream.write_all(&buf).await.unwrap();
+            // Keep the connection open like a real peer would
+            let _ = stream.read_buf(&mut BytesMut::new()).await;
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_search_session_collects_until_timeout() {
+        let timeout = Duration::from_millis(300);
+        let (session, peers) = SearchSession::new(42, timeout, 100);
+
+        let alice = mock_search_peer("alice", 42, &["a\\1.mp3", "a\\2.mp3"]).await;
+        let bob = mock_search_peer("bob", 42, &["b\\1.mp3"]).await;
+        let stale = mock_search_peer("carol", 7, &["c\\1.mp3"]).await;
+        for port in [alice, bob, stale] {
+            peers.connect_to_peer(Ipv4Addr::LOCALHOST, port, 1);
+        }
+
+        let started = std::time::Instant::now();
+        let mut results = session.collect().await;
+        assert!(started.elapsed() >= timeout);
+
+        results.sort_by(|a, b| a.file.filename.cmp(&b.file.filename));
+        let files: Vec<_> = results
+            .iter()
+            .map(|r| (r.username.as_str(), r.file.filename.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("alice", "a\\1.mp3"),
+                ("alice", "a\\2.mp3"),
+                ("bob", "b\\1.mp3")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_session_takes_routed_responses() {
+        let (session, peers) = SearchSession::new(9, Duration::from_secs(30), 100);
+        let response = |token| PeerMessage::FileSearchResponse {
+            username: "alice".to_string(),
+            token,
+            results: vec![SearchResultFile {
+                filename: "a\\1.flac".to_string(),
+                size: 1000,
+                extension: "flac".to_string(),
+                attributes: vec![],
+            }],
+            slot_free: false,
+            avg_speed: 100,
+            queue_length: 3,
+            private_results: vec![],
+        };
+        assert!(!peers.add_response(&response(8)));
+        assert!(peers.add_response(&response(9)));
+        // Once every handle is gone nothing more can arrive
+        drop(peers);
+
+        let results = session.collect().await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].availability(),
+            Some(PeerAvailability {
+                slot_free: false,
+                queue_length: 3,
+                avg_speed: 100
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_session_stops_at_max_results() {
+        let timeout = Duration::from_secs(30);
+        let (session, peers) = SearchSession::new(5, timeout, 2);
+
+        let port = mock_search_peer("alice", 5, &["1.mp3", "2.mp3", "3.mp3"]).await;
+        peers.connect_to_peer(Ipv4Addr::LOCALHOST, port, 1);
+
+        let started = std::time::Instant::now();
+        let results = session.collect().await;
+        assert!(started.elapsed() < timeout);
+        assert_eq!(results.len(), 2);
+    }
+
+    /// Read exactly one peer init frame, leaving whatever follows on the stream.
+    async fn read_peer_init(stream: &mut TcpStream) -> PeerInitMessage {
+        let len = stream.read_u32_le().await.unwrap();
+        let mut frame = BytesMut::zeroed(4 + len as usize);
+        frame[..4].copy_from_slice(&len.to_le_bytes());
+        stream.read_exact(&mut frame[4..]).await.unwrap();
+        read_peer_init_message(&mut frame).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_peer_connector_direct() {
+        let peer = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = peer.local_addr().unwrap().port() as u32;
+        let (requests_tx, mut requests_rx) = mpsc::unbounded_channel();
+        let connector = PeerConnector::new("me", requests_tx);
+
+        let (stream, accepted) = tokio::join!(
+            connector.connect("alice", Ipv4Addr::LOCALHOST, port, 9, ConnectionType::Peer),
+            peer.accept()
+        );
+        stream.unwrap();
+        let (mut incoming, _) = accepted.unwrap();
+
+        assert!(matches!(
+            read_peer_init(&mut incoming).await,
+            PeerInitMessage::PeerInit { username, connection_type: ConnectionType::Peer, token: 9 }
+                if username == "me"
+        ));
+        assert!(requests_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_peer_connector_falls_back_to_pierce_firewall() {
+        // A port nobody listens on stands in for a firewalled peer
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port() as u32;
+        drop(closed);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let (requests_tx, mut requests_rx) = mpsc::unbounded_channel();
+        let connector = PeerConnector::new("me", requests_tx);
+
+        let incoming = connector.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            if let PeerInitMessage::PierceFirewall { token } = read_peer_init(&mut stream).await {
+                incoming.pierce_firewall(token, stream).unwrap();
+            }
+        });
+
+        // The "server" relays ConnectToPeer, and the peer connects back to us
+        tokio::spawn(async move {
+            let Some(ServerRequest::ConnectToPeer {
+                token, username, ..
+            }) = requests_rx.recv().await
+            else {
+                panic!("expected ConnectToPeer");
+            };
+            assert_eq!(username, "alice");
+            let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+            let mut buf = BytesMut::new();
+            write_peer_init_message(&PeerInitMessage::PierceFirewall { token }, &mut buf).unwrap();
+            buf.extend_from_slice(b"hello");
+            stream.write_all(&buf).await.unwrap();
+            let _ = stream.read(&mut [0u8; 1]).await;
+        });
+
+        let mut stream = connector
+            .connect("alice", Ipv4Addr::LOCALHOST, port, 31, ConnectionType::Peer)
+            .await
+            .unwrap();
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_peer_connector_cant_connect() {
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port() as u32;
+        drop(closed);
+
+        let (requests_tx, mut requests_rx) = mpsc::unbounded_channel();
+        let connector = PeerConnector::new("me", requests_tx);
+
+        let server = connector.clone();
+        tokio::spawn(async move {
+            if let Some(ServerRequest::ConnectToPeer { token, .. }) = requests_rx.recv().await {
+                server.cant_connect(token);
+            }
+        });
+
+        match connector
+            .connect("alice", Ipv4Addr::LOCALHOST, port, 5, ConnectionType::Peer)
+            .await
+        {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_load_profile_login_uses_its_version() {
+        let config = r#"
+            [[profile]]
+            name = "official"
+            host = "server.slsknet.org"
+
+            [[profile]]
+            name = "legacy"
+            host = "legacy.example.org"
+            port = 2240
+            version = 157
+            minor_version = 19
+            hash_scheme = "none"
+        "#;
+        let profiles = ServerProfile::load_profiles(config).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].port, DEFAULT_SERVER_PORT);
+        assert_eq!(profiles[0].version, CLIENT_VERSION);
+
+        let legacy = ServerProfile::find(&profiles, "legacy").unwrap();
+        assert_eq!(legacy.port, 2240);
+        assert_eq!(legacy.hash_scheme, HashScheme::None);
+
+        let mut buf = BytesMut::new();
+        legacy.write_login("user", "pass", &mut buf);
+        match read_server_request(&mut buf.clone()).unwrap() {
+            ServerRequest::Login {
+                username,
+                version,
+                minor_version,
+                ..
+            } => {
+                assert_eq!(username, "user");
+                assert_eq!(version, 157);
+                assert_eq!(minor_version, 19);
+            }
+            other => panic!("unexpected request: {other:?}"),
+        }
+
+        // The default profile produces the same bytes as a plain Login request
+        let mut expected = BytesMut::new();
+        ServerRequest::Login {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+            version: CLIENT_VERSION,
+            minor_version: CLIENT_MINOR_VERSION,
+        }
+        .write_message(&mut expected).unwrap();
+        let mut actual = BytesMut::new();
+        ServerProfile::default().write_login("user", "pass", &mut actual);
+        assert_eq!(actual, expected);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_load_profiles_invalid() {
+        assert!(matches!(
+            ServerProfile::load_profiles("[[profile]]\nname = 1"),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_stream_partial_frame_then_eof() {
+        let (reader, mut writer) = tokio::io::duplex(64);
+        let mut messages = MessageStream::new(reader);
+
+        let mut frame = BytesMut::new();
+        ServerResponse::Relogged.write_message(&mut frame).unwrap();
+        ServerResponse::CheckPrivileges { time_left: 60 }.write_message(&mut frame).unwrap();
+
+        // Half of the first frame arrives, then nothing
+        writer.write_all(&frame[..5]).await.unwrap();
+        let err = messages
+            .next_message_timeout(Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout());
+
+        // The timed-out read kept the partial frame
+        writer.write_all(&frame[5..]).await.unwrap();
+        assert!(matches!(
+            messages.next_message_timeout(Duration::from_secs(1)).await,
+            Ok(Some(ServerResponse::Relogged))
+        ));
+        assert!(matches!(
+            messages.next_message_timeout(Duration::from_secs(1)).await,
+            Ok(Some(ServerResponse::CheckPrivileges { time_left: 60 }))
+        ));
+
+        drop(writer);
+        assert!(matches!(
+            messages.next_message_timeout(Duration::from_secs(1)).await,
+            Ok(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_stream_eof_mid_frame_is_error() {
+        let (reader, mut writer) = tokio::io::duplex(64);
+        let mut messages = MessageStream::new(reader);
+
+        let mut frame = BytesMut::new();
+        ServerResponse::Relogged.write_message(&mut frame).unwrap();
+        writer.write_all(&frame[..6]).await.unwrap();
+        drop(writer);
+
+        match messages.next_message().await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("unexpected result: {other:?}"),
+        }
     }
 }
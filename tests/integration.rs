@@ -13,7 +13,9 @@ use slsk_rs::peer::{
     FileAttribute, PeerMessage, SearchResultFile, SharedDirectory, SharedFile, read_peer_message,
 };
 use slsk_rs::peer_init::{PeerInitMessage, read_peer_init_message, write_peer_init_message};
-use slsk_rs::protocol::{ProtocolRead, ProtocolWrite, login_hash, zlib_compress, zlib_decompress};
+use slsk_rs::protocol::{
+    ProtocolRead, ProtocolWrite, login_hash, zlib_compress, zlib_compress_level, zlib_decompress,
+};
 use slsk_rs::server::{ServerCode, ServerRequest, UserStats};
 use std::net::Ipv4Addr;
 
@@ -143,6 +145,18 @@ mod compression {
         assert_eq!(decompressed, original);
         assert!(compressed.len() < original.len());
     }
+
+    #[test]
+    fn test_zlib_compression_levels() {
+        let original: Vec<u8> = (0..10000).map(|i| (i % 256) as u8).collect();
+        let fast = zlib_compress_level(&original, 1).unwrap();
+        let best = zlib_compress_level(&original, 9).unwrap();
+        assert!(best.len() < fast.len(), "{} >= {}", best.len(), fast.len());
+        assert_eq!(zlib_decompress(&fast).unwrap(), original);
+        assert_eq!(zlib_decompress(&best).unwrap(), original);
+        // Out of range levels clamp to the best compression
+        assert_eq!(zlib_compress_level(&original, 42).unwrap(), best);
+    }
 }
 
 mod login {
@@ -178,7 +192,7 @@ mod login {
             minor_version: 1,
         };
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        req.write_message(&mut buf).unwrap();
 
         // Verify structure: length (4) + code (4) + payload
         assert!(buf.len() > 8);
@@ -198,11 +212,10 @@ mod server_messages {
     fn test_set_wait_port_request() {
         let req = ServerRequest::SetWaitPort {
             port: 2234,
-            obfuscation_type: None,
-            obfuscated_port: None,
+            obfuscation: None,
         };
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        req.write_message(&mut buf).unwrap();
         assert!(buf.len() > 8);
     }
 
@@ -212,7 +225,7 @@ mod server_messages {
             username: "testuser".to_string(),
         };
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        req.write_message(&mut buf).unwrap();
         assert!(buf.len() > 8);
     }
 
@@ -223,7 +236,7 @@ mod server_messages {
             query: "pink floyd mp3".to_string(),
         };
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        req.write_message(&mut buf).unwrap();
         assert!(buf.len() > 8);
     }
 
@@ -234,7 +247,7 @@ mod server_messages {
             private: false,
         };
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        req.write_message(&mut buf).unwrap();
         assert!(buf.len() > 8);
     }
 
@@ -245,7 +258,7 @@ mod server_messages {
             message: "Hello there!".to_string(),
         };
         let mut buf = BytesMut::new();
-        req.write_message(&mut buf);
+        req.write_message(&mut buf).unwrap();
         assert!(buf.len() > 8);
     }
 
@@ -287,7 +300,7 @@ mod peer_messages {
             filename: "Music/Artist/Album/track01.mp3".to_string(),
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
 
         if let PeerMessage::QueueUpload { filename } = parsed {
@@ -306,7 +319,7 @@ mod peer_messages {
             file_size: None,
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
 
         if let PeerMessage::TransferRequest {
@@ -334,7 +347,7 @@ mod peer_messages {
             file_size: Some(50_000_000),
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
 
         if let PeerMessage::TransferRequest {
@@ -360,7 +373,7 @@ mod peer_messages {
             place: 5,
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
 
         if let PeerMessage::PlaceInQueueResponse { filename, place } = parsed {
@@ -377,7 +390,7 @@ mod peer_messages {
             filename: "missing.mp3".to_string(),
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
 
         if let PeerMessage::UploadFailed { filename } = parsed {
@@ -391,7 +404,7 @@ mod peer_messages {
     fn test_user_info_request_roundtrip() {
         let msg = PeerMessage::UserInfoRequest;
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
         assert!(matches!(parsed, PeerMessage::UserInfoRequest));
     }
@@ -400,7 +413,7 @@ mod peer_messages {
     fn test_shared_file_list_request_roundtrip() {
         let msg = PeerMessage::SharedFileListRequest;
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
         assert!(matches!(parsed, PeerMessage::SharedFileListRequest));
     }
@@ -412,7 +425,7 @@ mod peer_messages {
             folder: "Music/Jazz".to_string(),
         };
         let mut buf = BytesMut::new();
-        msg.write_message(&mut buf);
+        msg.write_message(&mut buf).unwrap();
         let parsed = read_peer_message(&mut buf.freeze()).unwrap();
 
         if let PeerMessage::FolderContentsRequest { token, folder } = parsed {
@@ -431,7 +444,7 @@ mod peer_init_messages {
     fn test_pierce_firewall_roundtrip() {
         let msg = PeerInitMessage::PierceFirewall { token: 0xCAFEBABE };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
         let parsed = read_peer_init_message(&mut buf.freeze()).unwrap();
 
         if let PeerInitMessage::PierceFirewall { token } = parsed {
@@ -449,7 +462,7 @@ mod peer_init_messages {
             token: 12345,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
         let parsed = read_peer_init_message(&mut buf.freeze()).unwrap();
 
         if let PeerInitMessage::PeerInit {
@@ -474,7 +487,7 @@ mod peer_init_messages {
             token: 99999,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
         let parsed = read_peer_init_message(&mut buf.freeze()).unwrap();
 
         if let PeerInitMessage::PeerInit {
@@ -499,7 +512,7 @@ mod peer_init_messages {
             token: 0,
         };
         let mut buf = BytesMut::new();
-        write_peer_init_message(&msg, &mut buf);
+        write_peer_init_message(&msg, &mut buf).unwrap();
         let parsed = read_peer_init_message(&mut buf.freeze()).unwrap();
 
         if let PeerInitMessage::PeerInit {
@@ -523,7 +536,7 @@ mod distributed_messages {
     fn test_ping_roundtrip() {
         let msg = DistributedMessage::Ping;
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
         assert!(matches!(parsed, DistributedMessage::Ping));
     }
@@ -537,7 +550,7 @@ mod distributed_messages {
             query: "beatles mp3".to_string(),
         };
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
 
         if let DistributedMessage::Search {
@@ -560,7 +573,7 @@ mod distributed_messages {
     fn test_branch_level_roundtrip() {
         let msg = DistributedMessage::BranchLevel { level: -1 };
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
 
         if let DistributedMessage::BranchLevel { level } = parsed {
@@ -576,7 +589,7 @@ mod distributed_messages {
             root: "rootuser".to_string(),
         };
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
 
         if let DistributedMessage::BranchRoot { root } = parsed {
@@ -590,7 +603,7 @@ mod distributed_messages {
     fn test_child_depth_roundtrip() {
         let msg = DistributedMessage::ChildDepth { depth: 3 };
         let mut buf = BytesMut::new();
-        write_distributed_message(&msg, &mut buf);
+        write_distributed_message(&msg, &mut buf).unwrap();
         let parsed = read_distributed_message(&mut buf.freeze()).unwrap();
 
         if let DistributedMessage::ChildDepth { depth } = parsed {
@@ -665,6 +678,18 @@ mod constants {
         assert_eq!(u32::from(UserStatus::Online), 2);
     }
 
+    #[test]
+    fn test_user_status_strings() {
+        assert_eq!(UserStatus::Offline.as_str(), "Offline");
+        assert_eq!(UserStatus::Away.as_str(), "Away");
+        assert_eq!(UserStatus::Online.to_string(), "Online");
+
+        for (i, status) in UserStatus::all().into_iter().enumerate() {
+            assert_eq!(u32::from(status), i as u32);
+            assert_eq!(UserStatus::try_from(u32::from(status)).unwrap(), status);
+        }
+    }
+
     #[test]
     fn test_upload_permission_conversions() {
         assert_eq!(
@@ -805,6 +830,29 @@ mod complex_structures {
         assert_eq!(parsed.attributes.len(), 2);
     }
 
+    #[test]
+    fn test_shared_file_over_4gb() {
+        let size = 1024 * 1024 * 1024 * 5; // 5GB
+        let file = SharedFile {
+            filename: "a.iso".to_string(),
+            size,
+            extension: "iso".to_string(),
+            attributes: vec![],
+        };
+        let mut buf = BytesMut::new();
+        file.write_to(&mut buf);
+
+        // Code byte, then the length-prefixed filename, then the size as the
+        // low 32 bits followed by the high 32 bits, i.e. a little-endian u64.
+        let size_at = 1 + 4 + file.filename.len();
+        assert_eq!(&buf[size_at..size_at + 4], &(size as u32).to_le_bytes());
+        assert_eq!(&buf[size_at + 4..size_at + 8], &((size >> 32) as u32).to_le_bytes());
+
+        let parsed = SharedFile::read_from(&mut buf.freeze()).unwrap();
+        assert_eq!(parsed.size, size);
+        assert_eq!(parsed.extension, "iso");
+    }
+
     #[test]
     fn test_shared_directory() {
         let dir = SharedDirectory {
@@ -859,4 +907,326 @@ mod complex_structures {
         assert_eq!(parsed.size, 10_000_000);
         assert_eq!(parsed.attributes.len(), 2);
     }
+
+    #[test]
+    fn test_search_result_file_over_4gb() {
+        let file = SearchResultFile {
+            filename: "Images\\a.iso".to_string(),
+            size: 1024 * 1024 * 1024 * 5, // 5GB
+            extension: "iso".to_string(),
+            attributes: vec![],
+        };
+        let mut buf = BytesMut::new();
+        file.write_to(&mut buf);
+        let parsed = SearchResultFile::read_from(&mut buf.freeze()).unwrap();
+        assert_eq!(parsed.size, 1024 * 1024 * 1024 * 5);
+        assert_eq!(parsed.extension, "iso");
+    }
+}
+
+mod frame_consistency {
+    use super::*;
+    use slsk_rs::FramedRead;
+    use slsk_rs::constants::{
+        LoginRejectionReason, ObfuscationType, TransferRejectionReason, UploadPermission,
+    };
+    use slsk_rs::server::{RoomUser, ServerResponse};
+    use std::fmt::Debug;
+
+    /// A message family and how it is framed on the wire.
+    trait Frame: FramedRead + MessageWrite + Debug {
+        fn write_frame(&self, buf: &mut BytesMut);
+    }
+
+    impl Frame for ServerRequest {
+        fn write_frame(&self, buf: &mut BytesMut) {
+            self.write_message(buf).unwrap();
+        }
+    }
+
+    impl Frame for ServerResponse {
+        fn write_frame(&self, buf: &mut BytesMut) {
+            self.write_message(buf).unwrap();
+        }
+    }
+
+    impl Frame for PeerMessage {
+        fn write_frame(&self, buf: &mut BytesMut) {
+            self.write_message(buf).unwrap();
+        }
+    }
+
+    impl Frame for PeerInitMessage {
+        fn write_frame(&self, buf: &mut BytesMut) {
+            write_peer_init_message(self, buf).unwrap();
+        }
+    }
+
+    impl Frame for DistributedMessage {
+        fn write_frame(&self, buf: &mut BytesMut) {
+            write_distributed_message(self, buf).unwrap();
+        }
+    }
+
+    /// Write `msg`, check its length prefix covers exactly the bytes written,
+    /// and check it reads back as the same message type without leftovers.
+    fn assert_frame_consistent<M: Frame>(msg: &M)
+    where
+        M::Code: PartialEq + Debug,
+    {
+        let mut buf = BytesMut::new();
+        msg.write_frame(&mut buf);
+
+        let declared = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        assert_eq!(declared + 4, buf.len(), "length prefix of {msg:?}");
+
+        let mut frame = buf.freeze();
+        let decoded = M::read_frame(&mut frame)
+            .unwrap_or_else(|e| panic!("{msg:?} did not read back: {e}"));
+        assert!(frame.is_empty(), "{msg:?} left {} bytes unread", frame.len());
+        assert_eq!(decoded.code(), msg.code(), "{msg:?} decoded as {decoded:?}");
+    }
+
+    fn s(value: &str) -> String {
+        value.to_string()
+    }
+
+    fn directory() -> SharedDirectory {
+        SharedDirectory {
+            path: s("Music\\Album"),
+            files: vec![SharedFile {
+                filename: s("01 Track.flac"),
+                size: 30_000_000,
+                extension: s("flac"),
+                attributes: vec![FileAttribute { code: 4, value: 44_100 }],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_server_request_frames() {
+        let requests = [
+            ServerRequest::Login {
+                username: s("alice"),
+                password: s("secret"),
+                version: 160,
+                minor_version: 1,
+            },
+            ServerRequest::SetWaitPort {
+                port: 2234,
+                obfuscation: Some((ObfuscationType::Rotated, 2235)),
+            },
+            ServerRequest::ServerPing,
+            ServerRequest::SetStatus {
+                status: UserStatus::Away,
+            },
+            ServerRequest::ConnectToPeer {
+                token: 1,
+                username: s("bob"),
+                connection_type: ConnectionType::Distributed,
+            },
+            ServerRequest::MessageUsers {
+                usernames: vec![s("bob"), s("carol")],
+                message: s("hi"),
+            },
+            ServerRequest::HaveNoParent { no_parent: true },
+        ];
+        for req in &requests {
+            assert_frame_consistent(req);
+        }
+    }
+
+    #[test]
+    fn test_server_response_frames() {
+        let stats = UserStats {
+            avg_speed: 1000,
+            upload_num: 2,
+            unknown: 0,
+            files: 30,
+            dirs: 3,
+        };
+        let responses = [
+            ServerResponse::LoginFailure {
+                reason: LoginRejectionReason::InvalidPassword,
+                detail: Some(s("wrong")),
+            },
+            ServerResponse::GetPeerAddress {
+                username: s("bob"),
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+                port: 2234,
+                obfuscation_type: ObfuscationType::Rotated,
+                obfuscated_port: 2235,
+            },
+            ServerResponse::JoinRoom {
+                room: s("lobby"),
+                users: vec![RoomUser {
+                    username: s("bob"),
+                    status: UserStatus::Online,
+                    stats: stats.clone(),
+                    slots_full: false,
+                    country_code: s("LT"),
+                }],
+                owner: None,
+                operators: vec![],
+            },
+            ServerResponse::MessageUser {
+                id: 9,
+                timestamp: 1_700_000_000,
+                username: s("bob"),
+                message: s("hello"),
+                new_message: true,
+            },
+            ServerResponse::GetUserStats {
+                username: s("bob"),
+                stats,
+            },
+            ServerResponse::Relogged,
+            ServerResponse::RoomList {
+                rooms: vec![(s("lobby"), 12)],
+                owned_private_rooms: vec![],
+                private_rooms: vec![(s("secret"), 2)],
+                operated_private_rooms: vec![s("secret")],
+            },
+        ];
+        for resp in &responses {
+            assert_frame_consistent(resp);
+        }
+    }
+
+    #[test]
+    fn test_peer_message_frames() {
+        let messages = [
+            PeerMessage::SharedFileListRequest,
+            PeerMessage::SharedFileListResponse {
+                directories: vec![directory()],
+                private_directories: vec![],
+            },
+            PeerMessage::FileSearchResponse {
+                username: s("bob"),
+                token: 5,
+                results: vec![SearchResultFile {
+                    filename: s("Music\\Album\\01 Track.mp3"),
+                    size: 5_000_000,
+                    extension: s("mp3"),
+                    attributes: vec![FileAttribute { code: 0, value: 320 }],
+                }],
+                slot_free: true,
+                avg_speed: 1000,
+                queue_length: 0,
+                private_results: vec![],
+            },
+            PeerMessage::UserInfoResponse {
+                description: s("hi"),
+                picture: Some(vec![1, 2, 3]),
+                total_uploads: 4,
+                queue_size: 0,
+                slots_free: true,
+                upload_permitted: Some(UploadPermission::Everyone),
+            },
+            PeerMessage::FolderContentsResponse {
+                token: 6,
+                folder: s("Music\\Album"),
+                directories: vec![directory()],
+            },
+            PeerMessage::TransferRequest {
+                direction: TransferDirection::Upload,
+                token: 7,
+                filename: s("Music\\Album\\01 Track.flac"),
+                file_size: Some(30_000_000),
+            },
+            PeerMessage::TransferResponse {
+                token: 7,
+                allowed: false,
+                file_size: None,
+                reason: Some(TransferRejectionReason::Queued),
+            },
+            PeerMessage::PlaceInQueueResponse {
+                filename: s("Music\\Album\\01 Track.flac"),
+                place: 3,
+            },
+        ];
+        for msg in &messages {
+            assert_frame_consistent(msg);
+        }
+    }
+
+    #[test]
+    fn test_peer_init_frames() {
+        assert_frame_consistent(&PeerInitMessage::PierceFirewall { token: 42 });
+        assert_frame_consistent(&PeerInitMessage::PeerInit {
+            username: s("alice"),
+            connection_type: ConnectionType::File,
+            token: 43,
+        });
+    }
+
+    #[test]
+    fn test_distributed_frames() {
+        let search = DistributedMessage::Search {
+            unknown: 0x31,
+            username: s("alice"),
+            token: 44,
+            query: s("some album"),
+        };
+        let messages = [
+            DistributedMessage::Ping,
+            DistributedMessage::BranchLevel { level: 2 },
+            DistributedMessage::BranchRoot { root: s("root") },
+            DistributedMessage::ChildDepth { depth: 1 },
+            DistributedMessage::embed(&search),
+            search,
+        ];
+        for msg in &messages {
+            assert_frame_consistent(msg);
+        }
+    }
+}
+
+mod browse {
+    use super::*;
+    use slsk_rs::constants::{DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT};
+    use slsk_rs::net::TransferTimeouts;
+    use slsk_rs::peer::connect_to_peer_and_browse;
+    use slsk_rs::server::{ServerConnection, ServerResponse};
+
+    /// Browse the user named by `SLSK_BROWSE_USER` on the live network.
+    #[tokio::test]
+    async fn test_browse_user_from_env() {
+        let (Some((username, password)), Ok(peer_username)) =
+            (load_env(), std::env::var("SLSK_BROWSE_USER"))
+        else {
+            eprintln!("Skipping test: SLSK_USERNAME/SLSK_PASSWORD/SLSK_BROWSE_USER not set");
+            return;
+        };
+
+        let mut conn = ServerConnection::connect(DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT)
+            .await
+            .unwrap();
+        conn.login(&username, &password).await.unwrap();
+        conn.send(&ServerRequest::GetPeerAddress {
+            username: peer_username.clone(),
+        })
+        .await
+        .unwrap();
+
+        let (ip, port) = loop {
+            if let ServerResponse::GetPeerAddress {
+                username, ip, port, ..
+            } = conn.next_message().await.unwrap()
+                && username == peer_username
+            {
+                break (ip, port);
+            }
+        };
+        if ip == Ipv4Addr::UNSPECIFIED {
+            eprintln!("Skipping test: {peer_username} is offline");
+            return;
+        }
+
+        let addr = format!("{ip}:{port}");
+        let directories = connect_to_peer_and_browse(&username, 0, &addr, &TransferTimeouts::new())
+            .await
+            .unwrap();
+        assert!(directories.iter().all(|dir| !dir.path.is_empty()));
+    }
 }